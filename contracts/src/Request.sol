@@ -12,22 +12,104 @@ interface IERC20 {
 }
 
 contract Request {
+    struct TokenInfo {
+        // 地址上没有合约代码时为 false 其余字段无意义
+        bool success;
+        bytes name;
+        bytes symbol;
+        bool decimalsKnown;
+        uint8 decimals;
+        uint256 totalSupply;
+    }
+
+    /// 低级调用 方法不存在 / revert 时返回 ok = false 而不是整个调用 revert
+    function _call(
+        address token,
+        bytes4 selector
+    ) internal view returns (bool ok, bytes memory data) {
+        (ok, data) = token.staticcall{gas: 100000}(
+            abi.encodeWithSelector(selector)
+        );
+        if (!ok) {
+            data = "";
+        }
+    }
+
+    function _callUint(
+        address token,
+        bytes4 selector
+    ) internal view returns (bool ok, uint256 value) {
+        bytes memory data;
+        (ok, data) = _call(token, selector);
+        if (!ok || data.length < 32) {
+            return (false, 0);
+        }
+        value = abi.decode(data, (uint256));
+    }
+
+    function _tokenInfo(
+        address tokenAddress
+    )
+        internal
+        view
+        returns (
+            bytes memory name,
+            bytes memory symbol,
+            bool decimalsKnown,
+            uint8 decimals,
+            uint256 totalSupply
+        )
+    {
+        (, name) = _call(tokenAddress, IERC20.name.selector);
+        (, symbol) = _call(tokenAddress, IERC20.symbol.selector);
+        (bool ok, uint256 value) = _callUint(
+            tokenAddress,
+            IERC20.decimals.selector
+        );
+        if (ok && value <= type(uint8).max) {
+            decimalsKnown = true;
+            decimals = uint8(value);
+        }
+        (, totalSupply) = _callUint(tokenAddress, IERC20.totalSupply.selector);
+    }
+
+    /// name / symbol 原样返回调用的返回数据
+    /// 标准代币返回 string 早期代币(MKR SAI)返回 bytes32 由调用方解码 调用失败时为空
     function getTokenInfo(
         address tokenAddress
     )
         external
         view
         returns (
-            string memory name,
-            string memory symbol,
+            bytes memory name,
+            bytes memory symbol,
+            bool decimalsKnown,
             uint8 decimals,
             uint256 totalSupply
         )
     {
-        IERC20 token = IERC20(tokenAddress);
-        name = token.name();
-        symbol = token.symbol();
-        decimals = token.decimals();
-        totalSupply = token.totalSupply();
+        return _tokenInfo(tokenAddress);
+    }
+
+    /// 一次调用读取多个代币 单个代币失败不影响其他代币
+    function getTokenInfos(
+        address[] calldata tokenAddresses
+    ) external view returns (TokenInfo[] memory infos) {
+        infos = new TokenInfo[](tokenAddresses.length);
+        for (uint256 i = 0; i < tokenAddresses.length; i++) {
+            address tokenAddress = tokenAddresses[i];
+            if (tokenAddress.code.length == 0) {
+                continue;
+            }
+            TokenInfo memory info = infos[i];
+            info.success = true;
+            (
+                info.name,
+                info.symbol,
+                info.decimalsKnown,
+                info.decimals,
+                info.totalSupply
+            ) = _tokenInfo(tokenAddress);
+        }
     }
 }
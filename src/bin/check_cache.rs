@@ -0,0 +1,26 @@
+use anyhow::Result;
+use log::info;
+use sandwinch_rs::common::{
+    cache_check::{repair_all, validate_all},
+    store::{Store, STORE_PATH},
+    utils::setup_logger,
+};
+
+/// 校验池子 / 代币缓存
+/// cargo run --bin check_cache            列出坏行(带行号) / 重复地址 / id 不递增 / 无效的池子关系
+/// cargo run --bin check_cache -- --repair 重写干净的 csv 缓存并清理数据库
+fn main() -> Result<()> {
+    setup_logger()?;
+    let repair = std::env::args().any(|arg| arg == "--repair");
+    let mut store = Store::open(STORE_PATH)?;
+    let issues = validate_all(&store)?;
+    for issue in &issues {
+        info!("{}", issue);
+    }
+    info!("Found {:?} issues", issues.len());
+    if repair && !issues.is_empty() {
+        repair_all(&mut store)?;
+        info!("Remaining issues: {:?}", validate_all(&store)?.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use anyhow::{anyhow, Result};
+use sandwinch_rs::common::{
+    dataset::{export_dataset, import_dataset},
+    store::Store,
+    utils::setup_logger,
+};
+
+/// 池子 / 代币数据集的导出与导入
+/// cargo run --bin dataset -- export cache/dataset.jsonl.gz
+/// cargo run --bin dataset -- import cache/dataset.jsonl.gz
+/// 导入之后正常启动 会从数据集的同步进度开始增量同步
+fn main() -> Result<()> {
+    setup_logger()?;
+    let args: Vec<String> = std::env::args().collect();
+    let (command, path) = match (args.get(1), args.get(2)) {
+        (Some(command), Some(path)) => (command.as_str(), path.as_str()),
+        _ => return Err(anyhow!("Usage: dataset <export|import> <path>")),
+    };
+    let mut store = Store::open_default()?;
+    match command {
+        "export" => export_dataset(&store, path),
+        "import" => import_dataset(&mut store, path),
+        _ => Err(anyhow!("Unknown command: {:?}", command)),
+    }
+}
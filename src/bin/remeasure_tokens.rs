@@ -0,0 +1,31 @@
+use anyhow::Result;
+use dotenv::dotenv;
+use sandwinch_rs::common::{
+    constants::Env,
+    risk::remeasure_stale_tokens,
+    transport::connect_provider,
+    utils::{init_main_currencies, setup_logger},
+};
+
+/// 重新测量过期的代币税率和风险属性
+/// cargo run --bin remeasure_tokens -- [max_age_blocks] [limit]
+/// 默认重新测量一周前(50400 个区块)测量的代币
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+    setup_logger()?;
+    init_main_currencies()?;
+    let args: Vec<String> = std::env::args().collect();
+    let max_age = match args.get(1) {
+        Some(arg) => arg.parse()?,
+        None => 50400,
+    };
+    let limit = match args.get(2) {
+        Some(arg) => arg.parse()?,
+        None => usize::MAX,
+    };
+    let env = Env::new()?;
+    let provider = connect_provider(&env.rpc_url).await?;
+    remeasure_stale_tokens(provider, max_age, limit).await?;
+    Ok(())
+}
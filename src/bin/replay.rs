@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use dotenv::dotenv;
+use log::info;
+use sandwinch_rs::{
+    common::{
+        transport::connect_provider,
+        utils::{init_main_currencies, setup_logger},
+    },
+    sandwich::{
+        bus::{BusConfig, EventBus},
+        replay::{replay_recordings, ReplayTiming},
+        strategy::run_sandwich_strategy,
+    },
+};
+use tokio::time::sleep;
+
+/// 不连接节点 回放记录的数据流运行策略
+/// cargo run --bin replay -- <记录文件或目录> <fixture 文件> [original|asap|<加速倍数>]
+/// 数据流用 RECORD_DIR 录制 节点请求用 RPC_FIXTURE_RECORD 录制 (同一次运行)
+/// asap 每个事件都等策略处理完再发送下一个 同样的输入每次运行结果一样
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+    setup_logger()?;
+    init_main_currencies()?;
+    let args: Vec<String> = std::env::args().collect();
+    let (recordings, fixture) = match (args.get(1), args.get(2)) {
+        (Some(recordings), Some(fixture)) => (recordings.clone(), fixture.clone()),
+        _ => {
+            return Err(anyhow!(
+                "Usage: replay <recordings> <fixture> [original|asap|<factor>]"
+            ))
+        }
+    };
+    let timing: ReplayTiming = match args.get(3) {
+        Some(arg) => arg.parse()?,
+        None => ReplayTiming::Asap,
+    };
+    let provider = connect_provider(&format!("fixture://{}", fixture)).await?;
+    let bus = EventBus::new(BusConfig::default());
+    let strategy = tokio::spawn(run_sandwich_strategy(provider, bus.clone()));
+    // 策略加载完池子 / 代币之后才订阅 在这之前发布的事件会丢失
+    while bus.subscriber_count() == 0 {
+        if strategy.is_finished() {
+            return Err(anyhow!("Strategy stopped before subscribing"));
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    replay_recordings(recordings, bus.clone(), timing).await?;
+    bus.wait_idle().await;
+    bus.log_stats();
+    info!("Replay finished");
+    Ok(())
+}
@@ -11,6 +11,12 @@ pub struct Abi {
     pub token: BaseContract,
     /// 三明治机器人合约的 ABI,用于执行套利交易
     pub sando_bot: BaseContract,
+    /// Balancer V2 Vault 的 ABI,用于查询池子余额
+    pub balancer_vault: BaseContract,
+    /// Balancer V2 加权池的 ABI,用于查询权重和手续费
+    pub balancer_pool: BaseContract,
+    /// Multicall3 的 ABI,用于一次请求读取大量池子的储备量
+    pub multicall: BaseContract,
 }
 
 impl Abi {
@@ -25,6 +31,7 @@ impl Abi {
                 "function token0() external view returns (address)",
                 "function token1() external view returns (address)",
                 "function getReserves() external view returns (uint112,uint112,uint32)",
+                "function swap(uint256,uint256,address,bytes) external",
             ])
             .unwrap(),
         );
@@ -48,11 +55,68 @@ impl Abi {
             parse_abi(&["function recoverToken(address,uint256) public"]).unwrap(),
         );
 
+        let balancer_vault = BaseContract::from(
+            parse_abi(&[
+                "function getPoolTokens(bytes32) external view returns (address[],uint256[],uint256)",
+            ])
+            .unwrap(),
+        );
+
+        let balancer_pool = BaseContract::from(
+            parse_abi(&[
+                "function getPoolId() external view returns (bytes32)",
+                "function getNormalizedWeights() external view returns (uint256[])",
+                "function getSwapFeePercentage() external view returns (uint256)",
+            ])
+            .unwrap(),
+        );
+
+        let multicall = BaseContract::from(
+            // parse_abi 不认识 (address,bool,bytes)[] 这种元组数组 要用 struct 声明
+            parse_abi(&[
+                "struct Call3 { address target; bool allowFailure; bytes callData; }",
+                "struct Result { bool success; bytes returnData; }",
+                "function aggregate3(Call3[] calls) external payable returns (Result[] returnData)",
+            ])
+            .unwrap(),
+        );
+
         Self {
             factory,
             pair,
             token,
             sando_bot,
+            balancer_vault,
+            balancer_pool,
+            multicall,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::ParamType;
+
+    #[test]
+    fn multicall_tuple_arrays() {
+        let abi = Abi::new();
+        let function = abi.multicall.abi().function("aggregate3").unwrap();
+        assert_eq!(
+            function.inputs[0].kind,
+            ParamType::Array(Box::new(ParamType::Tuple(vec![
+                ParamType::Address,
+                ParamType::Bool,
+                ParamType::Bytes,
+            ])))
+        );
+        assert_eq!(function.outputs.len(), 1);
+        assert_eq!(
+            function.outputs[0].kind,
+            ParamType::Array(Box::new(ParamType::Tuple(vec![
+                ParamType::Bool,
+                ParamType::Bytes,
+            ])))
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::common::constants::Env;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use ethers::types::{H256, U64};
 use teloxide::prelude::*;
 use teloxide::types::ChatId;
@@ -9,22 +9,28 @@ pub struct Alert {
 }
 // telegram 警告
 impl Alert {
-    pub fn new() -> Self {
-        let env = Env::new();
+    pub fn new() -> Result<Self> {
+        let env = Env::new()?;
         // 如果启用了警报功能
         if env.use_alert {
             let bot = Bot::from_env(); // 从环境变量创建机器人
-            let chat_id = ChatId(env.telegram_chat_id.parse::<i64>().unwrap());
-            Self {
+            let chat_id = env.telegram_chat_id.parse::<i64>().map_err(|e| {
+                anyhow!(
+                    "TELEGRAM_CHAT_ID = {:?} is invalid: {:?}",
+                    env.telegram_chat_id,
+                    e
+                )
+            })?;
+            Ok(Self {
                 bot: Some(bot),
-                chat_id: Some(chat_id),
-            }
+                chat_id: Some(ChatId(chat_id)),
+            })
         } else {
             // 未启用警报时返回空实例
-            Self {
+            Ok(Self {
                 bot: None,
                 chat_id: None,
-            }
+            })
         }
     }
     // 发送消息
@@ -0,0 +1,483 @@
+////////////////////////////////////////
+////Balancer V2 加权池///
+////////////////////////////////////////
+
+use anyhow::{anyhow, Result};
+use csv::StringRecord;
+use ethers::{
+    abi::{parse_abi, ParamType},
+    types::{Filter, TransactionRequest, H160, H256, U256, U64},
+};
+use ethers_providers::Middleware;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    abi::Abi,
+    constants::{BALANCER_VAULT, BALANCER_VAULT_DEPLOY_BLOCK},
+    store::{Store, BALANCER_POOLS_CHECKPOINT},
+    transport::RpcProvider,
+    utils::{csv_field, to_h160},
+};
+
+/// Balancer 的所有 swap 都由 Vault 发出 Swap 事件
+/// event Swap(bytes32 indexed poolId, address indexed tokenIn, address indexed tokenOut, uint256 amountIn, uint256 amountOut)
+pub static BALANCER_SWAP_EVENT_ID: &str = "0x2170c741";
+/// SwapInfo.version 中 Balancer V2 的标识 与 UniswapV2=2 区分
+pub static BALANCER_V2_VERSION: u8 = 20;
+/// 加权池的权重和手续费都是 18 位定点数
+pub static BALANCER_ONE: f64 = 1e18;
+/// 加权池单笔输入不能超过 balanceIn 的 30%
+pub static BALANCER_MAX_IN_RATIO: f64 = 0.3;
+
+/// Balancer V2 加权池
+/// 与 UniswapV2 不同 池子以 poolId 标识 代币数量不固定
+#[derive(Debug, Clone)]
+pub struct BalancerPool {
+    /// Vault 中的池子 id (前 20 字节就是池子地址)
+    pub pool_id: H256,
+    /// 池子合约地址
+    pub address: H160,
+    /// 池子内的代币 顺序与 Vault 注册顺序一致
+    pub tokens: Vec<H160>,
+    /// 归一化权重 18 位定点数 与 tokens 一一对应
+    pub weights: Vec<U256>,
+    /// swap 手续费 18 位定点数 (例如 0.3% = 3e15)
+    pub swap_fee: U256,
+    /// 注册池子的区块号
+    pub block_number: u64,
+}
+impl BalancerPool {
+    pub fn cache_row(&self) -> (String, String, String, String, String, u64) {
+        (
+            format!("{:?}", self.pool_id),
+            format!("{:?}", self.address),
+            self.tokens
+                .iter()
+                .map(|t| format!("{:?}", t))
+                .collect::<Vec<String>>()
+                .join(";"),
+            self.weights
+                .iter()
+                .map(|w| w.to_string())
+                .collect::<Vec<String>>()
+                .join(";"),
+            self.swap_fee.to_string(),
+            self.block_number,
+        )
+    }
+    /// 代币在池子中的下标
+    pub fn token_index(&self, token: H160) -> Option<usize> {
+        self.tokens.iter().position(|t| *t == token)
+    }
+}
+// 接受csv 字符 转换为BalancerPool实例 格式不对的行返回错误而不是 panic
+impl TryFrom<StringRecord> for BalancerPool {
+    type Error = anyhow::Error;
+
+    fn try_from(record: StringRecord) -> Result<Self> {
+        Ok(Self {
+            pool_id: H256::from_str(csv_field(&record, 0, "pool_id")?)?,
+            address: H160::from_str(csv_field(&record, 1, "address")?)?,
+            tokens: csv_field(&record, 2, "tokens")?
+                .split(';')
+                .map(H160::from_str)
+                .collect::<Result<Vec<H160>, _>>()?,
+            weights: csv_field(&record, 3, "weights")?
+                .split(';')
+                .map(U256::from_dec_str)
+                .collect::<Result<Vec<U256>, _>>()?,
+            swap_fee: U256::from_dec_str(csv_field(&record, 4, "swap_fee")?)?,
+            block_number: csv_field(&record, 5, "block_number")?.parse()?,
+        })
+    }
+}
+
+// 加载所有的 Balancer 加权池
+// 与 load_all_pools 一样的流程: 读数据库 -> 从 checkpoint 继续扫描 -> 写入新增的池子
+// 旧的 csv 缓存在第一次打开数据库时导入
+pub async fn load_all_balancer_pools(
+    provider: &Arc<RpcProvider>,
+    store: &Mutex<Store>,
+    chunk: u64,
+) -> Result<Vec<BalancerPool>> {
+    let mut pools = store.lock().unwrap().load_balancer_pools()?;
+    info!("Balancer pools loaded: {:?}", pools.len());
+
+    // 扫描进度记录在数据库的 sync_checkpoints 里 每完成一个范围推进一次
+    // 没有 checkpoint 的旧缓存从最新的池子的下一个区块开始
+    let checkpoint = store
+        .lock()
+        .unwrap()
+        .get_checkpoint(BALANCER_POOLS_CHECKPOINT)?;
+    let from_block = match checkpoint {
+        Some(block) => block + 1,
+        None => match pools.iter().map(|p| p.block_number).max() {
+            Some(block_number) => block_number + 1,
+            None => BALANCER_VAULT_DEPLOY_BLOCK,
+        },
+    };
+    // 导入的 csv 和扫描范围可能重叠 已经有的池子跳过
+    let mut known: HashSet<H256> = pools.iter().map(|p| p.pool_id).collect();
+    let to_block = provider.get_block_number().await?.as_u64();
+    let mut block_range = Vec::new();
+    let mut start_idx = from_block;
+    while start_idx <= to_block {
+        let end_idx = std::cmp::min(start_idx + chunk - 1, to_block);
+        block_range.push((start_idx, end_idx));
+        start_idx = end_idx + 1;
+    }
+    let pb = ProgressBar::new(block_range.len() as u64);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+        )
+        .unwrap()
+        .progress_chars("##-"),
+    );
+    let mut added = 0;
+    for range in block_range {
+        match load_balancer_weighted_pools(provider.clone(), range.0, range.1).await {
+            Ok(new_pools) => {
+                let new_pools: Vec<BalancerPool> = new_pools
+                    .into_iter()
+                    .filter(|pool| known.insert(pool.pool_id))
+                    .collect();
+                // 池子和 checkpoint 在同一个事务里写入
+                store.lock().unwrap().insert_balancer_pools(
+                    &new_pools,
+                    BALANCER_POOLS_CHECKPOINT,
+                    range.1,
+                )?;
+                added += new_pools.len();
+                pools.extend(new_pools);
+            }
+            // checkpoint 停在失败的范围之前 下次启动从这里重新扫描
+            Err(e) => {
+                warn!(
+                    "Balancer range {:?} failed, resume from here next time: {:?}",
+                    range, e
+                );
+                break;
+            }
+        }
+        pb.inc(1);
+    }
+    info!("Added {:?} new balancer pools", added);
+
+    Ok(pools)
+}
+
+/// 在区块范围内索引 Vault 的 PoolRegistered / TokensRegistered 事件
+/// 只保留加权池 (能返回 getNormalizedWeights 的池子)
+pub async fn load_balancer_weighted_pools(
+    provider: Arc<RpcProvider>,
+    from_block: u64,
+    to_block: u64,
+) -> Result<Vec<BalancerPool>> {
+    let abi = parse_abi(&[
+        "event PoolRegistered(bytes32 indexed poolId, address indexed poolAddress, uint8 specialization)",
+        "event TokensRegistered(bytes32 indexed poolId, address[] tokens, address[] assetManagers)",
+    ])?;
+    let pool_registered = abi.event("PoolRegistered")?.signature();
+    let tokens_registered = abi.event("TokensRegistered")?.signature();
+
+    let event_filter = Filter::new()
+        .address(to_h160(BALANCER_VAULT))
+        .from_block(U64::from(from_block))
+        .to_block(U64::from(to_block))
+        .topic0(vec![pool_registered, tokens_registered]);
+    let logs = provider.get_logs(&event_filter).await?;
+
+    // poolId -> (池子地址, 注册区块)
+    let mut registered: HashMap<H256, (H160, u64)> = HashMap::new();
+    // poolId -> 代币
+    let mut registered_tokens: HashMap<H256, Vec<H160>> = HashMap::new();
+    for log in logs {
+        let topic = log.topics[0];
+        let pool_id = log.topics[1];
+        if topic == pool_registered {
+            let pool_address = H160::from(log.topics[2]);
+            let block_number = log.block_number.unwrap_or_default().as_u64();
+            registered.insert(pool_id, (pool_address, block_number));
+        } else if topic == tokens_registered {
+            if let Ok(input) = ethers::abi::decode(
+                &[
+                    ParamType::Array(Box::new(ParamType::Address)),
+                    ParamType::Array(Box::new(ParamType::Address)),
+                ],
+                &log.data,
+            ) {
+                let tokens: Vec<H160> = input[0]
+                    .to_owned()
+                    .into_array()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|t| t.into_address())
+                    .collect();
+                registered_tokens
+                    .entry(pool_id)
+                    .or_insert_with(Vec::new)
+                    .extend(tokens);
+            }
+        }
+    }
+
+    let mut pools = Vec::new();
+    for (pool_id, (address, block_number)) in registered {
+        let tokens = match registered_tokens.remove(&pool_id) {
+            Some(tokens) => tokens,
+            None => continue,
+        };
+        // 非加权池(stable / linear 等)没有 getNormalizedWeights 直接跳过
+        let weights = match get_normalized_weights(&provider, address).await {
+            Ok(weights) => weights,
+            Err(_) => continue,
+        };
+        if weights.len() != tokens.len() {
+            continue;
+        }
+        let swap_fee = match get_swap_fee_percentage(&provider, address).await {
+            Ok(swap_fee) => swap_fee,
+            Err(e) => {
+                warn!(
+                    "Balancer pool {:?} swap fee failed, skipped: {:?}",
+                    address, e
+                );
+                continue;
+            }
+        };
+        pools.push(BalancerPool {
+            pool_id,
+            address,
+            tokens,
+            weights,
+            swap_fee,
+            block_number,
+        });
+    }
+    pools.sort_by_key(|p| p.block_number);
+
+    Ok(pools)
+}
+
+pub async fn get_normalized_weights(
+    provider: &Arc<RpcProvider>,
+    pool_address: H160,
+) -> Result<Vec<U256>> {
+    let abi = Abi::new();
+    let calldata = abi.balancer_pool.encode("getNormalizedWeights", ())?;
+    let tx = TransactionRequest::new().to(pool_address).data(calldata.0);
+    let result = provider.call(&tx.into(), None).await?;
+    let out: Vec<U256> = abi
+        .balancer_pool
+        .decode_output("getNormalizedWeights", result)?;
+    Ok(out)
+}
+
+pub async fn get_swap_fee_percentage(
+    provider: &Arc<RpcProvider>,
+    pool_address: H160,
+) -> Result<U256> {
+    let abi = Abi::new();
+    let calldata = abi.balancer_pool.encode("getSwapFeePercentage", ())?;
+    let tx = TransactionRequest::new().to(pool_address).data(calldata.0);
+    let result = provider.call(&tx.into(), None).await?;
+    let out: U256 = abi
+        .balancer_pool
+        .decode_output("getSwapFeePercentage", result)?;
+    Ok(out)
+}
+
+/// 从 Vault 读取池子在 block_number 时的余额 顺序与 pool.tokens 一致
+pub async fn get_pool_balances(
+    provider: &Arc<RpcProvider>,
+    pool_id: H256,
+    block_number: U64,
+) -> Result<Vec<U256>> {
+    let abi = Abi::new();
+    let calldata = abi.balancer_vault.encode("getPoolTokens", pool_id)?;
+    let tx = TransactionRequest::new()
+        .to(to_h160(BALANCER_VAULT))
+        .data(calldata.0);
+    let result = provider.call(&tx.into(), Some(block_number.into())).await?;
+    let out: (Vec<H160>, Vec<U256>, U256) =
+        abi.balancer_vault.decode_output("getPoolTokens", result)?;
+    Ok(out.1)
+}
+
+fn u256_to_f64(value: U256) -> f64 {
+    value.to_string().parse::<f64>().unwrap_or_default()
+}
+
+/// 加权池 outGivenIn
+/// 参考 Balancer WeightedMath._calcOutGivenIn:
+/// amountOut = balanceOut * (1 - (balanceIn / (balanceIn + amountIn)) ^ (weightIn / weightOut))
+/// 这里用 f64 近似 幂运算 只用于评估机会 不用于构造链上 calldata 的 minOut
+pub fn calc_out_given_in(
+    balance_in: U256,
+    weight_in: U256,
+    balance_out: U256,
+    weight_out: U256,
+    swap_fee: U256,
+    amount_in: U256,
+) -> Result<U256> {
+    let balance_in = u256_to_f64(balance_in);
+    let balance_out = u256_to_f64(balance_out);
+    let weight_in = u256_to_f64(weight_in) / BALANCER_ONE;
+    let weight_out = u256_to_f64(weight_out) / BALANCER_ONE;
+    let fee = u256_to_f64(swap_fee) / BALANCER_ONE;
+    // 手续费从输入中扣除
+    let amount_in = u256_to_f64(amount_in) * (1.0 - fee);
+    if weight_out == 0.0 || balance_in == 0.0 {
+        return Err(anyhow!("Invalid balancer pool state"));
+    }
+    if amount_in > balance_in * BALANCER_MAX_IN_RATIO {
+        return Err(anyhow!("Balancer max in ratio exceeded"));
+    }
+    let base = balance_in / (balance_in + amount_in);
+    let power = base.powf(weight_in / weight_out);
+    let amount_out = balance_out * (1.0 - power);
+    // 向下取整 与 Balancer 的舍入方向一致
+    Ok(U256::from_dec_str(&format!("{:.0}", amount_out.floor().max(0.0))).unwrap_or_default())
+}
+
+/// 根据池子和余额计算 token_in -> token_out 的输出
+pub fn get_amount_out(
+    pool: &BalancerPool,
+    balances: &Vec<U256>,
+    token_in: H160,
+    token_out: H160,
+    amount_in: U256,
+) -> Result<U256> {
+    let i = pool
+        .token_index(token_in)
+        .ok_or_else(|| anyhow!("Token in not in pool"))?;
+    let o = pool
+        .token_index(token_out)
+        .ok_or_else(|| anyhow!("Token out not in pool"))?;
+    if balances.len() != pool.tokens.len() {
+        return Err(anyhow!("Balances do not match pool tokens"));
+    }
+    calc_out_given_in(
+        balances[i],
+        pool.weights[i],
+        balances[o],
+        pool.weights[o],
+        pool.swap_fee,
+        amount_in,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eth(amount: u64) -> U256 {
+        U256::from(amount) * U256::exp10(18)
+    }
+
+    fn pool(weights: [u64; 2], swap_fee: U256) -> BalancerPool {
+        BalancerPool {
+            pool_id: H256::from_low_u64_be(1),
+            address: H160::from_low_u64_be(1),
+            tokens: vec![H160::from_low_u64_be(10), H160::from_low_u64_be(11)],
+            // 百分比 -> 18 位定点数
+            weights: weights
+                .iter()
+                .map(|w| U256::from(*w) * U256::exp10(16))
+                .collect(),
+            swap_fee,
+            block_number: 0,
+        }
+    }
+
+    fn assert_close(actual: U256, expected: U256) {
+        let diff = if actual > expected {
+            actual - expected
+        } else {
+            expected - actual
+        };
+        assert!(
+            diff <= expected / U256::exp10(9),
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn equal_weights_match_constant_product() {
+        // 50/50 没有手续费时 和 x * y = k 一样
+        let out = calc_out_given_in(
+            eth(100),
+            eth(1) / 2,
+            eth(5000),
+            eth(1) / 2,
+            U256::zero(),
+            eth(10),
+        )
+        .unwrap();
+        assert_close(out, eth(5000) * eth(10) / eth(110));
+    }
+
+    #[test]
+    fn swap_fee_is_taken_from_amount_in() {
+        // 0.3% 手续费
+        let out = calc_out_given_in(
+            eth(100),
+            eth(1) / 2,
+            eth(5000),
+            eth(1) / 2,
+            U256::from(3) * U256::exp10(15),
+            eth(10),
+        )
+        .unwrap();
+        let amount_in = eth(10) * U256::from(997) / U256::from(1000);
+        assert_close(out, eth(5000) * amount_in / (eth(100) + amount_in));
+    }
+
+    #[test]
+    fn uneven_weights() {
+        // 80/20: 1000 * (1 - (100 / 110) ^ 4)
+        let pool = pool([80, 20], U256::zero());
+        let balances = vec![eth(100), eth(1000)];
+        let out =
+            get_amount_out(&pool, &balances, pool.tokens[0], pool.tokens[1], eth(10)).unwrap();
+        let expected = 1000.0 * (1.0 - (100.0f64 / 110.0).powi(4));
+        assert_close(
+            out,
+            U256::from_dec_str(&format!("{:.0}", expected * 1e18)).unwrap(),
+        );
+    }
+
+    #[test]
+    fn max_in_ratio_is_enforced() {
+        let pool = pool([50, 50], U256::zero());
+        let balances = vec![eth(100), eth(100)];
+        assert!(get_amount_out(&pool, &balances, pool.tokens[0], pool.tokens[1], eth(29)).is_ok());
+        assert!(get_amount_out(&pool, &balances, pool.tokens[0], pool.tokens[1], eth(31)).is_err());
+    }
+
+    #[test]
+    fn unknown_token_or_balances_are_errors() {
+        let pool = pool([50, 50], U256::zero());
+        let balances = vec![eth(100), eth(100)];
+        let other = H160::from_low_u64_be(12);
+        assert!(get_amount_out(&pool, &balances, other, pool.tokens[1], eth(1)).is_err());
+        assert!(get_amount_out(&pool, &balances, pool.tokens[0], other, eth(1)).is_err());
+        assert!(get_amount_out(
+            &pool,
+            &vec![eth(100)],
+            pool.tokens[0],
+            pool.tokens[1],
+            eth(1)
+        )
+        .is_err());
+    }
+}
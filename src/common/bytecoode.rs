@@ -1,7 +1,12 @@
 use ethers::{prelude::Lazy, types::Bytes};
 
+// contracts/src/Request.sol 的 runtime bytecode
+// 目前的字节码是按 Request.sol 的 ABI 手写汇编的 行为和合约一致: 每个外部调用 gas 100000
+// 调用失败时 name / symbol 为空 decimals 超过 uint8 时 decimalsKnown = false 没有代码的地址 success = false
+// tokens.rs 的测试在 revm 里执行这段字节码 检查 getTokenInfo / getTokenInfos 的返回值
+// 修改合约之后在 contracts/ 下 forge build 用 out/Request.sol/Request.json 的 deployedBytecode 替换 测试要继续通过
 pub static REQUEST_BYTECODE: Lazy<Bytes> = Lazy::new(|| {
-    "0x608060409080825260048036101561001657600080fd5b6000803560e01c631f69565f1461002c57600080fd5b3461014857602093846003193601126102035782356001600160a01b03811693908490036101ff576306fdde0360e01b855282858281875afa9485156101f35783956101d7575b5081516395d89b4160e01b81529083828281885afa9182156101ca5784926101a6575b50825163313ce56760e01b81529487868381845afa95861561019c57908891869761015d575b5084516318160ddd60e01b815292839182905afa938415610152578094610119575b505061010a6100fd9660ff92845198899860808a5260808a019061022a565b918883039089015261022a565b93169084015260608301520390f35b909193508682813d831161014b575b610132818361024f565b810103126101485750519161010a6100fd6100de565b80fd5b503d610128565b8351903d90823e3d90fd5b8281939298503d8311610195575b610175818361024f565b81010312610191575160ff8116810361019157879095386100bc565b8480fd5b503d61016b565b84513d87823e3d90fd5b6101c39192503d8086833e6101bb818361024f565b810190610287565b9038610096565b50505051903d90823e3d90fd5b6101ec9195503d8085833e6101bb818361024f565b9338610073565b505051903d90823e3d90fd5b8280fd5b5080fd5b60005b83811061021a5750506000910152565b818101518382015260200161020a565b9060209161024381518092818552858086019101610207565b601f01601f1916010190565b90601f8019910116810190811067ffffffffffffffff82111761027157604052565b634e487b7160e01b600052604160045260246000fd5b6020818303126102f357805167ffffffffffffffff918282116102f357019082601f830112156102f357815190811161027157604051926102d2601f8301601f19166020018561024f565b818452602082840101116102f3576102f09160208085019101610207565b90565b600080fdfea264697066735822122004fbd047c788ee9f88c1adbdb92195b7b34a4454850b26610c1bca2cfdee742264736f6c63430008140033".parse().unwrap()
+    "0x3461002d576004361061002d57631f69565f60003560e01c146100335763bb17e44460003560e01c1461006f575b60006000fd5b6024361061002d5760043560805260805160a01c61002d5761020060a05261020060c052610063610100526101a4565b61020060e05103610200f35b6024361061002d5767ffffffffffffffff6004351161002d576004600435016101805236602061018051011161002d5761018051356101605267ffffffffffffffff610160511161002d576020610180510161018052366020610160510261018051011161002d5760206102005261016051610220526102406101a052602061016051026102400160e0526000610140525b61016051610140511015610198576101a05160e05103602061014051026101a05101526020610140510261018051013560805260805160a01c61002d576080513b6101675760c0602060e051015260e0604060e051015261010060e0510160e052610188565b600160e0515260e05160c052602060e0510160a052610188610100526101a4565b6001610140510161014052610101565b61020060e05103610200f35b60a060a0510160e0526306fdde0360e01b6000526000600060046000608051620186a0fa61012052610120513d026101c05260c05160e05103600060a05101526101c05160e051526101c0516000602060e051013e601f19601f6101c0510116602060e051010160e0526395d89b4160e01b6000526000600060046000608051620186a0fa61012052610120513d026101c05260c05160e05103602060a05101526101c05160e051526101c0516000602060e051013e601f19601f6101c0510116602060e051010160e05263313ce56760e01b6000526000600060046000608051620186a0fa6101205261012051156102c45760203d106102c4576020600060003e60ff600051116102c4576001604060a0510152600051606060a05101525b6318160ddd60e01b6000526000600060046000608051620186a0fa6101205261012051156103065760203d10610306576020600060003e600051608060a05101525b6101005156".parse().unwrap()
 });
 
 pub static SANDOOO_BYTECODE: Lazy<Bytes> = Lazy::new(|| {
@@ -0,0 +1,547 @@
+////////////////////////////////////////
+////缓存校验与修复///
+////////////////////////////////////////
+
+use anyhow::Result;
+use csv::StringRecord;
+use ethers::types::H160;
+use log::warn;
+use rusqlite::params;
+use std::{collections::HashMap, fmt, path::Path, str::FromStr};
+
+use super::{balancer::BalancerPool, pools::Pool, store::Store, tokens::Token};
+
+pub static POOLS_CSV: &str = "cache/.cached-pools.csv";
+pub static TOKENS_CSV: &str = "cache/.cached-tokens.csv";
+pub static BALANCER_POOLS_CSV: &str = "cache/.cached-balancer-pools.csv";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IssueKind {
+    /// 解析不了的行
+    BadRow,
+    /// 同一个地址出现了多次
+    DuplicateAddress,
+    /// id 没有递增
+    NonMonotonicId,
+    /// 池子和代币的关系指向不存在 / 不匹配的池子或代币
+    OrphanedPoolId,
+}
+
+/// 一个问题 line 是 csv 的行号 数据库的话是行的 id
+#[derive(Debug, Clone)]
+pub struct CacheIssue {
+    pub source: String,
+    pub line: u64,
+    pub kind: IssueKind,
+    pub detail: String,
+}
+impl fmt::Display for CacheIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} {:?} {}",
+            self.source, self.line, self.kind, self.detail
+        )
+    }
+}
+
+/// csv 缓存行的公共操作
+pub trait CacheRow: TryFrom<StringRecord, Error = anyhow::Error> {
+    fn header() -> &'static [&'static str];
+    fn address(&self) -> H160;
+    /// 没有 id 列的返回 None
+    fn id(&self) -> Option<i64>;
+    fn set_id(&mut self, id: i64);
+    fn write(&self, writer: &mut csv::Writer<std::fs::File>) -> Result<()>;
+}
+impl CacheRow for Pool {
+    fn header() -> &'static [&'static str] {
+        &[
+            "id",
+            "address",
+            "version",
+            "token0",
+            "token1",
+            "fee",
+            "block_number",
+            "timestamp",
+        ]
+    }
+    fn address(&self) -> H160 {
+        self.address
+    }
+    fn id(&self) -> Option<i64> {
+        Some(self.id)
+    }
+    fn set_id(&mut self, id: i64) {
+        self.id = id;
+    }
+    fn write(&self, writer: &mut csv::Writer<std::fs::File>) -> Result<()> {
+        writer.serialize(self.cache_row())?;
+        Ok(())
+    }
+}
+impl CacheRow for Token {
+    fn header() -> &'static [&'static str] {
+        &["id", "address", "name", "symbol", "decimals"]
+    }
+    fn address(&self) -> H160 {
+        self.address
+    }
+    fn id(&self) -> Option<i64> {
+        Some(self.id)
+    }
+    fn set_id(&mut self, id: i64) {
+        self.id = id;
+    }
+    fn write(&self, writer: &mut csv::Writer<std::fs::File>) -> Result<()> {
+        writer.serialize(self.cache_row())?;
+        Ok(())
+    }
+}
+impl CacheRow for BalancerPool {
+    fn header() -> &'static [&'static str] {
+        &[
+            "pool_id",
+            "address",
+            "tokens",
+            "weights",
+            "swap_fee",
+            "block_number",
+        ]
+    }
+    fn address(&self) -> H160 {
+        self.address
+    }
+    fn id(&self) -> Option<i64> {
+        None
+    }
+    fn set_id(&mut self, _id: i64) {}
+    fn write(&self, writer: &mut csv::Writer<std::fs::File>) -> Result<()> {
+        writer.serialize(self.cache_row())?;
+        Ok(())
+    }
+}
+
+/// 逐行解析 csv 返回 (行号, 解析结果) 和所有问题
+pub fn validate_csv<T: CacheRow>(path: &str) -> Result<(Vec<(u64, T)>, Vec<CacheIssue>)> {
+    let mut rows = Vec::new();
+    let mut issues = Vec::new();
+    let mut first_seen: HashMap<H160, u64> = HashMap::new();
+    let mut prev_id: Option<i64> = None;
+    let mut reader = csv::Reader::from_path(path)?;
+    for record in reader.records() {
+        let (line, parsed) = match record {
+            Ok(record) => (
+                record.position().map(|p| p.line()).unwrap_or_default(),
+                T::try_from(record),
+            ),
+            Err(e) => (
+                e.position().map(|p| p.line()).unwrap_or_default(),
+                Err(anyhow::Error::from(e)),
+            ),
+        };
+        let row = match parsed {
+            Ok(row) => row,
+            Err(e) => {
+                issues.push(CacheIssue {
+                    source: path.to_string(),
+                    line,
+                    kind: IssueKind::BadRow,
+                    detail: format!("{:?}", e),
+                });
+                continue;
+            }
+        };
+        if let Some(first) = first_seen.get(&row.address()) {
+            issues.push(CacheIssue {
+                source: path.to_string(),
+                line,
+                kind: IssueKind::DuplicateAddress,
+                detail: format!("{:?} already on line {:?}", row.address(), first),
+            });
+        } else {
+            first_seen.insert(row.address(), line);
+        }
+        if let Some(id) = row.id() {
+            if let Some(prev) = prev_id {
+                if id <= prev {
+                    issues.push(CacheIssue {
+                        source: path.to_string(),
+                        line,
+                        kind: IssueKind::NonMonotonicId,
+                        detail: format!("id {:?} after {:?}", id, prev),
+                    });
+                }
+            }
+            prev_id = Some(std::cmp::max(id, prev_id.unwrap_or(id)));
+        }
+        rows.push((line, row));
+    }
+    Ok((rows, issues))
+}
+
+/// 加载 csv 缓存 格式不对的行跳过并打印行号 不会因为一行坏数据中止启动
+pub fn load_csv_rows<T: CacheRow>(path: &str) -> Result<Vec<T>> {
+    let (rows, issues) = validate_csv::<T>(path)?;
+    for issue in issues.iter().filter(|i| i.kind == IssueKind::BadRow) {
+        warn!("Skipped invalid cache row {}", issue);
+    }
+    Ok(rows.into_iter().map(|(_, row)| row).collect())
+}
+
+/// 重写一份干净的 csv: 去掉坏行 / 重复地址保留第一次出现的 / 按原 id 排序后重新编号
+/// 先写临时文件再替换 返回写入的行数
+pub fn repair_csv<T: CacheRow>(path: &str) -> Result<usize> {
+    let (rows, _) = validate_csv::<T>(path)?;
+    let mut seen = HashMap::new();
+    let mut rows: Vec<T> = rows
+        .into_iter()
+        .filter(|(line, row)| *seen.entry(row.address()).or_insert(*line) == *line)
+        .map(|(_, row)| row)
+        .collect();
+    rows.sort_by_key(|row| row.id());
+    let tmp_path = format!("{}.tmp", path);
+    let mut writer = csv::Writer::from_path(&tmp_path)?;
+    writer.write_record(T::header())?;
+    for (i, row) in rows.iter_mut().enumerate() {
+        if row.id().is_some() {
+            row.set_id(i as i64);
+        }
+        row.write(&mut writer)?;
+    }
+    writer.flush()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(rows.len())
+}
+
+/// 校验数据库
+/// 1. 地址 / 哈希解析不了的池子和代币
+/// 2. 指向不存在的池子或代币的关系
+/// 3. 代币不是池子的 token0/token1 的关系
+pub fn validate_store(store: &Store) -> Result<Vec<CacheIssue>> {
+    let mut issues = Vec::new();
+    let source = "sqlite".to_string();
+    let mut stmt = store
+        .conn
+        .prepare("SELECT id, address, token0, token1, block_hash FROM pools")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?,
+        ))
+    })?;
+    for row in rows {
+        let (id, address, token0, token1, hash) = row?;
+        let bad = H160::from_str(&address).is_err()
+            || H160::from_str(&token0).is_err()
+            || H160::from_str(&token1).is_err()
+            || hash.map_or(false, |h| ethers::types::H256::from_str(&h).is_err());
+        if bad {
+            issues.push(CacheIssue {
+                source: format!("{}/pools", source),
+                line: id as u64,
+                kind: IssueKind::BadRow,
+                detail: format!("invalid address or hash in pool {:?}", address),
+            });
+        }
+    }
+    let mut stmt = store.conn.prepare("SELECT id, address FROM tokens")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for row in rows {
+        let (id, address) = row?;
+        if H160::from_str(&address).is_err() {
+            issues.push(CacheIssue {
+                source: format!("{}/tokens", source),
+                line: id as u64,
+                kind: IssueKind::BadRow,
+                detail: format!("invalid token address {:?}", address),
+            });
+        }
+    }
+    let mut stmt = store.conn.prepare(
+        "SELECT pt.pool_id, pt.token_id, p.id, t.id, t.address, p.token0, p.token1
+         FROM pool_tokens pt
+         LEFT JOIN pools p ON p.id = pt.pool_id
+         LEFT JOIN tokens t ON t.id = pt.token_id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, Option<i64>>(2)?,
+            row.get::<_, Option<i64>>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, Option<String>>(5)?,
+            row.get::<_, Option<String>>(6)?,
+        ))
+    })?;
+    for row in rows {
+        let (pool_id, token_id, pool, token, address, token0, token1) = row?;
+        let detail = if pool.is_none() {
+            format!("token {:?} refers to missing pool {:?}", token_id, pool_id)
+        } else if token.is_none() {
+            format!("pool {:?} refers to missing token {:?}", pool_id, token_id)
+        } else if address != token0 && address != token1 {
+            format!("token {:?} is not in pool {:?}", token_id, pool_id)
+        } else {
+            continue;
+        };
+        issues.push(CacheIssue {
+            source: format!("{}/pool_tokens", source),
+            line: pool_id as u64,
+            kind: IssueKind::OrphanedPoolId,
+            detail,
+        });
+    }
+    Ok(issues)
+}
+
+/// 修复数据库: 删除坏的池子 / 代币 和所有无效的关系 返回删除的行数
+pub fn repair_store(store: &mut Store) -> Result<usize> {
+    let issues = validate_store(store)?;
+    let tx = store.conn.transaction()?;
+    let mut removed = 0;
+    for issue in issues.iter().filter(|i| i.kind == IssueKind::BadRow) {
+        let table = if issue.source.ends_with("/pools") {
+            "pools"
+        } else {
+            "tokens"
+        };
+        let column = if table == "pools" {
+            "pool_id"
+        } else {
+            "token_id"
+        };
+        tx.execute(
+            &format!("DELETE FROM pool_tokens WHERE {} = ?1", column),
+            params![issue.line as i64],
+        )?;
+        removed += tx.execute(
+            &format!("DELETE FROM {} WHERE id = ?1", table),
+            params![issue.line as i64],
+        )?;
+    }
+    removed += tx.execute(
+        "DELETE FROM pool_tokens
+         WHERE pool_id NOT IN (SELECT id FROM pools)
+            OR token_id NOT IN (SELECT id FROM tokens)
+            OR NOT EXISTS (
+                SELECT 1 FROM pools p JOIN tokens t ON t.id = pool_tokens.token_id
+                WHERE p.id = pool_tokens.pool_id AND t.address IN (p.token0, p.token1)
+            )",
+        [],
+    )?;
+    tx.execute_batch(
+        "DELETE FROM token_taxes WHERE token_id NOT IN (SELECT id FROM tokens);
+         DELETE FROM token_risks WHERE token_id NOT IN (SELECT id FROM tokens);",
+    )?;
+    tx.commit()?;
+    Ok(removed)
+}
+
+/// 校验所有缓存 (存在的 csv 文件 + 数据库)
+pub fn validate_all(store: &Store) -> Result<Vec<CacheIssue>> {
+    let mut issues = Vec::new();
+    if Path::new(POOLS_CSV).exists() {
+        issues.extend(validate_csv::<Pool>(POOLS_CSV)?.1);
+    }
+    if Path::new(TOKENS_CSV).exists() {
+        issues.extend(validate_csv::<Token>(TOKENS_CSV)?.1);
+    }
+    if Path::new(BALANCER_POOLS_CSV).exists() {
+        issues.extend(validate_csv::<BalancerPool>(BALANCER_POOLS_CSV)?.1);
+    }
+    issues.extend(validate_store(store)?);
+    Ok(issues)
+}
+
+/// 修复所有缓存
+pub fn repair_all(store: &mut Store) -> Result<()> {
+    if Path::new(POOLS_CSV).exists() {
+        let rows = repair_csv::<Pool>(POOLS_CSV)?;
+        warn!("Rewrote {:?} with {:?} rows", POOLS_CSV, rows);
+    }
+    if Path::new(TOKENS_CSV).exists() {
+        let rows = repair_csv::<Token>(TOKENS_CSV)?;
+        warn!("Rewrote {:?} with {:?} rows", TOKENS_CSV, rows);
+    }
+    if Path::new(BALANCER_POOLS_CSV).exists() {
+        let rows = repair_csv::<BalancerPool>(BALANCER_POOLS_CSV)?;
+        warn!("Rewrote {:?} with {:?} rows", BALANCER_POOLS_CSV, rows);
+    }
+    let removed = repair_store(store)?;
+    warn!("Removed {:?} invalid rows from the store", removed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{pools::DexVariant, store::V2_POOLS_CHECKPOINT, tokens::KnownFields};
+
+    /// 临时文件 测试结束时删除
+    struct TempPath(String);
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "cache-check-{}-{}.csv",
+                name,
+                std::process::id()
+            ));
+            Self(path.to_string_lossy().to_string())
+        }
+    }
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn address(byte: u8) -> H160 {
+        H160::repeat_byte(byte)
+    }
+
+    fn pool_line(id: i64, pool: &str) -> String {
+        format!(
+            "{},{},2,{:?},{:?},300,10,100\n",
+            id,
+            pool,
+            address(0x01),
+            address(0x02)
+        )
+    }
+
+    fn kinds(issues: &[CacheIssue]) -> Vec<(u64, IssueKind)> {
+        issues.iter().map(|i| (i.line, i.kind)).collect()
+    }
+
+    #[test]
+    fn csv_issues_are_reported_and_repaired() {
+        let path = TempPath::new("pools");
+        let a1 = format!("{:?}", address(0xa1));
+        let a2 = format!("{:?}", address(0xa2));
+        let a3 = format!("{:?}", address(0xa3));
+        let mut text = format!("{}\n", Pool::header().join(","));
+        text.push_str(&pool_line(0, &a1));
+        text.push_str(&pool_line(1, "not-an-address"));
+        text.push_str(&pool_line(2, &a2));
+        text.push_str(&pool_line(3, &a1));
+        text.push_str(&pool_line(1, &a3));
+        std::fs::write(&path.0, text).unwrap();
+
+        let (rows, issues) = validate_csv::<Pool>(&path.0).unwrap();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(
+            kinds(&issues),
+            vec![
+                (3, IssueKind::BadRow),
+                (5, IssueKind::DuplicateAddress),
+                (6, IssueKind::NonMonotonicId),
+            ]
+        );
+        // 启动时只跳过坏行
+        assert_eq!(load_csv_rows::<Pool>(&path.0).unwrap().len(), 4);
+
+        // 重复地址保留第一次出现的 按原 id 排序后重新编号
+        assert_eq!(repair_csv::<Pool>(&path.0).unwrap(), 3);
+        let (rows, issues) = validate_csv::<Pool>(&path.0).unwrap();
+        assert!(issues.is_empty());
+        let rows: Vec<(i64, H160)> = rows.iter().map(|(_, p)| (p.id, p.address)).collect();
+        assert_eq!(
+            rows,
+            vec![(0, address(0xa1)), (1, address(0xa3)), (2, address(0xa2))]
+        );
+    }
+
+    #[test]
+    fn store_issues_are_reported_and_repaired() {
+        let mut store = Store::open(":memory:").unwrap();
+        let pool = Pool {
+            id: -1,
+            address: address(0xa1),
+            version: DexVariant::UniswapV2,
+            token0: address(0x01),
+            token1: address(0x02),
+            fee: 300,
+            block_number: 10,
+            timestamp: 100,
+            block_hash: None,
+        };
+        let pool_id = store
+            .insert_pools(vec![pool], Some((V2_POOLS_CHECKPOINT, 10)))
+            .unwrap()[0]
+            .id;
+        let tx = store.conn.transaction().unwrap();
+        let mut token_ids = Vec::new();
+        for byte in [0x01, 0x02, 0x03] {
+            token_ids.push(
+                Store::insert_token(
+                    &tx,
+                    &Token {
+                        id: -1,
+                        address: address(byte),
+                        name: String::from("Token"),
+                        symbol: String::from("TKN"),
+                        decimals: 18,
+                        pool_ids: Vec::new(),
+                        tax: None,
+                        known: KnownFields::default(),
+                        risk: None,
+                    },
+                )
+                .unwrap(),
+            );
+        }
+        for token_id in &token_ids[..2] {
+            Store::insert_pool_token(&tx, pool_id, *token_id).unwrap();
+        }
+        tx.commit().unwrap();
+        assert!(validate_store(&store).unwrap().is_empty());
+
+        // 旧版本写坏的数据: 坏地址的池子 / 代币不属于池子 / 指向不存在的池子
+        store
+            .conn
+            .execute_batch(&format!(
+                "PRAGMA foreign_keys = OFF;
+                 INSERT INTO pools (id, address, version, token0, token1, fee, block_number, timestamp)
+                 VALUES (100, 'bad', 2, 'bad', 'bad', 300, 1, 1);
+                 INSERT INTO pool_tokens (pool_id, token_id) VALUES ({pool}, {token});
+                 INSERT INTO pool_tokens (pool_id, token_id) VALUES (999, {token});
+                 PRAGMA foreign_keys = ON;",
+                pool = pool_id,
+                token = token_ids[2],
+            ))
+            .unwrap();
+        let issues = validate_store(&store).unwrap();
+        assert_eq!(
+            issues
+                .iter()
+                .filter(|i| i.kind == IssueKind::BadRow)
+                .count(),
+            1
+        );
+        assert_eq!(
+            issues
+                .iter()
+                .filter(|i| i.kind == IssueKind::OrphanedPoolId)
+                .count(),
+            2
+        );
+
+        assert_eq!(repair_store(&mut store).unwrap(), 3);
+        assert!(validate_store(&store).unwrap().is_empty());
+        // 正常的池子和关系不受影响
+        let pools = store.load_pools().unwrap();
+        assert_eq!(pools.len(), 1);
+        let tokens = store.load_tokens().unwrap();
+        assert_eq!(tokens[&address(0x01)].pool_ids, vec![pool_id]);
+        assert_eq!(tokens[&address(0x02)].pool_ids, vec![pool_id]);
+        assert!(tokens[&address(0x03)].pool_ids.is_empty());
+    }
+}
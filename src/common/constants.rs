@@ -1,7 +1,32 @@
+use anyhow::{anyhow, Result};
+use std::{fmt::Debug, str::FromStr};
+
 #[derive(Debug, Clone)]
 pub struct Env {
     pub https_url: String,
     pub wss_url: String,
+    /// 主节点的连接地址 ws:// wss:// / http:// https:// / IPC socket 路径
+    /// 没有配置 RPC_URL 时使用 WSS_URL
+    pub rpc_url: String,
+    /// 额外的 mempool 节点 (逗号分隔 可以为空) 和 rpc_url 一起订阅 pending 交易 按哈希去重
+    /// 每个地址和 rpc_url 一样可以是 WS 或 IPC
+    pub mempool_wss_urls: Vec<String>,
+    /// 订阅完整的 pending 交易 节点不支持时自动退回哈希订阅 默认开启
+    pub full_pending_txs: bool,
+    /// 策略的 pending 交易队列长度 默认 4096
+    pub pending_queue_capacity: usize,
+    /// 队列满了之后的丢弃策略 oldest / lowest_gas_price (默认)
+    pub pending_drop_policy: String,
+    /// 配置后把区块和 pending 交易记录到这个目录 (格式见 sandwich/recorder.rs)
+    pub record_dir: Option<String>,
+    /// 配置后把策略发出的节点请求和响应录制到这个文件 配合 replay 回放
+    pub fixture_record: Option<String>,
+    /// 同时做回执检查和 debug_traceCall 的交易数 默认 16
+    pub trace_workers: usize,
+    /// 单笔交易回执检查 + 追踪的超时 毫秒 默认 3000
+    pub trace_timeout_ms: u64,
+    /// 新代币税率和风险测量的超时 毫秒 默认 5000
+    pub measure_timeout_ms: u64,
     pub bot_address: String,
     pub private_key: String,
     pub identity_key: String,
@@ -10,22 +35,66 @@ pub struct Env {
     pub use_alert: bool,
     pub debug: bool,
 }
-fn get_env(key: &str) -> String {
-    std::env::var(key).unwrap()
+/// 必须配置的变量
+fn get_env(key: &str) -> Result<String> {
+    std::env::var(key).map_err(|_| anyhow!("{} is not set", key))
+}
+/// 必须配置的变量 按类型解析 格式不对时返回带变量名的错误
+fn parse_env<T>(key: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    let value = get_env(key)?;
+    value
+        .trim()
+        .parse::<T>()
+        .map_err(|e| anyhow!("{} = {:?} is invalid: {:?}", key, value, e))
+}
+/// 可选的变量 没有配置时用默认值
+fn parse_env_or<T>(key: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    match std::env::var(key) {
+        Ok(_) => parse_env(key),
+        Err(_) => Ok(default),
+    }
 }
 impl Env {
-    pub fn new() -> Self {
-        Env {
-            https_url: get_env("HTTPS_URL"),
-            wss_url: get_env("WSS_URL"),
-            bot_address: get_env("BOT_ADDRESS"),
-            private_key: get_env("PRIVATE_KEY"),
-            identity_key: get_env("IDENTITY_KEY"),
-            telegram_token: get_env("TELEGRAM_TOKEN"),
-            telegram_chat_id: get_env("TELEGRAM_CHAT_ID"),
-            use_alert: get_env("USE_ALERT").parse::<bool>().unwrap(),
-            debug: get_env("DEBUG").parse::<bool>().unwrap(),
-        }
+    /// 读取环境变量 缺少必须的变量 / 数值和开关格式不对时返回错误
+    pub fn new() -> Result<Self> {
+        Ok(Env {
+            https_url: get_env("HTTPS_URL")?,
+            wss_url: get_env("WSS_URL")?,
+            rpc_url: match std::env::var("RPC_URL") {
+                Ok(url) => url,
+                Err(_) => get_env("WSS_URL")?,
+            },
+            mempool_wss_urls: std::env::var("MEMPOOL_WSS_URLS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty())
+                .collect(),
+            full_pending_txs: parse_env_or("FULL_PENDING_TXS", true)?,
+            pending_queue_capacity: parse_env_or("PENDING_QUEUE_CAPACITY", 4096)?,
+            pending_drop_policy: std::env::var("PENDING_DROP_POLICY")
+                .unwrap_or_else(|_| "lowest_gas_price".to_string()),
+            record_dir: std::env::var("RECORD_DIR").ok(),
+            fixture_record: std::env::var("RPC_FIXTURE_RECORD").ok(),
+            trace_workers: parse_env_or("TRACE_WORKERS", 16)?,
+            trace_timeout_ms: parse_env_or("TRACE_TIMEOUT_MS", 3000)?,
+            measure_timeout_ms: parse_env_or("MEASURE_TIMEOUT_MS", 5000)?,
+            bot_address: get_env("BOT_ADDRESS")?,
+            private_key: get_env("PRIVATE_KEY")?,
+            identity_key: get_env("IDENTITY_KEY")?,
+            telegram_token: get_env("TELEGRAM_TOKEN")?,
+            telegram_chat_id: get_env("TELEGRAM_CHAT_ID")?,
+            use_alert: parse_env("USE_ALERT")?,
+            debug: parse_env("DEBUG")?,
+        })
     }
 }
 pub static COINBASE: &str = "0xDAFEA492D9c6733ae3d56b7Ed1ADB60692c98Bc5"; // Flashbots Builder
@@ -34,6 +103,26 @@ pub static WETH: &str = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
 pub static USDT: &str = "0xdAC17F958D2ee523a2206206994597C13D831ec7";
 pub static USDC: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
 
+pub static UNISWAP_V2_FACTORY: &str = "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f";
+
+// Multicall3 一次 eth_call 执行多个只读调用 (各条链地址相同)
+pub static MULTICALL3: &str = "0xcA11bde05977b3631167028862bE2a173976CA11";
+
+// 常见的路由 / 聚合器 受害者交易一般发往这些合约
+pub static UNISWAP_V2_ROUTER: &str = "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D";
+pub static UNIVERSAL_ROUTER: &str = "0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD";
+pub static UNIVERSAL_ROUTER_OLD: &str = "0xEf1c6E67703c7BD7107eed8303Fbe6EC2554BF6B";
+pub static SUSHISWAP_ROUTER: &str = "0xd9e1cE17f2641f24aE83637ab66a2cca9C378B9F";
+pub static ONEINCH_V5_ROUTER: &str = "0x1111111254EEB25477B68fb85Ed929f73A960582";
+pub static ONEINCH_V6_ROUTER: &str = "0x111111125421cA6dc452d289314280a0f8842A65";
+pub static ZEROX_EXCHANGE_PROXY: &str = "0xDef1C0ded9bec7F1a1670819833240f027b25EfF";
+pub static PARASWAP_AUGUSTUS_V5: &str = "0xDEF171Fe48CF0115B1d80b88dc8eAB59176FEe57";
+pub static METAMASK_SWAP_ROUTER: &str = "0x881D40237659C251811CEC9c364ef91dC08D300C";
+
+// Balancer V2 所有池子共用一个 Vault
+pub static BALANCER_VAULT: &str = "0xBA12222222228d8Ba445958a75a0704d566BF2C8";
+pub static BALANCER_VAULT_DEPLOY_BLOCK: u64 = 12272146;
+
 /*
 Can figure out the balance slot of ERC-20 tokens using the:
 EvmSimulator::get_balance_slot method
@@ -48,3 +137,26 @@ pub static USDC_BALANCE_SLOT: i32 = 9;
 pub static WETH_DECIMALS: u8 = 18;
 pub static USDT_DECIMALS: u8 = 6;
 pub static USDC_DECIMALS: u8 = 6;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_settings_name_the_variable() {
+        // 变量名只在这个测试里用 不和其他测试冲突
+        std::env::set_var("CONSTANTS_TEST_WORKERS", "sixteen");
+        let err = parse_env_or::<usize>("CONSTANTS_TEST_WORKERS", 16).unwrap_err();
+        assert!(err.to_string().contains("CONSTANTS_TEST_WORKERS"));
+        assert_eq!(
+            parse_env_or::<usize>("CONSTANTS_TEST_UNSET", 16).unwrap(),
+            16
+        );
+        std::env::set_var("CONSTANTS_TEST_WORKERS", " 8 ");
+        assert_eq!(
+            parse_env_or::<usize>("CONSTANTS_TEST_WORKERS", 16).unwrap(),
+            8
+        );
+        assert!(parse_env::<bool>("CONSTANTS_TEST_UNSET").is_err());
+    }
+}
@@ -0,0 +1,430 @@
+////////////////////////////////////////
+////池子/代币数据集 导出与导入///
+////////////////////////////////////////
+
+use anyhow::{anyhow, Result};
+use ethers::types::{H160, H256};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+};
+
+use super::{
+    pools::Pool,
+    risk::TokenRisk,
+    store::Store,
+    tokens::{KnownFields, Token, TokenTax},
+};
+
+/// 数据集格式版本 记录的字段有不兼容的变化时加 1
+pub static DATASET_VERSION: u32 = 1;
+pub static DATASET_FORMAT: &str = "sandwich-dataset";
+
+/// 代币记录 池子关系用池子地址表示 (不同机器上的 id 不一样)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenRecord {
+    pub address: H160,
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub known: KnownFields,
+    pub pools: Vec<H160>,
+    pub tax: Option<TokenTax>,
+    pub risk: Option<TokenRisk>,
+}
+
+/// gzip 压缩的 JSON lines 每行一条记录:
+/// 第一行 header / 然后 checkpoint / pool / token / 最后一行 checksum
+/// checksum 是 checksum 行之前所有行(含换行符)的 sha256
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Header {
+        format: String,
+        version: u32,
+        created_at: i64,
+        pools: usize,
+        tokens: usize,
+    },
+    Checkpoint {
+        name: String,
+        block_number: u64,
+        block_hash: Option<H256>,
+    },
+    Pool(Pool),
+    Token(TokenRecord),
+    Checksum {
+        sha256: String,
+    },
+}
+
+/// 把数据库里的池子 / 代币 / 同步进度导出到 path
+pub fn export_dataset(store: &Store, path: &str) -> Result<()> {
+    let pools = store.load_pools()?;
+    let tokens = store.load_tokens()?;
+    let checkpoints = store.load_checkpoints()?;
+    let pool_addresses: HashMap<i64, H160> = pools.iter().map(|p| (p.id, p.address)).collect();
+
+    let file = File::create(path)?;
+    let mut writer = GzEncoder::new(BufWriter::new(file), Compression::default());
+    let mut hasher = Sha256::new();
+    let mut write_record = |record: &Record| -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        hasher.update(line.as_bytes());
+        writer.write_all(line.as_bytes())?;
+        Ok(())
+    };
+
+    write_record(&Record::Header {
+        format: DATASET_FORMAT.to_string(),
+        version: DATASET_VERSION,
+        created_at: chrono::Utc::now().timestamp(),
+        pools: pools.len(),
+        tokens: tokens.len(),
+    })?;
+    for (name, block_number, block_hash) in checkpoints {
+        write_record(&Record::Checkpoint {
+            name,
+            block_number,
+            block_hash,
+        })?;
+    }
+    for pool in &pools {
+        write_record(&Record::Pool(*pool))?;
+    }
+    let mut tokens: Vec<Token> = tokens.into_values().collect();
+    tokens.sort_by_key(|t| t.id);
+    for token in tokens {
+        write_record(&Record::Token(TokenRecord {
+            address: token.address,
+            name: token.name,
+            symbol: token.symbol,
+            decimals: token.decimals,
+            known: token.known,
+            pools: token
+                .pool_ids
+                .iter()
+                .filter_map(|id| pool_addresses.get(id).cloned())
+                .collect(),
+            tax: token.tax,
+            risk: token.risk,
+        }))?;
+    }
+    let checksum = format!("{:x}", hasher.finalize());
+    let mut line = serde_json::to_string(&Record::Checksum { sha256: checksum })?;
+    line.push('\n');
+    writer.write_all(line.as_bytes())?;
+    writer.finish()?.flush()?;
+    info!("Exported {:?} pools to {:?}", pools.len(), path);
+    Ok(())
+}
+
+/// 读取并校验数据集 校验失败不返回任何记录
+pub fn read_dataset(path: &str) -> Result<Vec<Record>> {
+    let reader = BufReader::new(GzDecoder::new(File::open(path)?));
+    let mut hasher = Sha256::new();
+    let mut records = Vec::new();
+    let mut checksum = None;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if checksum.is_some() {
+            return Err(anyhow!("Line {:?}: data after checksum", i + 1));
+        }
+        let record: Record =
+            serde_json::from_str(&line).map_err(|e| anyhow!("Line {:?}: {:?}", i + 1, e))?;
+        match record {
+            Record::Checksum { sha256 } => checksum = Some(sha256),
+            record => {
+                hasher.update(line.as_bytes());
+                hasher.update(b"\n");
+                records.push(record);
+            }
+        }
+    }
+    let expected = checksum.ok_or_else(|| anyhow!("Dataset has no checksum (truncated?)"))?;
+    let actual = format!("{:x}", hasher.finalize());
+    if expected != actual {
+        return Err(anyhow!(
+            "Checksum mismatch: expected {:?}, got {:?}",
+            expected,
+            actual
+        ));
+    }
+    match records.first() {
+        Some(Record::Header {
+            format, version, ..
+        }) if format == DATASET_FORMAT && *version <= DATASET_VERSION => {}
+        Some(Record::Header {
+            format, version, ..
+        }) => return Err(anyhow!("Unsupported dataset {:?} v{:?}", format, version)),
+        _ => return Err(anyhow!("Dataset has no header")),
+    }
+    Ok(records)
+}
+
+/// 导入数据集 和已有的数据合并
+/// 已经存在的池子 / 代币保留本地的记录 税率和风险取测量区块较新的一个
+/// 同步进度取本地和数据集中较大的一个 之后从那里增量同步
+pub fn import_dataset(store: &mut Store, path: &str) -> Result<()> {
+    let records = read_dataset(path)?;
+    let local_checkpoints: HashMap<String, u64> = store
+        .load_checkpoints()?
+        .into_iter()
+        .map(|(name, block, _)| (name, block))
+        .collect();
+    let local_tokens = store.load_tokens()?;
+
+    let tx = store.conn.transaction()?;
+    // 池子地址 -> 本地的 id
+    let mut pool_ids: HashMap<H160, i64> = HashMap::new();
+    let (mut pools, mut tokens) = (0, 0);
+    for record in records {
+        match record {
+            Record::Checkpoint {
+                name,
+                block_number,
+                block_hash,
+            } => {
+                let local = local_checkpoints.get(&name).cloned();
+                if local.map_or(true, |local| block_number > local) {
+                    Store::set_checkpoint_with_hash(&tx, &name, block_number, block_hash)?;
+                }
+            }
+            Record::Pool(pool) => {
+                let id = Store::insert_pool(&tx, &pool)?;
+                pool_ids.insert(pool.address, id);
+                pools += 1;
+            }
+            Record::Token(record) => {
+                let token = Token {
+                    id: -1,
+                    address: record.address,
+                    name: record.name,
+                    symbol: record.symbol,
+                    decimals: record.decimals,
+                    pool_ids: Vec::new(),
+                    tax: None,
+                    known: record.known,
+                    risk: None,
+                };
+                let token_id = Store::insert_token(&tx, &token)?;
+                for pool in record.pools {
+                    if let Some(pool_id) = pool_ids.get(&pool) {
+                        Store::insert_pool_token(&tx, *pool_id, token_id)?;
+                    }
+                }
+                let local = local_tokens.get(&record.address);
+                if let Some(tax) = record.tax {
+                    match local.and_then(|t| t.tax) {
+                        Some(local_tax) if local_tax.block_number >= tax.block_number => {}
+                        _ => Store::write_token_tax(&tx, record.address, &tax)?,
+                    }
+                }
+                if let Some(risk) = record.risk {
+                    match local.and_then(|t| t.risk) {
+                        Some(local_risk) if local_risk.block_number >= risk.block_number => {}
+                        _ => Store::write_token_risk(&tx, record.address, &risk)?,
+                    }
+                }
+                tokens += 1;
+            }
+            Record::Header { .. } | Record::Checksum { .. } => {}
+        }
+    }
+    tx.commit()?;
+    info!(
+        "Imported {:?} pools / {:?} tokens from {:?}",
+        pools, tokens, path
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{pools::DexVariant, store::V2_POOLS_CHECKPOINT};
+    use std::io::Read;
+
+    /// 临时文件 测试结束时删除
+    struct TempPath(String);
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "dataset-{}-{}.jsonl.gz",
+                name,
+                std::process::id()
+            ));
+            Self(path.to_string_lossy().to_string())
+        }
+    }
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn address(byte: u8) -> H160 {
+        H160::repeat_byte(byte)
+    }
+
+    fn tax(sell_tax: u32, block_number: u64) -> TokenTax {
+        TokenTax {
+            buy_tax: 0,
+            sell_tax,
+            transfer_tax: 0,
+            rebasing: false,
+            block_number,
+        }
+    }
+
+    /// 池子 0xa1: 0x01/0x02 两个代币都测过税率 0x02 测过风险
+    fn source() -> Store {
+        let mut store = Store::open(":memory:").unwrap();
+        let pool = Pool {
+            id: -1,
+            address: address(0xa1),
+            version: DexVariant::UniswapV2,
+            token0: address(0x01),
+            token1: address(0x02),
+            fee: 300,
+            block_number: 10,
+            timestamp: 1000,
+            block_hash: Some(H256::repeat_byte(0x10)),
+        };
+        let pools = store
+            .insert_pools(vec![pool], Some((V2_POOLS_CHECKPOINT, 100)))
+            .unwrap();
+        let tx = store.conn.transaction().unwrap();
+        for byte in [0x01, 0x02] {
+            let token_id = Store::insert_token(
+                &tx,
+                &Token {
+                    id: -1,
+                    address: address(byte),
+                    name: format!("Token{}", byte),
+                    symbol: format!("T{}", byte),
+                    decimals: 18,
+                    pool_ids: Vec::new(),
+                    tax: None,
+                    known: KnownFields {
+                        name: true,
+                        symbol: byte == 0x01,
+                        decimals: true,
+                    },
+                    risk: None,
+                },
+            )
+            .unwrap();
+            Store::insert_pool_token(&tx, pools[0].id, token_id).unwrap();
+        }
+        tx.commit().unwrap();
+        store.save_token_tax(address(0x01), &tax(100, 50)).unwrap();
+        store.save_token_tax(address(0x02), &tax(200, 50)).unwrap();
+        store
+            .save_token_risk(address(0x02), &TokenRisk::unsafe_at(50))
+            .unwrap();
+        store
+    }
+
+    fn read_lines(path: &str) -> String {
+        let mut text = String::new();
+        GzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+
+    fn write_lines(path: &str, text: &str) {
+        let mut writer = GzEncoder::new(File::create(path).unwrap(), Compression::default());
+        writer.write_all(text.as_bytes()).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn export_and_import_round_trip() {
+        let path = TempPath::new("round-trip");
+        export_dataset(&source(), &path.0).unwrap();
+        let mut store = Store::open(":memory:").unwrap();
+        import_dataset(&mut store, &path.0).unwrap();
+
+        let pools = store.load_pools().unwrap();
+        assert_eq!(pools.len(), 1);
+        assert_eq!(pools[0].address, address(0xa1));
+        assert_eq!(pools[0].block_hash, Some(H256::repeat_byte(0x10)));
+        let tokens = store.load_tokens().unwrap();
+        assert_eq!(tokens.len(), 2);
+        let token = &tokens[&address(0x02)];
+        // 池子关系按地址重新对应到本地的 id
+        assert_eq!(token.pool_ids, vec![pools[0].id]);
+        assert!(!token.known.symbol);
+        assert_eq!(token.tax.map(|tax| tax.sell_tax), Some(200));
+        assert_eq!(token.risk, Some(TokenRisk::unsafe_at(50)));
+        assert_eq!(tokens[&address(0x01)].risk, None);
+        assert_eq!(
+            store.get_checkpoint(V2_POOLS_CHECKPOINT).unwrap(),
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn import_keeps_newer_local_data() {
+        let path = TempPath::new("merge");
+        export_dataset(&source(), &path.0).unwrap();
+        let mut store = source();
+        store.save_token_tax(address(0x01), &tax(300, 80)).unwrap();
+        store.save_token_tax(address(0x02), &tax(400, 20)).unwrap();
+        let tx = store.conn.transaction().unwrap();
+        Store::set_checkpoint(&tx, V2_POOLS_CHECKPOINT, 200).unwrap();
+        tx.commit().unwrap();
+        import_dataset(&mut store, &path.0).unwrap();
+
+        let tokens = store.load_tokens().unwrap();
+        // 本地测得更新的保留 数据集里更新的覆盖
+        assert_eq!(
+            tokens[&address(0x01)].tax.map(|tax| tax.sell_tax),
+            Some(300)
+        );
+        assert_eq!(
+            tokens[&address(0x02)].tax.map(|tax| tax.sell_tax),
+            Some(200)
+        );
+        assert_eq!(store.load_pools().unwrap().len(), 1);
+        assert_eq!(tokens[&address(0x01)].pool_ids.len(), 1);
+        assert_eq!(
+            store.get_checkpoint(V2_POOLS_CHECKPOINT).unwrap(),
+            Some(200)
+        );
+    }
+
+    #[test]
+    fn tampered_or_truncated_dataset_is_rejected() {
+        let path = TempPath::new("tampered");
+        export_dataset(&source(), &path.0).unwrap();
+        let text = read_lines(&path.0);
+        assert_eq!(
+            read_dataset(&path.0).unwrap().len(),
+            text.lines().count() - 1
+        );
+
+        write_lines(&path.0, &text.replace("Token1", "Token9"));
+        assert!(read_dataset(&path.0).is_err());
+
+        let truncated: Vec<&str> = text.lines().collect();
+        write_lines(
+            &path.0,
+            &format!("{}\n", truncated[..truncated.len() - 1].join("\n")),
+        );
+        assert!(read_dataset(&path.0).is_err());
+
+        // 导入失败时不写入任何东西
+        let mut store = Store::open(":memory:").unwrap();
+        assert!(import_dataset(&mut store, &path.0).is_err());
+        assert!(store.load_pools().unwrap().is_empty());
+    }
+}
@@ -1,6 +1,20 @@
 use std::sync::Arc;
 
+use anyhow::{anyhow, Result};
+use ethers::abi::{self, Token as AbiToken};
 use ethers::types::*;
+use ethers::utils::keccak256;
+use ethers_providers::Middleware;
+use revm::{
+    db::CacheDB,
+    primitives::{
+        AccountInfo, Address as rAddress, Bytecode, Bytes as rBytes, ExecutionResult, Output,
+        TxKind, B256, U256 as rU256,
+    },
+    Database, DatabaseRef, Evm,
+};
+
+use super::abi::Abi;
 /// 记录目标交易(被夹在三明治交易中间的交易)的详细信息
 /// 受害者hash
 #[derive(Debug, Clone, Default)]
@@ -20,9 +34,299 @@ pub struct VictimTx {
     /// 交易的 gas 上限,某些情况下可能没有设置
     pub gas_limit: Option<u64>,
 }
-#[derive(Clone)]
+/// 在模拟器中执行的交易
+#[derive(Debug, Clone, Default)]
+pub struct Tx {
+    pub caller: H160,
+    pub transact_to: H160,
+    pub data: Bytes,
+    pub value: U256,
+    pub gas_price: U256,
+    pub gas_limit: u64,
+}
+/// 模拟执行的结果
+#[derive(Debug, Clone, Default)]
+pub struct TxResult {
+    pub output: Bytes,
+    pub gas_used: u64,
+    pub gas_refunded: u64,
+}
+
+pub fn to_raddress(address: H160) -> rAddress {
+    rAddress::from_slice(address.as_bytes())
+}
+pub fn to_ru256(value: U256) -> rU256 {
+    rU256::from_limbs(value.0)
+}
+pub fn from_ru256(value: rU256) -> U256 {
+    U256(value.into_limbs())
+}
+
+/// 从节点按需读取状态的数据库
+/// 第一次访问某个账户/存储槽时通过 provider 读取 之后由 CacheDB 缓存
+/// revm 的 Database 是同步接口 这里用 block_in_place 在多线程 runtime 里等待异步请求
+/// 在 spawn_blocking 的线程里 block_in_place 直接执行 单线程 runtime 也可以用
+pub struct ProviderDB<M> {
+    pub provider: Arc<M>,
+    pub block_number: BlockId,
+}
+impl<M: Middleware + 'static> ProviderDB<M> {
+    fn block_on<F: std::future::Future>(&self, f: F) -> F::Output {
+        tokio::task::block_in_place(move || tokio::runtime::Handle::current().block_on(f))
+    }
+}
+impl<M: Middleware + 'static> DatabaseRef for ProviderDB<M> {
+    type Error = anyhow::Error;
+
+    fn basic_ref(&self, address: rAddress) -> Result<Option<AccountInfo>, Self::Error> {
+        let address = H160::from_slice(address.as_slice());
+        let block = Some(self.block_number);
+        let (balance, nonce, code) = self.block_on(async {
+            futures::try_join!(
+                self.provider.get_balance(address, block),
+                self.provider.get_transaction_count(address, block),
+                self.provider.get_code(address, block),
+            )
+        })
+        .map_err(|e| anyhow!("{:?}", e))?;
+        let bytecode = Bytecode::new_raw(rBytes::from(code.0));
+        Ok(Some(AccountInfo::new(
+            to_ru256(balance),
+            nonce.as_u64(),
+            bytecode.hash_slow(),
+            bytecode,
+        )))
+    }
+
+    fn code_by_hash_ref(&self, _code_hash: B256) -> Result<Bytecode, Self::Error> {
+        // basic_ref 已经带上了代码 不会走到这里
+        Err(anyhow!("code_by_hash should not be called"))
+    }
+
+    fn storage_ref(&self, address: rAddress, index: rU256) -> Result<rU256, Self::Error> {
+        let address = H160::from_slice(address.as_slice());
+        let mut slot = [0u8; 32];
+        from_ru256(index).to_big_endian(&mut slot);
+        let slot = H256::from(slot);
+        let value = self
+            .block_on(
+                self.provider
+                    .get_storage_at(address, slot, Some(self.block_number)),
+            )
+            .map_err(|e| anyhow!("{:?}", e))?;
+        Ok(to_ru256(U256::from_big_endian(value.as_bytes())))
+    }
+
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        let block = self
+            .block_on(self.provider.get_block(number))
+            .map_err(|e| anyhow!("{:?}", e))?;
+        match block.and_then(|b| b.hash) {
+            Some(hash) => Ok(B256::from_slice(hash.as_bytes())),
+            None => Ok(B256::ZERO),
+        }
+    }
+}
+
+/// 基于 revm 的本地模拟器
+/// 以 block_number 的链上状态为基础 所有修改只保存在本地 CacheDB 中
 pub struct EvmSimulator<M> {
     pub provider: Arc<M>,
     pub owner: H160,
-    pub evm: E,
+    pub block_number: U64,
+    pub base_fee: U256,
+    pub db: CacheDB<ProviderDB<M>>,
+    pub abi: Abi,
+}
+impl<M: Middleware + 'static> EvmSimulator<M> {
+    pub fn new(provider: Arc<M>, owner: Option<H160>, block_number: U64) -> Self {
+        let db = CacheDB::new(ProviderDB {
+            provider: provider.clone(),
+            block_number: BlockId::Number(BlockNumber::Number(block_number)),
+        });
+        Self {
+            provider,
+            owner: owner.unwrap_or_default(),
+            block_number,
+            base_fee: U256::zero(),
+            db,
+            abi: Abi::new(),
+        }
+    }
+
+    pub fn set_base_fee(&mut self, base_fee: U256) {
+        self.base_fee = base_fee;
+    }
+
+    /// 执行交易并把状态写入本地数据库
+    pub fn call(&mut self, tx: Tx) -> Result<TxResult> {
+        self._call(tx, true)
+    }
+
+    /// 执行交易但不修改状态
+    pub fn staticcall(&mut self, tx: Tx) -> Result<TxResult> {
+        self._call(tx, false)
+    }
+
+    fn _call(&mut self, tx: Tx, commit: bool) -> Result<TxResult> {
+        let block_number = self.block_number.as_u64() + 1;
+        let base_fee = to_ru256(self.base_fee);
+        let mut evm = Evm::builder()
+            .with_db(&mut self.db)
+            .modify_block_env(|b| {
+                b.number = rU256::from(block_number);
+                b.basefee = base_fee;
+            })
+            .modify_tx_env(|t| {
+                t.caller = to_raddress(tx.caller);
+                t.transact_to = TxKind::Call(to_raddress(tx.transact_to));
+                t.data = rBytes::from(tx.data.0.clone());
+                t.value = to_ru256(tx.value);
+                t.gas_price = to_ru256(tx.gas_price);
+                t.gas_limit = tx.gas_limit;
+                // 不检查 nonce
+                t.nonce = None;
+            })
+            .build();
+        let result = if commit {
+            evm.transact_commit()
+                .map_err(|e| anyhow!("EVM error: {:?}", e))?
+        } else {
+            evm.transact()
+                .map_err(|e| anyhow!("EVM error: {:?}", e))?
+                .result
+        };
+        match result {
+            ExecutionResult::Success {
+                gas_used,
+                gas_refunded,
+                output,
+                ..
+            } => {
+                let output = match output {
+                    Output::Call(o) => o,
+                    Output::Create(o, _) => o,
+                };
+                Ok(TxResult {
+                    output: Bytes::from(output.to_vec()),
+                    gas_used,
+                    gas_refunded,
+                })
+            }
+            ExecutionResult::Revert { gas_used, output } => {
+                Err(anyhow!("Revert: {:?} / Gas used: {:?}", output, gas_used))
+            }
+            ExecutionResult::Halt { reason, .. } => Err(anyhow!("Halt: {:?}", reason)),
+        }
+    }
+
+    pub fn get_eth_balance_of(&mut self, address: H160) -> Result<U256> {
+        let account = self
+            .db
+            .basic(to_raddress(address))?
+            .unwrap_or_default();
+        Ok(from_ru256(account.balance))
+    }
+
+    pub fn set_eth_balance(&mut self, address: H160, amount: U256) -> Result<()> {
+        let mut account = self
+            .db
+            .basic(to_raddress(address))?
+            .unwrap_or_default();
+        account.balance = to_ru256(amount);
+        self.db.insert_account_info(to_raddress(address), account);
+        Ok(())
+    }
+
+    /// 合约代码 (EOA 返回空)
+    pub fn get_code(&mut self, address: H160) -> Result<Vec<u8>> {
+        let account = self
+            .db
+            .basic(to_raddress(address))?
+            .unwrap_or_default();
+        Ok(account
+            .code
+            .map(|code| code.original_bytes().to_vec())
+            .unwrap_or_default())
+    }
+
+    pub fn get_storage(&mut self, address: H160, slot: U256) -> Result<U256> {
+        let value = self.db.storage(to_raddress(address), to_ru256(slot))?;
+        Ok(from_ru256(value))
+    }
+
+    pub fn insert_account_storage(&mut self, address: H160, slot: U256, value: U256) -> Result<()> {
+        self.db
+            .insert_account_storage(to_raddress(address), to_ru256(slot), to_ru256(value))?;
+        Ok(())
+    }
+
+    pub fn get_token_balance(&mut self, token: H160, owner: H160) -> Result<U256> {
+        let calldata = self.abi.token.encode("balanceOf", owner)?;
+        let value = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: token,
+            data: calldata.0.into(),
+            value: U256::zero(),
+            gas_price: self.base_fee,
+            gas_limit: 5000000,
+        })?;
+        let out: U256 = self.abi.token.decode_output("balanceOf", value.output)?;
+        Ok(out)
+    }
+
+    /// 直接改写 ERC20 balanceOf 映射对应的存储槽
+    /// slot = keccak256(abi.encode(owner, balance_slot))
+    pub fn set_token_balance(
+        &mut self,
+        token: H160,
+        owner: H160,
+        balance_slot: i32,
+        amount: U256,
+    ) -> Result<()> {
+        let slot = keccak256(abi::encode(&[
+            AbiToken::Address(owner),
+            AbiToken::Uint(U256::from(balance_slot)),
+        ]));
+        self.insert_account_storage(token, U256::from_big_endian(&slot), amount)
+    }
+
+    /// 暴力查找 ERC20 的 balance 存储槽
+    /// 改写候选槽之后 balanceOf 返回写入的值 就说明找到了
+    /// 使用代理模式的代币可能找不到
+    pub fn get_balance_slot(&mut self, token: H160) -> Result<i32> {
+        let probe = U256::from(0x1234567890u64);
+        let holder = H160::random();
+        for slot in 0..100 {
+            let slot_key = U256::from_big_endian(&keccak256(abi::encode(&[
+                AbiToken::Address(holder),
+                AbiToken::Uint(U256::from(slot)),
+            ])));
+            let original = from_ru256(
+                self.db
+                    .storage(to_raddress(token), to_ru256(slot_key))?,
+            );
+            self.insert_account_storage(token, slot_key, probe)?;
+            let balance = self.get_token_balance(token, holder).unwrap_or_default();
+            self.insert_account_storage(token, slot_key, original)?;
+            if balance == probe {
+                return Ok(slot);
+            }
+        }
+        Err(anyhow!("Balance slot not found"))
+    }
+
+    pub fn get_pair_reserves(&mut self, pair: H160) -> Result<(U256, U256)> {
+        let calldata = self.abi.pair.encode("getReserves", ())?;
+        let value = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: pair,
+            data: calldata.0.into(),
+            value: U256::zero(),
+            gas_price: self.base_fee,
+            gas_limit: 5000000,
+        })?;
+        let out: (U256, U256, u32) = self.abi.pair.decode_output("getReserves", value.output)?;
+        Ok((out.0, out.1))
+    }
 }
@@ -1,16 +1,17 @@
 use super::abi::Abi;
 use super::constants::Env;
+use super::transport::RpcProvider;
+use anyhow::{anyhow, Result};
 use ethers::prelude::*;
 use ethers::{middleware::SignerMiddleware, signers::LocalWallet, types::H160};
 use ethers_flashbots::*;
-use ethers_providers::{Provider, Ws};
 use std::str::FromStr;
 use std::{collections::HashMap, sync::Arc};
 use url::Url;
 /// 负责执行交易的核心组件,包含与区块链和 MEV 构建者交互所需的所有信息
 pub struct Executor {
-    /// 以太坊节点的 WebSocket Provider
-    pub provider: Arc<Provider<Ws>>,
+    /// 以太坊节点的 Provider (IPC / WS / HTTP 由配置决定)
+    pub provider: Arc<RpcProvider>,
     /// 所需合约的 ABI 集合
     pub abi: Abi,
     /// 机器人拥有者的钱包,用于签署交易
@@ -22,18 +23,20 @@ pub struct Executor {
     /// MEV 构建者的 URL 映射,key 是构建者名称,value 是其 RPC URL
     pub builder_urls: HashMap<String, Url>,
     /// 带有 Flashbots 中间件的签名客户端,用于提交 bundle 交易
-    pub client: SignerMiddleware<FlashbotsMiddleware<Arc<Provider<Ws>>, LocalWallet>, LocalWallet>,
+    pub client: SignerMiddleware<FlashbotsMiddleware<Arc<RpcProvider>, LocalWallet>, LocalWallet>,
 }
 impl Executor {
-    pub fn new(provider: Arc<Provider<Ws>>) -> Self {
-        let env = Env::new();
+    pub fn new(provider: Arc<RpcProvider>) -> Result<Self> {
+        let env = Env::new()?;
         let abi = Abi::new();
         // sandwich机器人合约地址
-        let bot_address = H160::from_str(&env.bot_address).unwrap();
+        let bot_address = H160::from_str(&env.bot_address)
+            .map_err(|e| anyhow!("BOT_ADDRESS = {:?} is invalid: {:?}", env.bot_address, e))?;
+        // 私钥出错时不把内容写进错误信息
         let owner = env
             .private_key
             .parse::<LocalWallet>()
-            .unwrap()
+            .map_err(|e| anyhow!("PRIVATE_KEY is invalid: {:?}", e))?
             .with_chain_id(1 as u64);
         // identity：
         // Flashbots 的身份钱包
@@ -43,7 +46,7 @@ impl Executor {
         let identity = env
             .identity_key
             .parse::<LocalWallet>()
-            .unwrap()
+            .map_err(|e| anyhow!("IDENTITY_KEY is invalid: {:?}", e))?
             .with_chain_id(1 as u64);
         // relay_url：
         // Flashbots 中继节点的 URL
@@ -113,7 +116,7 @@ impl Executor {
             Url::parse("https://rpc.idcmev.xyz").unwrap(),
         );
 
-        Self {
+        Ok(Self {
             provider,
             abi,
             owner,
@@ -121,6 +124,6 @@ impl Executor {
             bot_address,
             builder_urls,
             client,
-        }
+        })
     }
 }
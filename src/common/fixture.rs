@@ -0,0 +1,154 @@
+////////////////////////////////////////
+////JSON-RPC 响应录制与回放///
+////////////////////////////////////////
+
+// 文件格式: 每行一个 FixtureEntry
+//   {"method":"eth_getBlockByNumber","params":["0x10",false],"result":{...}}
+//   {"method":"eth_call","params":[...],"error":"execution reverted"}
+// 回放时按 method + params 查找 同一个请求录制了多次时按录制的顺序依次返回 最后一个重复使用
+
+use anyhow::Result;
+use async_trait::async_trait;
+use ethers_providers::{JsonRpcClient, ProviderError};
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    sync::{Arc, Mutex},
+};
+
+use super::transport::Transport;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixtureEntry {
+    pub method: String,
+    pub params: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn fixture_key(method: &str, params: &Value) -> String {
+    format!("{}:{}", method, params)
+}
+
+/// 从录制的响应回答请求 不需要节点 没有录制过的请求返回错误
+#[derive(Debug, Clone, Default)]
+pub struct FixtureClient {
+    responses: Arc<Mutex<HashMap<String, VecDeque<FixtureEntry>>>>,
+}
+impl FixtureClient {
+    pub fn load(path: &str) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut responses: HashMap<String, VecDeque<FixtureEntry>> = HashMap::new();
+        for line in reader.lines() {
+            let entry: FixtureEntry = serde_json::from_str(&line?)?;
+            responses
+                .entry(fixture_key(&entry.method, &entry.params))
+                .or_default()
+                .push_back(entry);
+        }
+        Ok(Self {
+            responses: Arc::new(Mutex::new(responses)),
+        })
+    }
+
+    fn next_entry(&self, key: &str) -> Option<FixtureEntry> {
+        let mut responses = self.responses.lock().unwrap();
+        let entries = responses.get_mut(key)?;
+        if entries.len() > 1 {
+            entries.pop_front()
+        } else {
+            entries.front().cloned()
+        }
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for FixtureClient {
+    type Error = ProviderError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params = serde_json::to_value(params)?;
+        let key = fixture_key(method, &params);
+        let entry = self
+            .next_entry(&key)
+            .ok_or_else(|| ProviderError::CustomError(format!("No fixture for {}", key)))?;
+        match (entry.result, entry.error) {
+            (Some(result), _) => Ok(serde_json::from_value(result)?),
+            (None, Some(error)) => Err(ProviderError::CustomError(error)),
+            (None, None) => Ok(serde_json::from_value(Value::Null)?),
+        }
+    }
+}
+
+/// 转发请求到真正的节点 同时把每个请求和响应写入 fixture 文件
+#[derive(Debug, Clone)]
+pub struct FixtureRecorder {
+    inner: Box<Transport>,
+    writer: Arc<Mutex<BufWriter<File>>>,
+}
+impl FixtureRecorder {
+    pub fn new(inner: Transport, path: &str) -> Result<Self> {
+        Ok(Self {
+            inner: Box::new(inner),
+            writer: Arc::new(Mutex::new(BufWriter::new(File::create(path)?))),
+        })
+    }
+
+    pub fn inner(&self) -> &Transport {
+        &self.inner
+    }
+
+    fn write_entry(&self, entry: &FixtureEntry) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, entry)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for FixtureRecorder {
+    type Error = ProviderError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params = serde_json::to_value(params)?;
+        // 先按 Value 取回结果 写入文件之后再转换成调用方需要的类型
+        let response: Result<Value, ProviderError> =
+            self.inner.request(method, params.clone()).await;
+        let entry = match &response {
+            Ok(result) => FixtureEntry {
+                method: method.to_string(),
+                params,
+                result: Some(result.clone()),
+                error: None,
+            },
+            Err(e) => FixtureEntry {
+                method: method.to_string(),
+                params,
+                result: None,
+                error: Some(e.to_string()),
+            },
+        };
+        match self.write_entry(&entry) {
+            Ok(_) => {}
+            Err(e) => warn!("Failed to record fixture: {:?}", e),
+        }
+        Ok(serde_json::from_value(response?)?)
+    }
+}
@@ -0,0 +1,451 @@
+////////////////////////////////////////
+////池子流动性分层///
+////////////////////////////////////////
+
+use ethers::types::{H160, U256};
+use log::info;
+use std::collections::HashMap;
+
+use super::{
+    constants::WETH,
+    pools::Pool,
+    reserves::ReserveBook,
+    utils::{get_main_currency, is_weth, return_main_and_target_currency, to_h160},
+};
+
+/// 按主币储备量划分的池子等级
+/// 以主币配置里的 min_liquidity 为单位: 不到 1 倍 Dead, 1~10 倍 Low, 10~100 倍 Medium, 再往上 High
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PoolTier {
+    /// 没有主币 / 没有储备量 / 流动性不足 不值得追踪和模拟
+    Dead,
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone)]
+pub struct LiquidityConfig {
+    /// 每隔多少个区块重新分层
+    pub refresh_interval: u64,
+    /// 一次三明治最多能拿走池子主币储备量的多少 (bps)
+    /// 换算成 ETH 之后和 gas 成本比较 估计的利润上限都不够 gas 的池子跳过
+    pub max_profit_bps: u64,
+    /// 一次三明治(前后两笔)大概消耗的 gas
+    pub sandwich_gas: u64,
+}
+impl Default for LiquidityConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: 50,
+            max_profit_bps: 100,
+            sandwich_gas: 250000,
+        }
+    }
+}
+
+/// 池子地址 -> 等级 用 ReserveBook 里的储备量计算 不需要额外请求
+#[derive(Debug, Clone, Default)]
+pub struct LiquidityTiers {
+    pub config: LiquidityConfig,
+    pub tiers: HashMap<H160, PoolTier>,
+    /// 池子主币一侧的储备量
+    pub main_reserves: HashMap<H160, U256>,
+    /// (token0, token1) -> 池子地址 用于追踪前按路由的 path 找池子
+    pub pairs_by_tokens: HashMap<(H160, H160), H160>,
+    /// 主币 -> (WETH 储备量, 主币储备量) 取自主币和 WETH 的池子 用于把主币换算成 ETH
+    pub eth_rates: HashMap<H160, (U256, U256)>,
+    /// 上次分层的区块
+    pub last_refresh: u64,
+}
+impl LiquidityTiers {
+    pub fn new(config: LiquidityConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn tier(&self, pair: &H160) -> PoolTier {
+        self.tiers.get(pair).cloned().unwrap_or(PoolTier::Dead)
+    }
+
+    /// 已经分过层的池子 (包括 Dead)
+    pub fn contains(&self, pair: &H160) -> bool {
+        self.tiers.contains_key(pair)
+    }
+
+    /// 两个代币之间的 V2 池子
+    pub fn pair_for(&self, token_a: H160, token_b: H160) -> Option<H160> {
+        self.pairs_by_tokens
+            .get(&sort_tokens(token_a, token_b))
+            .cloned()
+    }
+
+    /// 距离上次分层超过 refresh_interval 个区块时重新分层
+    pub fn maybe_refresh(
+        &mut self,
+        pools_map: &HashMap<H160, Pool>,
+        reserve_book: &ReserveBook,
+        block_number: u64,
+    ) {
+        if self.last_refresh > 0 && block_number < self.last_refresh + self.config.refresh_interval
+        {
+            return;
+        }
+        self.refresh(pools_map, reserve_book, block_number);
+    }
+
+    pub fn refresh(
+        &mut self,
+        pools_map: &HashMap<H160, Pool>,
+        reserve_book: &ReserveBook,
+        block_number: u64,
+    ) {
+        self.tiers.clear();
+        self.main_reserves.clear();
+        self.pairs_by_tokens.clear();
+        let mut counts: HashMap<PoolTier, usize> = HashMap::new();
+        for (address, pool) in pools_map {
+            let (tier, main_reserve) = classify(pool, reserve_book);
+            *counts.entry(tier).or_insert(0) += 1;
+            self.tiers.insert(*address, tier);
+            self.main_reserves.insert(*address, main_reserve);
+            self.pairs_by_tokens
+                .insert(sort_tokens(pool.token0, pool.token1), *address);
+        }
+        self.refresh_eth_rates(reserve_book);
+        self.last_refresh = block_number;
+        info!(
+            "Pool tiers at {:?}: high {:?} / medium {:?} / low {:?} / dead {:?}",
+            block_number,
+            counts.get(&PoolTier::High).unwrap_or(&0),
+            counts.get(&PoolTier::Medium).unwrap_or(&0),
+            counts.get(&PoolTier::Low).unwrap_or(&0),
+            counts.get(&PoolTier::Dead).unwrap_or(&0),
+        );
+    }
+
+    /// 新发现 / 新跟踪的池子 不等下一次分层 马上计算
+    pub fn update_pairs(
+        &mut self,
+        pairs: &[H160],
+        pools_map: &HashMap<H160, Pool>,
+        reserve_book: &ReserveBook,
+    ) {
+        for pair in pairs {
+            if let Some(pool) = pools_map.get(pair) {
+                self.insert_pair(pool, reserve_book.get(pair));
+            }
+        }
+    }
+
+    /// 用现场读取的储备量给单个池子分层 追踪工作线程发现新池子时调用
+    pub fn insert_pair(&mut self, pool: &Pool, reserves: Option<(U256, U256)>) {
+        let (tier, main_reserve) = classify_reserves(pool, reserves);
+        self.tiers.insert(pool.address, tier);
+        self.main_reserves.insert(pool.address, main_reserve);
+        self.pairs_by_tokens
+            .insert(sort_tokens(pool.token0, pool.token1), pool.address);
+    }
+
+    /// 用每个主币和 WETH 的池子的储备量作为换算比例 没有这样的池子的主币不换算
+    fn refresh_eth_rates(&mut self, reserve_book: &ReserveBook) {
+        self.eth_rates.clear();
+        let weth = to_h160(WETH);
+        for (&(token0, token1), pair) in &self.pairs_by_tokens {
+            let currency = if token0 == weth {
+                token1
+            } else if token1 == weth {
+                token0
+            } else {
+                continue;
+            };
+            if get_main_currency(currency).is_none() {
+                continue;
+            }
+            let (reserve0, reserve1) = match reserve_book.get(pair) {
+                Some(reserves) => reserves,
+                None => continue,
+            };
+            let rate = if token0 == weth {
+                (reserve0, reserve1)
+            } else {
+                (reserve1, reserve0)
+            };
+            if rate.1.is_zero() {
+                continue;
+            }
+            // 同一个主币有多个 WETH 池子时用 WETH 储备量最大的
+            match self.eth_rates.get(&currency) {
+                Some((weth_reserve, _)) if *weth_reserve >= rate.0 => {}
+                _ => {
+                    self.eth_rates.insert(currency, rate);
+                }
+            }
+        }
+    }
+
+    /// 把主币数量换算成 ETH 没有换算比例时返回 None
+    pub fn to_eth(&self, main_currency: H160, amount: U256) -> Option<U256> {
+        if is_weth(main_currency) {
+            return Some(amount);
+        }
+        let (weth_reserve, currency_reserve) = self.eth_rates.get(&main_currency)?;
+        amount
+            .checked_mul(*weth_reserve)
+            .map(|value| value / *currency_reserve)
+    }
+
+    /// 一次三明治按 base_fee 计算的 gas 成本 换算成主币 没有换算比例时返回 None
+    pub fn gas_cost_in(&self, main_currency: H160, base_fee: U256) -> Option<U256> {
+        let gas_cost = base_fee * U256::from(self.config.sandwich_gas);
+        if is_weth(main_currency) {
+            return Some(gas_cost);
+        }
+        let (weth_reserve, currency_reserve) = self.eth_rates.get(&main_currency)?;
+        if weth_reserve.is_zero() {
+            return None;
+        }
+        gas_cost
+            .checked_mul(*currency_reserve)
+            .map(|value| value / *weth_reserve)
+    }
+
+    /// 值得追踪和模拟的池子
+    /// 1. 不是 Dead
+    /// 2. 估计的利润上限换算成 ETH 之后要超过这次三明治的 gas 成本
+    ///    主币没有 WETH 池子换算时 只按等级判断
+    pub fn is_worth_simulating(&self, pair: &H160, main_currency: H160, base_fee: U256) -> bool {
+        if self.tier(pair) == PoolTier::Dead {
+            return false;
+        }
+        let main_reserve = self.main_reserves.get(pair).cloned().unwrap_or_default();
+        let max_profit = main_reserve * U256::from(self.config.max_profit_bps) / U256::from(10000);
+        let max_profit = match self.to_eth(main_currency, max_profit) {
+            Some(max_profit) => max_profit,
+            None => return true,
+        };
+        let gas_cost = base_fee * U256::from(self.config.sandwich_gas);
+        max_profit > gas_cost
+    }
+
+    /// 追踪前按路由 calldata 里的 path 判断 path 上所有的池子都已知并且不值得模拟时返回 false
+    /// 有不认识的池子(可能是刚创建的)时照常追踪
+    pub fn is_path_worth_tracing(&self, path: &[H160], base_fee: U256) -> bool {
+        for hop in path.windows(2) {
+            let pair = match self.pairs_by_tokens.get(&sort_tokens(hop[0], hop[1])) {
+                Some(pair) => pair,
+                None => return true,
+            };
+            let main_currency = match return_main_and_target_currency(hop[0], hop[1]) {
+                Some((main_currency, _)) => main_currency,
+                None => continue,
+            };
+            if self.is_worth_simulating(pair, main_currency, base_fee) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn sort_tokens(token0: H160, token1: H160) -> (H160, H160) {
+    if token0 < token1 {
+        (token0, token1)
+    } else {
+        (token1, token0)
+    }
+}
+
+/// 根据主币一侧的储备量给池子分层 返回 (等级, 主币储备量)
+pub fn classify(pool: &Pool, reserve_book: &ReserveBook) -> (PoolTier, U256) {
+    classify_reserves(pool, reserve_book.get(&pool.address))
+}
+
+/// 同 classify 储备量由调用方提供 没有储备量时是 Dead
+pub fn classify_reserves(pool: &Pool, reserves: Option<(U256, U256)>) -> (PoolTier, U256) {
+    let (main_currency, _) = match return_main_and_target_currency(pool.token0, pool.token1) {
+        Some(currencies) => currencies,
+        None => return (PoolTier::Dead, U256::zero()),
+    };
+    let mc = match get_main_currency(main_currency) {
+        Some(mc) => mc,
+        None => return (PoolTier::Dead, U256::zero()),
+    };
+    let (reserve0, reserve1) = match reserves {
+        Some(reserves) => reserves,
+        None => return (PoolTier::Dead, U256::zero()),
+    };
+    let main_reserve = if main_currency == pool.token0 {
+        reserve0
+    } else {
+        reserve1
+    };
+    let unit = mc.min_liquidity;
+    let tier = if main_reserve < unit {
+        PoolTier::Dead
+    } else if main_reserve < unit * U256::from(10) {
+        PoolTier::Low
+    } else if main_reserve < unit * U256::from(100) {
+        PoolTier::Medium
+    } else {
+        PoolTier::High
+    };
+    (tier, main_reserve)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{
+        constants::{USDC, USDT},
+        pools::DexVariant,
+    };
+
+    fn eth(amount: u64) -> U256 {
+        U256::from(amount) * U256::from(10).pow(U256::from(18))
+    }
+
+    fn usdt(amount: u64) -> U256 {
+        U256::from(amount) * U256::from(10).pow(U256::from(6))
+    }
+
+    fn gwei(amount: u64) -> U256 {
+        U256::from(amount) * U256::from(10).pow(U256::from(9))
+    }
+
+    fn token(byte: u8) -> H160 {
+        H160::repeat_byte(byte)
+    }
+
+    fn pool(address: u8, token_a: H160, token_b: H160) -> Pool {
+        let (token0, token1) = sort_tokens(token_a, token_b);
+        Pool {
+            id: address as i64,
+            address: H160::repeat_byte(address),
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            fee: 300,
+            block_number: 1,
+            timestamp: 0,
+            block_hash: None,
+        }
+    }
+
+    /// 测试用的池子表和储备量账本
+    #[derive(Default)]
+    struct Market {
+        pools: HashMap<H160, Pool>,
+        book: ReserveBook,
+    }
+    impl Market {
+        fn add(&mut self, pool: Pool, main: H160, main_reserve: U256, other_reserve: U256) {
+            let reserves = if pool.token0 == main {
+                (main_reserve, other_reserve)
+            } else {
+                (other_reserve, main_reserve)
+            };
+            self.book.reserves.insert(pool.address, reserves);
+            self.pools.insert(pool.address, pool);
+        }
+    }
+
+    #[test]
+    fn pools_are_tiered_by_main_reserve() {
+        let weth = to_h160(WETH);
+        // WETH 的 min_liquidity 是 10 ETH
+        // 0x01.. 排在 WETH 前面 0xf1.. 排在后面 两边都要能找到主币
+        let mut market = Market::default();
+        for (byte, reserve, tier) in [
+            (0x01, eth(5), PoolTier::Dead),
+            (0x02, eth(50), PoolTier::Low),
+            (0x03, eth(500), PoolTier::Medium),
+            (0x04, eth(5000), PoolTier::High),
+            (0xf1, eth(50), PoolTier::Low),
+        ] {
+            let pool = pool(byte, weth, token(byte));
+            market.add(pool, weth, reserve, U256::one());
+            assert_eq!(classify(&pool, &market.book), (tier, reserve));
+        }
+        // 没有主币 / 没有储备量
+        let no_main = pool(0x05, token(0x21), token(0x22));
+        assert_eq!(
+            classify_reserves(&no_main, Some((eth(1000), eth(1000)))).0,
+            PoolTier::Dead
+        );
+        let unknown = pool(0x06, weth, token(0x23));
+        assert_eq!(classify(&unknown, &market.book).0, PoolTier::Dead);
+    }
+
+    #[test]
+    fn main_currency_is_converted_to_eth() {
+        let weth = to_h160(WETH);
+        let usdt_address = to_h160(USDT);
+        // 1 ETH = 3000 USDT
+        let mut market = Market::default();
+        market.add(
+            pool(0x01, weth, usdt_address),
+            weth,
+            eth(1000),
+            usdt(3000000),
+        );
+        let mut tiers = LiquidityTiers::new(LiquidityConfig::default());
+        tiers.refresh(&market.pools, &market.book, 1);
+        assert_eq!(tiers.to_eth(usdt_address, usdt(3000)), Some(eth(1)));
+        assert_eq!(tiers.to_eth(weth, eth(7)), Some(eth(7)));
+        // 没有 WETH 池子的主币 / 不是主币 不换算
+        assert_eq!(tiers.to_eth(to_h160(USDC), usdt(1)), None);
+        assert_eq!(tiers.to_eth(token(0x31), U256::one()), None);
+        // gas 成本换算成主币: 250000 * 40 gwei = 0.01 ETH = 30 USDT
+        assert_eq!(tiers.gas_cost_in(weth, gwei(40)), Some(eth(1) / 100));
+        assert_eq!(tiers.gas_cost_in(usdt_address, gwei(40)), Some(usdt(30)));
+        assert_eq!(tiers.gas_cost_in(to_h160(USDC), gwei(40)), None);
+    }
+
+    #[test]
+    fn pools_below_gas_cost_are_skipped() {
+        let weth = to_h160(WETH);
+        let small = pool(0x01, weth, token(0x11));
+        let large = pool(0x02, weth, token(0x12));
+        let mut market = Market::default();
+        market.add(small, weth, eth(20), U256::one());
+        market.add(large, weth, eth(5000), U256::one());
+        let mut tiers = LiquidityTiers::new(LiquidityConfig::default());
+        tiers.refresh(&market.pools, &market.book, 1);
+        // 小池子的利润上限是 20 ETH 的 1% = 0.2 ETH  gas 是 250000 * base_fee
+        assert!(tiers.is_worth_simulating(&small.address, weth, gwei(10)));
+        assert!(!tiers.is_worth_simulating(&small.address, weth, gwei(1000)));
+        assert!(tiers.is_worth_simulating(&large.address, weth, gwei(1000)));
+        // 没有分过层的池子当作 Dead
+        assert!(!tiers.is_worth_simulating(&token(0x99), weth, gwei(10)));
+
+        // 路由 path: 有值得模拟的池子 / 有不认识的池子时追踪
+        assert!(!tiers.is_path_worth_tracing(&[weth, token(0x11)], gwei(1000)));
+        assert!(tiers.is_path_worth_tracing(&[token(0x11), weth, token(0x12)], gwei(1000)));
+        assert!(tiers.is_path_worth_tracing(&[weth, token(0x13)], gwei(1000)));
+        assert_eq!(tiers.pair_for(token(0x12), weth), Some(large.address));
+    }
+
+    #[test]
+    fn refresh_waits_for_interval() {
+        let weth = to_h160(WETH);
+        let target = pool(0x01, weth, token(0x11));
+        let mut market = Market::default();
+        market.add(target, weth, eth(50), U256::one());
+        let mut tiers = LiquidityTiers::new(LiquidityConfig::default());
+        tiers.maybe_refresh(&market.pools, &market.book, 100);
+        assert_eq!(tiers.tier(&target.address), PoolTier::Low);
+        market.add(target, weth, eth(5000), U256::one());
+        tiers.maybe_refresh(&market.pools, &market.book, 149);
+        assert_eq!(tiers.tier(&target.address), PoolTier::Low);
+        tiers.maybe_refresh(&market.pools, &market.book, 150);
+        assert_eq!(tiers.tier(&target.address), PoolTier::High);
+        // 新池子不等下一次分层
+        let new_pool = pool(0x02, weth, token(0x12));
+        // WETH 排在目标代币后面 是 reserve1
+        tiers.insert_pair(&new_pool, Some((U256::one(), eth(500))));
+        assert_eq!(tiers.tier(&new_pool.address), PoolTier::Medium);
+    }
+}
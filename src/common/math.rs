@@ -0,0 +1,110 @@
+////////////////////////////////////////
+////UniswapV2 本地计算///
+////////////////////////////////////////
+
+use ethers::types::U256;
+
+/// 税率用万分比(bps)表示 10000 = 100%
+pub static TAX_DENOMINATOR: u32 = 10000;
+
+/// UniswapV2 getAmountOut (0.3% 手续费)
+/// amountOut = amountIn * 997 * reserveOut / (reserveIn * 1000 + amountIn * 997)
+pub fn get_v2_amount_out(amount_in: U256, reserve_in: U256, reserve_out: U256) -> U256 {
+    if amount_in.is_zero() || reserve_in.is_zero() || reserve_out.is_zero() {
+        return U256::zero();
+    }
+    let amount_in_with_fee = amount_in * U256::from(997);
+    let numerator = amount_in_with_fee * reserve_out;
+    let denominator = reserve_in * U256::from(1000) + amount_in_with_fee;
+    numerator / denominator
+}
+
+/// 扣除转账税之后实际到账的数量
+pub fn apply_tax(amount: U256, tax_bps: u32) -> U256 {
+    let tax_bps = std::cmp::min(tax_bps, TAX_DENOMINATOR);
+    amount * U256::from(TAX_DENOMINATOR - tax_bps) / U256::from(TAX_DENOMINATOR)
+}
+
+/// 考虑转账税的 getAmountOut
+/// in_tax_bps: 转入池子时被扣的税(卖出税) 池子实际收到的数量变少
+/// out_tax_bps: 池子转出时被扣的税(买入税) 池子按完整数量计算 K 但我们收到的更少
+/// 返回 (池子转出的数量, 我们实际收到的数量)
+pub fn get_v2_amount_out_with_tax(
+    amount_in: U256,
+    reserve_in: U256,
+    reserve_out: U256,
+    in_tax_bps: u32,
+    out_tax_bps: u32,
+) -> (U256, U256) {
+    let received_by_pair = apply_tax(amount_in, in_tax_bps);
+    // 必须按池子实际收到的数量计算 否则 swap 会因为 K 校验失败而 revert
+    let amount_out = get_v2_amount_out(received_by_pair, reserve_in, reserve_out);
+    (amount_out, apply_tax(amount_out, out_tax_bps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eth(amount: u64) -> U256 {
+        U256::from(amount) * U256::exp10(18)
+    }
+
+    #[test]
+    fn amount_out() {
+        // 1000 * 997 * 10000 / (10000 * 1000 + 1000 * 997)
+        assert_eq!(
+            get_v2_amount_out(U256::from(1000), U256::from(10000), U256::from(10000)),
+            U256::from(906)
+        );
+        assert_eq!(
+            get_v2_amount_out(U256::zero(), eth(100), eth(100)),
+            U256::zero()
+        );
+        assert_eq!(
+            get_v2_amount_out(eth(1), U256::zero(), eth(100)),
+            U256::zero()
+        );
+    }
+
+    #[test]
+    fn without_tax_matches_amount_out() {
+        let amount_out = get_v2_amount_out(eth(1), eth(100), eth(5000));
+        assert_eq!(
+            get_v2_amount_out_with_tax(eth(1), eth(100), eth(5000), 0, 0),
+            (amount_out, amount_out)
+        );
+    }
+
+    #[test]
+    fn sell_tax_reduces_amount_received_by_pair() {
+        // 10% 卖出税 池子只收到 0.9
+        let expected = get_v2_amount_out(eth(9) / 10, eth(5000), eth(100));
+        assert_eq!(
+            get_v2_amount_out_with_tax(eth(1), eth(5000), eth(100), 1000, 0),
+            (expected, expected)
+        );
+    }
+
+    #[test]
+    fn buy_tax_reduces_amount_received() {
+        // 5% 买入税 池子按完整数量转出
+        let amount_out = get_v2_amount_out(eth(1), eth(100), eth(5000));
+        let (pair_out, received) = get_v2_amount_out_with_tax(eth(1), eth(100), eth(5000), 0, 500);
+        assert_eq!(pair_out, amount_out);
+        assert_eq!(received, amount_out * U256::from(95) / U256::from(100));
+    }
+
+    #[test]
+    fn tax_is_capped_at_100_percent() {
+        assert_eq!(apply_tax(eth(1), 20000), U256::zero());
+        assert_eq!(
+            get_v2_amount_out_with_tax(eth(1), eth(100), eth(5000), 0, 10000),
+            (get_v2_amount_out(eth(1), eth(100), eth(5000)), U256::zero())
+        );
+        assert_eq!(
+            get_v2_amount_out_with_tax(eth(1), eth(100), eth(5000), 10000, 0),
+            (U256::zero(), U256::zero())
+        );
+    }
+}
@@ -1,9 +1,21 @@
 pub mod abi;
 pub mod alert;
+pub mod balancer;
 pub mod bytecoode;
+pub mod cache_check;
 pub mod constants;
+pub mod dataset;
 pub mod evm;
 pub mod execution;
+pub mod fixture;
+pub mod liquidity;
+pub mod math;
 pub mod pools;
+pub mod reorg;
+pub mod reserves;
+pub mod risk;
+pub mod scanner;
+pub mod store;
 pub mod tokens;
+pub mod transport;
 pub mod utils;
@@ -5,16 +5,27 @@ use ethers::{
     middleware::gas_oracle::cache,
     types::{Filter, H160, H256, U256, U64},
 };
-use ethers_providers::{Middleware, Provider, Ws};
+use ethers_providers::{Middleware, ProviderError, RpcError};
+
+use super::{
+    abi::Abi,
+    constants::UNISWAP_V2_FACTORY,
+    reorg::{get_block_hash, rollback_if_reorged},
+    scanner::{scan_v2_pools, ScanConfig},
+    store::{Store, V2_POOLS_CHECKPOINT},
+    tokens::{get_token_info, Token},
+    transport::RpcProvider,
+    utils::{csv_field, to_h160},
+};
+use ethers::types::TransactionRequest;
+use futures::stream::{self, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
 use log::info;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    fs::{create_dir_all, OpenOptions},
-    path::Path,
+    collections::{HashMap, HashSet, VecDeque},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex, RwLock},
 };
 //Serialize 允许将数据结构转换为特定格式（如 JSON、YAML 等）
 //PartialEq 允许使用 == 和 != 运算符比较两个实例
@@ -28,6 +39,12 @@ impl DexVariant {
             DexVariant::UniswapV2 => 2,
         }
     }
+    pub fn from_version_num(version: u8) -> Self {
+        match version {
+            2 => DexVariant::UniswapV2,
+            _ => DexVariant::UniswapV2,
+        }
+    }
 }
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Pool {
@@ -40,6 +57,8 @@ pub struct Pool {
     pub fee: u32,
     pub block_number: u64,
     pub timestamp: u64,
+    // 创建区块的哈希 用于发现重组 旧的 csv 缓存里没有
+    pub block_hash: Option<H256>,
 }
 impl Pool {
     pub fn cache_row(&self) -> (i64, String, i32, String, String, u32, u64, u64) {
@@ -55,180 +74,133 @@ impl Pool {
         )
     }
 }
-// 接受csv 字符 转换为Pool实例
-impl From<StringRecord> for Pool {
-    fn from(record: StringRecord) -> Self {
+// 接受csv 字符 转换为Pool实例 格式不对的行返回错误而不是 panic
+impl TryFrom<StringRecord> for Pool {
+    type Error = anyhow::Error;
+
+    fn try_from(record: StringRecord) -> Result<Self> {
         // 获取版本 现只做V2
-        let version = match record.get(2).unwrap().parse().unwrap() {
-            2 => DexVariant::UniswapV2,
-            _ => DexVariant::UniswapV2,
-        };
-        Self {
-            id: record.get(0).unwrap().parse().unwrap(),
-            address: H160::from_str(record.get(1).unwrap()).unwrap(),
+        let version = DexVariant::from_version_num(csv_field(&record, 2, "version")?.parse()?);
+        Ok(Self {
+            id: csv_field(&record, 0, "id")?.parse()?,
+            address: H160::from_str(csv_field(&record, 1, "address")?)?,
             version,
-            token0: H160::from_str(record.get(3).unwrap()).unwrap(),
-            token1: H160::from_str(record.get(4).unwrap()).unwrap(),
-            fee: record.get(5).unwrap().parse().unwrap(),
-            block_number: record.get(6).unwrap().parse().unwrap(),
-            timestamp: record.get(7).unwrap().parse().unwrap(),
-        }
+            token0: H160::from_str(csv_field(&record, 3, "token0")?)?,
+            token1: H160::from_str(csv_field(&record, 4, "token1")?)?,
+            fee: csv_field(&record, 5, "fee")?.parse()?,
+            block_number: csv_field(&record, 6, "block_number")?.parse()?,
+            timestamp: csv_field(&record, 7, "timestamp")?.parse()?,
+            block_hash: None,
+        })
     }
 }
 // 加载所有的pool
+// 池子保存在 SQLite (cache/sandwich.db) 每扫完一个区块范围 新池子和同步进度在同一个事务里提交
+// 中断之后从 sync_checkpoints 记录的区块继续
 pub async fn load_all_pools(
-    wss_url: String,
+    provider: &Arc<RpcProvider>,
+    store: &Mutex<Store>,
     from_block: u64,
     chunk: u64,
 ) -> Result<(Vec<Pool>, i64)> {
-    // 创建池子缓存信息目录
-    match create_dir_all("cache") {
-        _ => {}
-    }
-    // 文件路径
-    let cache_file = "cache/.cached-pools.csv";
-    let file_path = Path::new(cache_file);
-    // 判断路径是否存在
-    let file_exists = file_path.exists();
-    let file_handle = OpenOptions::new()
-        .write(true)
-        .read(true)
-        .create(true)
-        .open(file_path)
-        .unwrap();
-    // 接收你的数据
-    // 把数据转换成 CSV 格式
-    // 然后通过底层的文件句柄（就是之前我们讨论的 file）写入到文件中
-    let mut writer = csv::Writer::from_writer(file_handle);
-    let mut pools: Vec<Pool> = Vec::new();
+    let mut pools: Vec<Pool> = store.lock().unwrap().load_pools()?;
     let mut v2_pool_cnt = 0;
-    // 如果文件存在直接从csv 读取并格式化
-    if file_exists {
-        let mut reader = csv::Reader::from_path(file_path)?;
-        for row in reader.records() {
-            let row = row.unwrap();
-            let pool = Pool::from(row);
-            match pool.version {
-                DexVariant::UniswapV2 => v2_pool_cnt += 1,
-            }
-            pools.push(pool);
+    for pool in &pools {
+        match pool.version {
+            DexVariant::UniswapV2 => v2_pool_cnt += 1,
         }
-    } else {
-        // 写入列
-        writer.write_record(&[
-            "id",
-            "address",
-            "version",
-            "token0",
-            "token1",
-            "fee",
-            "block_number",
-            "timestamp",
-        ])?;
     }
     info!("Pools loaded: {:?}", pools.len());
     info!("V2 pools: {:?}", v2_pool_cnt);
-    // 如果不存在 wss rpc 获取 并写入csv
-    // provider
-    let ws = Ws::connect(wss_url).await?;
-    let ws_provider = Arc::new(Provider::new(ws));
     // UniswapV2
-    // 事件
-    let pair_create_event = "PairCreated(address,address,address,unit256)";
-    // 事件转abi格式 .event() 是一个方法，它在 ABI 中查找名为 "PairCreated" 的事件
-    let abi = parse_abi(&[&format!("event {}", pair_create_event)]).unwrap();
-    // 事件签名唯一标识 或者说 topic[0]
-    let pair_created_signature = abi.event("PairCreated").unwrap().signature();
-    // 从文件存储的最新区块 查找到 rpc 获取的最新区块
-    let mut id = if pools.len() > 0 {
-        // as_ref() 借用 Option 内部值的引用
-        pools.last().as_ref().unwrap().id as i64
-    } else {
-        -1
-    };
-    let last_id = id as i64;
-    let from_block = if id != -1 {
-        pools.last().as_ref().unwrap().block_number
-    } else {
-        from_block
-    };
-    let to_block = ws_provider.get_block_number().await.unwrap().as_u64();
-    // 进度显示
-    let mut blocks_processed = 0;
-    let mut block_range = Vec::new();
-    loop {
-        let start_idx = from_block + blocks_processed;
-        let mut end_idx = start_idx + chunk - 1;
-        if end_idx > to_block {
-            end_idx = to_block;
-            block_range.push((start_idx, end_idx));
-            break;
-        }
-        block_range.push((start_idx, end_idx));
-        blocks_processed += chunk;
+    let (pair_create_event, pair_created_signature) = pair_created_event();
+    // 先确认缓存还在当前的链上 发生重组的话回滚到分叉点之前
+    if rollback_if_reorged(provider, store).await?.is_some() {
+        pools = store.lock().unwrap().load_pools()?;
     }
-    info!("Block range: {:?}", block_range);
-    let pb = ProgressBar::new(block_range.len() as u64);
-    pb.set_style(
-        ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-        )
-        .unwrap()
-        .progress_chars("##-"),
-    );
-    // 开始获取数据 添加进集合
-    for range in block_range {
-        let mut requests = Vec::new();
-        requests.push(tokio::task::spawn(load_uniswap_v2_pool(
-            ws_provider.clone(),
-            range.0,
-            range.1,
-            pair_create_event,
-            pair_created_signature,
-        )));
-        let results = futures::future::join_all(requests).await;
-        for result in results {
-            match result {
-                Ok(response) => match response {
-                    Ok(pools_response) => {
-                        pools.extend(pools_response);
-                    }
-                    _ => {}
-                },
-                _ => {}
-            }
+    // 已经存在的最大 id 之后新增的池子 id 都比它大
+    let last_id = pools.last().map(|p| p.id).unwrap_or(-1);
+    // 从上次同步完成的区块的下一个区块开始
+    let checkpoint = store.lock().unwrap().get_checkpoint(V2_POOLS_CHECKPOINT)?;
+    let from_block = match checkpoint {
+        Some(block) => block + 1,
+        None => from_block,
+    };
+    let to_block = provider.get_block_number().await.unwrap().as_u64();
+    info!("Block range: {:?}..={:?}", from_block, to_block);
+    // 并发 + 自适应范围扫描 失败的范围会重试 中断后从完成的位置继续
+    let scanned = scan_v2_pools(
+        provider.clone(),
+        store,
+        V2_POOLS_CHECKPOINT,
+        from_block,
+        to_block,
+        pair_create_event,
+        pair_created_signature,
+        ScanConfig::new(chunk),
+    )
+    .await;
+    let scanned = match scanned {
+        Ok(scanned) => scanned,
+        Err(e) => {
+            // 已经完成的范围都写进数据库了 从数据库重新读取
+            info!("Pool scan stopped: {:?}", e);
+            store.lock().unwrap().load_pools()?
         }
-
-        pb.inc(1);
-    }
-    //    遍历pool集合 递增id
+    };
+    // 重新扫描到的旧池子 id 不会超过 last_id
     let mut added = 0;
-    pools.sort_by_key(|p| p.block_number);
-    for pool in pools.iter_mut() {
-        if pool.id == -1 {
-            id += 1;
-            pool.id = id;
-        }
-        if (pool.id as i64) > last_id {
-            // 当你调用 writer.serialize() 时，数据通常会先存在内存缓冲区中
-            // 数据可能还在缓冲区
-            // 格式化pool 并写入csv
-            writer.serialize(pool.cache_row())?;
+    for pool in scanned {
+        if pool.id > last_id {
+            pools.push(pool);
             added += 1;
         }
     }
-    // writer.flush()? 的作用是强制将缓冲区中的数据写入到文件中
-    // 强制写入文件
-    writer.flush()?;
+    pools.sort_by_key(|p| p.id);
     info!("Added {:?} new pools", added);
 
     Ok((pools, last_id))
 }
+/// UniswapV2 PairCreated 事件和它的签名
+pub fn pair_created_event() -> (&'static str, H256) {
+    // 事件
+    let pair_create_event = "PairCreated(address,address,address,unit256)";
+    // 事件转abi格式 .event() 是一个方法，它在 ABI 中查找名为 "PairCreated" 的事件
+    let abi = parse_abi(&[&format!("event {}", pair_create_event)]).unwrap();
+    // 事件签名唯一标识 或者说 topic[0]
+    let pair_created_signature = abi.event("PairCreated").unwrap().signature();
+    (pair_create_event, pair_created_signature)
+}
+
+/// 扫描 [from_block, to_block] 的池子 并推进 checkpoint (重组回滚之后 / 运行时跟着新区块)
+pub async fn rescan_v2_pools(
+    provider: Arc<RpcProvider>,
+    store: &Mutex<Store>,
+    from_block: u64,
+    to_block: u64,
+) -> Result<Vec<Pool>> {
+    let (event, signature) = pair_created_event();
+    scan_v2_pools(
+        provider,
+        store,
+        V2_POOLS_CHECKPOINT,
+        from_block,
+        to_block,
+        event,
+        signature,
+        ScanConfig::new(to_block.saturating_sub(from_block) + 1),
+    )
+    .await
+}
+
+/// 获取池子创建区块时间戳的并发请求数
+static BLOCK_TIMESTAMP_CONCURRENCY: usize = 16;
+
 pub async fn load_uniswap_v2_pool(
-    provider: Arc<Provider<Ws>>,
+    provider: Arc<RpcProvider>,
     from_block: u64,
     to_block: u64,
-    event: &str,
+    event: &'static str,
     signature: H256,
 ) -> Result<Vec<Pool>> {
     let mut pools = Vec::new();
@@ -240,6 +212,23 @@ pub async fn load_uniswap_v2_pool(
         .event(event);
     // 获取所有的logs
     let logs = provider.get_logs(&event_filter).await?;
+    // 并发获取范围内所有出现过的区块的时间戳 同时最多 BLOCK_TIMESTAMP_CONCURRENCY 个请求
+    let mut block_numbers: Vec<U64> = logs
+        .iter()
+        .filter(|log| log.topics[0] == signature)
+        .map(|log| log.block_number.unwrap_or_default())
+        .collect();
+    block_numbers.dedup();
+    let mut blocks = stream::iter(block_numbers)
+        .map(|block_number| {
+            let provider = provider.clone();
+            async move { (block_number, provider.get_block(block_number).await) }
+        })
+        .buffer_unordered(BLOCK_TIMESTAMP_CONCURRENCY);
+    while let Some((block_number, block)) = blocks.next().await {
+        let block = block?.ok_or_else(|| anyhow::anyhow!("Block {:?} not found", block_number))?;
+        timestamp_map.insert(block_number, block.timestamp.as_u64());
+    }
     // 解析log
     for log in logs {
         let topic = log.topics[0];
@@ -247,15 +236,7 @@ pub async fn load_uniswap_v2_pool(
         if topic != signature {
             continue;
         }
-        let timestamp = if !timestamp_map.contains_key(&block_number) {
-            let block = provider.get_block(block_number).await.unwrap().unwrap();
-            let timestamp = block.timestamp.as_u64();
-            timestamp_map.insert(block_number, timestamp);
-            timestamp
-        } else {
-            let timestamp = *timestamp_map.get(&block_number).unwrap();
-            timestamp
-        };
+        let timestamp = *timestamp_map.get(&block_number).unwrap();
         let token0 = H160::from(log.topics[1]);
         let token1 = H160::from(log.topics[2]);
         // event PairCreated(address indexed token0, address indexed token1,address pair,uint256 allPairsLength);
@@ -275,6 +256,7 @@ pub async fn load_uniswap_v2_pool(
                 fee: 300,
                 block_number: block_number.as_u64(),
                 timestamp,
+                block_hash: log.block_hash,
             };
             pools.push(pool_data);
         };
@@ -282,3 +264,244 @@ pub async fn load_uniswap_v2_pool(
 
     Ok(pools)
 }
+
+
+/// 调用被 revert 时返回 None (合约没有这个方法) 节点 / 连接错误返回 Err
+async fn call_pair_address(
+    provider: &Arc<RpcProvider>,
+    to: H160,
+    calldata: ethers::types::Bytes,
+) -> Result<Option<ethers::types::Bytes>> {
+    let tx = TransactionRequest::new().to(to).data(calldata);
+    match provider.call(&tx.into(), None).await {
+        Ok(out) => Ok(Some(out)),
+        Err(e) if is_revert(&e) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// eth_call 的错误是不是合约 revert 回放时录制的错误是 CustomError 按错误信息判断
+fn is_revert(error: &ProviderError) -> bool {
+    match error {
+        ProviderError::CustomError(message) => message.contains("revert"),
+        _ => match error.as_error_response() {
+            Some(response) => response.is_revert(),
+            None => false,
+        },
+    }
+}
+
+/// 最多记录多少个被拒绝的地址
+pub static REJECTED_PAIRS_CAPACITY: usize = 10000;
+
+/// 发出过 Swap 事件但不是工厂创建的 UniswapV2 池子的地址
+/// 其它 fork 的池子 / 伪造事件的合约每笔交易都会出现 记录下来之后不再查询节点
+/// 超过容量时删除最早记录的地址
+#[derive(Debug)]
+pub struct RejectedPairs {
+    pairs: HashSet<H160>,
+    order: VecDeque<H160>,
+    capacity: usize,
+}
+impl RejectedPairs {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            pairs: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn contains(&self, pair: &H160) -> bool {
+        self.pairs.contains(pair)
+    }
+
+    pub fn insert(&mut self, pair: H160) {
+        if !self.pairs.insert(pair) {
+            return;
+        }
+        self.order.push_back(pair);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.pairs.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+/// 读取 token0 / token1 调用被 revert 或者返回的不是地址时返回 None
+async fn pair_tokens(
+    provider: &Arc<RpcProvider>,
+    abi: &Abi,
+    pair: H160,
+) -> Result<Option<(H160, H160)>> {
+    let mut tokens = Vec::new();
+    for method in ["token0", "token1"] {
+        let out = match call_pair_address(provider, pair, abi.pair.encode(method, ())?).await? {
+            Some(out) => out,
+            None => return Ok(None),
+        };
+        match abi.pair.decode_output::<H160, _>(method, out) {
+            Ok(token) => tokens.push(token),
+            Err(_) => return Ok(None),
+        }
+    }
+    Ok(Some((tokens[0], tokens[1])))
+}
+
+/// swap 日志里出现了 pools_map 里没有的池子(启动之后才创建 或者缓存里漏掉了)
+/// 现场查询 token0/token1 并通过工厂 getPair 确认是真正的 UniswapV2 池子
+/// 缺少的代币信息用 get_token_info 获取 然后插入 pools_map/tokens_map 并写入数据库
+/// 没有 token0/token1 或者不是工厂创建的地址记入 rejected_pairs 之后直接跳过
+/// 节点错误直接返回 不记入 rejected_pairs 下次遇到时重新查询
+/// 多个追踪工作线程共享这些表 节点请求全部完成之后才加锁 锁只在同步代码里持有
+pub async fn resolve_unknown_pair(
+    provider: &Arc<RpcProvider>,
+    block_number: U64,
+    pair: H160,
+    pools_map: &RwLock<HashMap<H160, Pool>>,
+    tokens_map: &RwLock<HashMap<H160, Token>>,
+    rejected_pairs: &Mutex<RejectedPairs>,
+    store: &Mutex<Store>,
+) -> Result<Option<Pool>> {
+    let rejected = rejected_pairs.lock().unwrap().contains(&pair);
+    if rejected {
+        return Ok(None);
+    }
+    // 其它工作线程已经收录了
+    let existing = pools_map.read().unwrap().get(&pair).cloned();
+    if existing.is_some() {
+        return Ok(existing);
+    }
+    let abi = Abi::new();
+    let (token0, token1) = match pair_tokens(provider, &abi, pair).await? {
+        Some(tokens) => tokens,
+        None => {
+            rejected_pairs.lock().unwrap().insert(pair);
+            return Ok(None);
+        }
+    };
+    // 任何合约都可以发出同样的 Swap 事件 必须确认是工厂创建的池子
+    let out = call_pair_address(
+        provider,
+        to_h160(UNISWAP_V2_FACTORY),
+        abi.factory.encode("getPair", (token0, token1))?,
+    )
+    .await?;
+    let factory_pair = match out {
+        Some(out) => abi.factory.decode_output::<H160, _>("getPair", out).ok(),
+        None => None,
+    };
+    if factory_pair != Some(pair) {
+        rejected_pairs.lock().unwrap().insert(pair);
+        return Ok(None);
+    }
+    // 代币信息 已经收录的代币记下 id 用于写入池子关系
+    let mut new_tokens = Vec::new();
+    let mut token_ids = Vec::new();
+    for token in [token0, token1] {
+        let known_id = tokens_map.read().unwrap().get(&token).map(|t| t.id);
+        if let Some(id) = known_id {
+            token_ids.push(id);
+            continue;
+        }
+        let token_info = get_token_info(provider, block_number.into(), token).await?;
+        new_tokens.push(Token {
+            id: -1,
+            address: token,
+            name: token_info.name,
+            symbol: token_info.symbol,
+            decimals: token_info.decimals,
+            pool_ids: Vec::new(),
+            tax: None,
+            known: token_info.known,
+            risk: None,
+        });
+    }
+    let mut pool = Pool {
+        id: -1,
+        address: pair,
+        version: DexVariant::UniswapV2,
+        token0,
+        token1,
+        fee: 300,
+        // 不知道创建区块 用发现时的区块
+        block_number: block_number.as_u64(),
+        timestamp: chrono::Utc::now().timestamp() as u64,
+        // 发现时的区块被重组掉的话 这个池子可能不存在
+        block_hash: Some(get_block_hash(provider, block_number.as_u64()).await?),
+    };
+    // 池子 / 代币 / 关系在一个事务里写入 id 由数据库分配
+    // 都是 INSERT OR IGNORE 同时发现同一个池子 / 代币的线程拿到的是同一行的 id
+    {
+        let mut store = store.lock().unwrap();
+        let tx = store.conn.transaction()?;
+        pool.id = Store::insert_pool(&tx, &pool)?;
+        for token_data in new_tokens.iter_mut() {
+            token_data.id = Store::insert_token(&tx, token_data)?;
+            token_ids.push(token_data.id);
+        }
+        for token_id in &token_ids {
+            Store::insert_pool_token(&tx, pool.id, *token_id)?;
+        }
+        // 两个代币都在上面收录了 之后的同步不用再处理这个池子
+        Store::mark_pool_resolved(&tx, pool.id)?;
+        tx.commit()?;
+    }
+    // 按 tokens -> pools 的顺序加锁 已经被其它线程写入的代币 / 池子保留原来的
+    let mut tokens_map = tokens_map.write().unwrap();
+    for token_data in new_tokens {
+        tokens_map.entry(token_data.address).or_insert(token_data);
+    }
+    for token in [token0, token1] {
+        if let Some(token_data) = tokens_map.get_mut(&token) {
+            if !token_data.pool_ids.contains(&pool.id) {
+                token_data.pool_ids.push(pool.id);
+            }
+        }
+    }
+    let mut pools_map = pools_map.write().unwrap();
+    if let Some(existing) = pools_map.get(&pair) {
+        return Ok(Some(*existing));
+    }
+    pools_map.insert(pair, pool);
+    info!("Discovered new pair: {:?}", pair);
+
+    Ok(Some(pool))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers_providers::{HttpClientError, JsonRpcError};
+
+    fn rpc_error(code: i64, message: &str) -> ProviderError {
+        ProviderError::JsonRpcClientError(Box::new(HttpClientError::JsonRpcError(JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        })))
+    }
+
+    #[test]
+    fn only_reverts_reject_a_pair() {
+        assert!(is_revert(&rpc_error(3, "execution reverted")));
+        assert!(is_revert(&ProviderError::CustomError(
+            "(code: 3, message: execution reverted, data: None)".to_string()
+        )));
+        // 节点的问题 不能把池子记入 rejected_pairs
+        assert!(!is_revert(&rpc_error(-32000, "header not found")));
+        assert!(!is_revert(&ProviderError::CustomError(
+            "No fixture for eth_call".to_string()
+        )));
+        assert!(!is_revert(&ProviderError::UnsupportedNodeClient));
+    }
+}
@@ -0,0 +1,187 @@
+////////////////////////////////////////
+////重组检测与回滚///
+////////////////////////////////////////
+
+use anyhow::{anyhow, Result};
+use ethers::types::{H160, H256};
+use ethers_providers::Middleware;
+use log::warn;
+use std::sync::{Arc, Mutex};
+
+use super::{
+    pools::{rescan_v2_pools, Pool},
+    store::{Store, V2_POOLS_CHECKPOINT},
+    transport::RpcProvider,
+};
+
+/// 只检查最近这么多个区块内的重组 更深的重组认为不会发生
+pub static MAX_REORG_DEPTH: u64 = 64;
+
+pub async fn get_block_hash(provider: &Arc<RpcProvider>, block_number: u64) -> Result<H256> {
+    let block = provider
+        .get_block(block_number)
+        .await?
+        .ok_or_else(|| anyhow!("Block {:?} not found", block_number))?;
+    block
+        .hash
+        .ok_or_else(|| anyhow!("Block {:?} has no hash", block_number))
+}
+
+/// 检查池子缓存的同步进度是否还在当前的链上
+/// checkpoint 区块的哈希变了 说明发生了重组:
+/// 从高到低检查最近创建的池子的区块哈希 第一个还在链上的区块就是分叉点 (找不到就回退 MAX_REORG_DEPTH)
+/// 回滚分叉点之后的池子 / 代币 / 进度 返回分叉点和被删除的池子
+pub async fn rollback_if_reorged(
+    provider: &Arc<RpcProvider>,
+    store: &Mutex<Store>,
+) -> Result<Option<(u64, Vec<H160>)>> {
+    let checkpoint_hash = store
+        .lock()
+        .unwrap()
+        .get_checkpoint_hash(V2_POOLS_CHECKPOINT)?;
+    let (checkpoint, hash) = match checkpoint_hash {
+        Some((block, Some(hash))) => (block, hash),
+        // 没有哈希的旧缓存无法校验
+        _ => return Ok(None),
+    };
+    if get_block_hash(provider, checkpoint).await? == hash {
+        return Ok(None);
+    }
+    let floor = checkpoint.saturating_sub(MAX_REORG_DEPTH);
+    let mut fork_point = floor;
+    let pool_block_hashes = store.lock().unwrap().pool_block_hashes_since(floor)?;
+    for (block, hash) in pool_block_hashes {
+        if block > checkpoint {
+            continue;
+        }
+        if get_block_hash(provider, block).await? == hash {
+            fork_point = block;
+            break;
+        }
+    }
+    warn!(
+        "Reorg detected: checkpoint {:?} is no longer canonical, rolling back to {:?}",
+        checkpoint, fork_point
+    );
+    let fork_hash = get_block_hash(provider, fork_point).await?;
+    let removed = store
+        .lock()
+        .unwrap()
+        .rollback_to(V2_POOLS_CHECKPOINT, fork_point, fork_hash)?;
+    Ok(Some((fork_point, removed)))
+}
+
+/// 新区块时的重组检查结果
+#[derive(Debug, Clone, Default)]
+pub struct ReorgOutcome {
+    /// 被重组掉的池子
+    pub removed: Vec<H160>,
+    /// 分叉点之后重新扫描到的池子
+    pub added: Vec<Pool>,
+}
+
+/// 每个新区块调用
+/// 1. 最近 MAX_REORG_DEPTH 个区块内记录的池子 (包括运行时发现的池子) 创建区块哈希不对的直接删除
+/// 2. checkpoint 还在最近 MAX_REORG_DEPTH 个区块内的话 校验它 重组了就回滚到分叉点
+/// 3. 从 checkpoint (或分叉点) 扫描到最新区块 checkpoint 跟着链头推进并记录哈希 下一个区块还能校验
+pub async fn check_reorg(
+    provider: &Arc<RpcProvider>,
+    store: &Mutex<Store>,
+    head: u64,
+) -> Result<ReorgOutcome> {
+    let mut outcome = ReorgOutcome::default();
+    let floor = head.saturating_sub(MAX_REORG_DEPTH);
+    let pool_block_hashes = store.lock().unwrap().pool_block_hashes_since(floor)?;
+    for (block, hash) in pool_block_hashes {
+        if get_block_hash(provider, block).await? != hash {
+            warn!("Pools created in reorged block {:?} removed", block);
+            let removed = store.lock().unwrap().remove_pools_with_block_hash(hash)?;
+            outcome.removed.extend(removed);
+        }
+    }
+    let checkpoint = store.lock().unwrap().get_checkpoint(V2_POOLS_CHECKPOINT)?;
+    let checkpoint = match checkpoint {
+        Some(checkpoint) => checkpoint,
+        // 还没有扫描过池子 启动时会完整扫描
+        None => return Ok(outcome),
+    };
+    let mut from_block = checkpoint + 1;
+    if checkpoint > floor {
+        if let Some((fork_point, removed)) = rollback_if_reorged(provider, store).await? {
+            outcome.removed.extend(removed);
+            from_block = fork_point + 1;
+        }
+    }
+    outcome.added = rescan_v2_pools(provider.clone(), store, from_block, head).await?;
+    Ok(outcome)
+}
+
+/// 记录上一次检查过的链头
+/// 新区块正好接在它后面时说明这段时间没有重组 只扫描新区块 不用逐个校验最近的区块哈希
+/// 第一次检查 / 跳过了区块 / 父哈希对不上时做完整的 check_reorg
+#[derive(Debug, Default)]
+pub struct ReorgWatcher {
+    last_head: Option<(u64, H256)>,
+}
+impl ReorgWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// head 的父区块就是上一次检查过的链头
+    pub fn extends(&self, head: u64, parent_hash: H256) -> bool {
+        match self.last_head {
+            Some((number, hash)) => number + 1 == head && hash == parent_hash,
+            None => false,
+        }
+    }
+
+    /// 每个新区块调用 检查失败时不记录链头 下一个区块做完整的检查
+    pub async fn check(
+        &mut self,
+        provider: &Arc<RpcProvider>,
+        store: &Mutex<Store>,
+        head: u64,
+    ) -> Result<ReorgOutcome> {
+        let block = provider
+            .get_block(head)
+            .await?
+            .ok_or_else(|| anyhow!("Block {:?} not found", head))?;
+        let hash = block
+            .hash
+            .ok_or_else(|| anyhow!("Block {:?} has no hash", head))?;
+        let outcome = if self.extends(head, block.parent_hash) {
+            let checkpoint = store.lock().unwrap().get_checkpoint(V2_POOLS_CHECKPOINT)?;
+            match checkpoint {
+                Some(checkpoint) if checkpoint < head => ReorgOutcome {
+                    removed: Vec::new(),
+                    added: rescan_v2_pools(provider.clone(), store, checkpoint + 1, head).await?,
+                },
+                _ => ReorgOutcome::default(),
+            }
+        } else {
+            check_reorg(provider, store, head).await?
+        };
+        self.last_head = Some((head, hash));
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_direct_child_of_the_last_head_skips_the_full_check() {
+        let mut watcher = ReorgWatcher::new();
+        let hash = H256::repeat_byte(0x11);
+        // 第一次检查
+        assert!(!watcher.extends(100, hash));
+        watcher.last_head = Some((100, hash));
+        assert!(watcher.extends(101, hash));
+        // 父哈希对不上 (重组) / 跳过了区块 / 同一高度的新区块
+        assert!(!watcher.extends(101, H256::repeat_byte(0x22)));
+        assert!(!watcher.extends(102, hash));
+        assert!(!watcher.extends(100, hash));
+    }
+}
@@ -0,0 +1,355 @@
+////////////////////////////////////////
+////V2 储备量账本///
+////////////////////////////////////////
+
+use anyhow::Result;
+use ethers::{
+    abi::ParamType,
+    types::{BlockNumber, Bytes, Filter, TransactionRequest, H160, H256, U256, U64},
+};
+use ethers_providers::Middleware;
+use futures::stream::{self, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+};
+
+use super::{
+    abi::Abi, constants::MULTICALL3, math::get_v2_amount_out, transport::RpcProvider,
+    utils::to_h160,
+};
+
+/// event Sync(uint112 reserve0, uint112 reserve1)
+pub static V2_SYNC_EVENT_SIGNATURE: &str =
+    "0x1c411e9a96e071241c2f21f7726b17ae89e3cab4c78be50e062b03a9fffbbad1";
+/// 一次 Multicall3 请求读取 getReserves 的池子数量
+pub static RESERVES_BATCH_SIZE: usize = 500;
+/// 同时进行的 Multicall3 请求数
+pub static RESERVES_CONCURRENCY: usize = 4;
+/// 漏掉的区块超过这个数量 直接全量同步
+pub static MAX_GAP_BLOCKS: u64 = 100;
+
+/// 内存中的 V2 储备量账本 key 是池子地址
+/// 启动时批量 getReserves 填充 之后每个新区块用 Sync 事件增量更新
+#[derive(Debug, Clone, Default)]
+pub struct ReserveBook {
+    /// pair -> (reserve0, reserve1)
+    pub reserves: HashMap<H160, (U256, U256)>,
+    /// 账本已经同步到的区块
+    pub last_block: u64,
+}
+impl ReserveBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, pair: &H160) -> Option<(U256, U256)> {
+        self.reserves.get(pair).cloned()
+    }
+
+    /// 用当前储备量计算 V2 的输出
+    pub fn get_amount_out(&self, pair: &H160, zero_for_one: bool, amount_in: U256) -> Option<U256> {
+        let (reserve0, reserve1) = self.get(pair)?;
+        if zero_for_one {
+            Some(get_v2_amount_out(amount_in, reserve0, reserve1))
+        } else {
+            Some(get_v2_amount_out(amount_in, reserve1, reserve0))
+        }
+    }
+
+    /// 启动时批量读取所有池子的储备量
+    pub async fn init(
+        &mut self,
+        provider: &Arc<RpcProvider>,
+        pairs: Vec<H160>,
+        block_number: U64,
+    ) -> Result<()> {
+        let pb = ProgressBar::new(pairs.len() as u64);
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+            )
+            .unwrap()
+            .progress_chars("##-"),
+        );
+        for batch in pairs.chunks(RESERVES_BATCH_SIZE * RESERVES_CONCURRENCY) {
+            let fetched = fetch_reserves(provider, batch, block_number).await;
+            self.reserves.extend(fetched);
+            pb.inc(batch.len() as u64);
+        }
+        self.last_block = block_number.as_u64();
+        info!("Reserve book initialized: {:?} pairs", self.reserves.len());
+        Ok(())
+    }
+
+    /// 开始跟踪新发现的池子
+    pub async fn track(
+        &mut self,
+        provider: &Arc<RpcProvider>,
+        pairs: Vec<H160>,
+        block_number: U64,
+    ) {
+        let pairs: Vec<H160> = pairs
+            .into_iter()
+            .filter(|pair| !self.reserves.contains_key(pair))
+            .collect();
+        if pairs.is_empty() {
+            return;
+        }
+        let fetched = fetch_reserves(provider, &pairs, block_number).await;
+        self.reserves.extend(fetched);
+    }
+
+    /// 账本在 block 之前漏掉的区块范围 还没有初始化时不算漏掉
+    pub fn missed_blocks(&self, block: u64) -> Option<(u64, u64)> {
+        if self.last_block > 0 && block > self.last_block + 1 {
+            Some((self.last_block + 1, block - 1))
+        } else {
+            None
+        }
+    }
+
+    /// 按日志顺序应用 Sync 事件 只更新账本里已经跟踪的池子
+    /// Sync 事件带的是完整的储备量 重复应用(例如重组后)也不会出错
+    pub fn apply_syncs(&mut self, syncs: Vec<(H160, (U256, U256))>) {
+        for (pair, reserves) in syncs {
+            if let Some(entry) = self.reserves.get_mut(&pair) {
+                *entry = reserves;
+            }
+        }
+    }
+
+    /// 新区块: 应用区块内的 Sync 事件
+    /// 如果中间漏掉了区块 找出漏掉的区块里有变动的池子 重新读取它们的储备量
+    pub async fn update(&mut self, provider: &Arc<RpcProvider>, block_number: U64) -> Result<()> {
+        let block = block_number.as_u64();
+        if let Some((gap_from, gap_to)) = self.missed_blocks(block) {
+            warn!(
+                "Reserve book missed blocks {:?}..={:?}, resyncing",
+                gap_from, gap_to
+            );
+            let affected = if gap_to - gap_from + 1 > MAX_GAP_BLOCKS {
+                self.reserves.keys().cloned().collect()
+            } else {
+                let logs = get_sync_logs(provider, gap_from, gap_to).await?;
+                logs.into_iter()
+                    .map(|(pair, _)| pair)
+                    .filter(|pair| self.reserves.contains_key(pair))
+                    .collect::<HashSet<H160>>()
+                    .into_iter()
+                    .collect::<Vec<H160>>()
+            };
+            for batch in affected.chunks(RESERVES_BATCH_SIZE * RESERVES_CONCURRENCY) {
+                let fetched = fetch_reserves(provider, batch, U64::from(gap_to)).await;
+                self.reserves.extend(fetched);
+            }
+        }
+        let syncs = get_sync_logs(provider, block, block).await?;
+        self.apply_syncs(syncs);
+        self.last_block = std::cmp::max(self.last_block, block);
+        Ok(())
+    }
+}
+
+/// 读取区块范围内所有的 Sync 事件 按日志顺序返回 (pair, (reserve0, reserve1))
+pub async fn get_sync_logs(
+    provider: &Arc<RpcProvider>,
+    from_block: u64,
+    to_block: u64,
+) -> Result<Vec<(H160, (U256, U256))>> {
+    let filter = Filter::new()
+        .from_block(BlockNumber::Number(U64::from(from_block)))
+        .to_block(BlockNumber::Number(U64::from(to_block)))
+        .topic0(H256::from_str(V2_SYNC_EVENT_SIGNATURE)?);
+    let logs = provider.get_logs(&filter).await?;
+    let mut syncs = Vec::new();
+    for log in logs {
+        if let Some(reserves) = decode_sync_data(&log.data) {
+            syncs.push((log.address, reserves));
+        }
+    }
+    Ok(syncs)
+}
+
+/// Sync 事件的 data: (reserve0, reserve1) 格式不对返回 None
+pub fn decode_sync_data(data: &Bytes) -> Option<(U256, U256)> {
+    let input = ethers::abi::decode(&[ParamType::Uint(112), ParamType::Uint(112)], data).ok()?;
+    let reserve0 = input[0].to_owned().into_uint()?;
+    let reserve1 = input[1].to_owned().into_uint()?;
+    Some((reserve0, reserve1))
+}
+
+/// 通过 Multicall3 读取池子的 getReserves 每 RESERVES_BATCH_SIZE 个池子一个请求
+/// 最多 RESERVES_CONCURRENCY 个请求同时进行 失败的池子 / 请求直接跳过
+pub async fn fetch_reserves(
+    provider: &Arc<RpcProvider>,
+    pairs: &[H160],
+    block_number: U64,
+) -> HashMap<H160, (U256, U256)> {
+    let abi = Abi::new();
+    let calldata = abi.pair.encode("getReserves", ()).unwrap();
+    let requests = pairs.chunks(RESERVES_BATCH_SIZE).map(|batch| {
+        let calls: Vec<(H160, bool, Bytes)> = batch
+            .iter()
+            .map(|pair| (*pair, true, calldata.clone()))
+            .collect();
+        let data = abi.multicall.encode("aggregate3", calls).unwrap();
+        let tx = TransactionRequest::new().to(to_h160(MULTICALL3)).data(data);
+        let provider = provider.clone();
+        async move {
+            let out = provider.call(&tx.into(), Some(block_number.into())).await;
+            (batch, out)
+        }
+    });
+    let results: Vec<_> = stream::iter(requests)
+        .buffer_unordered(RESERVES_CONCURRENCY)
+        .collect()
+        .await;
+    let mut reserves = HashMap::new();
+    for (batch, out) in results {
+        match out
+            .map_err(anyhow::Error::from)
+            .and_then(|out| decode_reserves(&abi, batch, out))
+        {
+            Ok(decoded) => reserves.extend(decoded),
+            Err(e) => warn!(
+                "Multicall getReserves for {:?} pairs failed: {:?}",
+                batch.len(),
+                e
+            ),
+        }
+    }
+    reserves
+}
+
+/// 解析一个 Multicall3 aggregate3 请求的返回 调用失败 / 返回格式不对的池子跳过
+pub fn decode_reserves(
+    abi: &Abi,
+    batch: &[H160],
+    out: Bytes,
+) -> Result<HashMap<H160, (U256, U256)>> {
+    let returns: Vec<(bool, Bytes)> = abi.multicall.decode_output("aggregate3", out)?;
+    let mut reserves = HashMap::new();
+    for (pair, (success, data)) in batch.iter().zip(returns) {
+        if !success {
+            continue;
+        }
+        if let Ok(out) = abi
+            .pair
+            .decode_output::<(U256, U256, u32), _>("getReserves", data)
+        {
+            reserves.insert(*pair, (out.0, out.1));
+        }
+    }
+    Ok(reserves)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{encode, Token};
+
+    fn pair(byte: u8) -> H160 {
+        H160::repeat_byte(byte)
+    }
+
+    fn reserves_output(reserve0: u64, reserve1: u64) -> Bytes {
+        encode(&[
+            Token::Uint(U256::from(reserve0)),
+            Token::Uint(U256::from(reserve1)),
+            Token::Uint(U256::from(1700000000u64)),
+        ])
+        .into()
+    }
+
+    fn book(pairs: &[(u8, u64, u64)], last_block: u64) -> ReserveBook {
+        ReserveBook {
+            reserves: pairs
+                .iter()
+                .map(|(byte, r0, r1)| (pair(*byte), (U256::from(*r0), U256::from(*r1))))
+                .collect(),
+            last_block,
+        }
+    }
+
+    #[test]
+    fn sync_data_decodes_both_reserves() {
+        let data: Bytes = encode(&[Token::Uint(U256::from(5)), Token::Uint(U256::from(7))]).into();
+        assert_eq!(
+            decode_sync_data(&data),
+            Some((U256::from(5), U256::from(7)))
+        );
+        assert_eq!(decode_sync_data(&Bytes::from(vec![0u8; 31])), None);
+    }
+
+    #[test]
+    fn multicall_output_skips_failed_pairs() {
+        let abi = Abi::new();
+        let returns = vec![
+            (true, reserves_output(100, 200)),
+            // getReserves revert 了
+            (false, Bytes::new()),
+            // 不是 V2 池子 返回的数据太短
+            (true, Bytes::from(vec![1u8; 8])),
+        ];
+        let out: Bytes = encode(&[Token::Array(
+            returns
+                .into_iter()
+                .map(|(success, data)| {
+                    Token::Tuple(vec![Token::Bool(success), Token::Bytes(data.to_vec())])
+                })
+                .collect(),
+        )])
+        .into();
+        let reserves = decode_reserves(&abi, &[pair(1), pair(2), pair(3)], out).unwrap();
+        assert_eq!(reserves.len(), 1);
+        assert_eq!(reserves[&pair(1)], (U256::from(100), U256::from(200)));
+        assert!(decode_reserves(&abi, &[pair(1)], Bytes::from(vec![0u8; 4])).is_err());
+    }
+
+    #[test]
+    fn syncs_update_tracked_pairs_in_log_order() {
+        let mut book = book(&[(1, 10, 10), (2, 20, 20)], 100);
+        book.apply_syncs(vec![
+            (pair(1), (U256::from(11), U256::from(9))),
+            (pair(3), (U256::from(1), U256::from(1))),
+            (pair(1), (U256::from(12), U256::from(8))),
+        ]);
+        assert_eq!(book.get(&pair(1)), Some((U256::from(12), U256::from(8))));
+        assert_eq!(book.get(&pair(2)), Some((U256::from(20), U256::from(20))));
+        // 没有跟踪的池子不加入账本
+        assert_eq!(book.get(&pair(3)), None);
+    }
+
+    #[test]
+    fn missed_blocks_only_after_init() {
+        assert_eq!(book(&[], 0).missed_blocks(105), None);
+        assert_eq!(book(&[], 100).missed_blocks(101), None);
+        assert_eq!(book(&[], 100).missed_blocks(100), None);
+        assert_eq!(book(&[], 100).missed_blocks(104), Some((101, 103)));
+    }
+
+    #[test]
+    fn amount_out_follows_direction() {
+        let book = book(&[(1, 1000000, 2000000)], 1);
+        let one_for_zero = book
+            .get_amount_out(&pair(1), false, U256::from(1000))
+            .unwrap();
+        let zero_for_one = book
+            .get_amount_out(&pair(1), true, U256::from(1000))
+            .unwrap();
+        assert_eq!(
+            zero_for_one,
+            get_v2_amount_out(U256::from(1000), U256::from(1000000), U256::from(2000000))
+        );
+        assert_eq!(
+            one_for_zero,
+            get_v2_amount_out(U256::from(1000), U256::from(2000000), U256::from(1000000))
+        );
+        assert!(zero_for_one > one_for_zero);
+        assert_eq!(book.get_amount_out(&pair(2), true, U256::from(1000)), None);
+    }
+}
@@ -0,0 +1,605 @@
+////////////////////////////////////////
+////代币风险分类///
+////////////////////////////////////////
+
+use anyhow::{anyhow, Result};
+use ethers::{
+    types::{H160, U256},
+    utils::id,
+};
+use ethers_providers::Middleware;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use super::{
+    evm::{EvmSimulator, Tx},
+    math::{get_v2_amount_out, TAX_DENOMINATOR},
+    store::Store,
+    tokens::{measure_token_tax, TokenTax},
+    transport::RpcProvider,
+    utils::get_main_currency,
+};
+
+/// EIP-1967 implementation 槽 keccak256("eip1967.proxy.implementation") - 1
+pub static EIP1967_IMPLEMENTATION_SLOT: &str =
+    "0x360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc";
+/// EIP-1967 beacon 槽 keccak256("eip1967.proxy.beacon") - 1
+pub static EIP1967_BEACON_SLOT: &str =
+    "0xa3f0ad74e5423aebfd80d3ef4346578335a9a72aeaee59ff6cb3582b35133d50";
+
+/// 单笔交易上限的查询函数
+static MAX_TX_SIGNATURES: &[&str] = &[
+    "_maxTxAmount()",
+    "maxTxAmount()",
+    "maxTransactionAmount()",
+    "maxTx()",
+    "_maxTx()",
+    "maxBuyAmount()",
+    "maxSellAmount()",
+];
+/// 单个地址持仓上限的查询函数
+static MAX_WALLET_SIGNATURES: &[&str] = &[
+    "_maxWalletSize()",
+    "maxWalletSize()",
+    "maxWallet()",
+    "_maxWalletToken()",
+    "maxWalletAmount()",
+    "maxHoldingAmount()",
+];
+static PAUSE_SIGNATURES: &[&str] = &["paused()", "pause()", "setPaused(bool)"];
+static BLACKLIST_SIGNATURES: &[&str] = &[
+    "blacklist(address)",
+    "addToBlacklist(address)",
+    "isBlacklisted(address)",
+    "setBlacklist(address,bool)",
+    "addBots(address[])",
+    "setBots(address[])",
+    "blockBots(address[])",
+    "isBot(address)",
+    "bots(address)",
+];
+/// owner 可以修改交易规则 / 增发的函数
+static OWNER_PRIVILEGE_SIGNATURES: &[&str] = &[
+    "setFee(uint256)",
+    "setFees(uint256,uint256)",
+    "setTaxes(uint256,uint256)",
+    "updateFees(uint256,uint256)",
+    "setBuyFee(uint256)",
+    "setSellFee(uint256)",
+    "mint(address,uint256)",
+    "setMaxTxAmount(uint256)",
+    "setTradingEnabled(bool)",
+    "excludeFromFee(address)",
+];
+
+/// 代币的风险属性 在 EvmSimulator 里测出来 和税率一起保存在代币缓存里
+/// 除了 sell_blocked 都是看合约代码里有没有对应的函数 属于启发式判断
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenRisk {
+    /// 普通地址买入之后无法卖回池子 (貔貅)
+    pub sell_blocked: bool,
+    /// 有生效中的单笔交易数量上限
+    pub max_tx: bool,
+    /// 有生效中的单个地址持仓上限
+    pub max_wallet: bool,
+    /// 可以暂停转账
+    pub pausable: bool,
+    /// 有黑名单 / 拉黑机器人的函数
+    pub blacklist: bool,
+    /// EIP-1967 可升级代理 逻辑随时可能被替换
+    pub upgradeable_proxy: bool,
+    /// owner 没有放弃权限 并且合约有改税率 / 增发之类的特权函数
+    pub owner_privileges: bool,
+    /// 测量时的区块号
+    pub block_number: u64,
+}
+impl TokenRisk {
+    /// 测量失败时按最危险处理
+    pub fn unsafe_at(block_number: u64) -> Self {
+        Self {
+            sell_blocked: true,
+            block_number,
+            ..Default::default()
+        }
+    }
+}
+
+/// 策略允许哪些风险
+#[derive(Debug, Clone)]
+pub struct RiskPolicy {
+    /// 买入 / 卖出 / 转账税的上限 (bps)
+    pub max_tax: u32,
+    pub allow_max_tx: bool,
+    pub allow_max_wallet: bool,
+    pub allow_pausable: bool,
+    pub allow_blacklist: bool,
+    pub allow_upgradeable_proxy: bool,
+    pub allow_owner_privileges: bool,
+}
+impl Default for RiskPolicy {
+    fn default() -> Self {
+        Self {
+            max_tax: 2500,
+            // 上限只影响夹的数量 不会卖不出去
+            allow_max_tx: true,
+            allow_max_wallet: true,
+            allow_pausable: false,
+            allow_blacklist: false,
+            allow_upgradeable_proxy: false,
+            // 新代币大多没有放弃 owner
+            allow_owner_privileges: true,
+        }
+    }
+}
+impl RiskPolicy {
+    /// 还没有测量的代币不允许
+    pub fn allows(&self, tax: Option<&TokenTax>, risk: Option<&TokenRisk>) -> bool {
+        let (tax, risk) = match (tax, risk) {
+            (Some(tax), Some(risk)) => (tax, risk),
+            _ => return false,
+        };
+        let max_tax = std::cmp::min(self.max_tax, TAX_DENOMINATOR);
+        if tax.buy_tax > max_tax || tax.sell_tax > max_tax || tax.transfer_tax > max_tax {
+            return false;
+        }
+        !(risk.sell_blocked
+            || (risk.max_tx && !self.allow_max_tx)
+            || (risk.max_wallet && !self.allow_max_wallet)
+            || (risk.pausable && !self.allow_pausable)
+            || (risk.blacklist && !self.allow_blacklist)
+            || (risk.upgradeable_proxy && !self.allow_upgradeable_proxy)
+            || (risk.owner_privileges && !self.allow_owner_privileges))
+    }
+}
+
+/// 函数选择器是否出现在合约代码里 (分发逻辑里是 PUSH4 selector)
+fn has_selector(code: &[u8], signature: &str) -> bool {
+    let selector = id(signature);
+    code.windows(5)
+        .any(|w| w[0] == 0x63 && w[1..] == selector[..])
+}
+
+fn has_any_selector(code: &[u8], signatures: &[&str]) -> bool {
+    signatures.iter().any(|sig| has_selector(code, sig))
+}
+
+/// 调用无参数的 view 函数 返回第一个字
+fn call_word<M: Middleware + 'static>(
+    simulator: &mut EvmSimulator<M>,
+    target: H160,
+    signature: &str,
+) -> Option<U256> {
+    let result = simulator
+        .staticcall(Tx {
+            caller: simulator.owner,
+            transact_to: target,
+            data: id(signature).to_vec().into(),
+            value: U256::zero(),
+            gas_price: simulator.base_fee,
+            gas_limit: 5000000,
+        })
+        .ok()?;
+    if result.output.len() < 32 {
+        return None;
+    }
+    Some(U256::from_big_endian(&result.output[..32]))
+}
+
+/// 有上限函数 并且上限小于总量 认为上限生效中
+fn has_active_limit<M: Middleware + 'static>(
+    simulator: &mut EvmSimulator<M>,
+    token: H160,
+    code: &[u8],
+    signatures: &[&str],
+    total_supply: U256,
+) -> bool {
+    for signature in signatures {
+        if !has_selector(code, signature) {
+            continue;
+        }
+        match call_word(simulator, token, signature) {
+            Some(limit) if !limit.is_zero() && limit < total_supply => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// 用一个全新的地址买入再卖出 卖出的任何一步 revert 就认为无法卖出
+fn is_sell_blocked<M: Middleware + 'static>(
+    simulator: &mut EvmSimulator<M>,
+    target_token: H160,
+    pair: H160,
+    main_currency: H160,
+    main_balance_slot: i32,
+    amount_in: U256,
+) -> Result<bool> {
+    let trader = H160::random();
+    let gas_price = simulator.base_fee;
+    simulator.set_eth_balance(trader, U256::from(10).pow(U256::from(20)))?;
+    let token = simulator.abi.token.clone();
+    let pair_abi = simulator.abi.pair.clone();
+    let token0_is_main = main_currency < target_token;
+    let swap_out = |amount: U256, main_out: bool| {
+        // main_out: 从池子换出主币
+        if token0_is_main == main_out {
+            (amount, U256::zero())
+        } else {
+            (U256::zero(), amount)
+        }
+    };
+
+    // 买入 买不到说明还没开放交易 不在这里判断
+    let (reserve0, reserve1) = simulator.get_pair_reserves(pair)?;
+    let (reserve_main, reserve_target) = if token0_is_main {
+        (reserve0, reserve1)
+    } else {
+        (reserve1, reserve0)
+    };
+    simulator.set_token_balance(main_currency, trader, main_balance_slot, amount_in)?;
+    simulator.call(Tx {
+        caller: trader,
+        transact_to: main_currency,
+        data: token.encode("transfer", (pair, amount_in))?.0.into(),
+        value: U256::zero(),
+        gas_price,
+        gas_limit: 5000000,
+    })?;
+    let (amount0_out, amount1_out) = swap_out(
+        get_v2_amount_out(amount_in, reserve_main, reserve_target),
+        false,
+    );
+    simulator.call(Tx {
+        caller: trader,
+        transact_to: pair,
+        data: pair_abi
+            .encode(
+                "swap",
+                (
+                    amount0_out,
+                    amount1_out,
+                    trader,
+                    ethers::types::Bytes::default(),
+                ),
+            )?
+            .0
+            .into(),
+        value: U256::zero(),
+        gas_price,
+        gas_limit: 5000000,
+    })?;
+    let bought = simulator.get_token_balance(target_token, trader)?;
+    if bought.is_zero() {
+        return Err(anyhow!("Buy received nothing"));
+    }
+
+    // 卖出: 转给池子 再从池子换出主币
+    let (reserve0, reserve1) = simulator.get_pair_reserves(pair)?;
+    let (reserve_main, reserve_target) = if token0_is_main {
+        (reserve0, reserve1)
+    } else {
+        (reserve1, reserve0)
+    };
+    let pair_before = simulator.get_token_balance(target_token, pair)?;
+    let transfer = simulator.call(Tx {
+        caller: trader,
+        transact_to: target_token,
+        data: token.encode("transfer", (pair, bought))?.0.into(),
+        value: U256::zero(),
+        gas_price,
+        gas_limit: 5000000,
+    });
+    if transfer.is_err() {
+        return Ok(true);
+    }
+    // 池子余额变少 说明代币在转账时动了池子的余额 当作卖不出去
+    let pair_received = match simulator
+        .get_token_balance(target_token, pair)?
+        .checked_sub(pair_before)
+    {
+        Some(pair_received) => pair_received,
+        None => return Ok(true),
+    };
+    let main_out = get_v2_amount_out(pair_received, reserve_target, reserve_main);
+    if main_out.is_zero() {
+        return Ok(true);
+    }
+    let (amount0_out, amount1_out) = swap_out(main_out, true);
+    let swap = simulator.call(Tx {
+        caller: trader,
+        transact_to: pair,
+        data: pair_abi
+            .encode(
+                "swap",
+                (
+                    amount0_out,
+                    amount1_out,
+                    trader,
+                    ethers::types::Bytes::default(),
+                ),
+            )?
+            .0
+            .into(),
+        value: U256::zero(),
+        gas_price,
+        gas_limit: 5000000,
+    });
+    Ok(swap.is_err())
+}
+
+/// 在模拟器中测量代币的风险属性
+/// 1. 全新地址买入再卖出 -> sell_blocked
+/// 2. 合约代码(代理的话加上实现合约的代码)里的函数选择器 -> 上限 / 暂停 / 黑名单 / 特权函数
+/// 3. EIP-1967 槽 -> 可升级代理
+pub fn measure_token_risk<M: Middleware + 'static>(
+    simulator: &mut EvmSimulator<M>,
+    target_token: H160,
+    pair: H160,
+    main_currency: H160,
+    main_balance_slot: i32,
+    amount_in: U256,
+) -> Result<TokenRisk> {
+    let mut code = simulator.get_code(target_token)?;
+    let implementation =
+        simulator.get_storage(target_token, U256::from_str(EIP1967_IMPLEMENTATION_SLOT)?)?;
+    let beacon = simulator.get_storage(target_token, U256::from_str(EIP1967_BEACON_SLOT)?)?;
+    let upgradeable_proxy = !implementation.is_zero() || !beacon.is_zero();
+    if !implementation.is_zero() {
+        let mut bytes = [0u8; 32];
+        implementation.to_big_endian(&mut bytes);
+        code.extend(simulator.get_code(H160::from_slice(&bytes[12..]))?);
+    }
+
+    let total_supply = call_word(simulator, target_token, "totalSupply()").unwrap_or(U256::MAX);
+    let max_tx = has_active_limit(
+        simulator,
+        target_token,
+        &code,
+        MAX_TX_SIGNATURES,
+        total_supply,
+    );
+    let max_wallet = has_active_limit(
+        simulator,
+        target_token,
+        &code,
+        MAX_WALLET_SIGNATURES,
+        total_supply,
+    );
+    let owner = call_word(simulator, target_token, "owner()").unwrap_or_default();
+    let owner_privileges = !owner.is_zero() && has_any_selector(&code, OWNER_PRIVILEGE_SIGNATURES);
+
+    let sell_blocked = is_sell_blocked(
+        simulator,
+        target_token,
+        pair,
+        main_currency,
+        main_balance_slot,
+        amount_in,
+    )?;
+
+    Ok(TokenRisk {
+        sell_blocked,
+        max_tx,
+        max_wallet,
+        pausable: has_any_selector(&code, PAUSE_SIGNATURES),
+        blacklist: has_any_selector(&code, BLACKLIST_SIGNATURES),
+        upgradeable_proxy,
+        owner_privileges,
+        block_number: simulator.block_number.as_u64(),
+    })
+}
+
+/// 重新测量过期的代币 (没有测量过 / 测量区块早于 head - max_age 的)
+/// 需要代币有一个和主币组成的池子 没有的跳过
+/// 返回重新测量的代币数量
+pub async fn remeasure_stale_tokens(
+    provider: Arc<RpcProvider>,
+    max_age: u64,
+    limit: usize,
+) -> Result<usize> {
+    let store = Store::open_default()?;
+    let head = provider.get_block_number().await?;
+    let min_block = head.as_u64().saturating_sub(max_age);
+    let tokens_map = store.load_tokens()?;
+    let pools_by_id: HashMap<i64, _> = store.load_pools()?.into_iter().map(|p| (p.id, p)).collect();
+    let stale: Vec<_> = tokens_map
+        .values()
+        .filter(|token| get_main_currency(token.address).is_none())
+        .filter(|token| match (&token.tax, &token.risk) {
+            (Some(tax), Some(risk)) => {
+                tax.block_number < min_block || risk.block_number < min_block
+            }
+            _ => true,
+        })
+        .take(limit)
+        .collect();
+    info!("Stale tokens: {:?}", stale.len());
+
+    let pb = ProgressBar::new(stale.len() as u64);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+        )
+        .unwrap()
+        .progress_chars("##-"),
+    );
+    // 模拟交易的发送者 零地址在一些代币里被当成销毁地址 / 黑名单 测量结果不准
+    let owner = H160::random();
+    let mut measured = 0;
+    for token in stale {
+        pb.inc(1);
+        // 找一个和主币组成的池子
+        let pool = token
+            .pool_ids
+            .iter()
+            .filter_map(|id| pools_by_id.get(id))
+            .find(|p| {
+                get_main_currency(p.token0).is_some() || get_main_currency(p.token1).is_some()
+            });
+        let pool = match pool {
+            Some(pool) => pool,
+            None => continue,
+        };
+        let main_currency = if get_main_currency(pool.token0).is_some() {
+            pool.token0
+        } else {
+            pool.token1
+        };
+        let mc = get_main_currency(main_currency).unwrap();
+        // 税率和风险分开模拟 互不影响状态
+        let mut simulator = EvmSimulator::new(provider.clone(), Some(owner), head);
+        let tax = measure_token_tax(
+            &mut simulator,
+            token.address,
+            pool.address,
+            main_currency,
+            mc.balance_slot,
+            mc.probe_amount,
+        )
+        .unwrap_or_else(|_| TokenTax::untradeable_at(head.as_u64()));
+        let mut simulator = EvmSimulator::new(provider.clone(), Some(owner), head);
+        let risk = match measure_token_risk(
+            &mut simulator,
+            token.address,
+            pool.address,
+            main_currency,
+            mc.balance_slot,
+            mc.probe_amount,
+        ) {
+            Ok(risk) => risk,
+            Err(e) => {
+                warn!("Risk measurement failed for {:?}: {:?}", token.address, e);
+                TokenRisk::unsafe_at(head.as_u64())
+            }
+        };
+        store.save_token_tax(token.address, &tax)?;
+        store.save_token_risk(token.address, &risk)?;
+        measured += 1;
+    }
+    info!("Re-measured {:?} tokens", measured);
+    Ok(measured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::tokens::{KnownFields, Token};
+
+    fn tax(bps: u32) -> TokenTax {
+        TokenTax {
+            buy_tax: bps,
+            sell_tax: 0,
+            transfer_tax: 0,
+            rebasing: false,
+            block_number: 1,
+        }
+    }
+
+    /// PUSH4 selector 后面跟 EQ 合约分发逻辑的样子
+    fn dispatch(signatures: &[&str]) -> Vec<u8> {
+        let mut code = vec![0x60, 0x80, 0x60, 0x40, 0x52];
+        for signature in signatures {
+            code.push(0x63);
+            code.extend(id(signature));
+            code.push(0x14);
+        }
+        code
+    }
+
+    #[test]
+    fn unmeasured_tokens_are_not_allowed() {
+        let policy = RiskPolicy::default();
+        assert!(!policy.allows(None, None));
+        assert!(!policy.allows(Some(&tax(0)), None));
+        assert!(!policy.allows(None, Some(&TokenRisk::default())));
+        assert!(policy.allows(Some(&tax(0)), Some(&TokenRisk::default())));
+    }
+
+    #[test]
+    fn policy_limits_tax_and_flags() {
+        let policy = RiskPolicy::default();
+        let clean = TokenRisk::default();
+        assert!(policy.allows(Some(&tax(policy.max_tax)), Some(&clean)));
+        assert!(!policy.allows(Some(&tax(policy.max_tax + 1)), Some(&clean)));
+        // 测量失败的代币无论策略怎么配置都不允许
+        assert!(!policy.allows(Some(&tax(0)), Some(&TokenRisk::unsafe_at(1))));
+        let permissive = RiskPolicy {
+            max_tax: u32::MAX,
+            allow_max_tx: true,
+            allow_max_wallet: true,
+            allow_pausable: true,
+            allow_blacklist: true,
+            allow_upgradeable_proxy: true,
+            allow_owner_privileges: true,
+        };
+        assert!(!permissive.allows(Some(&tax(0)), Some(&TokenRisk::unsafe_at(1))));
+        // max_tax 超过 100% 时按 100% 算
+        assert!(!permissive.allows(Some(&tax(TAX_DENOMINATOR + 1)), Some(&clean)));
+        let blacklist = TokenRisk {
+            blacklist: true,
+            ..Default::default()
+        };
+        assert!(!policy.allows(Some(&tax(0)), Some(&blacklist)));
+        assert!(permissive.allows(Some(&tax(0)), Some(&blacklist)));
+        let max_tx = TokenRisk {
+            max_tx: true,
+            ..Default::default()
+        };
+        assert!(policy.allows(Some(&tax(0)), Some(&max_tx)));
+    }
+
+    #[test]
+    fn selectors_are_found_only_as_push4() {
+        let code = dispatch(&["paused()", "setFee(uint256)"]);
+        assert!(has_selector(&code, "paused()"));
+        assert!(has_any_selector(&code, PAUSE_SIGNATURES));
+        assert!(has_any_selector(&code, OWNER_PRIVILEGE_SIGNATURES));
+        assert!(!has_any_selector(&code, BLACKLIST_SIGNATURES));
+        // 同样的 4 个字节不在 PUSH4 之后 (例如是别的数据的一部分) 不算
+        let mut data = vec![0x60];
+        data.extend(id("isBot(address)"));
+        assert!(!has_selector(&data, "isBot(address)"));
+        assert!(!has_selector(&[], "paused()"));
+    }
+
+    #[test]
+    fn risk_is_persisted_with_the_token() {
+        let mut store = Store::open(":memory:").unwrap();
+        let address = H160::repeat_byte(1);
+        let tx = store.conn.transaction().unwrap();
+        Store::insert_token(
+            &tx,
+            &Token {
+                id: -1,
+                address,
+                name: String::from("Token"),
+                symbol: String::from("TKN"),
+                decimals: 18,
+                pool_ids: Vec::new(),
+                tax: None,
+                known: KnownFields::default(),
+                risk: None,
+            },
+        )
+        .unwrap();
+        tx.commit().unwrap();
+        let risk = TokenRisk {
+            max_wallet: true,
+            upgradeable_proxy: true,
+            block_number: 42,
+            ..Default::default()
+        };
+        store.save_token_risk(address, &risk).unwrap();
+        assert_eq!(store.load_tokens().unwrap()[&address].risk, Some(risk));
+        // 重新测量覆盖旧的结果
+        store
+            .save_token_risk(address, &TokenRisk::unsafe_at(43))
+            .unwrap();
+        assert_eq!(
+            store.load_tokens().unwrap()[&address].risk,
+            Some(TokenRisk::unsafe_at(43))
+        );
+    }
+}
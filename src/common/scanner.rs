@@ -0,0 +1,419 @@
+////////////////////////////////////////
+////并发自适应日志扫描///
+////////////////////////////////////////
+
+use anyhow::{anyhow, Result};
+use ethers::types::H256;
+use futures::stream::{FuturesUnordered, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    pools::{load_uniswap_v2_pool, Pool},
+    reorg::get_block_hash,
+    store::Store,
+    transport::RpcProvider,
+};
+
+/// 扫描参数
+#[derive(Debug, Clone)]
+pub struct ScanConfig {
+    /// 同时请求的区块范围数量
+    pub concurrency: usize,
+    /// 初始的区块范围大小
+    pub initial_chunk: u64,
+    /// 节点报结果太多时 最小缩到这个范围
+    pub min_chunk: u64,
+    /// 结果稀疏时 最大放大到这个范围
+    pub max_chunk: u64,
+    /// 一个范围内的结果少于这个数量 认为结果稀疏 放大范围
+    pub sparse_threshold: usize,
+    /// 同一个范围最多重试的次数
+    pub max_retries: u32,
+}
+impl ScanConfig {
+    pub fn new(initial_chunk: u64) -> Self {
+        Self {
+            concurrency: 8,
+            initial_chunk,
+            min_chunk: 100,
+            max_chunk: 500000,
+            sparse_threshold: 1000,
+            max_retries: 5,
+        }
+    }
+}
+
+/// 节点返回的 "结果太多 / 范围太大" 类错误 需要缩小范围而不是重试
+pub fn is_range_too_large(err: &anyhow::Error) -> bool {
+    let msg = format!("{:?}", err).to_lowercase();
+    msg.contains("too many")
+        || msg.contains("more than")
+        || msg.contains("limit exceeded")
+        || msg.contains("response size")
+        || msg.contains("block range")
+        || msg.contains("query timeout")
+}
+
+/// 扫描 [from_block, to_block] 内的 PairCreated 事件 同时记录每个范围最后一个区块的哈希
+pub async fn scan_v2_pools(
+    provider: Arc<RpcProvider>,
+    store: &Mutex<Store>,
+    checkpoint_name: &str,
+    from_block: u64,
+    to_block: u64,
+    event: &'static str,
+    signature: H256,
+    config: ScanConfig,
+) -> Result<Vec<Pool>> {
+    scan_ranges(
+        store,
+        checkpoint_name,
+        from_block,
+        to_block,
+        config,
+        |start, end| {
+            let provider = provider.clone();
+            async move {
+                // 范围最后一个区块的哈希 用于之后发现重组
+                let pools =
+                    load_uniswap_v2_pool(provider.clone(), start, end, event, signature).await?;
+                let hash = get_block_hash(&provider, end).await?;
+                Ok((pools, hash))
+            }
+        },
+    )
+    .await
+}
+
+/// 用 fetch 分段读取 [from_block, to_block] fetch 返回范围内的池子和 end 区块的哈希
+/// 1. 最多 concurrency 个范围同时请求
+/// 2. 节点报结果太多 把范围对半拆开重新请求 并缩小之后的范围
+/// 3. 结果稀疏 放大之后的范围
+/// 4. 其他错误重试 超过 max_retries 次就停止 (已完成的部分保留)
+/// 5. 每个完成的范围和它的池子在一个事务里写入 synced_ranges
+///    checkpoint 只推进到连续完成的位置 中断之后可以精确续扫
+pub async fn scan_ranges<F, Fut>(
+    store: &Mutex<Store>,
+    checkpoint_name: &str,
+    from_block: u64,
+    to_block: u64,
+    config: ScanConfig,
+    fetch: F,
+) -> Result<Vec<Pool>>
+where
+    F: Fn(u64, u64) -> Fut,
+    Fut: Future<Output = Result<(Vec<Pool>, H256)>>,
+{
+    let mut pools = Vec::new();
+    if from_block > to_block {
+        return Ok(pools);
+    }
+    // 上次中断时 checkpoint 之后已经完成的范围 start -> (end, end 区块的哈希)
+    // 没有哈希的旧记录无法校验 重新扫描
+    let synced_ranges = store.lock().unwrap().load_synced_ranges(checkpoint_name)?;
+    let mut done: BTreeMap<u64, (u64, H256)> = synced_ranges
+        .into_iter()
+        .filter(|(_, end, _)| *end >= from_block)
+        .filter_map(|(start, end, hash)| hash.map(|h| (start, (end, h))))
+        .collect();
+    // 已知的 checkpoint 哈希 不知道时为 0 (不做校验)
+    let checkpoint_hash = store.lock().unwrap().get_checkpoint_hash(checkpoint_name)?;
+    let checkpoint_hash = match checkpoint_hash {
+        Some((block, Some(hash))) if block + 1 == from_block => hash,
+        _ => H256::zero(),
+    };
+    let mut checkpoint = (from_block - 1, checkpoint_hash);
+
+    let pb = ProgressBar::new(to_block - from_block + 1);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+        )
+        .unwrap()
+        .progress_chars("##-"),
+    );
+    for (start, (end, _)) in &done {
+        pb.inc(end - start + 1);
+    }
+
+    let mut chunk = config.initial_chunk;
+    let mut next_start = from_block;
+    // (start, end, 已重试次数)
+    let mut retry_queue: VecDeque<(u64, u64, u32)> = VecDeque::new();
+    let mut in_flight = FuturesUnordered::new();
+    loop {
+        while in_flight.len() < config.concurrency {
+            let range = match retry_queue.pop_front() {
+                Some(range) => Some(range),
+                None => next_range(&done, &mut next_start, to_block, chunk).map(|(s, e)| (s, e, 0)),
+            };
+            let (start, end, attempts) = match range {
+                Some(range) => range,
+                None => break,
+            };
+            let request = fetch(start, end);
+            in_flight.push(async move { (start, end, attempts, request.await) });
+        }
+        let (start, end, attempts, result) = match in_flight.next().await {
+            Some(out) => out,
+            None => break,
+        };
+        match result {
+            Ok((mut new_pools, end_hash)) => {
+                if new_pools.len() < config.sparse_threshold {
+                    chunk = std::cmp::min(chunk * 2, config.max_chunk);
+                }
+                new_pools.sort_by_key(|p| p.block_number);
+                pools.extend(complete_range(
+                    store,
+                    checkpoint_name,
+                    &mut done,
+                    &mut checkpoint,
+                    start,
+                    (end, end_hash),
+                    new_pools,
+                )?);
+                pb.inc(end - start + 1);
+            }
+            Err(e) if is_range_too_large(&e) && end > start => {
+                chunk = std::cmp::max((end - start + 1) / 2, config.min_chunk);
+                let mid = start + (end - start) / 2;
+                retry_queue.push_back((start, mid, attempts));
+                retry_queue.push_back((mid + 1, end, attempts));
+            }
+            Err(e) => {
+                if attempts + 1 >= config.max_retries {
+                    // 等正在请求的范围结束 让它们的结果也写进去
+                    while let Some((s, e2, _, r)) = in_flight.next().await {
+                        if let Ok((mut new_pools, end_hash)) = r {
+                            new_pools.sort_by_key(|p| p.block_number);
+                            complete_range(
+                                store,
+                                checkpoint_name,
+                                &mut done,
+                                &mut checkpoint,
+                                s,
+                                (e2, end_hash),
+                                new_pools,
+                            )?;
+                        }
+                    }
+                    return Err(anyhow!(
+                        "Range {:?}..={:?} failed after {:?} attempts: {:?}",
+                        start,
+                        end,
+                        attempts + 1,
+                        e
+                    ));
+                }
+                warn!(
+                    "Range {:?}..={:?} failed (attempt {:?}): {:?}",
+                    start,
+                    end,
+                    attempts + 1,
+                    e
+                );
+                retry_queue.push_back((start, end, attempts + 1));
+            }
+        }
+    }
+    info!("Scanned up to block {:?}", checkpoint.0);
+
+    Ok(pools)
+}
+
+/// 记录一个完成的范围 把连续完成的范围合并进 checkpoint
+/// 池子 / 范围 / checkpoint 在一个事务里提交
+fn complete_range(
+    store: &Mutex<Store>,
+    checkpoint_name: &str,
+    done: &mut BTreeMap<u64, (u64, H256)>,
+    checkpoint: &mut (u64, H256),
+    start: u64,
+    end: (u64, H256),
+    pools: Vec<Pool>,
+) -> Result<Vec<Pool>> {
+    done.insert(start, end);
+    while let Some(range_end) = done.get(&(checkpoint.0 + 1)).cloned() {
+        done.remove(&(checkpoint.0 + 1));
+        *checkpoint = range_end;
+    }
+    store
+        .lock()
+        .unwrap()
+        .commit_range(checkpoint_name, pools, start, end.0, end.1, *checkpoint)
+}
+
+/// 生成下一个要请求的范围 跳过已经完成的范围
+fn next_range(
+    done: &BTreeMap<u64, (u64, H256)>,
+    next_start: &mut u64,
+    to_block: u64,
+    chunk: u64,
+) -> Option<(u64, u64)> {
+    loop {
+        if *next_start > to_block {
+            return None;
+        }
+        // next_start 落在已完成的范围里 跳到范围之后
+        match done.range(..=*next_start).next_back() {
+            Some((_, (end, _))) if *end >= *next_start => {
+                *next_start = end + 1;
+                continue;
+            }
+            _ => {}
+        }
+        let start = *next_start;
+        let mut end = std::cmp::min(start + chunk - 1, to_block);
+        // 不要和后面已完成的范围重叠
+        if let Some((done_start, _)) = done.range(start..).next() {
+            end = std::cmp::min(end, done_start - 1);
+        }
+        *next_start = end + 1;
+        return Some((start, end));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::pools::DexVariant;
+    use ethers::types::H160;
+    use futures::future::{ready, Ready};
+
+    static NAME: &str = "test_pools";
+
+    /// 每 100 个区块一个池子 地址就是区块号
+    fn pools_in(start: u64, end: u64) -> Vec<Pool> {
+        (start..=end)
+            .filter(|block| block % 100 == 0)
+            .map(|block| Pool {
+                id: -1,
+                address: H160::from_low_u64_be(block),
+                version: DexVariant::UniswapV2,
+                token0: H160::from_low_u64_be(1),
+                token1: H160::from_low_u64_be(2),
+                fee: 300,
+                block_number: block,
+                timestamp: 0,
+                block_hash: Some(H256::from_low_u64_be(block)),
+            })
+            .collect()
+    }
+
+    fn config(initial_chunk: u64) -> ScanConfig {
+        ScanConfig {
+            concurrency: 2,
+            initial_chunk,
+            min_chunk: 100,
+            max_chunk: 100000,
+            sparse_threshold: 0,
+            max_retries: 3,
+        }
+    }
+
+    /// 模拟节点: 超过 limit 个区块的范围报结果太多 start 在 broken 里的范围报其它错误
+    fn node(
+        calls: &Mutex<Vec<(u64, u64)>>,
+        limit: u64,
+        broken: Option<u64>,
+    ) -> impl Fn(u64, u64) -> Ready<Result<(Vec<Pool>, H256)>> + '_ {
+        move |start, end| {
+            calls.lock().unwrap().push((start, end));
+            if end - start + 1 > limit {
+                return ready(Err(anyhow!("query returned more than 10000 results")));
+            }
+            if broken.map(|block| end >= block).unwrap_or(false) {
+                return ready(Err(anyhow!("connection reset by peer")));
+            }
+            ready(Ok((pools_in(start, end), H256::from_low_u64_be(end))))
+        }
+    }
+
+    #[test]
+    fn next_range_skips_done_ranges() {
+        let mut done = BTreeMap::new();
+        done.insert(11, (20, H256::zero()));
+        let mut next_start = 1;
+        assert_eq!(next_range(&done, &mut next_start, 100, 50), Some((1, 10)));
+        assert_eq!(next_range(&done, &mut next_start, 100, 50), Some((21, 70)));
+        assert_eq!(next_range(&done, &mut next_start, 100, 50), Some((71, 100)));
+        assert_eq!(next_range(&done, &mut next_start, 100, 50), None);
+    }
+
+    #[tokio::test]
+    async fn too_large_ranges_are_split() {
+        let store = Mutex::new(Store::open(":memory:").unwrap());
+        let calls = Mutex::new(Vec::new());
+        let pools = scan_ranges(
+            &store,
+            NAME,
+            1,
+            5000,
+            config(5000),
+            node(&calls, 1000, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(pools.len(), 50);
+        let calls = calls.into_inner().unwrap();
+        assert_eq!(calls[0], (1, 5000));
+        // 拆开之后的范围都不超过节点的限制 并且覆盖整个区间
+        let mut blocks = 0;
+        let store = store.into_inner().unwrap();
+        for (start, end) in calls.iter().filter(|(s, e)| e - s < 1000) {
+            blocks += end - start + 1;
+        }
+        assert_eq!(blocks, 5000);
+        assert_eq!(
+            store.get_checkpoint_hash(NAME).unwrap(),
+            Some((5000, Some(H256::from_low_u64_be(5000))))
+        );
+        assert_eq!(store.load_pools().unwrap().len(), 50);
+    }
+
+    #[tokio::test]
+    async fn failed_scan_keeps_progress_and_resumes() {
+        let store = Mutex::new(Store::open(":memory:").unwrap());
+        let calls = Mutex::new(Vec::new());
+        let result = scan_ranges(
+            &store,
+            NAME,
+            1,
+            4000,
+            config(1000),
+            node(&calls, 1000, Some(3000)),
+        )
+        .await;
+        assert!(result.is_err());
+        // 3000 之前的范围完成并写入 checkpoint 停在连续完成的位置
+        let checkpoint = store.lock().unwrap().get_checkpoint(NAME).unwrap();
+        assert_eq!(checkpoint, Some(2000));
+        assert_eq!(store.lock().unwrap().load_pools().unwrap().len(), 20);
+        // 从 checkpoint 继续 不再请求已经完成的范围
+        let calls = Mutex::new(Vec::new());
+        let pools = scan_ranges(
+            &store,
+            NAME,
+            2001,
+            4000,
+            config(1000),
+            node(&calls, 1000, None),
+        )
+        .await
+        .unwrap();
+        assert!(calls.lock().unwrap().iter().all(|(start, _)| *start > 2000));
+        assert_eq!(pools.len(), 20);
+        assert_eq!(
+            store.lock().unwrap().get_checkpoint(NAME).unwrap(),
+            Some(4000)
+        );
+        assert_eq!(store.lock().unwrap().load_pools().unwrap().len(), 40);
+    }
+}
@@ -0,0 +1,1139 @@
+////////////////////////////////////////
+////SQLite 本地存储///
+////////////////////////////////////////
+
+use anyhow::Result;
+use ethers::types::{H160, H256, U256};
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::create_dir_all,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::{
+    balancer::BalancerPool,
+    cache_check::{load_csv_rows, BALANCER_POOLS_CSV, POOLS_CSV, TOKENS_CSV},
+    pools::{DexVariant, Pool},
+    risk::TokenRisk,
+    tokens::{KnownFields, Token, TokenTax},
+};
+
+/// 数据库文件 替代原来的 cache/.cached-pools.csv / cache/.cached-tokens.csv
+pub static STORE_PATH: &str = "cache/sandwich.db";
+/// UniswapV2 池子扫描进度
+pub static V2_POOLS_CHECKPOINT: &str = "uniswap_v2_pools";
+/// Balancer 加权池扫描进度
+pub static BALANCER_POOLS_CHECKPOINT: &str = "balancer_pools";
+/// 其它连接(例如 check_cache / dataset)写入时 等待锁释放的最长时间
+pub static STORE_BUSY_TIMEOUT_MS: u64 = 5000;
+
+/// 进程内共享的数据库连接 启动时打开一次
+/// 只在同步代码里短暂加锁 不能跨 await 持有
+pub type SharedStore = Arc<Mutex<Store>>;
+
+/// 按顺序执行的 schema 迁移 数据库当前版本记录在 PRAGMA user_version
+/// 只能在末尾追加新的迁移 不能修改已经发布的迁移
+static MIGRATIONS: &[&str] = &[
+    // v1: 池子 / 代币 / 池子与代币的关系 / 同步进度
+    "CREATE TABLE pools (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        address TEXT NOT NULL UNIQUE,
+        version INTEGER NOT NULL,
+        token0 TEXT NOT NULL,
+        token1 TEXT NOT NULL,
+        fee INTEGER NOT NULL,
+        block_number INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE INDEX idx_pools_token0 ON pools(token0);
+    CREATE INDEX idx_pools_token1 ON pools(token1);
+    CREATE INDEX idx_pools_block_number ON pools(block_number);
+    CREATE TABLE tokens (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        address TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        symbol TEXT NOT NULL,
+        decimals INTEGER NOT NULL
+    );
+    CREATE TABLE pool_tokens (
+        pool_id INTEGER NOT NULL REFERENCES pools(id),
+        token_id INTEGER NOT NULL REFERENCES tokens(id),
+        PRIMARY KEY (pool_id, token_id)
+    );
+    CREATE INDEX idx_pool_tokens_token_id ON pool_tokens(token_id);
+    CREATE TABLE sync_checkpoints (
+        name TEXT PRIMARY KEY,
+        block_number INTEGER NOT NULL
+    );",
+    // v2: 代币税率
+    "CREATE TABLE token_taxes (
+        token_id INTEGER PRIMARY KEY REFERENCES tokens(id),
+        buy_tax INTEGER NOT NULL,
+        sell_tax INTEGER NOT NULL,
+        transfer_tax INTEGER NOT NULL,
+        rebasing INTEGER NOT NULL,
+        block_number INTEGER NOT NULL
+    );",
+    // v3: checkpoint 之后已经完成的区块范围 (并发扫描时完成顺序不固定)
+    "CREATE TABLE synced_ranges (
+        name TEXT NOT NULL,
+        from_block INTEGER NOT NULL,
+        to_block INTEGER NOT NULL,
+        PRIMARY KEY (name, from_block)
+    );",
+    // v4: 区块哈希 用于发现重组
+    "ALTER TABLE pools ADD COLUMN block_hash TEXT;
+    ALTER TABLE sync_checkpoints ADD COLUMN block_hash TEXT;
+    ALTER TABLE synced_ranges ADD COLUMN to_block_hash TEXT;",
+    // v5: 代币元数据哪些字段是读到的 (非标准代币)
+    "ALTER TABLE tokens ADD COLUMN name_known INTEGER NOT NULL DEFAULT 1;
+    ALTER TABLE tokens ADD COLUMN symbol_known INTEGER NOT NULL DEFAULT 1;
+    ALTER TABLE tokens ADD COLUMN decimals_known INTEGER NOT NULL DEFAULT 1;",
+    // v6: 代币风险属性
+    "CREATE TABLE token_risks (
+        token_id INTEGER PRIMARY KEY REFERENCES tokens(id),
+        sell_blocked INTEGER NOT NULL,
+        max_tx INTEGER NOT NULL,
+        max_wallet INTEGER NOT NULL,
+        pausable INTEGER NOT NULL,
+        blacklist INTEGER NOT NULL,
+        upgradeable_proxy INTEGER NOT NULL,
+        owner_privileges INTEGER NOT NULL,
+        block_number INTEGER NOT NULL
+    );",
+    // v7: 哪些池子的代币已经解析 / 读取失败的代币重试队列
+    "ALTER TABLE pools ADD COLUMN tokens_resolved INTEGER NOT NULL DEFAULT 0;
+    UPDATE pools SET tokens_resolved = 1
+        WHERE (SELECT COUNT(*) FROM pool_tokens WHERE pool_id = pools.id) = 2;
+    CREATE TABLE token_failures (
+        address TEXT PRIMARY KEY,
+        attempts INTEGER NOT NULL,
+        last_error TEXT NOT NULL,
+        last_block INTEGER NOT NULL
+    );",
+    // v8: Balancer 加权池 替代原来的 cache/.cached-balancer-pools.csv
+    // tokens / weights 按池子内的顺序用 ; 连接
+    "CREATE TABLE balancer_pools (
+        pool_id TEXT PRIMARY KEY,
+        address TEXT NOT NULL,
+        tokens TEXT NOT NULL,
+        weights TEXT NOT NULL,
+        swap_fee TEXT NOT NULL,
+        block_number INTEGER NOT NULL
+    );",
+];
+
+/// 读取元数据失败的代币
+#[derive(Debug, Clone)]
+pub struct TokenFailure {
+    pub attempts: u32,
+    pub last_error: String,
+    /// 最后一次尝试的区块
+    pub last_block: u64,
+}
+
+fn checkpoint_in_tx(tx: &Transaction, name: &str) -> Result<Option<u64>> {
+    let block: Option<i64> = tx
+        .query_row(
+            "SELECT block_number FROM sync_checkpoints WHERE name = ?1",
+            params![name],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(block.map(|b| b as u64))
+}
+
+/// 被删除的池子关联的代币 id
+fn token_ids_of_pools(
+    tx: &Transaction,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<i64>> {
+    let mut stmt = tx.prepare(sql)?;
+    let rows = stmt.query_map(params, |row| row.get::<_, i64>(0))?;
+    let mut ids = Vec::new();
+    for row in rows {
+        ids.push(row?);
+    }
+    Ok(ids)
+}
+
+/// 删除这些代币中不再属于任何池子的 (连同税率和风险)
+/// 只看被删除的池子关联的代币 其它没有池子的代币 (例如还没解析完的) 不受影响
+fn remove_orphaned_tokens(tx: &Transaction, token_ids: &[i64]) -> Result<usize> {
+    let mut removed = 0;
+    for token_id in token_ids {
+        let referenced: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM pool_tokens WHERE token_id = ?1)",
+            params![token_id],
+            |row| row.get(0),
+        )?;
+        if referenced {
+            continue;
+        }
+        tx.execute(
+            "DELETE FROM token_taxes WHERE token_id = ?1",
+            params![token_id],
+        )?;
+        tx.execute(
+            "DELETE FROM token_risks WHERE token_id = ?1",
+            params![token_id],
+        )?;
+        removed += tx.execute("DELETE FROM tokens WHERE id = ?1", params![token_id])?;
+    }
+    Ok(removed)
+}
+
+pub struct Store {
+    pub conn: Connection,
+}
+impl Store {
+    pub fn open(path: &str) -> Result<Self> {
+        if let Some(dir) = Path::new(path).parent() {
+            create_dir_all(dir)?;
+        }
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_millis(STORE_BUSY_TIMEOUT_MS))?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+        let mut store = Self { conn };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    /// 默认位置的数据库 第一次打开时导入旧的 csv 缓存
+    pub fn open_default() -> Result<Self> {
+        let mut store = Self::open(STORE_PATH)?;
+        store.import_legacy_csv()?;
+        Ok(store)
+    }
+
+    /// 打开默认位置的数据库 给策略 / 追踪工作线程共享
+    pub fn open_shared() -> Result<SharedStore> {
+        Ok(Arc::new(Mutex::new(Self::open_default()?)))
+    }
+
+    pub fn schema_version(&self) -> Result<usize> {
+        let version: i64 = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(version as usize)
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        let current = self.schema_version()?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.execute_batch(&format!("PRAGMA user_version = {}", i + 1))?;
+            tx.commit()?;
+            info!("Store migrated to schema v{:?}", i + 1);
+        }
+        Ok(())
+    }
+
+    pub fn get_checkpoint(&self, name: &str) -> Result<Option<u64>> {
+        let block: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT block_number FROM sync_checkpoints WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(block.map(|b| b as u64))
+    }
+
+    /// 同步进度和该区块的哈希
+    pub fn get_checkpoint_hash(&self, name: &str) -> Result<Option<(u64, Option<H256>)>> {
+        let row: Option<(i64, Option<String>)> = self
+            .conn
+            .query_row(
+                "SELECT block_number, block_hash FROM sync_checkpoints WHERE name = ?1",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        match row {
+            Some((block, hash)) => Ok(Some((
+                block as u64,
+                hash.map(|h| H256::from_str(&h)).transpose()?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_checkpoint(tx: &Transaction, name: &str, block_number: u64) -> Result<()> {
+        Self::set_checkpoint_with_hash(tx, name, block_number, None)
+    }
+
+    /// 所有的同步进度 (名字, 区块, 区块哈希)
+    pub fn load_checkpoints(&self) -> Result<Vec<(String, u64, Option<H256>)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, block_number, block_hash FROM sync_checkpoints ORDER BY name")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?;
+        let mut checkpoints = Vec::new();
+        for row in rows {
+            let (name, block, hash) = row?;
+            let hash = hash.map(|h| H256::from_str(&h)).transpose()?;
+            checkpoints.push((name, block as u64, hash));
+        }
+        Ok(checkpoints)
+    }
+
+    pub fn set_checkpoint_with_hash(
+        tx: &Transaction,
+        name: &str,
+        block_number: u64,
+        block_hash: Option<H256>,
+    ) -> Result<()> {
+        tx.execute(
+            "INSERT INTO sync_checkpoints (name, block_number, block_hash) VALUES (?1, ?2, ?3)
+             ON CONFLICT(name) DO UPDATE SET
+                block_number = excluded.block_number,
+                block_hash = excluded.block_hash",
+            params![
+                name,
+                block_number as i64,
+                block_hash.map(|h| format!("{:?}", h))
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// checkpoint 之后已经完成的范围 (from_block, to_block, to_block 的哈希)
+    pub fn load_synced_ranges(&self, name: &str) -> Result<Vec<(u64, u64, Option<H256>)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT from_block, to_block, to_block_hash FROM synced_ranges
+             WHERE name = ?1 ORDER BY from_block",
+        )?;
+        let rows = stmt.query_map(params![name], |row| {
+            Ok((
+                row.get::<_, i64>(0)? as u64,
+                row.get::<_, i64>(1)? as u64,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?;
+        let mut ranges = Vec::new();
+        for row in rows {
+            let (from_block, to_block, hash) = row?;
+            ranges.push((
+                from_block,
+                to_block,
+                hash.map(|h| H256::from_str(&h)).transpose()?,
+            ));
+        }
+        Ok(ranges)
+    }
+
+    /// 一个区块范围扫描完成: 写入池子 记录范围 推进 checkpoint
+    /// checkpoint 之前的范围记录不再需要 一起删除
+    pub fn commit_range(
+        &mut self,
+        name: &str,
+        pools: Vec<Pool>,
+        from_block: u64,
+        to_block: u64,
+        to_block_hash: H256,
+        checkpoint: (u64, H256),
+    ) -> Result<Vec<Pool>> {
+        let tx = self.conn.transaction()?;
+        let mut inserted = Vec::new();
+        for mut pool in pools {
+            pool.id = Self::insert_pool(&tx, &pool)?;
+            inserted.push(pool);
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO synced_ranges (name, from_block, to_block, to_block_hash)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                name,
+                from_block as i64,
+                to_block as i64,
+                format!("{:?}", to_block_hash)
+            ],
+        )?;
+        // 哈希为 0 表示不知道
+        let (checkpoint, checkpoint_hash) = checkpoint;
+        let checkpoint_hash = if checkpoint_hash.is_zero() {
+            None
+        } else {
+            Some(checkpoint_hash)
+        };
+        Self::set_checkpoint_with_hash(&tx, name, checkpoint, checkpoint_hash)?;
+        tx.execute(
+            "DELETE FROM synced_ranges WHERE name = ?1 AND to_block <= ?2",
+            params![name, checkpoint as i64],
+        )?;
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// 按 id 顺序读取所有池子
+    pub fn load_pools(&self) -> Result<Vec<Pool>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, address, version, token0, token1, fee, block_number, timestamp, block_hash
+             FROM pools ORDER BY id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, i64>(5)?,
+                row.get::<_, i64>(6)?,
+                row.get::<_, i64>(7)?,
+                row.get::<_, Option<String>>(8)?,
+            ))
+        })?;
+        let mut pools = Vec::new();
+        for row in rows {
+            let row = row?;
+            let id = row.0;
+            let parse = || -> Result<Pool> {
+                Ok(Pool {
+                    id: row.0,
+                    address: H160::from_str(&row.1)?,
+                    version: DexVariant::from_version_num(row.2 as u8),
+                    token0: H160::from_str(&row.3)?,
+                    token1: H160::from_str(&row.4)?,
+                    fee: row.5 as u32,
+                    block_number: row.6 as u64,
+                    timestamp: row.7 as u64,
+                    block_hash: row.8.clone().map(|h| H256::from_str(&h)).transpose()?,
+                })
+            };
+            // 坏行跳过 用 check_cache --repair 清理
+            match parse() {
+                Ok(pool) => pools.push(pool),
+                Err(e) => warn!("Skipped invalid pool row {:?}: {:?}", id, e),
+            }
+        }
+        Ok(pools)
+    }
+
+    /// 插入一个池子 id 由数据库分配 地址已存在时返回已有的 id
+    pub fn insert_pool(tx: &Transaction, pool: &Pool) -> Result<i64> {
+        let row = pool.cache_row();
+        tx.execute(
+            "INSERT OR IGNORE INTO pools
+                (address, version, token0, token1, fee, block_number, timestamp, block_hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                row.1,
+                row.2,
+                row.3,
+                row.4,
+                row.5,
+                row.6 as i64,
+                row.7 as i64,
+                pool.block_hash.map(|h| format!("{:?}", h))
+            ],
+        )?;
+        let id: i64 = tx.query_row(
+            "SELECT id FROM pools WHERE address = ?1",
+            params![row.1],
+            |row| row.get(0),
+        )?;
+        Ok(id)
+    }
+
+    /// 在一个事务里插入一批池子并推进同步进度 返回带 id 的池子
+    pub fn insert_pools(
+        &mut self,
+        pools: Vec<Pool>,
+        checkpoint: Option<(&str, u64)>,
+    ) -> Result<Vec<Pool>> {
+        let tx = self.conn.transaction()?;
+        let mut inserted = Vec::new();
+        for mut pool in pools {
+            pool.id = Self::insert_pool(&tx, &pool)?;
+            inserted.push(pool);
+        }
+        if let Some((name, block_number)) = checkpoint {
+            Self::set_checkpoint(&tx, name, block_number)?;
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// 按注册区块顺序读取所有 Balancer 加权池
+    pub fn load_balancer_pools(&self) -> Result<Vec<BalancerPool>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT pool_id, address, tokens, weights, swap_fee, block_number
+             FROM balancer_pools ORDER BY block_number, pool_id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, i64>(5)?,
+            ))
+        })?;
+        let mut pools = Vec::new();
+        for row in rows {
+            let row = row?;
+            let parse = || -> Result<BalancerPool> {
+                Ok(BalancerPool {
+                    pool_id: H256::from_str(&row.0)?,
+                    address: H160::from_str(&row.1)?,
+                    tokens: row
+                        .2
+                        .split(';')
+                        .map(H160::from_str)
+                        .collect::<Result<Vec<H160>, _>>()?,
+                    weights: row
+                        .3
+                        .split(';')
+                        .map(U256::from_dec_str)
+                        .collect::<Result<Vec<U256>, _>>()?,
+                    swap_fee: U256::from_dec_str(&row.4)?,
+                    block_number: row.5 as u64,
+                })
+            };
+            match parse() {
+                Ok(pool) => pools.push(pool),
+                Err(e) => warn!("Skipped invalid balancer pool row {:?}: {:?}", row.0, e),
+            }
+        }
+        Ok(pools)
+    }
+
+    fn insert_balancer_pool(tx: &Transaction, pool: &BalancerPool) -> Result<()> {
+        let row = pool.cache_row();
+        tx.execute(
+            "INSERT OR IGNORE INTO balancer_pools
+                (pool_id, address, tokens, weights, swap_fee, block_number)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![row.0, row.1, row.2, row.3, row.4, row.5 as i64],
+        )?;
+        Ok(())
+    }
+
+    /// 在一个事务里插入一批 Balancer 池子并推进扫描进度
+    pub fn insert_balancer_pools(
+        &mut self,
+        pools: &[BalancerPool],
+        name: &str,
+        block_number: u64,
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for pool in pools {
+            Self::insert_balancer_pool(&tx, pool)?;
+        }
+        Self::set_checkpoint(&tx, name, block_number)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 读取所有代币 包含 pool_ids 和税率
+    pub fn load_tokens(&self) -> Result<HashMap<H160, Token>> {
+        let mut pool_ids: HashMap<i64, Vec<i64>> = HashMap::new();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT token_id, pool_id FROM pool_tokens ORDER BY pool_id")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+        for row in rows {
+            let (token_id, pool_id) = row?;
+            pool_ids
+                .entry(token_id)
+                .or_insert_with(Vec::new)
+                .push(pool_id);
+        }
+
+        let mut risks: HashMap<i64, TokenRisk> = HashMap::new();
+        let mut stmt = self.conn.prepare(
+            "SELECT token_id, sell_blocked, max_tx, max_wallet, pausable, blacklist,
+                    upgradeable_proxy, owner_privileges, block_number
+             FROM token_risks",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                TokenRisk {
+                    sell_blocked: row.get(1)?,
+                    max_tx: row.get(2)?,
+                    max_wallet: row.get(3)?,
+                    pausable: row.get(4)?,
+                    blacklist: row.get(5)?,
+                    upgradeable_proxy: row.get(6)?,
+                    owner_privileges: row.get(7)?,
+                    block_number: row.get::<_, i64>(8)? as u64,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (token_id, risk) = row?;
+            risks.insert(token_id, risk);
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT t.id, t.address, t.name, t.symbol, t.decimals,
+                    x.buy_tax, x.sell_tax, x.transfer_tax, x.rebasing, x.block_number,
+                    t.name_known, t.symbol_known, t.decimals_known
+             FROM tokens t LEFT JOIN token_taxes x ON x.token_id = t.id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let tax = match row.get::<_, Option<i64>>(5)? {
+                Some(buy_tax) => Some(TokenTax {
+                    buy_tax: buy_tax as u32,
+                    sell_tax: row.get::<_, i64>(6)? as u32,
+                    transfer_tax: row.get::<_, i64>(7)? as u32,
+                    rebasing: row.get::<_, bool>(8)?,
+                    block_number: row.get::<_, i64>(9)? as u64,
+                }),
+                None => None,
+            };
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, i64>(4)?,
+                tax,
+                KnownFields {
+                    name: row.get::<_, bool>(10)?,
+                    symbol: row.get::<_, bool>(11)?,
+                    decimals: row.get::<_, bool>(12)?,
+                },
+            ))
+        })?;
+        let mut tokens_map = HashMap::new();
+        for row in rows {
+            let row = row?;
+            let address = match H160::from_str(&row.1) {
+                Ok(address) => address,
+                Err(e) => {
+                    warn!("Skipped invalid token row {:?}: {:?}", row.0, e);
+                    continue;
+                }
+            };
+            tokens_map.insert(
+                address,
+                Token {
+                    id: row.0,
+                    address,
+                    name: row.2,
+                    symbol: row.3,
+                    decimals: row.4 as u8,
+                    pool_ids: pool_ids.remove(&row.0).unwrap_or_default(),
+                    tax: row.5,
+                    known: row.6,
+                    risk: risks.remove(&row.0),
+                },
+            );
+        }
+        Ok(tokens_map)
+    }
+
+    /// 插入一个代币 id 由数据库分配 地址已存在时返回已有的 id
+    pub fn insert_token(tx: &Transaction, token: &Token) -> Result<i64> {
+        tx.execute(
+            "INSERT OR IGNORE INTO tokens
+                (address, name, symbol, decimals, name_known, symbol_known, decimals_known)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                format!("{:?}", token.address),
+                token.name,
+                token.symbol,
+                token.decimals,
+                token.known.name,
+                token.known.symbol,
+                token.known.decimals
+            ],
+        )?;
+        let id: i64 = tx.query_row(
+            "SELECT id FROM tokens WHERE address = ?1",
+            params![format!("{:?}", token.address)],
+            |row| row.get(0),
+        )?;
+        Ok(id)
+    }
+
+    pub fn insert_pool_token(tx: &Transaction, pool_id: i64, token_id: i64) -> Result<()> {
+        tx.execute(
+            "INSERT OR IGNORE INTO pool_tokens (pool_id, token_id) VALUES (?1, ?2)",
+            params![pool_id, token_id],
+        )?;
+        Ok(())
+    }
+
+    /// 代币已经全部收录的池子 id
+    pub fn resolved_pool_ids(&self) -> Result<HashSet<i64>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id FROM pools WHERE tokens_resolved = 1")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        let mut ids = HashSet::new();
+        for row in rows {
+            ids.insert(row?);
+        }
+        Ok(ids)
+    }
+
+    pub fn mark_pool_resolved(tx: &Transaction, pool_id: i64) -> Result<()> {
+        tx.execute(
+            "UPDATE pools SET tokens_resolved = 1 WHERE id = ?1",
+            params![pool_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn load_token_failures(&self) -> Result<HashMap<H160, TokenFailure>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT address, attempts, last_error, last_block FROM token_failures")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                TokenFailure {
+                    attempts: row.get(1)?,
+                    last_error: row.get(2)?,
+                    last_block: row.get::<_, i64>(3)? as u64,
+                },
+            ))
+        })?;
+        let mut failures = HashMap::new();
+        for row in rows {
+            let (address, failure) = row?;
+            match H160::from_str(&address) {
+                Ok(address) => {
+                    failures.insert(address, failure);
+                }
+                Err(e) => warn!("Skipping token failure {:?}: {:?}", address, e),
+            }
+        }
+        Ok(failures)
+    }
+
+    /// 失败次数加 1 并记录这次的错误
+    pub fn record_token_failure(
+        tx: &Transaction,
+        address: H160,
+        error: &str,
+        block_number: u64,
+    ) -> Result<()> {
+        tx.execute(
+            "INSERT INTO token_failures (address, attempts, last_error, last_block)
+             VALUES (?1, 1, ?2, ?3)
+             ON CONFLICT(address) DO UPDATE SET
+                attempts = attempts + 1,
+                last_error = excluded.last_error,
+                last_block = excluded.last_block",
+            params![format!("{:?}", address), error, block_number as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn clear_token_failure(tx: &Transaction, address: H160) -> Result<()> {
+        tx.execute(
+            "DELETE FROM token_failures WHERE address = ?1",
+            params![format!("{:?}", address)],
+        )?;
+        Ok(())
+    }
+
+    pub fn save_token_tax(&self, address: H160, tax: &TokenTax) -> Result<()> {
+        Self::write_token_tax(&self.conn, address, tax)
+    }
+
+    /// 事务里也可以用 (Transaction 可以当作 Connection 使用)
+    pub fn write_token_tax(conn: &Connection, address: H160, tax: &TokenTax) -> Result<()> {
+        conn.execute(
+            "INSERT INTO token_taxes (token_id, buy_tax, sell_tax, transfer_tax, rebasing, block_number)
+             SELECT id, ?2, ?3, ?4, ?5, ?6 FROM tokens WHERE address = ?1
+             ON CONFLICT(token_id) DO UPDATE SET
+                buy_tax = excluded.buy_tax,
+                sell_tax = excluded.sell_tax,
+                transfer_tax = excluded.transfer_tax,
+                rebasing = excluded.rebasing,
+                block_number = excluded.block_number",
+            params![
+                format!("{:?}", address),
+                tax.buy_tax,
+                tax.sell_tax,
+                tax.transfer_tax,
+                tax.rebasing,
+                tax.block_number as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn save_token_risk(&self, address: H160, risk: &TokenRisk) -> Result<()> {
+        Self::write_token_risk(&self.conn, address, risk)
+    }
+
+    pub fn write_token_risk(conn: &Connection, address: H160, risk: &TokenRisk) -> Result<()> {
+        conn.execute(
+            "INSERT INTO token_risks (token_id, sell_blocked, max_tx, max_wallet, pausable,
+                blacklist, upgradeable_proxy, owner_privileges, block_number)
+             SELECT id, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9 FROM tokens WHERE address = ?1
+             ON CONFLICT(token_id) DO UPDATE SET
+                sell_blocked = excluded.sell_blocked,
+                max_tx = excluded.max_tx,
+                max_wallet = excluded.max_wallet,
+                pausable = excluded.pausable,
+                blacklist = excluded.blacklist,
+                upgradeable_proxy = excluded.upgradeable_proxy,
+                owner_privileges = excluded.owner_privileges,
+                block_number = excluded.block_number",
+            params![
+                format!("{:?}", address),
+                risk.sell_blocked,
+                risk.max_tx,
+                risk.max_wallet,
+                risk.pausable,
+                risk.blacklist,
+                risk.upgradeable_proxy,
+                risk.owner_privileges,
+                risk.block_number as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// block_number 之后(不含)创建的池子的创建区块和哈希 按区块从高到低
+    pub fn pool_block_hashes_since(&self, block_number: u64) -> Result<Vec<(u64, H256)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT block_number, block_hash FROM pools
+             WHERE block_number > ?1 AND block_hash IS NOT NULL
+             ORDER BY block_number DESC",
+        )?;
+        let rows = stmt.query_map(params![block_number as i64], |row| {
+            Ok((row.get::<_, i64>(0)? as u64, row.get::<_, String>(1)?))
+        })?;
+        let mut hashes = Vec::new();
+        for row in rows {
+            let (block, hash) = row?;
+            hashes.push((block, H256::from_str(&hash)?));
+        }
+        Ok(hashes)
+    }
+
+    /// 删除创建区块哈希为 block_hash 的池子 (该区块已经被重组掉)
+    pub fn remove_pools_with_block_hash(&mut self, block_hash: H256) -> Result<Vec<H160>> {
+        let hash = format!("{:?}", block_hash);
+        let tx = self.conn.transaction()?;
+        let removed = {
+            let mut stmt = tx.prepare("SELECT address FROM pools WHERE block_hash = ?1")?;
+            let rows = stmt.query_map(params![hash], |row| row.get::<_, String>(0))?;
+            let mut removed = Vec::new();
+            for row in rows {
+                removed.push(H160::from_str(&row?)?);
+            }
+            removed
+        };
+        let token_ids = token_ids_of_pools(
+            &tx,
+            "SELECT DISTINCT token_id FROM pool_tokens
+             WHERE pool_id IN (SELECT id FROM pools WHERE block_hash = ?1)",
+            params![hash],
+        )?;
+        tx.execute(
+            "DELETE FROM pool_tokens WHERE pool_id IN (SELECT id FROM pools WHERE block_hash = ?1)",
+            params![hash],
+        )?;
+        tx.execute("DELETE FROM pools WHERE block_hash = ?1", params![hash])?;
+        remove_orphaned_tokens(&tx, &token_ids)?;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    /// 回滚到 block_number (含) 为止的状态
+    /// 删除之后创建的池子 它们的关系 只属于这些池子的代币 以及之后完成的范围
+    /// 返回被删除的池子地址
+    pub fn rollback_to(
+        &mut self,
+        name: &str,
+        block_number: u64,
+        block_hash: H256,
+    ) -> Result<Vec<H160>> {
+        let tx = self.conn.transaction()?;
+        let removed = {
+            let mut stmt = tx.prepare("SELECT address FROM pools WHERE block_number > ?1")?;
+            let rows =
+                stmt.query_map(params![block_number as i64], |row| row.get::<_, String>(0))?;
+            let mut removed = Vec::new();
+            for row in rows {
+                removed.push(H160::from_str(&row?)?);
+            }
+            removed
+        };
+        let token_ids = token_ids_of_pools(
+            &tx,
+            "SELECT DISTINCT token_id FROM pool_tokens
+             WHERE pool_id IN (SELECT id FROM pools WHERE block_number > ?1)",
+            params![block_number as i64],
+        )?;
+        tx.execute(
+            "DELETE FROM pool_tokens WHERE pool_id IN (SELECT id FROM pools WHERE block_number > ?1)",
+            params![block_number as i64],
+        )?;
+        tx.execute(
+            "DELETE FROM pools WHERE block_number > ?1",
+            params![block_number as i64],
+        )?;
+        remove_orphaned_tokens(&tx, &token_ids)?;
+        tx.execute(
+            "DELETE FROM synced_ranges WHERE name = ?1 AND to_block > ?2",
+            params![name, block_number as i64],
+        )?;
+        let checkpoint = checkpoint_in_tx(&tx, name)?;
+        if checkpoint.map(|c| c > block_number).unwrap_or(false) {
+            Self::set_checkpoint_with_hash(&tx, name, block_number, Some(block_hash))?;
+        }
+        tx.commit()?;
+        info!(
+            "Rolled back {:?} pools created after block {:?}",
+            removed.len(),
+            block_number
+        );
+        Ok(removed)
+    }
+
+    /// 查询包含某个代币的所有池子 id
+    pub fn pool_ids_by_token(&self, address: H160) -> Result<Vec<i64>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT p.pool_id FROM pool_tokens p JOIN tokens t ON t.id = p.token_id
+             WHERE t.address = ?1 ORDER BY p.pool_id",
+        )?;
+        let rows = stmt.query_map(params![format!("{:?}", address)], |row| row.get(0))?;
+        let mut ids = Vec::new();
+        for row in rows {
+            ids.push(row?);
+        }
+        Ok(ids)
+    }
+
+    /// 数据库为空且存在旧的 csv 缓存时 一次性导入
+    /// 导入之后旧文件不再使用 可以手动删除
+    pub fn import_legacy_csv(&mut self) -> Result<()> {
+        self.import_legacy_balancer_csv()?;
+        let pool_count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM pools", [], |row| row.get(0))?;
+        if pool_count > 0 || !Path::new(POOLS_CSV).exists() {
+            return Ok(());
+        }
+        // 格式不对的行跳过并打印行号
+        let pools: Vec<Pool> = load_csv_rows(POOLS_CSV)?;
+        let tokens: Vec<Token> = if Path::new(TOKENS_CSV).exists() {
+            load_csv_rows(TOKENS_CSV)?
+        } else {
+            Vec::new()
+        };
+        let tx = self.conn.transaction()?;
+        let mut last_block = 0;
+        for pool in pools {
+            last_block = std::cmp::max(last_block, pool.block_number);
+            Self::insert_pool(&tx, &pool)?;
+        }
+        // csv 时代按 last.block_number 续扫 这里同样从最后一个区块重新扫描
+        if last_block > 0 {
+            Self::set_checkpoint(&tx, V2_POOLS_CHECKPOINT, last_block - 1)?;
+        }
+        if !tokens.is_empty() {
+            for token in &tokens {
+                Self::insert_token(&tx, token)?;
+            }
+            // 池子和代币的关系按地址重新建立
+            tx.execute_batch(
+                "INSERT OR IGNORE INTO pool_tokens (pool_id, token_id)
+                 SELECT p.id, t.id FROM pools p JOIN tokens t ON t.address = p.token0
+                 UNION ALL
+                 SELECT p.id, t.id FROM pools p JOIN tokens t ON t.address = p.token1",
+            )?;
+        }
+        tx.commit()?;
+        info!("Imported legacy csv caches into {:?}", STORE_PATH);
+        Ok(())
+    }
+
+    /// Balancer 池子表为空且存在旧的 csv 缓存时导入 扫描进度本来就在数据库里
+    fn import_legacy_balancer_csv(&mut self) -> Result<()> {
+        let pool_count: i64 =
+            self.conn
+                .query_row("SELECT COUNT(*) FROM balancer_pools", [], |row| row.get(0))?;
+        if pool_count > 0 || !Path::new(BALANCER_POOLS_CSV).exists() {
+            return Ok(());
+        }
+        // 格式不对的行跳过并打印行号
+        let pools: Vec<BalancerPool> = load_csv_rows(BALANCER_POOLS_CSV)?;
+        let tx = self.conn.transaction()?;
+        for pool in &pools {
+            Self::insert_balancer_pool(&tx, pool)?;
+        }
+        tx.commit()?;
+        info!(
+            "Imported {:?} balancer pools from {:?}",
+            pools.len(),
+            BALANCER_POOLS_CSV
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(byte: u8) -> H160 {
+        H160::repeat_byte(byte)
+    }
+
+    fn pool(byte: u8, token0: u8, token1: u8, block_number: u64) -> Pool {
+        Pool {
+            id: -1,
+            address: address(byte),
+            version: DexVariant::UniswapV2,
+            token0: address(token0),
+            token1: address(token1),
+            fee: 300,
+            block_number,
+            timestamp: 0,
+            block_hash: Some(H256::from_low_u64_be(block_number)),
+        }
+    }
+
+    fn token(byte: u8) -> Token {
+        Token {
+            id: -1,
+            address: address(byte),
+            name: String::from("Token"),
+            symbol: String::from("TKN"),
+            decimals: 18,
+            pool_ids: Vec::new(),
+            tax: None,
+            known: KnownFields::default(),
+            risk: None,
+        }
+    }
+
+    fn tax() -> TokenTax {
+        TokenTax {
+            buy_tax: 100,
+            sell_tax: 200,
+            transfer_tax: 0,
+            rebasing: false,
+            block_number: 1,
+        }
+    }
+
+    /// 池子 A (区块 10): X/Y  池子 B (区块 20): Y/Z  代币 W 还没有池子
+    /// 每个代币都有税率
+    fn setup() -> Store {
+        let mut store = Store::open(":memory:").unwrap();
+        let pools = store
+            .insert_pools(
+                vec![pool(0xa1, 0x01, 0x02, 10), pool(0xb1, 0x02, 0x03, 20)],
+                Some((V2_POOLS_CHECKPOINT, 20)),
+            )
+            .unwrap();
+        let tx = store.conn.transaction().unwrap();
+        let mut ids = HashMap::new();
+        for byte in [0x01, 0x02, 0x03, 0x04] {
+            ids.insert(
+                address(byte),
+                Store::insert_token(&tx, &token(byte)).unwrap(),
+            );
+        }
+        for pool in &pools {
+            for token in [pool.token0, pool.token1] {
+                Store::insert_pool_token(&tx, pool.id, ids[&token]).unwrap();
+            }
+        }
+        tx.commit().unwrap();
+        for byte in [0x01, 0x02, 0x03, 0x04] {
+            store.save_token_tax(address(byte), &tax()).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn migrations_reach_latest_version() {
+        let mut store = Store::open(":memory:").unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        // 已经是最新版本 再执行一次不做任何事
+        store.migrate().unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        for table in [
+            "pools",
+            "tokens",
+            "pool_tokens",
+            "token_taxes",
+            "balancer_pools",
+        ] {
+            let exists: bool = store
+                .conn
+                .query_row(
+                    "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+                    params![table],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert!(exists, "missing table {}", table);
+        }
+    }
+
+    #[test]
+    fn rollback_keeps_tokens_of_surviving_pools() {
+        let mut store = setup();
+        let removed = store
+            .rollback_to(V2_POOLS_CHECKPOINT, 15, H256::from_low_u64_be(15))
+            .unwrap();
+        assert_eq!(removed, vec![address(0xb1)]);
+        let pools = store.load_pools().unwrap();
+        assert_eq!(pools.len(), 1);
+        assert_eq!(pools[0].address, address(0xa1));
+        let tokens = store.load_tokens().unwrap();
+        // Z 只属于被回滚的池子 被删除
+        assert!(!tokens.contains_key(&address(0x03)));
+        // Y 同时属于留下来的池子 连同税率一起保留
+        let shared = &tokens[&address(0x02)];
+        assert_eq!(shared.pool_ids, vec![pools[0].id]);
+        assert_eq!(shared.tax.map(|tax| tax.sell_tax), Some(200));
+        assert!(tokens.contains_key(&address(0x01)));
+        // 和回滚无关的代币不受影响
+        assert!(tokens.contains_key(&address(0x04)));
+        assert_eq!(
+            store.get_checkpoint_hash(V2_POOLS_CHECKPOINT).unwrap(),
+            Some((15, Some(H256::from_low_u64_be(15))))
+        );
+    }
+
+    #[test]
+    fn removing_reorged_block_keeps_shared_tokens() {
+        let mut store = setup();
+        let removed = store
+            .remove_pools_with_block_hash(H256::from_low_u64_be(20))
+            .unwrap();
+        assert_eq!(removed, vec![address(0xb1)]);
+        let tokens = store.load_tokens().unwrap();
+        assert!(!tokens.contains_key(&address(0x03)));
+        assert!(tokens.contains_key(&address(0x02)));
+        assert!(tokens.contains_key(&address(0x04)));
+        // 其它区块的哈希不匹配 不删除
+        assert!(store
+            .remove_pools_with_block_hash(H256::from_low_u64_be(11))
+            .unwrap()
+            .is_empty());
+        assert_eq!(store.load_pools().unwrap().len(), 1);
+    }
+}
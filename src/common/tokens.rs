@@ -1,18 +1,45 @@
-use std::{collections::HashMap, fs::OpenOptions, path::Path, str::FromStr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
 
-use super::{pools::Pool, utils::create_new_wallet};
+use super::{
+    evm::{EvmSimulator, Tx},
+    math::{get_v2_amount_out, TAX_DENOMINATOR},
+    pools::Pool,
+    risk::TokenRisk,
+    store::{Store, TokenFailure},
+    transport::RpcProvider,
+    utils::{create_new_wallet, csv_field},
+};
 use crate::common::bytecoode::REQUEST_BYTECODE;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use csv::StringRecord;
 use ethers::{
-    abi::parse_abi,
-    types::{BlockNumber, TransactionRequest, H160, U256, U64},
+    abi::{parse_abi, Detokenize, ParamType},
+    types::{BlockNumber, Bytes, TransactionRequest, H160, U256, U64},
 };
 use ethers_contract::BaseContract;
 use ethers_providers::RawCall;
-use ethers_providers::{spoof, Provider, Ws};
+use ethers_providers::{spoof, Middleware};
+use futures::stream::{self, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
-use log::info;
+use log::{info, warn};
+use rusqlite::Transaction;
+use serde::{Deserialize, Serialize};
+
+/// 一次 getTokenInfos 调用读取的代币数量
+pub static TOKEN_INFO_BATCH_SIZE: usize = 100;
+/// 同时请求的批次数量
+pub static TOKEN_INFO_CONCURRENCY: usize = 4;
+/// 读取失败的代币最多重试的次数
+pub static MAX_TOKEN_ATTEMPTS: u32 = 5;
+/// 每个代币预留的 gas (4 个低级调用)
+pub static TOKEN_INFO_GAS_PER_TOKEN: u64 = 250000;
+/// geth 默认的 eth_call gas 上限 (--rpc.gascap) 一批的 gas 不能超过它
+pub static RPC_GAS_CAP: u64 = 50000000;
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub id: i64,
@@ -21,19 +48,87 @@ pub struct Token {
     pub symbol: String,
     pub decimals: u8,
     pub pool_ids: Vec<i64>,
+    /// 模拟转账测出来的税率 None 表示还没有测量
+    pub tax: Option<TokenTax>,
+    /// 哪些元数据是从链上读到的
+    pub known: KnownFields,
+    /// 模拟测出来的风险属性 None 表示还没有测量
+    pub risk: Option<TokenRisk>,
 }
-impl From<StringRecord> for Token {
-    fn from(record: StringRecord) -> Self {
+/// 代币元数据里哪些字段是真正读到的
+/// 非标准代币可能没有 name/symbol/decimals 方法 读不到的字段用默认值填充(空字符串 / 0)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct KnownFields {
+    pub name: bool,
+    pub symbol: bool,
+    pub decimals: bool,
+}
+impl Default for KnownFields {
+    fn default() -> Self {
         Self {
-            id: record.get(0).unwrap().parse().unwrap(),
-            address: H160::from_str(record.get(1).unwrap()).unwrap(),
-            name: String::from(record.get(2).unwrap()),
-            symbol: String::from(record.get(3).unwrap()),
-            decimals: record.get(4).unwrap().parse().unwrap(),
-            pool_ids: Vec::new(),
+            name: true,
+            symbol: true,
+            decimals: true,
         }
     }
 }
+/// 代币的转账税 / rebase 信息 税率单位是万分比(bps)
+/// 用 EvmSimulator 在 block_number 的状态下实际转账测出来
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TokenTax {
+    /// 从池子买入时 我们少收到的比例
+    pub buy_tax: u32,
+    /// 卖给池子时 池子少收到的比例
+    pub sell_tax: u32,
+    /// 普通地址之间转账 接收方少收到的比例
+    pub transfer_tax: u32,
+    /// 余额和储备量 / 转账之后的余额对不上 (变多或者变少) 说明代币会自己改变余额(rebase / 分红)
+    pub rebasing: bool,
+    /// 测量时的区块号
+    pub block_number: u64,
+}
+impl TokenTax {
+    /// 测量失败(例如无法卖出)按 100% 税处理
+    pub fn untradeable_at(block_number: u64) -> Self {
+        Self {
+            buy_tax: TAX_DENOMINATOR,
+            sell_tax: TAX_DENOMINATOR,
+            transfer_tax: TAX_DENOMINATOR,
+            rebasing: false,
+            block_number,
+        }
+    }
+    pub fn has_tax(&self) -> bool {
+        self.buy_tax > 0 || self.sell_tax > 0 || self.transfer_tax > 0
+    }
+    pub fn cache_row(&self, address: H160) -> (String, u32, u32, u32, bool, u64) {
+        (
+            format!("{:?}", address),
+            self.buy_tax,
+            self.sell_tax,
+            self.transfer_tax,
+            self.rebasing,
+            self.block_number,
+        )
+    }
+}
+impl TryFrom<StringRecord> for Token {
+    type Error = anyhow::Error;
+
+    fn try_from(record: StringRecord) -> Result<Self> {
+        Ok(Self {
+            id: csv_field(&record, 0, "id")?.parse()?,
+            address: H160::from_str(csv_field(&record, 1, "address")?)?,
+            name: String::from(csv_field(&record, 2, "name")?),
+            symbol: String::from(csv_field(&record, 3, "symbol")?),
+            decimals: csv_field(&record, 4, "decimals")?.parse()?,
+            pool_ids: Vec::new(),
+            tax: None,
+            known: KnownFields::default(),
+            risk: None,
+        })
+    }
+}
 impl Token {
     pub fn cache_row(&self) -> (i64, String, String, String, u8) {
         (
@@ -51,126 +146,400 @@ pub struct TokenInfo {
     pub name: String,
     pub symbol: String,
     pub decimals: u8,
+    pub known: KnownFields,
+}
+impl TokenInfo {
+    /// 按读到的字段构造 读不到的用默认值并标记为未知
+    pub fn new(
+        address: H160,
+        name: Option<String>,
+        symbol: Option<String>,
+        decimals: Option<u8>,
+    ) -> Self {
+        Self {
+            address,
+            known: KnownFields {
+                name: name.is_some(),
+                symbol: symbol.is_some(),
+                decimals: decimals.is_some(),
+            },
+            name: name.unwrap_or_default(),
+            symbol: symbol.unwrap_or_default(),
+            decimals: decimals.unwrap_or_default(),
+        }
+    }
 }
 
+/// 同步池子的代币
+/// 1. 只处理还没有解析过代币的池子 (pools.tokens_resolved) 两个代币都收录后标记为已解析
+/// 2. 读取失败的代币进入重试队列 记录次数和最后的错误 之后每次同步重试 超过 MAX_TOKEN_ATTEMPTS 次不再重试
 pub async fn load_all_tokens(
-    provider: &Arc<Provider<Ws>>,
+    provider: &Arc<RpcProvider>,
+    store: &Mutex<Store>,
     block_number: U64,
     pools: &Vec<Pool>,
-    prev_pool_id: i64,
 ) -> Result<HashMap<H160, Token>> {
-    // 与加载所有的池子 同样的步骤
-    // 设置缓存文件路径
-    let cache_file = "cache/.cached-tokens.csv";
-    let file_path = Path::new(cache_file);
-    let file_exists = file_path.exists();
-    // 打开文件，设置写入和追加权限
-    let file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(file_path)
-        .unwrap();
-    // 创建 CSV writer 用于写入数据
-    let mut writer = csv::Writer::from_writer(file);
-    // 创建代币地址到代币信息的映射
-    let mut tokens_map: HashMap<H160, Token> = HashMap::new();
-    let mut token_id = 0;
-    // 如果缓存文件存在，读取已有的代币数据
-    if file_exists {
-        let mut reader = csv::Reader::from_path(file_path)?;
-
-        for row in reader.records() {
-            let row = row.unwrap();
-            let token = Token::from(row);
-            tokens_map.insert(token.address, token);
-            token_id += 1;
+    // 代币 / 池子与代币的关系 / 税率都保存在 SQLite 中
+    // 创建代币地址到代币信息的映射 pool_ids 和税率一起读出来
+    let (mut tokens_map, resolved, failures) = {
+        let store = store.lock().unwrap();
+        (
+            store.load_tokens()?,
+            store.resolved_pool_ids()?,
+            store.load_token_failures()?,
+        )
+    };
+    let pools: Vec<&Pool> = pools
+        .iter()
+        .filter(|pool| !resolved.contains(&pool.id))
+        .collect();
+    // 先收集还没有收录的代币 再分批一次读取
+    let mut missing = Vec::new();
+    let mut seen = HashSet::new();
+    let mut given_up = 0;
+    for pool in &pools {
+        for token in vec![pool.token0, pool.token1] {
+            if tokens_map.contains_key(&token) || !seen.insert(token) {
+                continue;
+            }
+            match failures.get(&token) {
+                Some(failure) if failure.attempts >= MAX_TOKEN_ATTEMPTS => given_up += 1,
+                _ => missing.push(token),
+            }
         }
-    } else {
-        // 如果是新文件，写入CSV表头
-        writer.write_record(&["id", "address", "name", "symbol", "decimals"])?;
     }
-    // 创建进度条
-    let pb = ProgressBar::new(pools.len() as u64);
-    pb.set_style(
-        ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-        )
-        .unwrap()
-        .progress_chars("##-"),
+    info!(
+        "Unresolved pools: {:?} / fetching {:?} tokens / {:?} tokens over retry limit",
+        pools.len(),
+        missing.len(),
+        given_up
     );
-    // 记录当前token_id，用于后续判断新增代币
-    let new_token_id = token_id;
-    // 遍历所有池子，获取代币信息
-    for pool in pools {
-        let pool_id = pool.id;
-        // 跳过太老的池子（优化性能）
-        if pool_id < prev_pool_id - 50 {
-            continue;
-        }
+    let token_infos = fetch_token_infos(provider, block_number.into(), missing).await;
+    // 新代币 / 池子关系 / 重试队列 / 解析标记在同一个事务里写入
+    let mut store = store.lock().unwrap();
+    let tx = store.conn.transaction()?;
+    let (added, failed) = save_token_infos(
+        &tx,
+        token_infos,
+        &pools,
+        &mut tokens_map,
+        block_number.as_u64(),
+    )?;
+    tx.commit()?;
+    info!("Token count: {:?}", tokens_map.len());
+    info!("Added {:?} new tokens / {:?} failed", added, failed);
 
-        let token0 = pool.token0;
-        let token1 = pool.token1;
-        // 处理池子中的两个代币
-        for token in vec![token0, token1] {
-            // 如果代币还未收录，获取其信息
-            if !tokens_map.contains_key(&token) {
-                match get_token_info(provider, block_number.into(), token).await {
-                    Ok(token_info) => {
-                        tokens_map.insert(
-                            token,
-                            Token {
-                                id: token_id,
-                                address: token,
-                                name: token_info.name,
-                                symbol: token_info.symbol,
-                                decimals: token_info.decimals,
-                                pool_ids: Vec::new(),
-                            },
-                        );
-                        token_id += 1;
+    Ok(tokens_map)
+}
+
+/// 运行时扫描到的池子 (例如重组之后重新扫描到的) 读取还没有收录的代币
+/// 节点请求在加锁之前完成 返回 (两个代币都收录了的池子, 还要重试的池子)
+/// 代币超过 MAX_TOKEN_ATTEMPTS 次读取失败的池子不再重试 两边都不返回
+pub async fn load_pool_tokens(
+    provider: &Arc<RpcProvider>,
+    store: &Mutex<Store>,
+    tokens_map: &RwLock<HashMap<H160, Token>>,
+    block_number: U64,
+    pools: &[Pool],
+) -> Result<(Vec<Pool>, Vec<Pool>)> {
+    let failures = store.lock().unwrap().load_token_failures()?;
+    // 涉及到的已收录代币 复制一份 写入数据库时在上面记录池子关系
+    let mut known = HashMap::new();
+    let mut missing = Vec::new();
+    {
+        let tokens_map = tokens_map.read().unwrap();
+        for pool in pools {
+            for token in [pool.token0, pool.token1] {
+                if known.contains_key(&token) || missing.contains(&token) {
+                    continue;
+                }
+                match (tokens_map.get(&token), failures.get(&token)) {
+                    (Some(token_data), _) => {
+                        known.insert(token, token_data.clone());
                     }
-                    Err(_) => {}
+                    (None, Some(failure)) if failure.attempts >= MAX_TOKEN_ATTEMPTS => {}
+                    (None, _) => missing.push(token),
                 }
             }
         }
-        // 更新进度条
-        pb.inc(1);
     }
-    // 更新代币与池子的关联关系
+    let token_infos = fetch_token_infos(provider, block_number.into(), missing).await;
+    let pool_refs: Vec<&Pool> = pools.iter().collect();
+    {
+        let mut store = store.lock().unwrap();
+        let tx = store.conn.transaction()?;
+        save_token_infos(
+            &tx,
+            token_infos,
+            &pool_refs,
+            &mut known,
+            block_number.as_u64(),
+        )?;
+        tx.commit()?;
+    }
+    // 其它工作线程可能同时收录了同一个代币 合并池子关系
+    {
+        let mut tokens_map = tokens_map.write().unwrap();
+        for (address, token_data) in &known {
+            match tokens_map.get_mut(address) {
+                Some(existing) => {
+                    for pool_id in &token_data.pool_ids {
+                        if !existing.pool_ids.contains(pool_id) {
+                            existing.pool_ids.push(*pool_id);
+                        }
+                    }
+                }
+                None => {
+                    tokens_map.insert(*address, token_data.clone());
+                }
+            }
+        }
+    }
+    let (resolved, pending) = split_resolved_pools(pools, &known, &failures);
+    Ok((resolved, pending))
+}
+
+/// 把池子分成两个代币都收录了的 / 还要重试的 failures 是这次读取之前的失败次数
+/// 这次又失败的代币次数会再加 1 所以之前已经失败 MAX_TOKEN_ATTEMPTS - 1 次的不再重试
+fn split_resolved_pools(
+    pools: &[Pool],
+    tokens_map: &HashMap<H160, Token>,
+    failures: &HashMap<H160, TokenFailure>,
+) -> (Vec<Pool>, Vec<Pool>) {
+    let mut resolved = Vec::new();
+    let mut pending = Vec::new();
     for pool in pools {
-        let pool_id = pool.id;
+        let missing: Vec<H160> = [pool.token0, pool.token1]
+            .into_iter()
+            .filter(|token| !tokens_map.contains_key(token))
+            .collect();
+        if missing.is_empty() {
+            resolved.push(pool.clone());
+            continue;
+        }
+        let given_up = missing.iter().any(|token| match failures.get(token) {
+            Some(failure) => failure.attempts + 1 >= MAX_TOKEN_ATTEMPTS,
+            None => false,
+        });
+        if !given_up {
+            pending.push(pool.clone());
+        }
+    }
+    (resolved, pending)
+}
 
-        let token0 = pool.token0;
-        let token1 = pool.token1;
-        for token in vec![token0, token1] {
-            if let Some(token_map) = tokens_map.get_mut(&token) {
-                token_map.pool_ids.push(pool_id);
+/// 把读取到的代币信息写入数据库和 tokens_map
+/// 读取失败的代币记录到重试队列 两个代币都收录了的池子标记为已解析
+/// 返回 (新收录的代币数, 失败的代币数)
+fn save_token_infos(
+    tx: &Transaction,
+    token_infos: HashMap<H160, Result<TokenInfo, String>>,
+    pools: &[&Pool],
+    tokens_map: &mut HashMap<H160, Token>,
+    block_number: u64,
+) -> Result<(usize, usize)> {
+    let mut added = 0;
+    let mut failed = 0;
+    for (token, info) in token_infos {
+        match info {
+            Ok(token_info) => {
+                let mut token_data = Token {
+                    id: -1,
+                    address: token,
+                    name: token_info.name,
+                    symbol: token_info.symbol,
+                    decimals: token_info.decimals,
+                    pool_ids: Vec::new(),
+                    tax: None,
+                    known: token_info.known,
+                    risk: None,
+                };
+                token_data.id = Store::insert_token(tx, &token_data)?;
+                Store::clear_token_failure(tx, token)?;
+                tokens_map.insert(token, token_data);
+                added += 1;
+            }
+            Err(e) => {
+                Store::record_token_failure(tx, token, &e, block_number)?;
+                failed += 1;
             }
         }
     }
-    info!("Token count: {:?}", tokens_map.len());
-    // 保存新增的代币信息到缓存文件
-    let mut added = 0;
-    let mut tokens_vec: Vec<&Token> = tokens_map.values().collect();
-    tokens_vec.sort_by_key(|t| t.id);
-    for token in tokens_vec.iter() {
-        if token.id >= new_token_id {
-            writer.serialize(token.cache_row())?;
-            added += 1;
+    for pool in pools {
+        let pool_id = pool.id;
+        let mut both_known = true;
+        for token in [pool.token0, pool.token1] {
+            // 更新代币与池子的关联关系
+            match tokens_map.get_mut(&token) {
+                Some(token_data) => {
+                    if !token_data.pool_ids.contains(&pool_id) {
+                        Store::insert_pool_token(tx, pool_id, token_data.id)?;
+                        token_data.pool_ids.push(pool_id);
+                    }
+                }
+                None => both_known = false,
+            }
+        }
+        if both_known {
+            Store::mark_pool_resolved(tx, pool_id)?;
         }
     }
-    // 确保数据写入磁盘
-    writer.flush()?;
-    info!("Added {:?} new tokens", added);
+    Ok((added, failed))
+}
 
-    Ok(tokens_map)
+/// 期望数量和实际到账数量的差 换算成万分比
+fn tax_bps(expected: U256, actual: U256) -> u32 {
+    if expected.is_zero() || actual >= expected {
+        return 0;
+    }
+    ((expected - actual) * U256::from(TAX_DENOMINATOR) / expected).as_u32()
 }
-pub async fn get_token_info(
-    provider: &Arc<Provider<Ws>>,
+
+/// 在模拟器中测量代币的买入/卖出/转账税
+/// 1. 用 main_currency 在 pair 里买入 target_token 对比 getAmountOut 的理论值 -> 买入税
+/// 2. 把买到的一半转给一个新地址 -> 转账税
+/// 3. 把剩下的转给 pair 看 pair 实际收到多少 -> 卖出税
+pub fn measure_token_tax<M: Middleware + 'static>(
+    simulator: &mut EvmSimulator<M>,
+    target_token: H160,
+    pair: H160,
+    main_currency: H160,
+    main_balance_slot: i32,
+    amount_in: U256,
+) -> Result<TokenTax> {
+    let owner = simulator.owner;
+    let gas_price = simulator.base_fee;
+    simulator.set_eth_balance(owner, U256::from(10).pow(U256::from(20)))?;
+    let token = simulator.abi.token.clone();
+    let pair_abi = simulator.abi.pair.clone();
+
+    let (reserve0, reserve1) = simulator.get_pair_reserves(pair)?;
+    let token0_is_main = main_currency < target_token;
+    let (reserve_main, reserve_target) = if token0_is_main {
+        (reserve0, reserve1)
+    } else {
+        (reserve1, reserve0)
+    };
+    // rebase 代币会在没有转账的情况下改变池子的余额 使储备量和余额不一致 (变多变少都算)
+    let pair_balance = simulator.get_token_balance(target_token, pair)?;
+    let mut rebasing = pair_balance != reserve_target;
+
+    // 买入
+    simulator.set_token_balance(main_currency, owner, main_balance_slot, amount_in)?;
+    simulator.call(Tx {
+        caller: owner,
+        transact_to: main_currency,
+        data: token.encode("transfer", (pair, amount_in))?.0.into(),
+        value: U256::zero(),
+        gas_price,
+        gas_limit: 5000000,
+    })?;
+    let expected_out = get_v2_amount_out(amount_in, reserve_main, reserve_target);
+    let (amount0_out, amount1_out) = if token0_is_main {
+        (U256::zero(), expected_out)
+    } else {
+        (expected_out, U256::zero())
+    };
+    let before = simulator.get_token_balance(target_token, owner)?;
+    simulator.call(Tx {
+        caller: owner,
+        transact_to: pair,
+        data: pair_abi
+            .encode(
+                "swap",
+                (
+                    amount0_out,
+                    amount1_out,
+                    owner,
+                    ethers::types::Bytes::default(),
+                ),
+            )?
+            .0
+            .into(),
+        value: U256::zero(),
+        gas_price,
+        gas_limit: 5000000,
+    })?;
+    // 余额变少说明代币在买入时扣了我们的余额 这次测量不可信
+    let received = simulator
+        .get_token_balance(target_token, owner)?
+        .checked_sub(before)
+        .ok_or_else(|| anyhow!("Balance decreased after buy"))?;
+    if received.is_zero() {
+        return Err(anyhow!("Buy received nothing"));
+    }
+    let buy_tax = tax_bps(expected_out, received);
+
+    // 普通转账
+    let recipient = H160::random();
+    let transfer_amount = received / 2;
+    simulator.call(Tx {
+        caller: owner,
+        transact_to: target_token,
+        data: token
+            .encode("transfer", (recipient, transfer_amount))?
+            .0
+            .into(),
+        value: U256::zero(),
+        gas_price,
+        gas_limit: 5000000,
+    })?;
+    let transfer_received = simulator.get_token_balance(target_token, recipient)?;
+    let transfer_tax = tax_bps(transfer_amount, transfer_received);
+    // 转账之后 发送方应该正好少了 transfer_amount 池子的余额应该还等于 swap 之后的储备量
+    // 分红 / rebase 代币在转账时会改变其它持有者的余额
+    let owner_after = simulator.get_token_balance(target_token, owner)?;
+    let (reserve0, reserve1) = simulator.get_pair_reserves(pair)?;
+    let reserve_target = if token0_is_main { reserve1 } else { reserve0 };
+    if owner_after + transfer_amount != before + received
+        || simulator.get_token_balance(target_token, pair)? != reserve_target
+    {
+        rebasing = true;
+    }
+
+    // 卖出: 转给池子
+    let sell_amount = simulator.get_token_balance(target_token, owner)?;
+    let pair_before = simulator.get_token_balance(target_token, pair)?;
+    simulator.call(Tx {
+        caller: owner,
+        transact_to: target_token,
+        data: token.encode("transfer", (pair, sell_amount))?.0.into(),
+        value: U256::zero(),
+        gas_price,
+        gas_limit: 5000000,
+    })?;
+    let pair_received = simulator
+        .get_token_balance(target_token, pair)?
+        .checked_sub(pair_before)
+        .ok_or_else(|| anyhow!("Pair balance decreased after sell"))?;
+    let sell_tax = tax_bps(sell_amount, pair_received);
+
+    Ok(TokenTax {
+        buy_tax,
+        sell_tax,
+        transfer_tax,
+        rebasing,
+        block_number: simulator.block_number.as_u64(),
+    })
+}
+
+/// contracts/src/Request.sol 的 ABI
+fn request_abi() -> Result<BaseContract> {
+    Ok(BaseContract::from(parse_abi(&[
+        "function getTokenInfo(address) external returns (bytes,bytes,bool,uint8,uint256)",
+        // 返回值写成 ((...)[]) 时 parse_abi 会把元组拆成 6 个返回值 要用 struct 声明
+        "struct TokenInfo { bool success; bytes name; bytes symbol; bool decimalsKnown; uint8 decimals; uint256 totalSupply; }",
+        "function getTokenInfos(address[]) external returns (TokenInfo[])",
+    ])?))
+}
+
+/// 在模拟状态里调用 Request 合约 不需要真正部署
+async fn call_request(
+    provider: &Arc<RpcProvider>,
     block_number: BlockNumber,
-    token_address: H160,
-) -> Result<TokenInfo> {
+    calldata: Bytes,
+    gas: u64,
+) -> Result<Bytes> {
     // 区块链模拟
     // 创建一个新钱包作为交易发起者
     let owner = create_new_wallet().1;
@@ -188,17 +557,6 @@ pub async fn get_token_info(
     state
         .account(request_address)
         .code((*REQUEST_BYTECODE).clone());
-    //BaseContract 是 ethers-rs 中的一个核心类型，用于处理与智能合约交互的底层操作
-    // BaseContract 更像是一个工具，用来处理 ABI 编解码
-    // 创建 BaseContract，只包含 ABI 定义
-    // 主要用来：
-    // 解码返回数据（将返回的字节码转换为我们需要的类型）
-    // 编码函数调用数据（将函数名和参数转换为字节码）
-    let request_abi = BaseContract::from(parse_abi(&[
-        "function getTokenInfo(address) external returns (string,string,uint8,uint256)",
-    ])?);
-    // 编码调用数据calldata
-    let calldata = request_abi.encode("getTokenInfo", token_address)?;
     // wei
     let gas_price = U256::from(1000)
         .checked_mul(U256::from(10).pow(U256::from(9)))
@@ -210,7 +568,7 @@ pub async fn get_token_info(
         .value(U256::zero())
         .data(calldata.0)
         .nonce(U256::zero())
-        .gas(5000000)
+        .gas(gas)
         .gas_price(gas_price)
         .chain_id(1)
         .into();
@@ -224,14 +582,222 @@ pub async fn get_token_info(
         // 如果不指定，默认是最新区块
         .block(block_number.into())
         .await?;
-    let out: (String, String, u8, U256) = request_abi.decode_output("getTokenInfo", result)?;
-    let token_info = TokenInfo {
-        address: token_address,
-        name: out.0,
-        symbol: out.1,
-        decimals: out.2,
+    Ok(result)
+}
+
+pub async fn get_token_info(
+    provider: &Arc<RpcProvider>,
+    block_number: BlockNumber,
+    token_address: H160,
+) -> Result<TokenInfo> {
+    //BaseContract 是 ethers-rs 中的一个核心类型，用于处理与智能合约交互的底层操作
+    // BaseContract 更像是一个工具，用来处理 ABI 编解码
+    // 创建 BaseContract，只包含 ABI 定义
+    // 主要用来：
+    // 解码返回数据（将返回的字节码转换为我们需要的类型）
+    // 编码函数调用数据（将函数名和参数转换为字节码）
+    let request_abi = request_abi()?;
+    // 编码调用数据calldata
+    let calldata = request_abi.encode("getTokenInfo", token_address)?;
+    // 调用失败(代币 revert / 节点错误)时 改为直接低级调用代币的各个方法
+    let result = match call_request(provider, block_number, calldata, 5000000).await {
+        Ok(result) => result,
+        Err(_) => return get_token_info_direct(provider, block_number, token_address).await,
+    };
+    decode_token_info(token_address, result)
+}
+
+/// 按 Request.sol 的 ABI 严格解码返回值
+/// 普通解码不检查长度和多余的数据 旧版字节码的返回值也可能解出错误的字段
+/// 所以解出来的值重新编码之后必须和返回值完全一致 否则说明 REQUEST_BYTECODE 和 Request.sol 不一致
+fn decode_request_output<D: Detokenize>(name: &str, result: &Bytes) -> Result<D> {
+    let request_abi = request_abi()?;
+    let function = request_abi.abi().function(name)?;
+    let tokens = function.decode_output(result)?;
+    if ethers::abi::encode(&tokens) != result.to_vec() {
+        return Err(anyhow!(
+            "{} output does not match Request.sol, REQUEST_BYTECODE is stale",
+            name
+        ));
+    }
+    Ok(D::from_tokens(tokens)?)
+}
+
+/// 解码 getTokenInfo 的返回值
+fn decode_token_info(token_address: H160, result: Bytes) -> Result<TokenInfo> {
+    let out: (Bytes, Bytes, bool, u8, U256) = decode_request_output("getTokenInfo", &result)?;
+    Ok(TokenInfo::new(
+        token_address,
+        decode_string_or_bytes32(&out.0),
+        decode_string_or_bytes32(&out.1),
+        if out.2 { Some(out.3) } else { None },
+    ))
+}
+
+/// 一次 eth_call 读取一批代币的信息 返回值和 tokens 一一对应
+/// 地址上没有合约代码的代币为 None
+pub async fn get_token_infos(
+    provider: &Arc<RpcProvider>,
+    block_number: BlockNumber,
+    tokens: &[H160],
+) -> Result<Vec<Option<TokenInfo>>> {
+    let calldata = request_abi()?.encode("getTokenInfos", tokens.to_vec())?;
+    let gas = std::cmp::min(TOKEN_INFO_GAS_PER_TOKEN * tokens.len() as u64, RPC_GAS_CAP);
+    let result = call_request(provider, block_number, calldata, gas).await?;
+    decode_token_infos(tokens, result)
+}
+
+/// 解码 getTokenInfos 的返回值
+fn decode_token_infos(tokens: &[H160], result: Bytes) -> Result<Vec<Option<TokenInfo>>> {
+    let out: Vec<(bool, Bytes, Bytes, bool, u8, U256)> =
+        decode_request_output("getTokenInfos", &result)?;
+    if out.len() != tokens.len() {
+        return Err(anyhow!(
+            "getTokenInfos returned {:?} results for {:?} tokens",
+            out.len(),
+            tokens.len()
+        ));
+    }
+    let infos = tokens
+        .iter()
+        .zip(out)
+        .map(
+            |(token, (success, name, symbol, decimals_known, decimals, _))| {
+                if !success {
+                    return None;
+                }
+                Some(TokenInfo::new(
+                    *token,
+                    decode_string_or_bytes32(&name),
+                    decode_string_or_bytes32(&symbol),
+                    if decimals_known { Some(decimals) } else { None },
+                ))
+            },
+        )
+        .collect();
+    Ok(infos)
+}
+
+/// 分批并发读取代币信息
+/// 每批 TOKEN_INFO_BATCH_SIZE 个代币一次 eth_call 最多 TOKEN_INFO_CONCURRENCY 批同时请求
+/// 整批失败(节点错误 / 超时)时 这一批改为逐个读取
+/// 每个代币都有一个结果 失败的是错误信息
+pub async fn fetch_token_infos(
+    provider: &Arc<RpcProvider>,
+    block_number: BlockNumber,
+    tokens: Vec<H160>,
+) -> HashMap<H160, Result<TokenInfo, String>> {
+    let pb = ProgressBar::new(tokens.len() as u64);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+        )
+        .unwrap()
+        .progress_chars("##-"),
+    );
+    let batches = tokens
+        .chunks(TOKEN_INFO_BATCH_SIZE)
+        .map(|batch| batch.to_vec())
+        .map(|batch| async move {
+            match get_token_infos(provider, block_number, &batch).await {
+                Ok(infos) => batch
+                    .iter()
+                    .zip(infos)
+                    .map(|(token, info)| {
+                        (*token, info.ok_or_else(|| "no contract code".to_string()))
+                    })
+                    .collect::<Vec<_>>(),
+                Err(e) => {
+                    warn!("Token info batch failed, fetching one by one: {:?}", e);
+                    let mut infos = Vec::new();
+                    for token in &batch {
+                        let info = get_token_info(provider, block_number, *token)
+                            .await
+                            .map_err(|e| format!("{:?}", e));
+                        infos.push((*token, info));
+                    }
+                    infos
+                }
+            }
+        });
+    let mut results = stream::iter(batches).buffer_unordered(TOKEN_INFO_CONCURRENCY);
+    let mut token_infos = HashMap::new();
+    while let Some(infos) = results.next().await {
+        pb.inc(infos.len() as u64);
+        token_infos.extend(infos);
+    }
+    token_infos
+}
+
+/// 不经过 Request 合约 逐个方法 eth_call 读取代币信息 调用失败的字段标记为未知
+pub async fn get_token_info_direct(
+    provider: &Arc<RpcProvider>,
+    block_number: BlockNumber,
+    token_address: H160,
+) -> Result<TokenInfo> {
+    let abi = BaseContract::from(parse_abi(&[
+        "function name() external view returns (string)",
+        "function symbol() external view returns (string)",
+        "function decimals() external view returns (uint8)",
+    ])?);
+    let mut fields = Vec::new();
+    for method in ["name", "symbol", "decimals"] {
+        let tx = TransactionRequest::new()
+            .to(token_address)
+            .data(abi.encode(method, ())?)
+            .into();
+        // revert / 方法不存在 都当作读不到
+        let out = provider.call(&tx, Some(block_number.into())).await.ok();
+        fields.push(out);
+    }
+    let decimals = match &fields[2] {
+        Some(out) if out.len() >= 32 => {
+            let value = U256::from_big_endian(&out[..32]);
+            if value <= U256::from(u8::MAX) {
+                Some(value.as_u32() as u8)
+            } else {
+                None
+            }
+        }
+        _ => None,
     };
-    Ok(token_info)
+    Ok(TokenInfo::new(
+        token_address,
+        fields[0]
+            .as_ref()
+            .and_then(|out| decode_string_or_bytes32(out)),
+        fields[1]
+            .as_ref()
+            .and_then(|out| decode_string_or_bytes32(out)),
+        decimals,
+    ))
+}
+
+/// 解码 name() / symbol() 的返回数据
+/// 标准代币返回 abi 编码的 string 早期代币(MKR SAI)返回 bytes32 (右侧补 0)
+/// 非 utf8 的字节按 lossy 转换 空数据或者解不出来返回 None
+pub fn decode_string_or_bytes32(data: &[u8]) -> Option<String> {
+    let raw = if data.len() == 32 {
+        let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+        data[..end].to_vec()
+    } else if data.len() >= 64 {
+        // string 和 bytes 的 abi 编码相同 按 bytes 解码可以避免非 utf8 时报错
+        match ethers::abi::decode(&[ParamType::Bytes], data) {
+            Ok(tokens) => tokens[0].clone().into_bytes()?,
+            Err(_) => return None,
+        }
+    } else {
+        return None;
+    };
+    let value = String::from_utf8_lossy(&raw)
+        .trim_matches(char::from(0))
+        .trim()
+        .to_string();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
 }
 /*
 spoof:
@@ -259,3 +825,349 @@ spoof:
     某些复杂的交互可能难以完全模拟
     需要了解合约的存储布局才能正确设置状态
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::evm::to_raddress;
+    use ethers::abi::Token as AbiToken;
+    use ethers::utils::id;
+    use revm::{
+        db::{CacheDB, EmptyDB},
+        primitives::{
+            AccountInfo, Bytecode, Bytes as rBytes, ExecutionResult, Output, TxKind, U256 as rU256,
+        },
+        Evm,
+    };
+
+    const REQUEST: u64 = 0x1000;
+    const STANDARD: u64 = 0x2001;
+    const BYTES32: u64 = 0x2002;
+    const REVERTING: u64 = 0x2003;
+    const BIG_DECIMALS: u64 = 0x2004;
+    const EOA: u64 = 0x3000;
+
+    /// 按选择器返回固定数据的合约 没有匹配的选择器时 revert
+    fn responder(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        // selector = calldataload(0) >> 224
+        let mut code = vec![0x60, 0x00, 0x35, 0x60, 0xe0, 0x1c];
+        let dispatch_len = code.len() + entries.len() * 11 + 5;
+        let body_len = 16;
+        let data_start = dispatch_len + entries.len() * body_len;
+        let mut bodies: Vec<u8> = Vec::new();
+        let mut data: Vec<u8> = Vec::new();
+        for (i, (signature, out)) in entries.iter().enumerate() {
+            let dest = dispatch_len + i * body_len;
+            // dup1 push4 selector eq push2 dest jumpi
+            code.extend([0x80, 0x63]);
+            code.extend(id(signature));
+            code.extend([0x14, 0x61, (dest >> 8) as u8, dest as u8, 0x57]);
+            // jumpdest codecopy(0, offset, len) return(0, len)
+            let offset = data_start + data.len();
+            let len = out.len();
+            bodies.extend([0x5b, 0x61, (len >> 8) as u8, len as u8]);
+            bodies.extend([0x61, (offset >> 8) as u8, offset as u8, 0x60, 0x00, 0x39]);
+            bodies.extend([0x61, (len >> 8) as u8, len as u8, 0x60, 0x00, 0xf3]);
+            data.extend(out);
+        }
+        // revert(0, 0)
+        code.extend([0x60, 0x00, 0x80, 0xfd, 0x00]);
+        code.extend(bodies);
+        code.extend(data);
+        code
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        ethers::abi::encode(&[AbiToken::String(value.to_string())])
+    }
+
+    fn word(value: u64) -> Vec<u8> {
+        ethers::abi::encode(&[AbiToken::Uint(U256::from(value))])
+    }
+
+    fn bytes32(value: &str) -> Vec<u8> {
+        let mut data = value.as_bytes().to_vec();
+        data.resize(32, 0);
+        data
+    }
+
+    fn deploy(db: &mut CacheDB<EmptyDB>, address: u64, code: Vec<u8>) {
+        let bytecode = Bytecode::new_raw(rBytes::from(code));
+        db.insert_account_info(
+            to_raddress(H160::from_low_u64_be(address)),
+            AccountInfo::new(rU256::ZERO, 0, bytecode.hash_slow(), bytecode),
+        );
+    }
+
+    /// Request 合约和几种代币
+    fn setup() -> CacheDB<EmptyDB> {
+        let mut db = CacheDB::new(EmptyDB::default());
+        deploy(&mut db, REQUEST, REQUEST_BYTECODE.to_vec());
+        deploy(
+            &mut db,
+            STANDARD,
+            responder(&[
+                ("name()", string("Wrapped Ether")),
+                ("symbol()", string("WETH")),
+                ("decimals()", word(18)),
+                ("totalSupply()", word(1000000)),
+            ]),
+        );
+        // MKR 这类 name / symbol 返回 bytes32 的代币
+        deploy(
+            &mut db,
+            BYTES32,
+            responder(&[
+                ("name()", bytes32("Maker")),
+                ("symbol()", bytes32("MKR")),
+                ("decimals()", word(18)),
+                ("totalSupply()", word(7)),
+            ]),
+        );
+        deploy(&mut db, REVERTING, vec![0x60, 0x00, 0x80, 0xfd]);
+        deploy(
+            &mut db,
+            BIG_DECIMALS,
+            responder(&[
+                (
+                    "symbol()",
+                    string("A symbol that is longer than thirty two bytes"),
+                ),
+                ("decimals()", word(300)),
+            ]),
+        );
+        db
+    }
+
+    fn call(db: &mut CacheDB<EmptyDB>, calldata: Bytes, gas_limit: u64) -> Bytes {
+        let mut evm = Evm::builder()
+            .with_db(db)
+            .modify_tx_env(|t| {
+                t.caller = to_raddress(H160::from_low_u64_be(0x9999));
+                t.transact_to = TxKind::Call(to_raddress(H160::from_low_u64_be(REQUEST)));
+                t.data = rBytes::from(calldata.to_vec());
+                t.gas_limit = gas_limit;
+                t.gas_price = rU256::ZERO;
+                t.nonce = None;
+            })
+            .build();
+        match evm.transact().unwrap().result {
+            ExecutionResult::Success {
+                output: Output::Call(output),
+                ..
+            } => Bytes::from(output.to_vec()),
+            result => panic!("Request call failed: {:?}", result),
+        }
+    }
+
+    fn addresses(tokens: &[u64]) -> Vec<H160> {
+        tokens
+            .iter()
+            .map(|token| H160::from_low_u64_be(*token))
+            .collect()
+    }
+
+    #[test]
+    fn batch_gas_is_under_rpc_cap() {
+        assert!(TOKEN_INFO_GAS_PER_TOKEN * TOKEN_INFO_BATCH_SIZE as u64 <= RPC_GAS_CAP);
+    }
+
+    #[test]
+    fn get_token_infos_decodes_each_kind_of_token() {
+        let mut db = setup();
+        let tokens = addresses(&[STANDARD, EOA, BYTES32, REVERTING, BIG_DECIMALS]);
+        let calldata = request_abi()
+            .unwrap()
+            .encode("getTokenInfos", tokens.clone())
+            .unwrap();
+        let result = call(&mut db, calldata, 5000000);
+        let infos = decode_token_infos(&tokens, result).unwrap();
+        assert_eq!(infos.len(), 5);
+
+        let standard = infos[0].clone().unwrap();
+        assert_eq!(standard.address, tokens[0]);
+        assert_eq!(standard.name, "Wrapped Ether");
+        assert_eq!(standard.symbol, "WETH");
+        assert_eq!(standard.decimals, 18);
+        assert!(standard.known.name && standard.known.symbol && standard.known.decimals);
+
+        // 没有代码的地址
+        assert!(infos[1].is_none());
+
+        let maker = infos[2].clone().unwrap();
+        assert_eq!(maker.name, "Maker");
+        assert_eq!(maker.symbol, "MKR");
+        assert_eq!(maker.decimals, 18);
+
+        // 所有调用都 revert 字段都未知
+        let reverting = infos[3].clone().unwrap();
+        assert!(!reverting.known.name && !reverting.known.symbol && !reverting.known.decimals);
+
+        // decimals 超过 uint8
+        let big = infos[4].clone().unwrap();
+        assert!(!big.known.name);
+        assert_eq!(big.symbol, "A symbol that is longer than thirty two bytes");
+        assert!(!big.known.decimals);
+    }
+
+    #[test]
+    fn full_batch_fits_in_batch_gas() {
+        let mut db = setup();
+        let tokens: Vec<H160> = (0..TOKEN_INFO_BATCH_SIZE)
+            .map(|i| H160::from_low_u64_be(if i % 2 == 0 { STANDARD } else { BYTES32 }))
+            .collect();
+        let calldata = request_abi()
+            .unwrap()
+            .encode("getTokenInfos", tokens.clone())
+            .unwrap();
+        let gas = std::cmp::min(TOKEN_INFO_GAS_PER_TOKEN * tokens.len() as u64, RPC_GAS_CAP);
+        let result = call(&mut db, calldata, gas);
+        let infos = decode_token_infos(&tokens, result).unwrap();
+        assert_eq!(infos.len(), TOKEN_INFO_BATCH_SIZE);
+        assert!(infos.iter().all(|info| info.is_some()));
+    }
+
+    #[test]
+    fn result_count_must_match_tokens() {
+        let mut db = setup();
+        let tokens = addresses(&[STANDARD, BYTES32]);
+        let calldata = request_abi()
+            .unwrap()
+            .encode("getTokenInfos", tokens.clone())
+            .unwrap();
+        let result = call(&mut db, calldata, 5000000);
+        assert!(decode_token_infos(&tokens[..1], result).is_err());
+    }
+
+    fn single(db: &mut CacheDB<EmptyDB>, token: H160) -> Bytes {
+        let calldata = request_abi()
+            .unwrap()
+            .encode("getTokenInfo", token)
+            .unwrap();
+        call(db, calldata, 5000000)
+    }
+
+    #[test]
+    fn get_token_info_decodes_request_output() {
+        let mut db = setup();
+        let token = H160::from_low_u64_be(STANDARD);
+        let info = decode_token_info(token, single(&mut db, token)).unwrap();
+        assert_eq!(info.name, "Wrapped Ether");
+        assert_eq!(info.symbol, "WETH");
+        assert_eq!(info.decimals, 18);
+
+        let token = H160::from_low_u64_be(BYTES32);
+        let info = decode_token_info(token, single(&mut db, token)).unwrap();
+        assert_eq!(info.symbol, "MKR");
+
+        let token = H160::from_low_u64_be(REVERTING);
+        let info = decode_token_info(token, single(&mut db, token)).unwrap();
+        assert!(!info.known.name && !info.known.symbol && !info.known.decimals);
+    }
+
+    #[test]
+    fn old_layout_is_rejected() {
+        // 旧版 Request.sol 返回 (string, string, uint8, uint256)
+        let result = ethers::abi::encode(&[
+            AbiToken::String("Wrapped Ether".to_string()),
+            AbiToken::String("WETH".to_string()),
+            AbiToken::Uint(U256::from(18)),
+            AbiToken::Uint(U256::from(1000000)),
+        ]);
+        let token = H160::from_low_u64_be(STANDARD);
+        assert!(decode_token_info(token, Bytes::from(result.clone())).is_err());
+        assert!(decode_token_infos(&[token], Bytes::from(result)).is_err());
+    }
+
+    #[test]
+    fn truncated_or_padded_output_is_rejected() {
+        let mut db = setup();
+        let token = H160::from_low_u64_be(STANDARD);
+        let result = single(&mut db, token).to_vec();
+        let mut padded = result.clone();
+        padded.extend([0u8; 32]);
+        assert!(decode_token_info(token, Bytes::from(padded)).is_err());
+        let truncated = result[..result.len() - 32].to_vec();
+        assert!(decode_token_info(token, Bytes::from(truncated)).is_err());
+
+        let tokens = addresses(&[STANDARD, BYTES32]);
+        let calldata = request_abi()
+            .unwrap()
+            .encode("getTokenInfos", tokens.clone())
+            .unwrap();
+        let result = call(&mut db, calldata, 5000000).to_vec();
+        assert!(decode_token_infos(&tokens, Bytes::from(result.clone())).is_ok());
+        let mut padded = result.clone();
+        padded.extend([0u8; 32]);
+        assert!(decode_token_infos(&tokens, Bytes::from(padded)).is_err());
+        let truncated = result[..result.len() - 32].to_vec();
+        assert!(decode_token_infos(&tokens, Bytes::from(truncated)).is_err());
+    }
+
+    fn info_of(address: H160) -> TokenInfo {
+        TokenInfo::new(
+            address,
+            Some("Token".to_string()),
+            Some("TKN".to_string()),
+            Some(18),
+        )
+    }
+
+    fn v2_pool(address: u64, token0: H160, token1: H160) -> Pool {
+        Pool {
+            id: 0,
+            address: H160::from_low_u64_be(address),
+            version: crate::common::pools::DexVariant::UniswapV2,
+            token0,
+            token1,
+            fee: 300,
+            block_number: 1,
+            timestamp: 0,
+            block_hash: None,
+        }
+    }
+
+    #[test]
+    fn pools_with_failed_tokens_stay_unresolved_until_retry_limit() {
+        let mut store = Store::open(":memory:").unwrap();
+        let (a, b, c) = (
+            H160::from_low_u64_be(0xa),
+            H160::from_low_u64_be(0xb),
+            H160::from_low_u64_be(0xc),
+        );
+        let pools = store
+            .insert_pools(vec![v2_pool(0x1, a, b), v2_pool(0x2, b, c)], None)
+            .unwrap();
+        let mut token_infos = HashMap::new();
+        token_infos.insert(a, Ok(info_of(a)));
+        token_infos.insert(b, Ok(info_of(b)));
+        token_infos.insert(c, Err("no contract code".to_string()));
+        let mut tokens_map = HashMap::new();
+        let tx = store.conn.transaction().unwrap();
+        let pool_refs: Vec<&Pool> = pools.iter().collect();
+        let (added, failed) =
+            save_token_infos(&tx, token_infos, &pool_refs, &mut tokens_map, 100).unwrap();
+        tx.commit().unwrap();
+        assert_eq!((added, failed), (2, 1));
+        // 只有两个代币都收录的池子标记为已解析 代币记录了所属的池子
+        let resolved = store.resolved_pool_ids().unwrap();
+        assert!(resolved.contains(&pools[0].id));
+        assert!(!resolved.contains(&pools[1].id));
+        assert_eq!(tokens_map[&b].pool_ids, vec![pools[0].id, pools[1].id]);
+        let failures = store.load_token_failures().unwrap();
+        assert_eq!(failures[&c].attempts, 1);
+        assert_eq!(failures[&c].last_block, 100);
+
+        // 失败的代币留在队列里 之前的失败次数达到上限时不再重试
+        let (done, pending) = split_resolved_pools(&pools, &tokens_map, &failures);
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].address, pools[0].address);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].address, pools[1].address);
+        let mut given_up = failures.clone();
+        given_up.get_mut(&c).unwrap().attempts = MAX_TOKEN_ATTEMPTS - 1;
+        let (done, pending) = split_resolved_pools(&pools, &tokens_map, &given_up);
+        assert_eq!(done.len(), 1);
+        assert!(pending.is_empty());
+    }
+}
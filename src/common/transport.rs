@@ -0,0 +1,113 @@
+////////////////////////////////////////
+////节点连接方式 IPC / WS / HTTP///
+////////////////////////////////////////
+
+use anyhow::Result;
+use async_trait::async_trait;
+use ethers::types::U256;
+use ethers_providers::{Http, Ipc, JsonRpcClient, Provider, ProviderError, PubsubClient, Ws};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt::Debug, str::FromStr, sync::Arc};
+
+use super::fixture::{FixtureClient, FixtureRecorder};
+
+/// 整个程序使用的 provider 连接方式在配置里选择
+pub type RpcProvider = Provider<Transport>;
+
+/// 根据配置选择的连接方式
+/// 节点和程序在同一台机器上时用 IPC: 追踪和 pending 交易的延迟在 1ms 以内
+/// HTTP 不支持订阅 只能用于扫描池子 / 读取代币这类一次性的请求
+/// Fixture 从录制的响应回答请求 配合回放的数据流在没有节点的情况下运行策略
+#[derive(Debug, Clone)]
+pub enum Transport {
+    Ws(Ws),
+    Ipc(Ipc),
+    Http(Http),
+    Fixture(FixtureClient),
+    /// 录制经过的请求和响应 生成 Fixture 使用的文件
+    Recording(FixtureRecorder),
+}
+
+impl Transport {
+    /// ws:// wss:// -> WS / http:// https:// -> HTTP / fixture://<文件> -> Fixture
+    /// 其它当作 IPC socket 路径 (可以带 ipc:// 前缀)
+    pub async fn connect(url: &str) -> Result<Self> {
+        let transport = if let Some(path) = url.strip_prefix("fixture://") {
+            Transport::Fixture(FixtureClient::load(path)?)
+        } else if url.starts_with("ws://") || url.starts_with("wss://") {
+            Transport::Ws(Ws::connect(url).await?)
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            Transport::Http(Http::from_str(url)?)
+        } else {
+            Transport::Ipc(Ipc::connect(url.trim_start_matches("ipc://")).await?)
+        };
+        Ok(transport)
+    }
+
+    pub fn supports_subscriptions(&self) -> bool {
+        match self {
+            Transport::Ws(_) | Transport::Ipc(_) => true,
+            Transport::Http(_) | Transport::Fixture(_) => false,
+            Transport::Recording(recorder) => recorder.inner().supports_subscriptions(),
+        }
+    }
+}
+
+/// 按配置连接节点
+pub async fn connect_provider(url: &str) -> Result<Arc<RpcProvider>> {
+    Ok(Arc::new(Provider::new(Transport::connect(url).await?)))
+}
+
+/// 连接节点 并把所有请求和响应录制到 fixture_path
+pub async fn connect_recording_provider(url: &str, fixture_path: &str) -> Result<Arc<RpcProvider>> {
+    let transport = Transport::Recording(FixtureRecorder::new(
+        Transport::connect(url).await?,
+        fixture_path,
+    )?);
+    Ok(Arc::new(Provider::new(transport)))
+}
+
+#[async_trait]
+impl JsonRpcClient for Transport {
+    type Error = ProviderError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        match self {
+            Transport::Ws(ws) => ws.request(method, params).await.map_err(Into::into),
+            Transport::Ipc(ipc) => ipc.request(method, params).await.map_err(Into::into),
+            Transport::Http(http) => http.request(method, params).await.map_err(Into::into),
+            Transport::Fixture(fixture) => fixture.request(method, params).await,
+            Transport::Recording(recorder) => recorder.request(method, params).await,
+        }
+    }
+}
+
+impl PubsubClient for Transport {
+    type NotificationStream = <Ws as PubsubClient>::NotificationStream;
+
+    fn subscribe<T: Into<U256>>(&self, id: T) -> Result<Self::NotificationStream, Self::Error> {
+        match self {
+            Transport::Ws(ws) => ws.subscribe(id).map_err(Into::into),
+            Transport::Ipc(ipc) => ipc.subscribe(id).map_err(Into::into),
+            Transport::Recording(recorder) => recorder.inner().subscribe(id),
+            Transport::Http(_) | Transport::Fixture(_) => Err(ProviderError::CustomError(
+                "Transport does not support subscriptions".to_string(),
+            )),
+        }
+    }
+
+    fn unsubscribe<T: Into<U256>>(&self, id: T) -> Result<(), Self::Error> {
+        match self {
+            Transport::Ws(ws) => ws.unsubscribe(id).map_err(Into::into),
+            Transport::Ipc(ipc) => ipc.unsubscribe(id).map_err(Into::into),
+            Transport::Recording(recorder) => recorder.inner().unsubscribe(id),
+            Transport::Http(_) | Transport::Fixture(_) => Err(ProviderError::CustomError(
+                "Transport does not support subscriptions".to_string(),
+            )),
+        }
+    }
+}
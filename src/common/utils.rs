@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr, sync::OnceLock};
 
 use crate::common::constants::*;
 use anyhow::{Ok, Result};
@@ -69,9 +69,14 @@ pub fn create_new_wallet() -> (LocalWallet, H160) {
 pub fn to_h160(str_address: &'static str) -> H160 {
     H160::from_str(str_address).unwrap()
 }
+/// 读取 csv 行的第 index 列 缺少时返回带列名的错误
+pub fn csv_field<'a>(record: &'a csv::StringRecord, index: usize, name: &str) -> Result<&'a str> {
+    record
+        .get(index)
+        .ok_or_else(|| anyhow::anyhow!("missing column {:?}", name))
+}
 pub fn is_main_currency(token_address: H160) -> bool {
-    let main_currencies = vec![to_h160(WETH), to_h160(USDT), to_h160(USDC)];
-    main_currencies.contains(&token_address)
+    main_currencies().contains_key(&token_address)
 }
 pub fn is_weth(token_address: H160) -> bool {
     token_address == H160::from_str(WETH).unwrap()
@@ -93,12 +98,9 @@ pub fn return_main_and_target_currency(token0: H160, token1: H160) -> Option<(H1
     }
     // 如果两个代币都是主要代币（如 WETH/USDC 交易对）
     if token0_supported && token1_supported {
-        // 创建主要代币对象以获取权重
-        let mc0 = MainCurrency::new(token0);
-        let mc1 = MainCurrency::new(token1);
-        // 获取两个代币的权重
-        let token0_weight = mc0.weight();
-        let token1_weight = mc1.weight();
+        // 从主要货币表中获取两个代币的权重
+        let token0_weight = main_currencies()[&token0].weight;
+        let token1_weight = main_currencies()[&token1].weight;
         // 返回权重更高的代币作为主要代币
         if token0_weight > token1_weight {
             return Some((token0, token1));
@@ -115,52 +117,147 @@ pub fn return_main_and_target_currency(token0: H160, token1: H160) -> Option<(H1
         return Some((token1, token0));
     }
 }
+/// 主要货币配置
+/// 只有至少包含一种主要货币的交易对才会被处理
 #[derive(Debug, Clone)]
-pub enum MainCurrency {
-    WETH,
-    USDT,
-    USDC,
-    Default, // Pairs that aren't WETH/Stable pairs. Default to WETH for now
+pub struct MainCurrency {
+    pub address: H160,
+    pub symbol: String,
+    pub decimals: u8,
+    /// ERC20 balanceOf 映射所在的存储槽 用于在模拟器中直接设置余额
+    pub balance_slot: i32,
+    /// 两个主要货币组成交易对时 权重高的作为主要货币
+    pub weight: u8,
+    /// 试探用的小额输入 (例如 0.01 ETH / 10 USDT)
+    pub probe_amount: U256,
+    /// 池子里这种主币的储备量低于它 认为池子没有流动性 (默认 probe_amount 的 1000 倍)
+    pub min_liquidity: U256,
 }
-
 impl MainCurrency {
-    pub fn new(address: H160) -> Self {
-        if address == to_h160(WETH) {
-            MainCurrency::WETH
-        } else if address == to_h160(USDT) {
-            MainCurrency::USDT
-        } else if address == to_h160(USDC) {
-            MainCurrency::USDC
-        } else {
-            MainCurrency::Default
+    pub fn new(
+        address: H160,
+        symbol: &str,
+        decimals: u8,
+        balance_slot: i32,
+        weight: u8,
+        probe_amount: U256,
+    ) -> Self {
+        Self {
+            address,
+            symbol: symbol.to_string(),
+            decimals,
+            balance_slot,
+            weight,
+            probe_amount,
+            min_liquidity: probe_amount * U256::from(1000),
         }
     }
-    /*
-    We score the currencies by importance
-    WETH has the highest importance, and USDT, USDC in the following order
-    */
-    pub fn weight(&self) -> u8 {
-        match self {
-            MainCurrency::WETH => 3,
-            MainCurrency::USDT => 2,
-            MainCurrency::USDC => 1,
-            MainCurrency::Default => 3, // default is WETH
+}
+/// 主要货币表 key 是代币地址
+/// 如果设置了 MAIN_CURRENCIES_FILE 从 csv 读取, 否则使用内置的 WETH/USDT/USDC
+/// csv 列: address,symbol,decimals,balance_slot,weight,probe_amount[,min_liquidity]
+static MAIN_CURRENCIES: OnceLock<HashMap<H160, MainCurrency>> = OnceLock::new();
+/// 启动时调用 读取主要货币配置 配置文件有错时直接返回错误 不会等到第一笔交易才 panic
+/// 地址重复 / 已经初始化过(包括调用之前已经用过内置的主要货币)时也返回错误
+pub fn init_main_currencies() -> Result<()> {
+    let currencies = match std::env::var("MAIN_CURRENCIES_FILE") {
+        std::result::Result::Ok(path) => load_main_currencies(&path)
+            .and_then(index_main_currencies)
+            .map_err(|e| anyhow::anyhow!("MAIN_CURRENCIES_FILE {:?}: {:?}", path, e))?,
+        Err(_) => index_main_currencies(default_main_currencies())?,
+    };
+    MAIN_CURRENCIES
+        .set(currencies)
+        .map_err(|_| anyhow::anyhow!("Main currencies are already initialized"))
+}
+/// 按地址建表 同一个地址出现两次时返回错误
+fn index_main_currencies(currencies: Vec<MainCurrency>) -> Result<HashMap<H160, MainCurrency>> {
+    let mut indexed = HashMap::new();
+    for currency in currencies {
+        let address = currency.address;
+        if indexed.insert(address, currency).is_some() {
+            return Err(anyhow::anyhow!("Duplicate main currency {:?}", address));
         }
     }
-    pub fn decimals(&self) -> u8 {
-        match self {
-            MainCurrency::WETH => WETH_DECIMALS,
-            MainCurrency::USDT => USDC_DECIMALS,
-            MainCurrency::USDC => USDC_DECIMALS,
-            MainCurrency::Default => WETH_DECIMALS,
+    Ok(indexed)
+}
+/// 没有调用 init_main_currencies 时 (例如只读缓存的工具) 使用内置的主要货币
+fn main_currencies() -> &'static HashMap<H160, MainCurrency> {
+    MAIN_CURRENCIES.get_or_init(|| {
+        default_main_currencies()
+            .into_iter()
+            .map(|c| (c.address, c))
+            .collect()
+    })
+}
+/*
+We score the currencies by importance
+WETH has the highest importance, and USDT, USDC in the following order
+*/
+pub fn default_main_currencies() -> Vec<MainCurrency> {
+    vec![
+        MainCurrency::new(
+            to_h160(WETH),
+            "WETH",
+            WETH_DECIMALS,
+            WETH_BALANCE_SLOT,
+            3,
+            U256::from(10).pow(U256::from(WETH_DECIMALS - 2)), // 0.01 ETH
+        ),
+        MainCurrency::new(
+            to_h160(USDT),
+            "USDT",
+            USDT_DECIMALS,
+            USDT_BALANCE_SLOT,
+            2,
+            U256::from(10) * U256::from(10).pow(U256::from(USDT_DECIMALS)), // 10 USDT
+        ),
+        MainCurrency::new(
+            to_h160(USDC),
+            "USDC",
+            USDC_DECIMALS,
+            USDC_BALANCE_SLOT,
+            1,
+            U256::from(10) * U256::from(10).pow(U256::from(USDC_DECIMALS)), // 10 USDC
+        ),
+    ]
+}
+pub fn load_main_currencies(path: &str) -> Result<Vec<MainCurrency>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let mut currencies = Vec::new();
+    for row in reader.records() {
+        let row = row?;
+        let mut currency = MainCurrency::new(
+            H160::from_str(csv_field(&row, 0, "address")?)?,
+            csv_field(&row, 1, "symbol")?,
+            csv_field(&row, 2, "decimals")?.parse()?,
+            csv_field(&row, 3, "balance_slot")?.parse()?,
+            csv_field(&row, 4, "weight")?.parse()?,
+            U256::from_dec_str(csv_field(&row, 5, "probe_amount")?)?,
+        );
+        // 可选列 没有时用默认值
+        if let Some(min_liquidity) = row.get(6).filter(|v| !v.is_empty()) {
+            currency.min_liquidity = U256::from_dec_str(min_liquidity)?;
         }
+        currencies.push(currency);
     }
-    pub fn balance_slot(&self) -> i32 {
-        match self {
-            MainCurrency::WETH => WETH_BALANCE_SLOT,
-            MainCurrency::USDT => USDT_BALANCE_SLOT,
-            MainCurrency::USDC => USDC_BALANCE_SLOT,
-            MainCurrency::Default => WETH_BALANCE_SLOT,
-        }
+    Ok(currencies)
+}
+/// 查询主要货币配置 不是主要货币返回 None
+pub fn get_main_currency(address: H160) -> Option<&'static MainCurrency> {
+    main_currencies().get(&address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_main_currencies_are_rejected() {
+        let currencies = default_main_currencies();
+        assert_eq!(index_main_currencies(currencies.clone()).unwrap().len(), 3);
+        let mut duplicated = currencies.clone();
+        duplicated.push(currencies[1].clone());
+        assert!(index_main_currencies(duplicated).is_err());
     }
 }
@@ -1,38 +1,121 @@
-use std::sync::Arc;
-
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use dotenv::dotenv;
-use ethers_providers::{Provider, Ws};
-use log::info;
+use log::{info, warn};
 use sandwinch_rs::{
-    common::{constants::Env, utils::setup_logger},
+    common::{
+        constants::Env,
+        transport::{connect_provider, connect_recording_provider, RpcProvider, Transport},
+        utils::{init_main_currencies, setup_logger},
+    },
     sandwich::{
+        bus::{BusConfig, EventBus},
+        mempool::stream_mempool,
+        recorder::{Recorder, RecorderConfig},
         strategy::run_sandwich_strategy,
-        streams::{stream_new_block, stream_pending_transactions, Event},
+        streams::{
+            connect_with_backoff, stream_new_block, stream_pending_transactions, wait_backoff,
+            PendingTxMode, ReconnectConfig,
+        },
     },
 };
-use tokio::sync::broadcast::Sender;
-use tokio::{sync::broadcast, task::JoinSet};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::task::JoinSet;
+
+/// 策略出错(节点断开)或者 panic 时 重新连接节点并重启策略 事件总线关闭时结束
+/// 第一次运行使用已经连接好的 provider 录制节点请求只覆盖第一次运行
+async fn supervise_strategy(
+    env: Env,
+    bus: EventBus,
+    mut provider: Arc<RpcProvider>,
+    config: ReconnectConfig,
+) {
+    let mut backoff = config.initial_backoff;
+    loop {
+        let started = Instant::now();
+        match tokio::spawn(run_sandwich_strategy(provider, bus.clone())).await {
+            Ok(Ok(_)) => {
+                info!("Strategy stopped");
+                return;
+            }
+            Ok(Err(e)) => warn!("Strategy failed, restarting in {:?}: {:?}", backoff, e),
+            Err(e) => warn!("Strategy panicked, restarting in {:?}: {:?}", backoff, e),
+        }
+        // 运行了一段时间才失败 不是连续失败 退避时间从头开始
+        if started.elapsed() > config.max_backoff {
+            backoff = config.initial_backoff;
+        }
+        wait_backoff(&config, &mut backoff).await;
+        provider = connect_with_backoff(&env.rpc_url, &config, &mut backoff).await;
+    }
+}
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
     setup_logger()?;
-    let env = Env::new();
-    let ws = Ws::connect(env.wss_url.clone()).await?;
-    let ws_provider = Arc::new(Provider::new(ws));
+    // 主要货币配置有错时启动就失败
+    init_main_currencies()?;
+    let env = Env::new()?;
+    // IPC / WS / HTTP 由 RPC_URL 决定 区块和 pending 交易需要订阅 不能用 HTTP
+    let provider = connect_provider(&env.rpc_url).await?;
+    let transport: &Transport = (*provider).as_ref();
+    if !transport.supports_subscriptions() {
+        return Err(anyhow!("RPC_URL must be an IPC path or a WebSocket url"));
+    }
     // 多线程跑 pendingtx & new block
-    let (event_sender, _): (Sender<Event>, _) = broadcast::channel(512);
+    // 新区块走 watch 总是最新 pending 交易走有界队列 满了按配置的策略丢弃
+    let mut bus = EventBus::new(BusConfig {
+        pending_capacity: env.pending_queue_capacity,
+        drop_policy: env.pending_drop_policy.parse()?,
+    });
+    // 可选: 把收到的区块和 pending 交易写到磁盘 用于排查和回放
+    if let Some(dir) = &env.record_dir {
+        bus = bus.with_recorder(Recorder::start(RecorderConfig::new(dir.clone()))?);
+    }
     let mut set = JoinSet::new();
-    // 更新block信息
-    set.spawn(stream_new_block(ws_provider.clone(), event_sender.clone()));
-    // 获取pendingtx
-    set.spawn(stream_pending_transactions(
-        ws_provider.clone(),
-        event_sender.clone(),
+    // 更新block信息 断开 / 30 秒没有新区块时重连 pending 交易 10 秒没有消息时重连
+    let block_reconnect = ReconnectConfig::new(Duration::from_secs(30));
+    let pending_reconnect = ReconnectConfig::new(Duration::from_secs(10));
+    set.spawn(stream_new_block(
+        env.rpc_url.clone(),
+        bus.clone(),
+        block_reconnect,
     ));
-    set.spawn(run_sandwich_strategy(
-        ws_provider.clone(),
-        event_sender.clone(),
+    let pending_mode = if env.full_pending_txs {
+        PendingTxMode::FullBody
+    } else {
+        PendingTxMode::Hash
+    };
+    // 获取pendingtx 配置了额外的 mempool 节点时和主节点一起订阅并去重
+    if env.mempool_wss_urls.is_empty() {
+        set.spawn(stream_pending_transactions(
+            env.rpc_url.clone(),
+            bus.clone(),
+            pending_reconnect,
+            pending_mode,
+        ));
+    } else {
+        let mut urls = vec![env.rpc_url.clone()];
+        urls.extend(env.mempool_wss_urls.clone());
+        set.spawn(stream_mempool(
+            urls,
+            bus.clone(),
+            pending_reconnect,
+            pending_mode,
+        ));
+    }
+    // 录制策略的节点请求 回放时代替节点
+    let strategy_provider = match &env.fixture_record {
+        Some(path) => connect_recording_provider(&env.rpc_url, path).await?,
+        None => provider.clone(),
+    };
+    set.spawn(supervise_strategy(
+        env.clone(),
+        bus.clone(),
+        strategy_provider,
+        ReconnectConfig::new(Duration::from_secs(60)),
     ));
     while let Some(res) = set.join_next().await {
         info!("{:?}", res);
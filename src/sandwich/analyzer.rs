@@ -0,0 +1,293 @@
+////////////////////////////////////////
+////追踪结果分析///
+////////////////////////////////////////
+
+// 追踪完成的调用帧在工作线程里解析: swap 信息 / 新池子的储备量和分层 / 目标代币的税率和风险测量
+// 这几步都要访问节点或者在模拟器里执行 放在策略循环里会卡住区块处理
+// 池子 / 代币 / 分层是工作线程和策略共享的状态 锁只在同步代码里持有 不跨 await
+
+use ethers::types::{CallFrame, H160, H256, U256};
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use tokio::time::timeout;
+
+use crate::common::{
+    balancer::{get_pool_balances, BalancerPool, BALANCER_V2_VERSION},
+    evm::EvmSimulator,
+    liquidity::LiquidityTiers,
+    pools::{Pool, RejectedPairs},
+    reserves::fetch_reserves,
+    risk::{measure_token_risk, RiskPolicy, TokenRisk},
+    store::SharedStore,
+    tokens::{measure_token_tax, Token, TokenTax},
+    transport::RpcProvider,
+    utils::get_main_currency,
+};
+
+use super::{
+    simulation::{swap_info_from_frame, PendingTxInfo, SwapInfo},
+    streams::{NewBlock, NewPendingTx},
+};
+
+/// 策略和追踪工作线程共享的市场状态
+/// 同时持有多个锁时按 tokens -> pools -> tiers 的顺序加锁
+pub struct MarketState {
+    pub pools: RwLock<HashMap<H160, Pool>>,
+    pub tokens: RwLock<HashMap<H160, Token>>,
+    /// Balancer 加权池 启动后不再变化
+    pub balancer_pools: HashMap<H256, BalancerPool>,
+    /// 确认过不是 UniswapV2 池子的地址 不再查询节点
+    pub rejected_pairs: Mutex<RejectedPairs>,
+    pub tiers: RwLock<LiquidityTiers>,
+    /// 允许夹哪些风险的代币
+    pub risk_policy: RiskPolicy,
+    /// 测量税率和风险时模拟器里的账户
+    pub owner: H160,
+    /// 启动时打开的数据库 保存新池子 / 测量结果 / 重组回滚
+    pub store: SharedStore,
+}
+
+/// 一笔追踪完成的交易的分析结果
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    /// touched_pairs 为空说明不是受害者
+    /// added_block 是追踪时的区块 executable 由策略按 nonce 索引设置
+    pub info: PendingTxInfo,
+    /// 目标代币 (已经测量过税率和风险) 用于估算利润
+    pub tokens: HashMap<H160, Token>,
+    /// 新发现的池子的储备量 由策略加入储备量账本
+    pub reserves: HashMap<H160, (U256, U256)>,
+    /// 受害者 swap 的 Balancer 池子和追踪区块时的余额 用于估算利润
+    pub balancer: HashMap<H256, (BalancerPool, Vec<U256>)>,
+}
+
+pub async fn analyze(
+    provider: &Arc<RpcProvider>,
+    market: &MarketState,
+    mut pending_tx: NewPendingTx,
+    block: &NewBlock,
+    frame: &CallFrame,
+    measure_timeout: Duration,
+) -> Analysis {
+    let tx_hash = pending_tx.tx.hash;
+    let swap_info = match swap_info_from_frame(
+        provider,
+        block,
+        tx_hash,
+        frame,
+        &market.pools,
+        &market.tokens,
+        &market.rejected_pairs,
+        &market.store,
+        &market.balancer_pools,
+    )
+    .await
+    {
+        Ok(swap_info) => swap_info,
+        Err(_) => Vec::new(),
+    };
+    // 运行时新发现的池子 读取储备量之后马上分层
+    let new_pairs: Vec<H160> = {
+        let tiers = market.tiers.read().unwrap();
+        swap_info
+            .iter()
+            .filter(|info| info.version == 2)
+            .map(|info| info.target_pair)
+            .filter(|pair| !tiers.contains(pair))
+            .collect()
+    };
+    let reserves = if new_pairs.is_empty() {
+        HashMap::new()
+    } else {
+        fetch_reserves(provider, &new_pairs, block.block_number).await
+    };
+    {
+        let pools = market.pools.read().unwrap();
+        let mut tiers = market.tiers.write().unwrap();
+        for pair in &new_pairs {
+            if let Some(pool) = pools.get(pair) {
+                tiers.insert_pair(pool, reserves.get(pair).cloned());
+            }
+        }
+    }
+    // 流动性不足 / 利润上限不够 gas 的池子 不测量也不模拟
+    // 路由交易追踪前已经检查过 这里处理聚合器等只有追踪之后才知道池子的交易
+    let swap_info: Vec<SwapInfo> = {
+        let tiers = market.tiers.read().unwrap();
+        swap_info
+            .into_iter()
+            .filter(|info| {
+                info.version != 2
+                    || tiers.is_worth_simulating(
+                        &info.target_pair,
+                        info.main_currency,
+                        block.next_base_fee,
+                    )
+            })
+            .collect()
+    };
+    // 第一次遇到的目标代币 先在模拟器里测量转账税和风险属性
+    // 测量失败(例如无法卖出)按 100% 税 / 无法卖出处理 之后不会再被夹
+    // 测量要在 V2 池子里买卖 Balancer 的目标代币用它和主币的 V2 池子测量 没有这样的池子时不测量
+    for info in &swap_info {
+        let pair = if info.version == 2 {
+            info.target_pair
+        } else {
+            match market
+                .tiers
+                .read()
+                .unwrap()
+                .pair_for(info.target_token, info.main_currency)
+            {
+                Some(pair) => pair,
+                None => continue,
+            }
+        };
+        let (need_tax, need_risk) = match market.tokens.read().unwrap().get(&info.target_token) {
+            Some(token) => (token.tax.is_none(), token.risk.is_none()),
+            None => (false, false),
+        };
+        if !need_tax && !need_risk {
+            continue;
+        }
+        let mc = match get_main_currency(info.main_currency) {
+            Some(mc) => mc,
+            None => continue,
+        };
+        // 模拟器按需从节点读取状态 是同步阻塞的 放到阻塞线程池里测量
+        // 超时 / 追踪被取消时不再等待 线程里的测量跑完之后结果丢弃 代币保持未测量 下次遇到时重新测量
+        let provider = provider.clone();
+        let owner = market.owner;
+        let block_number = block.block_number;
+        let (target_token, main_currency) = (info.target_token, info.main_currency);
+        let (balance_slot, probe_amount) = (mc.balance_slot, mc.probe_amount);
+        let measurement = tokio::task::spawn_blocking(move || {
+            // 税率和风险分开模拟 互不影响状态
+            let tax = if need_tax {
+                let mut simulator = EvmSimulator::new(provider.clone(), Some(owner), block_number);
+                let tax = measure_token_tax(
+                    &mut simulator,
+                    target_token,
+                    pair,
+                    main_currency,
+                    balance_slot,
+                    probe_amount,
+                )
+                .unwrap_or_else(|_| TokenTax::untradeable_at(block_number.as_u64()));
+                Some(tax)
+            } else {
+                None
+            };
+            let risk = if need_risk {
+                let mut simulator = EvmSimulator::new(provider, Some(owner), block_number);
+                let risk = measure_token_risk(
+                    &mut simulator,
+                    target_token,
+                    pair,
+                    main_currency,
+                    balance_slot,
+                    probe_amount,
+                )
+                .unwrap_or_else(|_| TokenRisk::unsafe_at(block_number.as_u64()));
+                Some(risk)
+            } else {
+                None
+            };
+            (tax, risk)
+        });
+        let (tax, risk) = match timeout(measure_timeout, measurement).await {
+            Ok(Ok(measured)) => measured,
+            Ok(Err(e)) => {
+                warn!("Measurement of {:?} failed: {:?}", target_token, e);
+                continue;
+            }
+            Err(_) => {
+                info!("Measurement of {:?} timed out", target_token);
+                continue;
+            }
+        };
+        // 多个工作线程可能同时测量同一个代币 结果一样 后写入的覆盖先写入的
+        {
+            let store = market.store.lock().unwrap();
+            if let Some(tax) = &tax {
+                match store.save_token_tax(info.target_token, tax) {
+                    Ok(_) => {}
+                    Err(e) => warn!("Save tax of {:?} failed: {:?}", info.target_token, e),
+                }
+            }
+            if let Some(risk) = &risk {
+                match store.save_token_risk(info.target_token, risk) {
+                    Ok(_) => {}
+                    Err(e) => warn!("Save risk of {:?} failed: {:?}", info.target_token, e),
+                }
+            }
+        }
+        if let Some(token) = market.tokens.write().unwrap().get_mut(&info.target_token) {
+            if tax.is_some() {
+                token.tax = tax;
+            }
+            if risk.is_some() {
+                token.risk = risk;
+            }
+        }
+    }
+    // 模拟之前按风险过滤 没有测量过税率和风险的代币 (例如没有 V2 池子的 Balancer 代币) 会在这里被过滤掉
+    let mut tokens = HashMap::new();
+    let swap_info: Vec<SwapInfo> = {
+        let tokens_map = market.tokens.read().unwrap();
+        swap_info
+            .into_iter()
+            .filter(|info| match tokens_map.get(&info.target_token) {
+                Some(token) => {
+                    let allowed = market
+                        .risk_policy
+                        .allows(token.tax.as_ref(), token.risk.as_ref());
+                    if allowed {
+                        tokens.insert(info.target_token, token.clone());
+                    }
+                    allowed
+                }
+                None => false,
+            })
+            .collect()
+    };
+    // Balancer 池子的余额 读取失败的池子估算利润时跳过
+    let mut balancer = HashMap::new();
+    for info in &swap_info {
+        if info.version != BALANCER_V2_VERSION {
+            continue;
+        }
+        let pool_id = match info.balancer_pool_id {
+            Some(pool_id) => pool_id,
+            None => continue,
+        };
+        if balancer.contains_key(&pool_id) {
+            continue;
+        }
+        let pool = match market.balancer_pools.get(&pool_id) {
+            Some(pool) => pool,
+            None => continue,
+        };
+        match get_pool_balances(provider, pool_id, block.block_number).await {
+            Ok(balances) => {
+                balancer.insert(pool_id, (pool.clone(), balances));
+            }
+            Err(e) => info!("Balancer pool {:?} balances failed: {:?}", pool_id, e),
+        }
+    }
+    pending_tx.added_block = Some(block.block_number);
+    Analysis {
+        info: PendingTxInfo {
+            pending_tx,
+            touched_pairs: swap_info,
+            executable: false,
+        },
+        tokens,
+        reserves,
+        balancer,
+    }
+}
@@ -1,12 +1,11 @@
 use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Result;
-use ethers::types::{H256, U256};
-use ethers_providers::{Provider, Ws};
+use ethers::types::{H160, H256, I256, U256};
 
 use crate::common::{
-    evm::VictimTx,
-    utils::{is_weth, MainCurrency},
+    balancer::BalancerPool, evm::VictimTx, reserves::ReserveBook, tokens::Token,
+    transport::RpcProvider, utils::get_main_currency,
 };
 
 use super::{
@@ -15,17 +14,25 @@ use super::{
 };
 // 三明治套利的"预备"阶段
 pub async fn appetizer(
-    provider: &Arc<Provider<Ws>>,
+    provider: &Arc<RpcProvider>,
     new_block: &NewBlock,
     tx_hash: H256,
     victim_gas_price: U256,
     pending_txs: &HashMap<H256, PendingTxInfo>,
     promising_sandwiches: &mut HashMap<H256, Vec<Sandwich>>,
+    reserve_book: &ReserveBook,
+    balancer: &HashMap<H256, (BalancerPool, Vec<U256>)>,
+    tokens_map: &HashMap<H160, Token>,
+    gas_costs: &HashMap<H160, U256>,
 ) {
     // pending_txs是被模拟执行过之后 取出来的swap信息
     // 从pending_txs中获取这次tx_hash交易的信息，并创建 victim_tx（目标交易）结构
-    // pub struct PendingTxInfo {pub pending_tx: NewPendingTx,pub touched_pairs: Vec<SwapInfo>,}
+    // pub struct PendingTxInfo {pub pending_tx: NewPendingTx,pub touched_pairs: Vec<SwapInfo>,pub executable: bool}
     let pending_tx_info = pending_txs.get(&tx_hash).unwrap();
+    // nonce 有空缺 受害者交易暂时不能打包
+    if !pending_tx_info.executable {
+        return;
+    }
     let pending_tx = &pending_tx_info.pending_tx;
     //make sandwiches and simulate
     let victim_tx = VictimTx {
@@ -51,18 +58,15 @@ pub async fn appetizer(
             _ => {}
         }
         let main_currecy = info.main_currency;
-        let mc = MainCurrency::new(main_currecy);
-        let decimals = mc.decimals();
-        let small_amount_in = if is_weth(main_currecy) {
-            // 0.01ETH
-            U256::from(10).pow(U256::from(decimals - 2))
-        } else {
-            U256::from(10) * U256::from(10).pow(U256::from(decimals)) // 10 USDT, 10 USDC
+        let mc = match get_main_currency(main_currecy) {
+            Some(mc) => mc,
+            None => continue,
         };
+        let small_amount_in = mc.probe_amount;
         let base_fee = new_block.next_base_fee;
         let max_fee = base_fee;
         // 构建一个三明治信息结构
-        let mut sandwich = Sandwich {
+        let sandwich = Sandwich {
             amount_in: small_amount_in,
             swap_info: info.clone(),
             victim_tx: victim_tx.clone(),
@@ -72,7 +76,28 @@ pub async fn appetizer(
         let batch_sandwich = BatchSandwich {
             sandwiches: vec![sandwich.clone()],
         };
-        // 三明治机会模拟执行
-        let simulated_sandwich = batch_sandwich.
+        // 先用本地储备量 / Balancer 余额和测量过的税率估算 扣税之后不赚钱的三明治不再模拟
+        let reserves: HashMap<H160, (U256, U256)> = batch_sandwich
+            .sandwiches
+            .iter()
+            .filter_map(|s| {
+                let pair = s.swap_info.target_pair;
+                reserve_book.get(&pair).map(|r| (pair, r))
+            })
+            .collect();
+        // 收益要超过前后两笔交易的 gas 成本 无法换算 gas 成本的主币不夹
+        let gas_cost = match gas_costs.get(&main_currecy) {
+            Some(gas_cost) => I256::from_raw(*gas_cost),
+            None => continue,
+        };
+        let revenue = batch_sandwich.estimate_revenue(&reserves, balancer, tokens_map);
+        match revenue.get(&main_currecy) {
+            Some(revenue) if *revenue > gas_cost => {}
+            _ => continue,
+        }
+        promising_sandwiches
+            .entry(tx_hash)
+            .or_insert_with(Vec::new)
+            .push(sandwich);
     }
 }
@@ -0,0 +1,445 @@
+////////////////////////////////////////
+////区块 / pending 交易事件总线///
+////////////////////////////////////////
+
+use anyhow::{anyhow, Result};
+use ethers::types::{U256, U64};
+use log::{info, warn};
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{watch, Notify},
+    time::sleep,
+};
+
+use super::{
+    recorder::Recorder,
+    streams::{BlockGap, Event, NewBlock, NewPendingTx},
+};
+
+/// 每隔多少个区块打印一次队列统计
+pub static STATS_BLOCKS: u64 = 100;
+
+/// pending 队列满了之后丢弃哪笔交易
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// 丢弃最早进入队列的交易
+    Oldest,
+    /// 丢弃 gas price 最低的交易 (新交易本身最低时丢弃新交易) 低价交易几乎不会被夹
+    LowestGasPrice,
+}
+impl FromStr for DropPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "oldest" => Ok(DropPolicy::Oldest),
+            "lowest_gas_price" => Ok(DropPolicy::LowestGasPrice),
+            _ => Err(anyhow!("Unknown drop policy {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BusConfig {
+    /// 每个订阅者的 pending 队列最多保存多少笔交易
+    pub pending_capacity: usize,
+    pub drop_policy: DropPolicy,
+}
+impl Default for BusConfig {
+    fn default() -> Self {
+        Self {
+            pending_capacity: 4096,
+            drop_policy: DropPolicy::LowestGasPrice,
+        }
+    }
+}
+
+/// 交易愿意支付的最高 gas price
+fn gas_price(tx: &NewPendingTx) -> U256 {
+    tx.tx
+        .max_fee_per_gas
+        .or(tx.tx.gas_price)
+        .unwrap_or_default()
+}
+
+/// 单个订阅者的有界 pending 队列
+#[derive(Debug)]
+pub struct PendingQueue {
+    config: BusConfig,
+    queue: Mutex<VecDeque<NewPendingTx>>,
+    notify: Notify,
+    /// 进入队列的交易数
+    pub pushed: AtomicU64,
+    /// 队列满了被丢弃的交易数
+    pub dropped: AtomicU64,
+    /// 队列出现过的最大长度
+    pub max_depth: AtomicU64,
+    /// 订阅者处理太慢 直接跳到最新区块时 跳过的区块数
+    pub skipped_blocks: AtomicU64,
+    /// 订阅者正在等待下一个事件 (上一个事件已经处理完)
+    waiting: AtomicBool,
+    /// 订阅者收到的最新区块号
+    seen_head: AtomicU64,
+    /// 订阅者在事件之外还没有处理完的工作 (例如追踪工作池里的交易) 都为 0 才算空闲
+    background: Mutex<Vec<Arc<AtomicUsize>>>,
+}
+impl PendingQueue {
+    fn new(config: BusConfig) -> Self {
+        Self {
+            config,
+            queue: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            pushed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            max_depth: AtomicU64::new(0),
+            skipped_blocks: AtomicU64::new(0),
+            waiting: AtomicBool::new(false),
+            seen_head: AtomicU64::new(0),
+            background: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn push(&self, tx: NewPendingTx) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.config.pending_capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            match self.config.drop_policy {
+                DropPolicy::Oldest => {
+                    queue.pop_front();
+                }
+                DropPolicy::LowestGasPrice => {
+                    let lowest = queue
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, queued)| gas_price(queued))
+                        .map(|(i, queued)| (i, gas_price(queued)));
+                    match lowest {
+                        Some((i, price)) if price < gas_price(&tx) => {
+                            queue.remove(i);
+                        }
+                        // 新交易的 gas price 最低 直接丢弃
+                        _ => return,
+                    }
+                }
+            }
+        }
+        queue.push_back(tx);
+        self.pushed.fetch_add(1, Ordering::Relaxed);
+        self.max_depth.fetch_max(queue.len() as u64, Ordering::Relaxed);
+        drop(queue);
+        self.notify.notify_one();
+    }
+
+    async fn pop(&self) -> NewPendingTx {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(tx) = queue.pop_front() {
+                    // 和出队在同一个锁里 wait_idle 不会看到队列空了但还在等待的中间状态
+                    self.waiting.store(false, Ordering::Release);
+                    return tx;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// 队列为空 订阅者收到了 head 在等待下一个事件 并且没有后台工作
+    fn is_idle(&self, head: u64) -> bool {
+        let queue = self.queue.lock().unwrap();
+        queue.is_empty()
+            && self.waiting.load(Ordering::Acquire)
+            && self.seen_head.load(Ordering::Acquire) >= head
+            && self
+                .background
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|count| count.load(Ordering::Acquire) == 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+}
+
+/// 区块走 watch: 订阅者总是拿到最新的区块 不会因为 pending 交易太多被挤掉
+/// pending 交易走每个订阅者各自的有界队列 满了按 drop_policy 丢弃并计数
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    config: BusConfig,
+    head: Arc<watch::Sender<NewBlock>>,
+    queues: Arc<Mutex<Vec<Arc<PendingQueue>>>>,
+    /// 数据源报告的漏掉的区块数
+    pub source_gaps: Arc<AtomicU64>,
+    /// 可选的记录器 区块和 pending 交易同时写入磁盘
+    recorder: Option<Recorder>,
+}
+impl EventBus {
+    pub fn new(config: BusConfig) -> Self {
+        let (head, _) = watch::channel(NewBlock::default());
+        Self {
+            config,
+            head: Arc::new(head),
+            queues: Arc::new(Mutex::new(Vec::new())),
+            source_gaps: Arc::new(AtomicU64::new(0)),
+            recorder: None,
+        }
+    }
+
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub fn subscribe(&self) -> EventReceiver {
+        let pending = Arc::new(PendingQueue::new(self.config.clone()));
+        self.queues.lock().unwrap().push(pending.clone());
+        EventReceiver {
+            head: self.head.subscribe(),
+            pending,
+            queues: self.queues.clone(),
+            last_block: None,
+            queued_block: None,
+        }
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.queues.lock().unwrap().len()
+    }
+
+    /// 等待所有订阅者处理完已经发布的事件 (队列为空 收到了最新区块 在等待下一个事件 后台工作也处理完了)
+    /// 回放时用来保证每次运行的事件顺序一样 实时数据流不需要
+    pub async fn wait_idle(&self) {
+        loop {
+            let head = self.head.borrow().block_number.as_u64();
+            let idle = self
+                .queues
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|queue| queue.is_idle(head));
+            if idle {
+                return;
+            }
+            sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// source 是送达事件的数据源 只用于记录
+    pub fn publish(&self, source: &str, event: Event) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(source, &event);
+        }
+        match event {
+            Event::Block(block) => {
+                let block_number = block.block_number.as_u64();
+                self.head.send_replace(block);
+                if block_number % STATS_BLOCKS == 0 {
+                    self.log_stats();
+                }
+            }
+            Event::PendingTx(tx) => {
+                for queue in self.queues.lock().unwrap().iter() {
+                    queue.push(tx.clone());
+                }
+            }
+            // 订阅者根据区块号自己发现漏掉的区块 这里只计数
+            Event::Gap(gap) => {
+                let missed = gap.to_block.as_u64() - gap.from_block.as_u64() + 1;
+                self.source_gaps.fetch_add(missed, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn log_stats(&self) {
+        for (i, queue) in self.queues.lock().unwrap().iter().enumerate() {
+            info!(
+                "Event bus subscriber {:?}: queued {:?} / pushed {:?} / dropped {:?} / max depth {:?} / skipped blocks {:?} / source gaps {:?}",
+                i,
+                queue.len(),
+                queue.pushed.load(Ordering::Relaxed),
+                queue.dropped.load(Ordering::Relaxed),
+                queue.max_depth.load(Ordering::Relaxed),
+                queue.skipped_blocks.load(Ordering::Relaxed),
+                self.source_gaps.load(Ordering::Relaxed),
+            );
+        }
+        if let Some(recorder) = &self.recorder {
+            info!(
+                "Recorder dropped {:?} entries",
+                recorder.dropped.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+/// 订阅者一侧 新区块优先于 pending 交易
+pub struct EventReceiver {
+    head: watch::Receiver<NewBlock>,
+    pending: Arc<PendingQueue>,
+    /// 总线的订阅者队列 退订时删除自己的队列
+    queues: Arc<Mutex<Vec<Arc<PendingQueue>>>>,
+    last_block: Option<U64>,
+    /// 先发送 Gap 事件 之后再发送的区块
+    queued_block: Option<NewBlock>,
+}
+impl EventReceiver {
+    /// 订阅者把事件交给后台处理时(例如提交给追踪工作池) 登记未完成的数量
+    /// wait_idle 会一直等到它变成 0
+    pub fn track_background(&self, outstanding: Arc<AtomicUsize>) {
+        self.pending.background.lock().unwrap().push(outstanding);
+    }
+
+    /// 区块号不连续时(数据源漏掉了 或者处理太慢被 watch 合并了)先返回 Gap 再返回区块
+    /// 总线已经关闭时返回错误
+    pub async fn recv(&mut self) -> Result<Event> {
+        // 再次调用 recv 说明上一个事件已经处理完
+        self.pending.waiting.store(true, Ordering::Release);
+        let event = self.next_event().await;
+        self.pending.waiting.store(false, Ordering::Release);
+        if let Ok(Event::Block(block)) = &event {
+            self.pending
+                .seen_head
+                .store(block.block_number.as_u64(), Ordering::Release);
+        }
+        event
+    }
+
+    async fn next_event(&mut self) -> Result<Event> {
+        if let Some(block) = self.queued_block.take() {
+            return Ok(Event::Block(block));
+        }
+        tokio::select! {
+            biased;
+            changed = self.head.changed() => {
+                if changed.is_err() {
+                    return Err(anyhow!("Event bus closed"));
+                }
+                let block = self.head.borrow_and_update().clone();
+                match self.last_block {
+                    Some(last) if block.block_number > last + 1 => {
+                        let gap = BlockGap {
+                            from_block: last + 1,
+                            to_block: block.block_number - 1,
+                        };
+                        let skipped = gap.to_block.as_u64() - gap.from_block.as_u64() + 1;
+                        self.pending.skipped_blocks.fetch_add(skipped, Ordering::Relaxed);
+                        warn!("Event receiver lagged {:?} blocks", skipped);
+                        self.last_block = Some(block.block_number);
+                        self.queued_block = Some(block);
+                        Ok(Event::Gap(gap))
+                    }
+                    _ => {
+                        self.last_block = Some(block.block_number);
+                        Ok(Event::Block(block))
+                    }
+                }
+            }
+            tx = self.pending.pop() => Ok(Event::PendingTx(tx)),
+        }
+    }
+}
+
+/// 订阅者退出(例如策略重启)后 总线不再往它的队列里推送交易 wait_idle 也不再等它
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.queues
+            .lock()
+            .unwrap()
+            .retain(|queue| !Arc::ptr_eq(queue, &self.pending));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Transaction, H256};
+
+    fn pending_tx(gas_price: u64) -> NewPendingTx {
+        NewPendingTx {
+            added_block: None,
+            tx: Transaction {
+                hash: H256::random(),
+                gas_price: Some(U256::from(gas_price)),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn queue(capacity: usize, drop_policy: DropPolicy) -> PendingQueue {
+        PendingQueue::new(BusConfig {
+            pending_capacity: capacity,
+            drop_policy,
+        })
+    }
+
+    fn queued_prices(queue: &PendingQueue) -> Vec<U256> {
+        queue.queue.lock().unwrap().iter().map(gas_price).collect()
+    }
+
+    #[test]
+    fn oldest_policy_drops_front() {
+        let queue = queue(2, DropPolicy::Oldest);
+        queue.push(pending_tx(1));
+        queue.push(pending_tx(2));
+        queue.push(pending_tx(3));
+        assert_eq!(queued_prices(&queue), vec![U256::from(2), U256::from(3)]);
+        assert_eq!(queue.pushed.load(Ordering::Relaxed), 3);
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(queue.max_depth.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn lowest_gas_price_policy_drops_cheapest_queued() {
+        let queue = queue(2, DropPolicy::LowestGasPrice);
+        queue.push(pending_tx(5));
+        queue.push(pending_tx(1));
+        queue.push(pending_tx(3));
+        // 顺序不变 只删除最便宜的
+        assert_eq!(queued_prices(&queue), vec![U256::from(5), U256::from(3)]);
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn lowest_gas_price_policy_drops_cheaper_new_tx() {
+        let queue = queue(2, DropPolicy::LowestGasPrice);
+        queue.push(pending_tx(5));
+        queue.push(pending_tx(3));
+        queue.push(pending_tx(2));
+        // 和队列里最低的一样 也丢弃新交易
+        queue.push(pending_tx(3));
+        assert_eq!(queued_prices(&queue), vec![U256::from(5), U256::from(3)]);
+        assert_eq!(queue.pushed.load(Ordering::Relaxed), 2);
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn eip1559_tx_uses_max_fee() {
+        let queue = queue(1, DropPolicy::LowestGasPrice);
+        queue.push(pending_tx(10));
+        let mut tx = pending_tx(0);
+        tx.tx.gas_price = None;
+        tx.tx.max_fee_per_gas = Some(U256::from(20));
+        queue.push(tx);
+        assert_eq!(queued_prices(&queue), vec![U256::from(20)]);
+    }
+
+    #[test]
+    fn drop_policy_from_str() {
+        assert_eq!(DropPolicy::from_str("oldest").unwrap(), DropPolicy::Oldest);
+        assert_eq!(
+            DropPolicy::from_str("lowest_gas_price").unwrap(),
+            DropPolicy::LowestGasPrice
+        );
+        assert!(DropPolicy::from_str("newest").is_err());
+    }
+}
@@ -0,0 +1,241 @@
+////////////////////////////////////////
+////多节点 mempool 汇总///
+////////////////////////////////////////
+
+use ethers::types::*;
+use log::{info, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc, time::timeout};
+use tokio_stream::StreamExt;
+
+use super::bus::EventBus;
+use super::recorder::source_label;
+use super::streams::{
+    connect_with_backoff, subscribe_pending, wait_backoff, Event, NewPendingTx, PendingTxMode,
+    ReconnectConfig,
+};
+
+/// 记住最近多少个交易哈希用于去重
+pub static SEEN_CAPACITY: usize = 100000;
+/// 多久打印一次各节点的统计
+pub static STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 单个节点的统计
+#[derive(Debug, Clone, Default)]
+pub struct SourceStats {
+    pub url: String,
+    /// 收到的交易数 (含重复)
+    pub received: u64,
+    /// 最先送达的交易数 只有这部分交易是这个节点额外带来的
+    pub first: u64,
+    /// 不是最先送达时 比最先送达的节点晚了多久 (累计 / 最大)
+    pub total_delay: Duration,
+    pub max_delay: Duration,
+}
+impl SourceStats {
+    /// 晚到的交易的平均延迟
+    pub fn avg_delay(&self) -> Duration {
+        let late = self.received - self.first;
+        if late == 0 {
+            return Duration::ZERO;
+        }
+        // 按纳秒计算 晚到的交易超过 u32::MAX 笔时也不会截断
+        Duration::from_nanos((self.total_delay.as_nanos() / late as u128) as u64)
+    }
+}
+
+/// 按交易哈希去重 记录每个交易是哪个节点最先送达的
+#[derive(Debug, Default)]
+pub struct MempoolAggregator {
+    pub sources: Vec<SourceStats>,
+    /// 哈希 -> 最先收到的时间
+    seen: HashMap<H256, Instant>,
+    /// 收到的顺序 超过 SEEN_CAPACITY 时从最早的开始删除
+    order: VecDeque<H256>,
+}
+impl MempoolAggregator {
+    pub fn new(urls: &[String]) -> Self {
+        Self {
+            sources: urls
+                .iter()
+                .map(|url| SourceStats {
+                    url: url.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// 记录 source 在 received_at 收到的交易 第一次见到时返回 true
+    pub fn observe(&mut self, source: usize, hash: H256, received_at: Instant) -> bool {
+        self.sources[source].received += 1;
+        match self.seen.get(&hash) {
+            Some(first_at) => {
+                let delay = received_at.saturating_duration_since(*first_at);
+                let stats = &mut self.sources[source];
+                stats.total_delay += delay;
+                stats.max_delay = stats.max_delay.max(delay);
+                false
+            }
+            None => {
+                self.sources[source].first += 1;
+                self.seen.insert(hash, received_at);
+                self.order.push_back(hash);
+                if self.order.len() > SEEN_CAPACITY {
+                    if let Some(old) = self.order.pop_front() {
+                        self.seen.remove(&old);
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    pub fn log_stats(&self) {
+        let unique = self.sources.iter().map(|s| s.first).sum::<u64>().max(1);
+        for stats in &self.sources {
+            info!(
+                "Mempool source {:?}: received {:?} / first {:?} ({:.1}%) / avg delay {:?} / max delay {:?}",
+                stats.url,
+                stats.received,
+                stats.first,
+                stats.first as f64 * 100.0 / unique as f64,
+                stats.avg_delay(),
+                stats.max_delay
+            );
+        }
+    }
+}
+
+/// 订阅一个节点的 pending 交易 连同节点编号和收到的时间转发给汇总任务
+/// 断开或者卡住时按 config 重连 单个节点的问题不影响其它节点
+/// 每个节点单独决定是否退回哈希订阅
+async fn stream_source(
+    source: usize,
+    url: String,
+    sender: mpsc::UnboundedSender<(usize, Transaction, Instant)>,
+    config: ReconnectConfig,
+    mut mode: PendingTxMode,
+) {
+    let mut backoff = config.initial_backoff;
+    loop {
+        let provider = connect_with_backoff(&url, &config, &mut backoff).await;
+        let mut stream = match subscribe_pending(&provider, &mut mode, config.stall_timeout).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Mempool source {:?} subscribe failed: {:?}", url, e);
+                wait_backoff(&config, &mut backoff).await;
+                continue;
+            }
+        };
+        loop {
+            let tx = match timeout(config.stall_timeout, stream.next()).await {
+                Ok(Some(tx)) => tx,
+                Ok(None) => {
+                    warn!("Mempool source {:?} stream ended, reconnecting", url);
+                    break;
+                }
+                Err(_) => {
+                    warn!("Mempool source {:?} stalled, reconnecting", url);
+                    break;
+                }
+            };
+            backoff = config.initial_backoff;
+            if sender.send((source, tx, Instant::now())).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// 同时订阅多个节点的 pending 交易 按哈希去重后发到同一个 Event::PendingTx 通道
+pub async fn stream_mempool(
+    urls: Vec<String>,
+    bus: EventBus,
+    config: ReconnectConfig,
+    mode: PendingTxMode,
+) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    for (source, url) in urls.iter().enumerate() {
+        tokio::spawn(stream_source(
+            source,
+            url.clone(),
+            sender.clone(),
+            config.clone(),
+            mode,
+        ));
+    }
+    drop(sender);
+    let sources: Vec<String> = urls.iter().map(|url| source_label(url)).collect();
+    let mut aggregator = MempoolAggregator::new(&urls);
+    let mut last_stats = Instant::now();
+    while let Some((source, tx, received_at)) = receiver.recv().await {
+        if aggregator.observe(source, tx.hash, received_at) {
+            bus.publish(&sources[source], Event::PendingTx(NewPendingTx {
+                added_block: None,
+                tx,
+            }));
+        }
+        if last_stats.elapsed() >= STATS_INTERVAL {
+            aggregator.log_stats();
+            last_stats = Instant::now();
+        }
+    }
+    aggregator.log_stats();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_first_delivery_is_forwarded_and_late_ones_add_delay() {
+        let urls = vec!["ws://a".to_string(), "ws://b".to_string()];
+        let mut aggregator = MempoolAggregator::new(&urls);
+        let start = Instant::now();
+        let (tx1, tx2) = (H256::repeat_byte(1), H256::repeat_byte(2));
+
+        assert!(aggregator.observe(0, tx1, start));
+        assert!(!aggregator.observe(1, tx1, start + Duration::from_millis(30)));
+        assert!(aggregator.observe(1, tx2, start + Duration::from_millis(40)));
+        assert!(!aggregator.observe(0, tx2, start + Duration::from_millis(50)));
+        assert!(!aggregator.observe(1, tx1, start + Duration::from_millis(90)));
+
+        let (a, b) = (&aggregator.sources[0], &aggregator.sources[1]);
+        assert_eq!((a.received, a.first), (2, 1));
+        assert_eq!((b.received, b.first), (3, 1));
+        assert_eq!(a.avg_delay(), Duration::from_millis(10));
+        assert_eq!(b.max_delay, Duration::from_millis(90));
+        assert_eq!(b.avg_delay(), Duration::from_millis(60));
+    }
+
+    #[test]
+    fn avg_delay_does_not_truncate_large_late_counts() {
+        let late = u32::MAX as u64 + 2;
+        let stats = SourceStats {
+            received: late,
+            first: 0,
+            total_delay: Duration::from_secs(late),
+            ..Default::default()
+        };
+        assert_eq!(stats.avg_delay(), Duration::from_secs(1));
+        assert_eq!(SourceStats::default().avg_delay(), Duration::ZERO);
+    }
+
+    #[test]
+    fn oldest_hashes_are_forgotten_past_capacity() {
+        let mut aggregator = MempoolAggregator::new(&["ws://a".to_string()]);
+        let now = Instant::now();
+        for i in 0..=SEEN_CAPACITY as u64 {
+            assert!(aggregator.observe(0, H256::from_low_u64_be(i), now));
+        }
+        assert_eq!(aggregator.seen.len(), SEEN_CAPACITY);
+        // 最早的哈希已经被删掉 再收到时当作新交易
+        assert!(aggregator.observe(0, H256::from_low_u64_be(0), now));
+        assert!(!aggregator.observe(0, H256::from_low_u64_be(SEEN_CAPACITY as u64), now));
+    }
+}
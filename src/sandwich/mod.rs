@@ -1,4 +1,12 @@
+pub mod analyzer;
 pub mod appetizer;
+pub mod bus;
+pub mod mempool;
+pub mod nonces;
+pub mod prefilter;
+pub mod recorder;
+pub mod replay;
 pub mod simulation;
 pub mod strategy;
 pub mod streams;
+pub mod tracer;
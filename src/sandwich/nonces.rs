@@ -0,0 +1,370 @@
+////////////////////////////////////////
+////按 (sender, nonce) 索引 pending 交易///
+////////////////////////////////////////
+
+// 同一个账户同一个 nonce 只有一笔交易能上链
+// 用户加速 / 取消交易时会用更高的 gas 重新发送同一个 nonce 的交易 旧交易永远不会上链
+// 这里记录每个 (sender, nonce) 当前有效的交易 新交易按节点的规则(gas 提高至少 10%)替换旧交易
+// 同时记录账户的下一个 nonce 用来判断交易是不是马上可以打包
+
+use ethers::types::{Transaction, H160, H256, U256, U64};
+use log::info;
+use std::collections::{HashMap, HashSet};
+
+/// 替换交易至少要提高多少 gas (和 geth 默认的 txpool.pricebump 一样)
+pub static PRICE_BUMP_PERCENT: u64 = 10;
+
+/// 交易的最高 gas price 和小费 legacy 交易两者相同
+fn fees(tx: &Transaction) -> (U256, U256) {
+    let fee_cap = tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default();
+    let tip = tx.max_priority_fee_per_gas.unwrap_or(fee_cap);
+    (fee_cap, tip)
+}
+
+fn bumped(old: U256) -> U256 {
+    old * (100 + PRICE_BUMP_PERCENT) / 100
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    hash: H256,
+    fee_cap: U256,
+    tip: U256,
+    /// 收到交易时的区块
+    added_block: U64,
+}
+
+/// 新交易和索引里已有交易的关系
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    /// 第一次见到这个 (sender, nonce)
+    New,
+    /// 同一笔交易
+    Known,
+    /// 替换了旧交易 旧交易需要从 pending_txs / promising_sandwiches 里删除
+    Replaces(H256),
+    /// gas 提高不够 节点不会接受 忽略新交易
+    Underpriced(H256),
+    /// nonce 已经被上链的交易用掉了
+    Stale,
+}
+
+#[derive(Debug, Default)]
+pub struct NonceIndex {
+    /// (sender, nonce) -> 当前有效的交易
+    slots: HashMap<(H160, U256), Slot>,
+    /// 哈希 -> (sender, nonce)
+    keys: HashMap<H256, (H160, U256)>,
+    /// 账户的下一个 nonce (追踪时查询 / 上链的交易)
+    next_nonces: HashMap<H160, U256>,
+    pub replaced: u64,
+    pub underpriced: u64,
+}
+impl NonceIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 收到新的 pending 交易时调用 New / Replaces 时交易成为这个 nonce 的有效交易
+    pub fn admit(&mut self, tx: &Transaction, block_number: U64) -> Admission {
+        let key = (tx.from, tx.nonce);
+        match self.next_nonces.get(&tx.from) {
+            Some(next_nonce) if tx.nonce < *next_nonce => return Admission::Stale,
+            _ => {}
+        }
+        let (fee_cap, tip) = fees(tx);
+        let replaced = match self.slots.get(&key) {
+            Some(slot) if slot.hash == tx.hash => return Admission::Known,
+            Some(slot) => {
+                if fee_cap < bumped(slot.fee_cap) || tip < bumped(slot.tip) {
+                    self.underpriced += 1;
+                    return Admission::Underpriced(slot.hash);
+                }
+                self.replaced += 1;
+                self.keys.remove(&slot.hash);
+                Some(slot.hash)
+            }
+            None => None,
+        };
+        self.slots.insert(
+            key,
+            Slot {
+                hash: tx.hash,
+                fee_cap,
+                tip,
+                added_block: block_number,
+            },
+        );
+        self.keys.insert(tx.hash, key);
+        match replaced {
+            Some(old) => Admission::Replaces(old),
+            None => Admission::New,
+        }
+    }
+
+    /// 这个 (sender, nonce) 已经有交易 即使新交易不需要追踪也要检查是不是替换
+    pub fn contains(&self, tx: &Transaction) -> bool {
+        self.slots.contains_key(&(tx.from, tx.nonce))
+    }
+
+    /// 交易还是它的 (sender, nonce) 的有效交易 (没有被替换 / 没有上链)
+    pub fn is_current(&self, tx_hash: &H256) -> bool {
+        self.keys.contains_key(tx_hash)
+    }
+
+    /// 交易不再追踪时调用 (上链 / 过期 / 追踪失败)
+    pub fn remove(&mut self, tx_hash: &H256) {
+        if let Some(key) = self.keys.remove(tx_hash) {
+            self.slots.remove(&key);
+        }
+    }
+
+    /// 记录账户的下一个 nonce 返回 nonce 已经被用掉的交易
+    pub fn set_next_nonce(&mut self, sender: H160, next_nonce: U256) -> Vec<H256> {
+        let known = self.next_nonces.entry(sender).or_default();
+        if next_nonce > *known {
+            *known = next_nonce;
+        }
+        let next_nonce = *known;
+        let stale: Vec<H256> = self
+            .slots
+            .iter()
+            .filter(|((from, nonce), _)| *from == sender && *nonce < next_nonce)
+            .map(|(_, slot)| slot.hash)
+            .collect();
+        for tx_hash in &stale {
+            self.remove(tx_hash);
+        }
+        stale
+    }
+
+    /// 区块里上链的交易 返回因此失效的 pending 交易 (被同一个 nonce 的其它交易抢先上链)
+    pub fn on_landed(&mut self, txs: &[Transaction]) -> Vec<H256> {
+        let mut stale = Vec::new();
+        for tx in txs {
+            self.remove(&tx.hash);
+            stale.extend(self.set_next_nonce(tx.from, tx.nonce + 1));
+        }
+        stale
+    }
+
+    /// nonce 等于账户的下一个 nonce 没有空缺
+    pub fn is_executable(&self, tx: &Transaction) -> bool {
+        self.next_nonces.get(&tx.from) == Some(&tx.nonce)
+    }
+
+    /// 删除 blocks 个区块之前收到的交易 (和 pending_txs 保留的区块数一致)
+    /// 只保留还有 pending 交易的账户的 nonce
+    pub fn prune(&mut self, block_number: U64, blocks: u64) {
+        let expired: Vec<H256> = self
+            .slots
+            .values()
+            .filter(|slot| block_number.saturating_sub(slot.added_block) >= U64::from(blocks))
+            .map(|slot| slot.hash)
+            .collect();
+        for tx_hash in &expired {
+            self.remove(tx_hash);
+        }
+        let senders: HashSet<H160> = self.slots.keys().map(|(sender, _)| *sender).collect();
+        self.next_nonces
+            .retain(|sender, _| senders.contains(sender));
+    }
+
+    pub fn log_stats(&self) {
+        info!(
+            "Nonce index: tracked {:?} / senders {:?} / replaced {:?} / underpriced {:?}",
+            self.slots.len(),
+            self.next_nonces.len(),
+            self.replaced,
+            self.underpriced
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// EIP-1559 交易 fee_cap / tip 单位 gwei
+    fn tx(from: H160, nonce: u64, fee_cap: u64, tip: u64) -> Transaction {
+        Transaction {
+            hash: H256::random(),
+            from,
+            nonce: U256::from(nonce),
+            transaction_type: Some(U64::from(2)),
+            max_fee_per_gas: Some(U256::from(fee_cap) * U256::exp10(9)),
+            max_priority_fee_per_gas: Some(U256::from(tip) * U256::exp10(9)),
+            ..Default::default()
+        }
+    }
+
+    fn legacy_tx(from: H160, nonce: u64, gas_price: u64) -> Transaction {
+        Transaction {
+            hash: H256::random(),
+            from,
+            nonce: U256::from(nonce),
+            transaction_type: Some(U64::zero()),
+            gas_price: Some(U256::from(gas_price) * U256::exp10(9)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn replacement_needs_ten_percent_bump() {
+        let sender = H160::random();
+        let mut index = NonceIndex::new();
+        let old = tx(sender, 5, 100, 10);
+        assert_eq!(index.admit(&old, U64::from(1)), Admission::New);
+        assert_eq!(index.admit(&old, U64::from(1)), Admission::Known);
+
+        // 只提高了 9%
+        let low = tx(sender, 5, 109, 11);
+        assert_eq!(
+            index.admit(&low, U64::from(1)),
+            Admission::Underpriced(old.hash)
+        );
+        assert!(index.is_current(&old.hash));
+        assert!(!index.is_current(&low.hash));
+
+        // 刚好 10%
+        let bumped = tx(sender, 5, 110, 11);
+        assert_eq!(
+            index.admit(&bumped, U64::from(1)),
+            Admission::Replaces(old.hash)
+        );
+        assert!(!index.is_current(&old.hash));
+        assert!(index.is_current(&bumped.hash));
+        assert_eq!(index.replaced, 1);
+        assert_eq!(index.underpriced, 1);
+    }
+
+    #[test]
+    fn replacement_needs_both_fee_cap_and_tip_bumped() {
+        let sender = H160::random();
+        let mut index = NonceIndex::new();
+        let old = tx(sender, 0, 100, 10);
+        index.admit(&old, U64::from(1));
+
+        // 只提高 fee cap
+        let fee_cap_only = tx(sender, 0, 200, 10);
+        assert_eq!(
+            index.admit(&fee_cap_only, U64::from(1)),
+            Admission::Underpriced(old.hash)
+        );
+        // 只提高小费
+        let tip_only = tx(sender, 0, 100, 20);
+        assert_eq!(
+            index.admit(&tip_only, U64::from(1)),
+            Admission::Underpriced(old.hash)
+        );
+        assert!(index.is_current(&old.hash));
+    }
+
+    #[test]
+    fn legacy_gas_price_counts_as_fee_cap_and_tip() {
+        let sender = H160::random();
+        let mut index = NonceIndex::new();
+        let old = legacy_tx(sender, 3, 100);
+        index.admit(&old, U64::from(1));
+
+        // 换成 1559 交易取消 小费也要比旧的 gas price 高 10%
+        let cancel = tx(sender, 3, 200, 50);
+        assert_eq!(
+            index.admit(&cancel, U64::from(1)),
+            Admission::Underpriced(old.hash)
+        );
+        let cancel = legacy_tx(sender, 3, 110);
+        assert_eq!(
+            index.admit(&cancel, U64::from(1)),
+            Admission::Replaces(old.hash)
+        );
+    }
+
+    #[test]
+    fn landed_tx_with_same_nonce_makes_victim_stale() {
+        let sender = H160::random();
+        let mut index = NonceIndex::new();
+        let victim = tx(sender, 7, 100, 10);
+        let later = tx(sender, 8, 100, 10);
+        index.admit(&victim, U64::from(1));
+        index.admit(&later, U64::from(1));
+
+        // 同一个 nonce 的另一笔交易(例如没见过的取消交易)上链
+        let cancel = tx(sender, 7, 300, 30);
+        assert_eq!(index.on_landed(&[cancel]), vec![victim.hash]);
+        assert!(!index.is_current(&victim.hash));
+        assert!(index.is_current(&later.hash));
+        assert!(index.is_executable(&later));
+
+        // 之后再收到这个 nonce 的交易
+        let resend = tx(sender, 7, 400, 40);
+        assert_eq!(index.admit(&resend, U64::from(2)), Admission::Stale);
+        assert!(!index.contains(&resend));
+    }
+
+    #[test]
+    fn landed_victim_is_not_reported_stale() {
+        let sender = H160::random();
+        let mut index = NonceIndex::new();
+        let victim = tx(sender, 0, 100, 10);
+        index.admit(&victim, U64::from(1));
+
+        assert!(index.on_landed(&[victim.clone()]).is_empty());
+        assert!(!index.is_current(&victim.hash));
+    }
+
+    #[test]
+    fn nonce_gap_is_not_executable() {
+        let sender = H160::random();
+        let mut index = NonceIndex::new();
+        let victim = tx(sender, 5, 100, 10);
+        index.admit(&victim, U64::from(1));
+        // 下一个 nonce 还不知道
+        assert!(!index.is_executable(&victim));
+
+        assert!(index.set_next_nonce(sender, U256::from(4)).is_empty());
+        assert!(!index.is_executable(&victim));
+
+        // nonce 4 上链之后空缺补上
+        assert!(index.on_landed(&[tx(sender, 4, 100, 10)]).is_empty());
+        assert!(index.is_executable(&victim));
+    }
+
+    #[test]
+    fn next_nonce_never_goes_back() {
+        let sender = H160::random();
+        let mut index = NonceIndex::new();
+        let victim = tx(sender, 5, 100, 10);
+        index.admit(&victim, U64::from(1));
+
+        // 追踪结果晚到 查询到的 nonce 比已知的小
+        assert_eq!(
+            index.set_next_nonce(sender, U256::from(6)),
+            vec![victim.hash]
+        );
+        assert!(index.set_next_nonce(sender, U256::from(2)).is_empty());
+        assert_eq!(
+            index.admit(&tx(sender, 5, 200, 20), U64::from(2)),
+            Admission::Stale
+        );
+    }
+
+    #[test]
+    fn prune_drops_expired_slots_and_senders() {
+        let sender = H160::random();
+        let mut index = NonceIndex::new();
+        let victim = tx(sender, 1, 100, 10);
+        index.admit(&victim, U64::from(10));
+        index.set_next_nonce(sender, U256::from(1));
+
+        index.prune(U64::from(12), 3);
+        assert!(index.is_current(&victim.hash));
+        assert!(index.is_executable(&victim));
+
+        index.prune(U64::from(13), 3);
+        assert!(!index.is_current(&victim.hash));
+        // 没有 pending 交易的账户不再保留 nonce
+        assert!(!index.is_executable(&victim));
+        assert_eq!(index.admit(&victim, U64::from(13)), Admission::New);
+    }
+}
@@ -0,0 +1,321 @@
+////////////////////////////////////////
+////追踪前的 pending 交易过滤///
+////////////////////////////////////////
+
+use anyhow::{anyhow, Result};
+use ethers::{
+    abi::ParamType,
+    types::{Transaction, H160, U256},
+    utils::hex,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::common::{constants::*, utils::to_h160};
+
+/// 过滤规则 配置文件为 JSON (PREFILTER_FILE) 字段见 prefilter.example.json
+/// 地址 / 数值和 JSON-RPC 一样用 0x 开头的十六进制字符串
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreFilterConfig {
+    /// 只追踪发往这些合约(路由 / 聚合器)的交易 为空时不限制
+    pub allowed_to: Vec<H160>,
+    /// 只追踪这些 4-byte selector 的调用 为空时不限制 格式 "0x38ed1739"
+    pub allowed_selectors: Vec<String>,
+    /// 不追踪这些 selector 的调用 优先于 allowed_selectors
+    pub denied_selectors: Vec<String>,
+    /// 带 ETH 的交易(ETH 换代币) value 至少要这么多
+    pub min_eth_in: U256,
+    /// 不追踪这些地址发出的交易 (其它机器人 / 自己)
+    pub denied_senders: Vec<H160>,
+}
+impl Default for PreFilterConfig {
+    fn default() -> Self {
+        Self {
+            allowed_to: vec![
+                UNISWAP_V2_ROUTER,
+                UNIVERSAL_ROUTER,
+                UNIVERSAL_ROUTER_OLD,
+                SUSHISWAP_ROUTER,
+                ONEINCH_V5_ROUTER,
+                ONEINCH_V6_ROUTER,
+                ZEROX_EXCHANGE_PROXY,
+                PARASWAP_AUGUSTUS_V5,
+                METAMASK_SWAP_ROUTER,
+                BALANCER_VAULT,
+            ]
+            .into_iter()
+            .map(to_h160)
+            .collect(),
+            allowed_selectors: Vec::new(),
+            // ERC20 transfer / approve / transferFrom / ERC721 setApprovalForAll
+            denied_selectors: vec![
+                "0xa9059cbb".to_string(),
+                "0x095ea7b3".to_string(),
+                "0x23b872dd".to_string(),
+                "0xa22cb465".to_string(),
+            ],
+            min_eth_in: U256::from(10).pow(U256::from(16)), // 0.01 ETH
+            denied_senders: Vec::new(),
+        }
+    }
+}
+impl PreFilterConfig {
+    /// 设置了 PREFILTER_FILE 时从 JSON 读取 否则使用内置规则
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("PREFILTER_FILE") {
+            Ok(path) => Ok(serde_json::from_reader(std::fs::File::open(path)?)?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// 交易被哪条规则拒绝
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FilterRule {
+    /// 创建合约 / 没有 calldata (普通转账)
+    NoCall,
+    DeniedSender,
+    ToNotAllowed,
+    DeniedSelector,
+    SelectorNotAllowed,
+    ValueTooSmall,
+}
+
+fn parse_selector(selector: &str) -> Result<[u8; 4]> {
+    let bytes = hex::decode(selector.trim_start_matches("0x"))?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("Selector must be 4 bytes: {:?}", selector))
+}
+
+fn parse_selectors(selectors: &[String]) -> Result<HashSet<[u8; 4]>> {
+    selectors.iter().map(|s| parse_selector(s)).collect()
+}
+
+/// 在 debug_traceCall 之前用交易本身的字段过滤 每条规则拒绝了多少交易都有计数
+#[derive(Debug, Clone)]
+pub struct PreFilter {
+    allowed_to: HashSet<H160>,
+    allowed_selectors: HashSet<[u8; 4]>,
+    denied_selectors: HashSet<[u8; 4]>,
+    min_eth_in: U256,
+    denied_senders: HashSet<H160>,
+    pub passed: u64,
+    pub rejected: HashMap<FilterRule, u64>,
+}
+impl PreFilter {
+    pub fn new(config: PreFilterConfig) -> Result<Self> {
+        Ok(Self {
+            allowed_to: config.allowed_to.into_iter().collect(),
+            allowed_selectors: parse_selectors(&config.allowed_selectors)?,
+            denied_selectors: parse_selectors(&config.denied_selectors)?,
+            min_eth_in: config.min_eth_in,
+            denied_senders: config.denied_senders.into_iter().collect(),
+            passed: 0,
+            rejected: HashMap::new(),
+        })
+    }
+
+    /// 第一条拒绝这笔交易的规则 None 表示通过
+    pub fn rejection(&self, tx: &Transaction) -> Option<FilterRule> {
+        let to = match tx.to {
+            Some(to) if tx.input.len() >= 4 => to,
+            _ => return Some(FilterRule::NoCall),
+        };
+        if self.denied_senders.contains(&tx.from) {
+            return Some(FilterRule::DeniedSender);
+        }
+        if !self.allowed_to.is_empty() && !self.allowed_to.contains(&to) {
+            return Some(FilterRule::ToNotAllowed);
+        }
+        let selector: [u8; 4] = tx.input[0..4].try_into().unwrap();
+        if self.denied_selectors.contains(&selector) {
+            return Some(FilterRule::DeniedSelector);
+        }
+        if !self.allowed_selectors.is_empty() && !self.allowed_selectors.contains(&selector) {
+            return Some(FilterRule::SelectorNotAllowed);
+        }
+        if !tx.value.is_zero() && tx.value < self.min_eth_in {
+            return Some(FilterRule::ValueTooSmall);
+        }
+        None
+    }
+
+    /// 检查并计数 通过时返回 true
+    pub fn allows(&mut self, tx: &Transaction) -> bool {
+        match self.rejection(tx) {
+            Some(rule) => {
+                *self.rejected.entry(rule).or_insert(0) += 1;
+                false
+            }
+            None => {
+                self.passed += 1;
+                true
+            }
+        }
+    }
+
+    pub fn log_stats(&self) {
+        let mut rejected: Vec<_> = self.rejected.iter().collect();
+        rejected.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+        info!(
+            "Pre-filter passed {:?} / rejected {:?}",
+            self.passed, rejected
+        );
+    }
+}
+
+/// UniswapV2Router02 的 swap 方法 selector -> path 之前有几个 uint256 参数
+/// ETH 换代币的方法只有 amountOutMin 其它方法有 amountIn / amountOut 和 amountOutMin / amountInMax
+static V2_ROUTER_SWAPS: [([u8; 4], usize); 9] = [
+    ([0x38, 0xed, 0x17, 0x39], 2), // swapExactTokensForTokens
+    ([0x88, 0x03, 0xdb, 0xee], 2), // swapTokensForExactTokens
+    ([0x7f, 0xf3, 0x6a, 0xb5], 1), // swapExactETHForTokens
+    ([0x4a, 0x25, 0xd9, 0x4a], 2), // swapTokensForExactETH
+    ([0x18, 0xcb, 0xaf, 0xe5], 2), // swapExactTokensForETH
+    ([0xfb, 0x3b, 0xdb, 0x41], 1), // swapETHForExactTokens
+    ([0x5c, 0x11, 0xd7, 0x95], 2), // swapExactTokensForTokensSupportingFeeOnTransferTokens
+    ([0xb6, 0xf9, 0xde, 0x95], 1), // swapExactETHForTokensSupportingFeeOnTransferTokens
+    ([0x79, 0x1a, 0xc9, 0x47], 2), // swapExactTokensForETHSupportingFeeOnTransferTokens
+];
+
+/// 发往 UniswapV2 路由的 swap 交易 不用追踪就能从 calldata 解出经过的代币
+/// 其它合约(聚合器 / Universal Router)的交易返回 None 只能追踪之后才知道池子
+pub fn v2_router_path(tx: &Transaction) -> Option<Vec<H160>> {
+    if tx.to != Some(to_h160(UNISWAP_V2_ROUTER)) || tx.input.len() < 4 {
+        return None;
+    }
+    let selector: [u8; 4] = tx.input[0..4].try_into().ok()?;
+    let (_, uints) = V2_ROUTER_SWAPS.iter().find(|(s, _)| *s == selector)?;
+    let mut params = vec![ParamType::Uint(256); *uints];
+    params.push(ParamType::Array(Box::new(ParamType::Address)));
+    params.push(ParamType::Address);
+    params.push(ParamType::Uint(256));
+    let tokens = ethers::abi::decode(&params, &tx.input[4..]).ok()?;
+    let path = tokens[*uints]
+        .clone()
+        .into_array()?
+        .into_iter()
+        .filter_map(|token| token.into_address())
+        .collect();
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::Bytes;
+
+    /// 发往 UniswapV2 路由的 swapExactETHForTokens
+    fn router_tx() -> Transaction {
+        Transaction {
+            from: H160::random(),
+            to: Some(to_h160(UNISWAP_V2_ROUTER)),
+            input: Bytes::from(vec![0x7f, 0xf3, 0x6a, 0xb5, 0, 0, 0, 0]),
+            value: U256::exp10(18),
+            ..Default::default()
+        }
+    }
+
+    fn prefilter() -> PreFilter {
+        PreFilter::new(PreFilterConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn router_swap_passes() {
+        assert_eq!(prefilter().rejection(&router_tx()), None);
+        // 代币换代币 不带 ETH
+        let mut tx = router_tx();
+        tx.value = U256::zero();
+        assert_eq!(prefilter().rejection(&tx), None);
+    }
+
+    #[test]
+    fn transfers_and_deploys_are_no_call() {
+        let mut transfer = router_tx();
+        transfer.input = Bytes::new();
+        assert_eq!(prefilter().rejection(&transfer), Some(FilterRule::NoCall));
+        let mut short = router_tx();
+        short.input = Bytes::from(vec![0x7f, 0xf3, 0x6a]);
+        assert_eq!(prefilter().rejection(&short), Some(FilterRule::NoCall));
+        let mut deploy = router_tx();
+        deploy.to = None;
+        assert_eq!(prefilter().rejection(&deploy), Some(FilterRule::NoCall));
+    }
+
+    #[test]
+    fn rules_in_order() {
+        let tx = router_tx();
+        let config = PreFilterConfig {
+            denied_senders: vec![tx.from],
+            ..Default::default()
+        };
+        assert_eq!(
+            PreFilter::new(config).unwrap().rejection(&tx),
+            Some(FilterRule::DeniedSender)
+        );
+
+        let mut tx = router_tx();
+        tx.to = Some(H160::random());
+        assert_eq!(prefilter().rejection(&tx), Some(FilterRule::ToNotAllowed));
+
+        // ERC20 approve 发往路由
+        let mut tx = router_tx();
+        tx.input = Bytes::from(vec![0x09, 0x5e, 0xa7, 0xb3]);
+        assert_eq!(prefilter().rejection(&tx), Some(FilterRule::DeniedSelector));
+
+        let mut tx = router_tx();
+        tx.value = U256::exp10(15);
+        assert_eq!(prefilter().rejection(&tx), Some(FilterRule::ValueTooSmall));
+    }
+
+    #[test]
+    fn allowed_selectors() {
+        let config = PreFilterConfig {
+            allowed_selectors: vec!["0x38ed1739".to_string()],
+            ..Default::default()
+        };
+        let filter = PreFilter::new(config).unwrap();
+        assert_eq!(
+            filter.rejection(&router_tx()),
+            Some(FilterRule::SelectorNotAllowed)
+        );
+        let mut tx = router_tx();
+        tx.input = Bytes::from(vec![0x38, 0xed, 0x17, 0x39]);
+        assert_eq!(filter.rejection(&tx), None);
+    }
+
+    #[test]
+    fn empty_allowed_to_allows_any_contract() {
+        let config = PreFilterConfig {
+            allowed_to: Vec::new(),
+            ..Default::default()
+        };
+        let mut tx = router_tx();
+        tx.to = Some(H160::random());
+        assert_eq!(PreFilter::new(config).unwrap().rejection(&tx), None);
+    }
+
+    #[test]
+    fn invalid_selector_config() {
+        let config = PreFilterConfig {
+            denied_selectors: vec!["0x1234".to_string()],
+            ..Default::default()
+        };
+        assert!(PreFilter::new(config).is_err());
+    }
+
+    #[test]
+    fn allows_counts_by_rule() {
+        let mut filter = prefilter();
+        let mut transfer = router_tx();
+        transfer.input = Bytes::new();
+        assert!(filter.allows(&router_tx()));
+        assert!(!filter.allows(&transfer));
+        assert!(!filter.allows(&transfer));
+        assert_eq!(filter.passed, 1);
+        assert_eq!(filter.rejected.get(&FilterRule::NoCall), Some(&2));
+    }
+}
@@ -0,0 +1,194 @@
+////////////////////////////////////////
+////mempool / 区块数据流记录///
+////////////////////////////////////////
+
+// 文件格式 (RECORD_FORMAT / RECORD_VERSION = 1)
+// 目录下按时间轮换的 gzip 文件 stream-<UTC yyyymmdd-HHMMSS>-<序号>.ndjson.gz
+// 正在写入的文件以 .part 结尾 写完(轮换 / 程序退出)后去掉 .part 程序崩溃时 .part 文件可能被截断
+// 解压后每行一个 JSON 对象 type 字段区分类型:
+//   {"type":"header","format":"sandwich-stream","version":1,"created_at":<unix 秒>}  每个文件的第一行
+//   {"type":"block","received_at":<unix 毫秒>,"source":"<节点>","block":{"block_number":..,"base_fee":..,"next_base_fee":..}}
+//   {"type":"pending_tx","received_at":<unix 毫秒>,"source":"<节点>","tx":{<eth_getTransactionByHash 的格式>}}
+// 数字类型和 JSON-RPC 一样是 0x 开头的十六进制字符串
+// source 是送达的节点: WS / HTTP 为主机名 IPC 为 socket 文件名 (不记录完整 url 避免泄露 api key)
+// 字段有不兼容的变化时 RECORD_VERSION 加 1 读取方遇到更高的版本应当拒绝
+
+use anyhow::Result;
+use ethers::types::Transaction;
+use flate2::{write::GzEncoder, Compression};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{create_dir_all, rename, File},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+use super::streams::{Event, NewBlock};
+
+pub static RECORD_FORMAT: &str = "sandwich-stream";
+pub static RECORD_VERSION: u32 = 1;
+
+/// 文件里的一行
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamRecord {
+    Header {
+        format: String,
+        version: u32,
+        created_at: i64,
+    },
+    Block {
+        /// 收到的时间 unix 毫秒
+        received_at: i64,
+        source: String,
+        block: NewBlock,
+    },
+    PendingTx {
+        received_at: i64,
+        source: String,
+        tx: Transaction,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct RecorderConfig {
+    pub dir: String,
+    /// 一个文件最多写多少条记录
+    pub rotate_entries: u64,
+    /// 一个文件最多写多长时间
+    pub rotate_interval: Duration,
+    /// 等待写入的记录最多缓存多少条 写入跟不上时丢弃新记录 不会阻塞数据流
+    pub channel_capacity: usize,
+}
+impl RecorderConfig {
+    pub fn new(dir: String) -> Self {
+        Self {
+            dir,
+            rotate_entries: 1000000,
+            rotate_interval: Duration::from_secs(3600),
+            channel_capacity: 65536,
+        }
+    }
+}
+
+/// 记录数据源的名字 不包含 url 的路径和参数
+pub fn source_label(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(parsed) if parsed.host_str().is_some() => parsed.host_str().unwrap().to_string(),
+        _ => Path::new(url.trim_start_matches("ipc://"))
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| url.to_string()),
+    }
+}
+
+/// 记录器的发送端 record 从不等待 写文件在单独的阻塞线程里
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    sender: mpsc::Sender<StreamRecord>,
+    /// 写入跟不上被丢弃的记录数
+    pub dropped: Arc<AtomicU64>,
+}
+impl Recorder {
+    pub fn start(config: RecorderConfig) -> Result<Self> {
+        create_dir_all(&config.dir)?;
+        let (sender, receiver) = mpsc::channel(config.channel_capacity);
+        tokio::task::spawn_blocking(move || match write_loop(&config, receiver) {
+            Ok(_) => {}
+            Err(e) => warn!("Recorder stopped: {:?}", e),
+        });
+        Ok(Self {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn record(&self, source: &str, event: &Event) {
+        let received_at = chrono::Utc::now().timestamp_millis();
+        let record = match event {
+            Event::Block(block) => StreamRecord::Block {
+                received_at,
+                source: source.to_string(),
+                block: block.clone(),
+            },
+            Event::PendingTx(pending_tx) => StreamRecord::PendingTx {
+                received_at,
+                source: source.to_string(),
+                tx: pending_tx.tx.clone(),
+            },
+            Event::Gap(_) => return,
+        };
+        if self.sender.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+struct RecordFile {
+    writer: GzEncoder<BufWriter<File>>,
+    path: String,
+    entries: u64,
+    opened_at: Instant,
+}
+
+fn open_file(dir: &str, seq: u64) -> Result<RecordFile> {
+    let path = format!(
+        "{}/stream-{}-{:04}.ndjson.gz",
+        dir,
+        chrono::Utc::now().format("%Y%m%d-%H%M%S"),
+        seq
+    );
+    let file = File::create(format!("{}.part", path))?;
+    let mut file = RecordFile {
+        writer: GzEncoder::new(BufWriter::new(file), Compression::fast()),
+        path,
+        entries: 0,
+        opened_at: Instant::now(),
+    };
+    write_record(
+        &mut file,
+        &StreamRecord::Header {
+            format: RECORD_FORMAT.to_string(),
+            version: RECORD_VERSION,
+            created_at: chrono::Utc::now().timestamp(),
+        },
+    )?;
+    Ok(file)
+}
+
+fn write_record(file: &mut RecordFile, record: &StreamRecord) -> Result<()> {
+    serde_json::to_writer(&mut file.writer, record)?;
+    file.writer.write_all(b"\n")?;
+    Ok(())
+}
+
+fn close_file(file: RecordFile) -> Result<()> {
+    file.writer.finish()?.flush()?;
+    rename(format!("{}.part", file.path), &file.path)?;
+    info!("Recorded {:?} entries to {:?}", file.entries, file.path);
+    Ok(())
+}
+
+fn write_loop(config: &RecorderConfig, mut receiver: mpsc::Receiver<StreamRecord>) -> Result<()> {
+    let mut seq = 0;
+    let mut file = open_file(&config.dir, seq)?;
+    while let Some(record) = receiver.blocking_recv() {
+        write_record(&mut file, &record)?;
+        file.entries += 1;
+        if file.entries >= config.rotate_entries
+            || file.opened_at.elapsed() >= config.rotate_interval
+        {
+            close_file(file)?;
+            seq += 1;
+            file = open_file(&config.dir, seq)?;
+        }
+    }
+    close_file(file)
+}
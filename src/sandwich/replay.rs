@@ -0,0 +1,198 @@
+////////////////////////////////////////
+////回放记录的数据流///
+////////////////////////////////////////
+
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use std::{
+    fs::{read_dir, File},
+    io::{BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+use tokio::time::sleep;
+
+use super::{
+    bus::EventBus,
+    recorder::{StreamRecord, RECORD_FORMAT, RECORD_VERSION},
+    streams::{Event, NewPendingTx},
+};
+
+/// 回放的速度
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayTiming {
+    /// 按记录时的间隔
+    Original,
+    /// 间隔除以倍数
+    Accelerated(f64),
+    /// 不等待 尽快发送
+    Asap,
+}
+impl FromStr for ReplayTiming {
+    type Err = anyhow::Error;
+
+    /// original / asap / 数字(加速倍数)
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "original" => Ok(ReplayTiming::Original),
+            "asap" => Ok(ReplayTiming::Asap),
+            _ => match s.trim_end_matches('x').parse::<f64>() {
+                Ok(factor) if factor > 0.0 => Ok(ReplayTiming::Accelerated(factor)),
+                _ => Err(anyhow!("Unknown replay timing {:?}", s)),
+            },
+        }
+    }
+}
+impl ReplayTiming {
+    fn delay(&self, elapsed_ms: i64) -> Option<Duration> {
+        let elapsed = Duration::from_millis(elapsed_ms.max(0) as u64);
+        match self {
+            ReplayTiming::Original => Some(elapsed),
+            ReplayTiming::Accelerated(factor) => Some(elapsed.div_f64(*factor)),
+            ReplayTiming::Asap => None,
+        }
+    }
+}
+
+/// path 是单个记录文件 或者记录目录 (按文件名排序 也就是按时间排序)
+pub fn recording_files(path: &str) -> Result<Vec<String>> {
+    if Path::new(path).is_file() {
+        return Ok(vec![path.to_string()]);
+    }
+    let mut files: Vec<String> = read_dir(path)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().to_string_lossy().to_string())
+        .filter(|file| file.ends_with(".ndjson.gz") || file.ends_with(".ndjson.gz.part"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// 读取一个记录文件 没写完的 .part 文件读到截断的位置为止
+pub fn read_recording(path: &str) -> Result<Vec<StreamRecord>> {
+    let reader = BufReader::new(GzDecoder::new(File::open(path)?));
+    let mut records = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("{:?} truncated at line {:?}: {:?}", path, i + 1, e);
+                break;
+            }
+        };
+        let record: StreamRecord = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("{:?} line {:?}: {:?}", path, i + 1, e);
+                continue;
+            }
+        };
+        match &record {
+            StreamRecord::Header {
+                format, version, ..
+            } => {
+                if format != RECORD_FORMAT || *version > RECORD_VERSION {
+                    return Err(anyhow!(
+                        "Unsupported recording {:?} v{:?} in {:?}",
+                        format,
+                        version,
+                        path
+                    ));
+                }
+            }
+            _ => records.push(record),
+        }
+    }
+    Ok(records)
+}
+
+/// 把记录的区块 / pending 交易按 timing 发布到事件总线 返回发布的事件数
+pub async fn replay_recordings(path: String, bus: EventBus, timing: ReplayTiming) -> Result<usize> {
+    let mut published = 0;
+    let mut last_received_at: Option<i64> = None;
+    for file in recording_files(&path)? {
+        info!("Replaying {:?}", file);
+        for record in read_recording(&file)? {
+            let (received_at, source, event) = match record {
+                StreamRecord::Block {
+                    received_at,
+                    source,
+                    block,
+                } => (received_at, source, Event::Block(block)),
+                StreamRecord::PendingTx {
+                    received_at,
+                    source,
+                    tx,
+                } => (
+                    received_at,
+                    source,
+                    Event::PendingTx(NewPendingTx {
+                        added_block: None,
+                        tx,
+                    }),
+                ),
+                StreamRecord::Header { .. } => continue,
+            };
+            if let Some(last) = last_received_at {
+                if let Some(delay) = timing.delay(received_at - last) {
+                    sleep(delay).await;
+                }
+            }
+            last_received_at = Some(received_at);
+            bus.publish(&source, event);
+            // 尽快发送时等订阅者处理完再发下一个 保证每次运行结果一样
+            if timing == ReplayTiming::Asap {
+                bus.wait_idle().await;
+            }
+            published += 1;
+        }
+    }
+    info!("Replayed {:?} events", published);
+    Ok(published)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timing_from_str() {
+        assert_eq!(
+            ReplayTiming::from_str("original").unwrap(),
+            ReplayTiming::Original
+        );
+        assert_eq!(ReplayTiming::from_str("asap").unwrap(), ReplayTiming::Asap);
+        assert_eq!(
+            ReplayTiming::from_str("2").unwrap(),
+            ReplayTiming::Accelerated(2.0)
+        );
+        assert_eq!(
+            ReplayTiming::from_str("0.5x").unwrap(),
+            ReplayTiming::Accelerated(0.5)
+        );
+    }
+
+    #[test]
+    fn timing_from_str_rejects_invalid() {
+        for s in ["", "fast", "0", "-2", "x", "Original"] {
+            assert!(ReplayTiming::from_str(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn timing_delay() {
+        assert_eq!(
+            ReplayTiming::Original.delay(1500),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            ReplayTiming::Accelerated(4.0).delay(1000),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(ReplayTiming::Asap.delay(1000), None);
+        // 时钟回拨时不等待
+        assert_eq!(ReplayTiming::Original.delay(-10), Some(Duration::ZERO));
+    }
+}
@@ -1,17 +1,31 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use ethers::{
     abi::{Bytes, ParamType},
     types::{
         transaction::eip2930::AccessList, CallConfig, CallFrame, CallLogFrame,
         GethDebugBuiltInTracerConfig, GethDebugBuiltInTracerType, GethDebugTracerConfig,
         GethDebugTracerType, GethDebugTracingCallOptions, GethTrace, GethTraceFrame, H160, H256,
-        U256, U64,
+        I256, U256, U64,
     },
 };
-use ethers_providers::{Middleware, Provider, Ws};
-use std::{collections::HashMap, str::FromStr, sync::Arc};
+use ethers_providers::Middleware;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
 
-use crate::common::{evm::VictimTx, pools::Pool, utils::return_main_and_target_currency};
+use crate::common::{
+    balancer::{get_amount_out, BalancerPool, BALANCER_SWAP_EVENT_ID, BALANCER_V2_VERSION},
+    constants::BALANCER_VAULT,
+    evm::{EvmSimulator, Tx, VictimTx},
+    math::{apply_tax, get_v2_amount_out, get_v2_amount_out_with_tax},
+    pools::{resolve_unknown_pair, Pool, RejectedPairs},
+    store::Store,
+    tokens::{Token, TokenTax},
+    transport::RpcProvider,
+    utils::{get_main_currency, return_main_and_target_currency, to_h160},
+};
 
 use super::streams::{NewBlock, NewPendingTx};
 /// DEX 交易信息
@@ -25,12 +39,18 @@ pub struct SwapInfo {
     pub main_currency: H160,
     /// 目标代币的地址
     pub target_token: H160,
-    /// DEX 版本(如 UniswapV2=2, V3=3)
+    /// DEX 版本(如 UniswapV2=2, V3=3, BalancerV2=20)
     pub version: u8,
     /// token0 是否是主要货币
     pub token0_is_main: bool,
     /// 交易方向(买入/卖出)
     pub direction: SwapDirection,
+    /// Balancer 池子的 poolId,只有 Balancer swap 才有
+    pub balancer_pool_id: Option<H256>,
+    /// 受害者实际转入池子的数量(来自 Swap 事件)
+    pub amount_in: U256,
+    /// 受害者从池子转出的数量(来自 Swap 事件)
+    pub amount_out: U256,
 }
 /// 交易方向枚举
 #[derive(Debug, Clone)]
@@ -45,6 +65,9 @@ pub struct PendingTxInfo {
     pub pending_tx: NewPendingTx,
     /// 该交易涉及的所有交易对信息
     pub touched_pairs: Vec<SwapInfo>,
+    /// nonce 等于账户的下一个 nonce 可以直接打包
+    /// 同一账户更早的交易还没上链(nonce 有空缺)时为 false 不能夹
+    pub executable: bool,
 }
 /// 三明治交易机会的信息
 #[derive(Debug, Clone)]
@@ -81,9 +104,26 @@ pub struct OptimizedSandwich {
 pub static V2_SWAP_EVENT_ID: &str = "0xd78ad95f";
 /// 用于追踪和分析以太坊交易执行过程
 pub async fn debug_trace_call(
-    provider: &Arc<Provider<Ws>>,
+    provider: &Arc<RpcProvider>,
     new_block: &NewBlock,
     pending_tx: &NewPendingTx,
+) -> Result<Option<CallFrame>> {
+    // 获取 nonce 是为了确保在模拟交易时使用正确的 nonce
+    // 确保模拟交易时的状态是准确的
+    // 避免因 nonce 不匹配导致的模拟失败
+    // 让追踪结果更接近实际执行情况
+    let nonce = provider
+        .get_transaction_count(pending_tx.tx.from, Some(new_block.block_number.into()))
+        .await
+        .unwrap_or_default();
+    debug_trace_call_with_nonce(provider, new_block, pending_tx, nonce).await
+}
+/// 用账户在 new_block 的 nonce 追踪 调用方已经查询过 nonce 时使用
+pub async fn debug_trace_call_with_nonce(
+    provider: &Arc<RpcProvider>,
+    new_block: &NewBlock,
+    pending_tx: &NewPendingTx,
+    nonce: U256,
 ) -> Result<Option<CallFrame>> {
     //////////////////////////////////////////////////////////////
     // 这些配置是告诉 provider(远程节点)你要如何追踪交易//
@@ -116,14 +156,6 @@ pub async fn debug_trace_call(
     ));
     let block_number = new_block.block_number;
     let mut tx = pending_tx.tx.clone();
-    // 获取 nonce 是为了确保在模拟交易时使用正确的 nonce
-    // 确保模拟交易时的状态是准确的
-    // 避免因 nonce 不匹配导致的模拟失败
-    // 让追踪结果更接近实际执行情况
-    let nonce = provider
-        .get_transaction_count(tx.from, Some(block_number.into()))
-        .await
-        .unwrap_or_default();
     tx.nonce = nonce;
     // 调用节点的 debug_trace_call 接口模拟执行交易
     // &tx: 要模拟的交易
@@ -150,22 +182,52 @@ pub async fn debug_trace_call(
 /// 提取相关的交易对信息
 /// 确定交易的方向(买入/卖出)
 pub async fn extract_swap_info(
-    provider: &Arc<Provider<Ws>>,
+    provider: &Arc<RpcProvider>,
     new_block: &NewBlock,
     pending_tx: &NewPendingTx,
-    pools_map: &HashMap<H160, Pool>,
+    pools_map: &RwLock<HashMap<H160, Pool>>,
+    tokens_map: &RwLock<HashMap<H160, Token>>,
+    rejected_pairs: &Mutex<RejectedPairs>,
+    store: &Mutex<Store>,
+    balancer_pools_map: &HashMap<H256, BalancerPool>,
 ) -> Result<Vec<SwapInfo>> {
-    let tx_hash = pending_tx.tx.hash;
-    let mut swap_info_vec = Vec::new();
     // 把 pending_tx 在当前区块状态下模拟执行 获取执行结果
     let frame = debug_trace_call(provider, new_block, pending_tx).await?;
     // 没有获取到交易信息 直接返回
-    if frame.is_none() {
-        return Ok(swap_info_vec);
+    match frame {
+        Some(frame) => {
+            swap_info_from_frame(
+                provider,
+                new_block,
+                pending_tx.tx.hash,
+                &frame,
+                pools_map,
+                tokens_map,
+                rejected_pairs,
+                store,
+                balancer_pools_map,
+            )
+            .await
+        }
+        None => Ok(Vec::new()),
     }
-    let frame: CallFrame = frame.unwrap();
+}
+/// 从 debug_traceCall 的调用帧里解析 swap 信息
+/// 在追踪工作池里调用 这里只在遇到未知的池子时才会访问节点
+pub async fn swap_info_from_frame(
+    provider: &Arc<RpcProvider>,
+    new_block: &NewBlock,
+    tx_hash: H256,
+    frame: &CallFrame,
+    pools_map: &RwLock<HashMap<H160, Pool>>,
+    tokens_map: &RwLock<HashMap<H160, Token>>,
+    rejected_pairs: &Mutex<RejectedPairs>,
+    store: &Mutex<Store>,
+    balancer_pools_map: &HashMap<H256, BalancerPool>,
+) -> Result<Vec<SwapInfo>> {
+    let mut swap_info_vec = Vec::new();
     let mut logs = Vec::new();
-    extract_logs(&frame, &mut logs);
+    extract_logs(frame, &mut logs);
     // 识别 Uniswap V2 的 swap 事件
     // 提取相关的交易信息
     // 确定交易方向
@@ -181,11 +243,25 @@ pub async fn extract_swap_info(
                     if is_v2_swap {
                         let pair_address = log.address.unwrap();
                         // 检查交易对地址是否在我们跟踪的池子列表中
-                        let pool = pools_map.get(&pair_address);
-                        if pool.is_none() {
-                            continue;
-                        }
-                        let pool = pool.unwrap();
+                        // 不在的话现场查询 新池子在同一个区块内就能被夹
+                        let known = pools_map.read().unwrap().get(&pair_address).cloned();
+                        let pool = match known {
+                            Some(pool) => pool,
+                            None => match resolve_unknown_pair(
+                                provider,
+                                new_block.block_number,
+                                pair_address,
+                                pools_map,
+                                tokens_map,
+                                rejected_pairs,
+                                store,
+                            )
+                            .await
+                            {
+                                Ok(Some(pool)) => pool,
+                                _ => continue,
+                            },
+                        };
                         let token0 = pool.token0;
                         let token1 = pool.token1;
                         // 获取主要货币 与 目标货币  主要货币是否为token0
@@ -194,7 +270,7 @@ pub async fn extract_swap_info(
                                 Some(out) => (out.0, out.1, out.0 == token0),
                                 None => continue,
                             };
-                        let (in0, _, _, out1) = match ethers::abi::decode(
+                        let (in0, in1, out0, out1) = match ethers::abi::decode(
                             &[
                                 ParamType::Uint(256),
                                 ParamType::Uint(256),
@@ -236,6 +312,11 @@ pub async fn extract_swap_info(
                                 SwapDirection::Buy
                             }
                         };
+                        let (amount_in, amount_out) = if zero_for_one {
+                            (in0, out1)
+                        } else {
+                            (in1, out0)
+                        };
                         let swap_info = SwapInfo {
                             tx_hash,
                             target_pair: pair_address,
@@ -244,8 +325,26 @@ pub async fn extract_swap_info(
                             version: 2,
                             token0_is_main,
                             direction,
+                            balancer_pool_id: None,
+                            amount_in,
+                            amount_out,
                         };
                         swap_info_vec.push(swap_info);
+                    } else if selector == BALANCER_SWAP_EVENT_ID {
+                        // Balancer 的 swap 事件都由 Vault 发出 池子地址查不到 只能通过 poolId 识别
+                        if log.address.unwrap_or_default() != to_h160(BALANCER_VAULT)
+                            || topics.len() < 4
+                        {
+                            continue;
+                        }
+                        if let Some(swap_info) = decode_balancer_swap(
+                            tx_hash,
+                            topics,
+                            log.data.as_ref().unwrap(),
+                            balancer_pools_map,
+                        ) {
+                            swap_info_vec.push(swap_info);
+                        }
                     }
                 }
             }
@@ -254,6 +353,40 @@ pub async fn extract_swap_info(
     }
     Ok(swap_info_vec)
 }
+/// 解析 Vault 的 Swap 事件
+/// Swap(bytes32 indexed poolId, address indexed tokenIn, address indexed tokenOut, uint256 amountIn, uint256 amountOut)
+pub fn decode_balancer_swap(
+    tx_hash: H256,
+    topics: &Vec<H256>,
+    data: &ethers::types::Bytes,
+    balancer_pools_map: &HashMap<H256, BalancerPool>,
+) -> Option<SwapInfo> {
+    let pool_id = topics[1];
+    let token_in = H160::from(topics[2]);
+    let token_out = H160::from(topics[3]);
+    // 只处理我们索引过的加权池
+    let pool = balancer_pools_map.get(&pool_id)?;
+    let (main_currency, target_token) = return_main_and_target_currency(token_in, token_out)?;
+    let amounts = ethers::abi::decode(&[ParamType::Uint(256), ParamType::Uint(256)], data).ok()?;
+    // 用主要货币买入目标代币就是 Buy
+    let direction = if token_in == main_currency {
+        SwapDirection::Buy
+    } else {
+        SwapDirection::Sell
+    };
+    Some(SwapInfo {
+        tx_hash,
+        target_pair: pool.address,
+        main_currency,
+        target_token,
+        version: BALANCER_V2_VERSION,
+        token0_is_main: pool.tokens.first() == Some(&main_currency),
+        direction,
+        balancer_pool_id: Some(pool_id),
+        amount_in: amounts[0].to_owned().into_uint()?,
+        amount_out: amounts[1].to_owned().into_uint()?,
+    })
+}
 pub fn extract_logs(call_frame: &CallFrame, logs: &mut Vec<CallLogFrame>) {
     // 如果调用帧中有日志
     if let Some(ref logs_vec) = call_frame.logs {
@@ -271,6 +404,103 @@ pub fn extract_logs(call_frame: &CallFrame, logs: &mut Vec<CallLogFrame>) {
         }
     }
 }
+/// 在 Balancer 池子里用 token_in 换 token_out 并更新余额
+/// 没有余额 / 超过输入上限时返回 None 手续费留在池子里 所以 balanceIn 增加完整的 amount_in
+fn balancer_swap(
+    balancer: &mut HashMap<H256, (BalancerPool, Vec<U256>)>,
+    info: &SwapInfo,
+    token_in: H160,
+    token_out: H160,
+    amount_in: U256,
+) -> Option<U256> {
+    let (pool, balances) = balancer.get_mut(&info.balancer_pool_id?)?;
+    let amount_out = get_amount_out(pool, balances, token_in, token_out, amount_in).ok()?;
+    let i = pool.token_index(token_in)?;
+    let o = pool.token_index(token_out)?;
+    balances[o] = balances[o].checked_sub(amount_out)?;
+    balances[i] += amount_in;
+    Some(amount_out)
+}
+/// 模拟执行整个 batch 的结果
+#[derive(Debug, Default, Clone)]
+pub struct SimulatedBundle {
+    /// 按主要货币统计的收益 (backrun 收到的主币 - frontrun 投入的主币) 没有扣 gas
+    pub revenue: HashMap<H160, I256>,
+    /// 所有 frontrun 用掉的 gas
+    pub front_gas_used: u64,
+    /// 所有 backrun 用掉的 gas
+    pub back_gas_used: u64,
+    /// 前后交易按 base_fee 计算的 gas 成本 单位是 wei
+    pub gas_cost: U256,
+}
+/// 在模拟器里用 owner 的 token_in 在 V2 池子里换 token_out
+/// 和 UniswapV2 一样按池子余额减去储备量计算实际转入的数量 转账税自然被扣掉
+/// 返回 owner 实际收到的 token_out 和两笔交易用掉的 gas
+fn v2_swap<M: Middleware + 'static>(
+    simulator: &mut EvmSimulator<M>,
+    pair: H160,
+    token_in: H160,
+    token_out: H160,
+    amount_in: U256,
+    gas_price: U256,
+) -> Result<(U256, u64)> {
+    let owner = simulator.owner;
+    let token = simulator.abi.token.clone();
+    let pair_abi = simulator.abi.pair.clone();
+    let transfer = simulator.call(Tx {
+        caller: owner,
+        transact_to: token_in,
+        data: token.encode("transfer", (pair, amount_in))?.0.into(),
+        value: U256::zero(),
+        gas_price,
+        gas_limit: 5000000,
+    })?;
+    let (reserve0, reserve1) = simulator.get_pair_reserves(pair)?;
+    let token0_is_in = token_in < token_out;
+    let (reserve_in, reserve_out) = if token0_is_in {
+        (reserve0, reserve1)
+    } else {
+        (reserve1, reserve0)
+    };
+    let pair_received = simulator
+        .get_token_balance(token_in, pair)?
+        .checked_sub(reserve_in)
+        .ok_or_else(|| anyhow!("Pair balance below reserve"))?;
+    let amount_out = get_v2_amount_out(pair_received, reserve_in, reserve_out);
+    if amount_out.is_zero() {
+        return Err(anyhow!("Swap output is zero"));
+    }
+    let (amount0_out, amount1_out) = if token0_is_in {
+        (U256::zero(), amount_out)
+    } else {
+        (amount_out, U256::zero())
+    };
+    let before = simulator.get_token_balance(token_out, owner)?;
+    let swap = simulator.call(Tx {
+        caller: owner,
+        transact_to: pair,
+        data: pair_abi
+            .encode(
+                "swap",
+                (
+                    amount0_out,
+                    amount1_out,
+                    owner,
+                    ethers::types::Bytes::default(),
+                ),
+            )?
+            .0
+            .into(),
+        value: U256::zero(),
+        gas_price,
+        gas_limit: 5000000,
+    })?;
+    let received = simulator
+        .get_token_balance(token_out, owner)?
+        .checked_sub(before)
+        .ok_or_else(|| anyhow!("Balance decreased after swap"))?;
+    Ok((received, transfer.gas_used + swap.gas_used))
+}
 ///所有三明治机会Vec
 #[derive(Debug, Default, Clone)]
 pub struct BatchSandwich {
@@ -295,6 +525,138 @@ impl BatchSandwich {
         tx_hashes.dedup();
         tx_hashes.join("-")
     }
+    /// 用本地 V2 / Balancer 加权池公式估算整个 batch 的收益 按主要货币分别统计
+    /// 顺序与打包顺序一致: 所有 frontrun -> 所有 victim -> 所有 backrun
+    /// 会扣除代币的买入/卖出税 rebase 代币和未测量税率的代币直接跳过
+    /// Balancer 的 Vault 按 amountIn 记账 不支持转账扣税的代币 有税的代币也跳过
+    /// reserves: pair -> (reserve0, reserve1)
+    /// balancer: poolId -> (池子, 余额)
+    pub fn estimate_revenue(
+        &self,
+        reserves: &HashMap<H160, (U256, U256)>,
+        balancer: &HashMap<H256, (BalancerPool, Vec<U256>)>,
+        tokens_map: &HashMap<H160, Token>,
+    ) -> HashMap<H160, I256> {
+        let mut reserves = reserves.clone();
+        let mut balancer = balancer.clone();
+        let mut revenue: HashMap<H160, I256> = HashMap::new();
+        // 每个三明治 frontrun 实际收到的目标代币数量
+        let mut bought = vec![U256::zero(); self.sandwiches.len()];
+        let tax_of = |token: H160| -> Option<TokenTax> {
+            let tax = tokens_map.get(&token)?.tax?;
+            if tax.rebasing {
+                None
+            } else {
+                Some(tax)
+            }
+        };
+        // (主要货币储备, 目标代币储备)
+        let get_reserves = |reserves: &HashMap<H160, (U256, U256)>, info: &SwapInfo| {
+            reserves.get(&info.target_pair).map(|r| {
+                if info.token0_is_main {
+                    (r.0, r.1)
+                } else {
+                    (r.1, r.0)
+                }
+            })
+        };
+        let set_reserves =
+            |reserves: &mut HashMap<H160, (U256, U256)>, info: &SwapInfo, main, target| {
+                let value = if info.token0_is_main {
+                    (main, target)
+                } else {
+                    (target, main)
+                };
+                reserves.insert(info.target_pair, value);
+            };
+        // frontrun: 买入
+        for (i, sandwich) in self.sandwiches.iter().enumerate() {
+            let info = &sandwich.swap_info;
+            if info.version == BALANCER_V2_VERSION {
+                match tax_of(info.target_token) {
+                    Some(tax) if !tax.has_tax() => {}
+                    _ => continue,
+                }
+                bought[i] = balancer_swap(
+                    &mut balancer,
+                    info,
+                    info.main_currency,
+                    info.target_token,
+                    sandwich.amount_in,
+                )
+                .unwrap_or_default();
+                continue;
+            }
+            let (tax, (r_main, r_target)) =
+                match (tax_of(info.target_token), get_reserves(&reserves, info)) {
+                    (Some(tax), Some(r)) if info.version == 2 => (tax, r),
+                    _ => continue,
+                };
+            let (pair_out, received) =
+                get_v2_amount_out_with_tax(sandwich.amount_in, r_main, r_target, 0, tax.buy_tax);
+            bought[i] = received;
+            set_reserves(
+                &mut reserves,
+                info,
+                r_main + sandwich.amount_in,
+                r_target - pair_out,
+            );
+        }
+        // victim: 受害者的买入
+        for (i, sandwich) in self.sandwiches.iter().enumerate() {
+            let info = &sandwich.swap_info;
+            if bought[i].is_zero() {
+                continue;
+            }
+            if info.version == BALANCER_V2_VERSION {
+                // 超过输入上限时受害者的交易会 revert 余额不变
+                balancer_swap(
+                    &mut balancer,
+                    info,
+                    info.main_currency,
+                    info.target_token,
+                    info.amount_in,
+                );
+                continue;
+            }
+            let (r_main, r_target) = get_reserves(&reserves, info).unwrap();
+            let victim_out = get_v2_amount_out(info.amount_in, r_main, r_target);
+            set_reserves(
+                &mut reserves,
+                info,
+                r_main + info.amount_in,
+                r_target - victim_out,
+            );
+        }
+        // backrun: 卖出 frontrun 实际收到的全部代币
+        for (i, sandwich) in self.sandwiches.iter().enumerate() {
+            let info = &sandwich.swap_info;
+            if bought[i].is_zero() {
+                continue;
+            }
+            let main_received = if info.version == BALANCER_V2_VERSION {
+                balancer_swap(
+                    &mut balancer,
+                    info,
+                    info.target_token,
+                    info.main_currency,
+                    bought[i],
+                )
+                .unwrap_or_default()
+            } else {
+                let tax = tax_of(info.target_token).unwrap();
+                let (r_main, r_target) = get_reserves(&reserves, info).unwrap();
+                let (main_out, main_received) =
+                    get_v2_amount_out_with_tax(bought[i], r_target, r_main, tax.sell_tax, 0);
+                let target_in = apply_tax(bought[i], tax.sell_tax);
+                set_reserves(&mut reserves, info, r_main - main_out, r_target + target_in);
+                main_received
+            };
+            let profit = I256::from_raw(main_received) - I256::from_raw(sandwich.amount_in);
+            *revenue.entry(info.main_currency).or_insert(I256::zero()) += profit;
+        }
+        revenue
+    }
     // 获取目标交易哈希
     pub fn victim_tx_hashes(&self) {}
     // 获取目标代币
@@ -305,18 +667,99 @@ impl BatchSandwich {
     pub fn encode_frontrun_tx() {}
     // 编码后置交易（卖出）
     pub fn encode_backrun_tx() {}
-    // 模拟执行
-    pub async fn simulate(
+    /// 在模拟器里按打包顺序执行: 所有 frontrun -> 所有 victim -> 所有 backrun
+    /// owner 直接和 V2 池子交互(主币转入池子再调用 swap) Balancer 的三明治不模拟
+    /// 返回按主要货币统计的收益和前后交易用掉的 gas gas 成本由调用方换算成主币后扣除
+    /// 模拟器的数据库是同步接口 要在 spawn_blocking 里调用
+    pub fn simulate(
         &self,
-        provider: Arc<Provider<Ws>>,
+        provider: Arc<RpcProvider>,
         owner: Option<H160>,
         block_number: U64,
         base_fee: U256,
         max_fee: U256,
-        front_access_list: Option<AccessList>,
-        back_access_list: Option<AccessList>,
-        bot_address: Option<H160>,
-    ) {
-        let mut simulator = 
+    ) -> Result<SimulatedBundle> {
+        let sandwiches: Vec<&Sandwich> = self
+            .sandwiches
+            .iter()
+            .filter(|s| s.swap_info.version == 2)
+            .collect();
+        if sandwiches.is_empty() {
+            return Err(anyhow!("No V2 sandwich to simulate"));
+        }
+        let mut simulator = EvmSimulator::new(provider, owner, block_number);
+        simulator.set_base_fee(base_fee);
+        let owner = simulator.owner;
+        simulator.set_eth_balance(owner, U256::from(10).pow(U256::from(20)))?;
+        // 所有 frontrun 需要的主币 直接写进 owner 的余额
+        let mut total_in: HashMap<H160, U256> = HashMap::new();
+        for sandwich in &sandwiches {
+            *total_in
+                .entry(sandwich.swap_info.main_currency)
+                .or_insert(U256::zero()) += sandwich.amount_in;
+        }
+        for (main_currency, amount) in &total_in {
+            let mc = get_main_currency(*main_currency)
+                .ok_or_else(|| anyhow!("Unknown main currency {:?}", main_currency))?;
+            simulator.set_token_balance(*main_currency, owner, mc.balance_slot, *amount)?;
+        }
+
+        let mut bundle = SimulatedBundle::default();
+        // frontrun: 买入 记录实际收到的目标代币(已经扣了买入税)
+        let mut bought = Vec::with_capacity(sandwiches.len());
+        for sandwich in &sandwiches {
+            let info = &sandwich.swap_info;
+            let (received, gas_used) = v2_swap(
+                &mut simulator,
+                info.target_pair,
+                info.main_currency,
+                info.target_token,
+                sandwich.amount_in,
+                max_fee,
+            )
+            .map_err(|e| anyhow!("Frontrun {:?} failed: {:?}", info.target_pair, e))?;
+            bought.push(received);
+            bundle.front_gas_used += gas_used;
+        }
+        // victim: 同一笔交易只执行一次
+        let mut executed = Vec::new();
+        for sandwich in &sandwiches {
+            let victim_tx = &sandwich.victim_tx;
+            if executed.contains(&victim_tx.tx_hash) {
+                continue;
+            }
+            executed.push(victim_tx.tx_hash);
+            simulator
+                .call(Tx {
+                    caller: victim_tx.from,
+                    transact_to: victim_tx.to,
+                    data: victim_tx.data.clone(),
+                    value: victim_tx.value,
+                    gas_price: victim_tx.gas_price,
+                    gas_limit: victim_tx.gas_limit.unwrap_or(5000000),
+                })
+                .map_err(|e| anyhow!("Victim {:?} failed: {:?}", victim_tx.tx_hash, e))?;
+        }
+        // backrun: 卖出 frontrun 收到的全部代币
+        for (sandwich, amount) in sandwiches.iter().zip(bought) {
+            let info = &sandwich.swap_info;
+            let (received, gas_used) = v2_swap(
+                &mut simulator,
+                info.target_pair,
+                info.target_token,
+                info.main_currency,
+                amount,
+                max_fee,
+            )
+            .map_err(|e| anyhow!("Backrun {:?} failed: {:?}", info.target_pair, e))?;
+            bundle.back_gas_used += gas_used;
+            let profit = I256::from_raw(received) - I256::from_raw(sandwich.amount_in);
+            *bundle
+                .revenue
+                .entry(info.main_currency)
+                .or_insert(I256::zero()) += profit;
+        }
+        bundle.gas_cost = base_fee * U256::from(bundle.front_gas_used + bundle.back_gas_used);
+        Ok(bundle)
     }
 }
@@ -1,47 +1,68 @@
-use std::{collections::HashMap, str::FromStr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
 
 use crate::{
     common::{
         alert::Alert,
+        balancer::{load_all_balancer_pools, BalancerPool},
         constants::Env,
         execution::Executor,
-        pools::{load_all_pools, Pool},
-        tokens::load_all_tokens,
+        liquidity::{LiquidityConfig, LiquidityTiers},
+        pools::{load_all_pools, Pool, RejectedPairs, REJECTED_PAIRS_CAPACITY},
+        reorg::ReorgWatcher,
+        reserves::ReserveBook,
+        risk::RiskPolicy,
+        store::Store,
+        tokens::{load_all_tokens, load_pool_tokens},
+        transport::RpcProvider,
         utils::calculate_next_block_base_fee,
     },
     sandwich::{
-        simulation::{extract_swap_info, PendingTxInfo, Sandwich},
+        analyzer::MarketState,
+        appetizer::appetizer,
+        nonces::{Admission, NonceIndex},
+        prefilter::{v2_router_path, PreFilter, PreFilterConfig},
+        simulation::{PendingTxInfo, Sandwich},
         streams::NewBlock,
+        tracer::{TraceResult, TracerConfig, TracerPool},
     },
 };
 use bounded_vec_deque::BoundedVecDeque;
 use ethers::{
-    signers::{LocalWallet, Signer},
+    signers::Signer,
     types::{BlockNumber, H160, H256, U256, U64},
 };
 use ethers_core::k256::elliptic_curve::rand_core::block;
-use ethers_providers::{Middleware, Provider, Ws};
+use ethers_providers::Middleware;
 use futures::executor;
+use anyhow::{anyhow, Result};
 use log::info;
-use tokio::sync::broadcast::Sender;
 
-use super::streams::Event;
+use super::{bus::EventBus, streams::Event};
+
+/// 策略循环的输入: 事件总线的事件 / 工作池追踪完成的交易
+enum StrategyEvent {
+    Bus(Event),
+    Traced(TraceResult),
+}
 // 获取所有的池子->
-pub async fn run_sandwich_strategy(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
+/// 事件总线关闭时返回 Ok 节点请求失败时返回错误 由调用方重新连接并重启
+pub async fn run_sandwich_strategy(provider: Arc<RpcProvider>, bus: EventBus) -> Result<()> {
     /////////////////////////
     ////////基础配置/////////
     ////////////////////////
-    let env = Env::new();
+    let env = Env::new()?;
+    // 数据库只打开一次 加载 / 重组检查 / 追踪工作线程共享
+    let store = Store::open_shared()?;
 
     // 获取所有的池子
-    let (pools, prev_pool_id) = load_all_pools(env.wss_url.clone(), 10000000, 50000)
-        .await
-        .unwrap();
+    let (pools, _) = load_all_pools(&provider, &store, 10000000, 50000).await?;
     // 根据最新区块获取池子所有token信息 并进行表绑定
-    let block_number = provider.get_block_number().await.unwrap();
-    let tokens_map = load_all_tokens(&provider, block_number, &pools, prev_pool_id)
-        .await
-        .unwrap();
+    let block_number = provider.get_block_number().await?;
+    let tokens_map = load_all_tokens(&provider, &store, block_number, &pools).await?;
     info!("Tokens map count: {:?}", tokens_map.len());
     // 过滤掉没有存储token信息的池子
     let pools_vec: Vec<Pool> = pools
@@ -59,40 +80,50 @@ pub async fn run_sandwich_strategy(provider: Arc<Provider<Ws>>, event_sender: Se
         .into_iter()
         .map(|p| (p.address, p))
         .collect();
+    // 确认过不是 UniswapV2 池子的地址 不再查询节点
+    let rejected_pairs = RejectedPairs::new(REJECTED_PAIRS_CAPACITY);
+    // 储备量账本 启动时批量读取 之后用每个区块的 Sync 事件更新
+    let mut reserve_book = ReserveBook::new();
+    reserve_book
+        .init(&provider, pools_map.keys().cloned().collect(), block_number)
+        .await?;
+    // 按主币储备量给池子分层 流动性不足的池子不追踪不模拟
+    let mut liquidity_tiers = LiquidityTiers::new(LiquidityConfig::default());
+    liquidity_tiers.refresh(&pools_map, &reserve_book, block_number.as_u64());
+    // Balancer 加权池 swap 都从 Vault 发出 需要用 poolId 索引
+    let balancer_pools = load_all_balancer_pools(&provider, &store, 50000).await?;
+    let balancer_pools_map: HashMap<H256, BalancerPool> = balancer_pools
+        .into_iter()
+        .map(|p| (p.pool_id, p))
+        .collect();
+    info!("Balancer pools count: {:?}", balancer_pools_map.len());
     // 获取最新的区块信息
     let block = provider
         .get_block(BlockNumber::Latest)
-        .await
-        .unwrap()
-        .unwrap();
+        .await?
+        .ok_or_else(|| anyhow!("Latest block not found"))?;
+    let base_fee = block
+        .base_fee_per_gas
+        .ok_or_else(|| anyhow!("Latest block has no base fee"))?;
     // 最新区块信息 新的区块事件 会不断覆盖这个数据结构
     let mut new_block = NewBlock {
-        block_number: block.number.unwrap(),
-        base_fee: block.base_fee_per_gas.unwrap(),
-        next_base_fee: calculate_next_block_base_fee(
-            block.gas_used,
-            block.gas_limit,
-            block.base_fee_per_gas.unwrap(),
-        ),
+        block_number: block
+            .number
+            .ok_or_else(|| anyhow!("Latest block has no number"))?,
+        base_fee,
+        next_base_fee: calculate_next_block_base_fee(block.gas_used, block.gas_limit, base_fee),
     };
     // 创建Tg Alert
-    let alert = Alert::new();
-    // 创建执行器实例flash bot
-    let executor = Executor::new(provider);
-    // 三明治机器人合约地址
-    let bot_address = H160::from_str(&env.bot_address).unwrap();
-    //    钱包signer
-    let wallet = env
-        .private_key
-        .parse::<LocalWallet>()
-        .unwrap()
-        .with_chain_id(1 as u64);
+    let alert = Alert::new()?;
+    // 创建执行器实例flash bot 合约地址和私钥在这里检查
+    let executor = Executor::new(provider.clone())?;
     // owner地址
-    let owner = wallet.address();
+    let owner = executor.owner.address();
     /////////////////////////
     ///////////交易//////////
     ////////////////////////
-    let mut event_receiver = event_sender.subscribe();
+    // 新区块优先 处理太慢跳过的区块以 Gap 事件补上
+    let mut event_receiver = bus.subscribe();
     // 为什么需要这个 HashMap:
     // 三明治交易需要追踪和分析待处理的交易来寻找套利机会
     // 需要快速查找和更新交易状态(HashMap 提供 O(1) 的查找效率)
@@ -102,24 +133,151 @@ pub async fn run_sandwich_strategy(provider: Arc<Provider<Ws>>, event_sender: Se
     // 创建一个新的 HashMap 用于存储潜在的三明治交易机会 用于记录和跟踪可能的套利机会
     let mut promising_sandwiches: HashMap<H256, Vec<Sandwich>> = HashMap::new();
     let mut simulated_bundle_ids = BoundedVecDeque::new(30);
+    // 池子 / 代币 / 分层交给追踪工作池共享 工作线程里解析 swap 和测量代币
+    let market = Arc::new(MarketState {
+        pools: RwLock::new(pools_map),
+        tokens: RwLock::new(tokens_map),
+        balancer_pools: balancer_pools_map,
+        rejected_pairs: Mutex::new(rejected_pairs),
+        tiers: RwLock::new(liquidity_tiers),
+        // 允许夹哪些风险的代币
+        risk_policy: RiskPolicy::default(),
+        owner,
+        store,
+    });
+    // debug_traceCall 之前的过滤 转账 / 授权 / 不是发往路由的交易直接跳过
+    let mut prefilter = PreFilter::new(PreFilterConfig::from_env()?)?;
+    // 回执检查和追踪在工作池里并发进行 不阻塞区块处理
+    let mut tracer = TracerPool::start(
+        provider.clone(),
+        TracerConfig {
+            workers: env.trace_workers,
+            timeout: Duration::from_millis(env.trace_timeout_ms),
+            measure_timeout: Duration::from_millis(env.measure_timeout_ms),
+            ..Default::default()
+        },
+        market.clone(),
+    );
+    // 回放时等追踪中的交易也处理完 再发送下一个事件
+    event_receiver.track_background(tracer.outstanding());
+    // 最新区块里已经上链的交易 追踪结果晚到时用来丢弃
+    let mut landed_txs: HashSet<H256> = HashSet::new();
+    // 按 (sender, nonce) 索引追踪中和已经解析的交易 处理加速 / 取消交易
+    let mut nonce_index = NonceIndex::new();
+    // 链头接着上一个区块时只扫描新区块 出现重组 / 跳过区块时才完整检查
+    let mut reorg_watcher = ReorgWatcher::new();
+    // 重新扫描到的池子里还没有收录的代币 每个区块重试读取
+    let mut unresolved_pools: Vec<Pool> = Vec::new();
     //    接受线程消息 执行策略
     loop {
-        match event_receiver.recv().await {
-            Ok(event) => match event {
+        // 追踪完成的交易优先 数量受工作池限制 不会把区块事件饿死
+        let event = tokio::select! {
+            biased;
+            Some(traced) = tracer.recv() => Ok(StrategyEvent::Traced(traced)),
+            event = event_receiver.recv() => event.map(StrategyEvent::Bus),
+        };
+        match event {
+            Ok(StrategyEvent::Bus(event)) => match event {
                 // 接受新的区块生成打包消息 如果区块中的交易txs 在pending_txs中存在 那么代表交易已经完成  直接清除
                 Event::Block(block) => {
                     // 更新最新区块信息
                     new_block = block;
-                    let block_with_txs = provider
-                        .get_block_with_txs(new_block.block_number)
+                    // 应用区块内的 Sync 事件 漏掉的区块会自动重新同步
+                    match reserve_book.update(&provider, new_block.block_number).await {
+                        Ok(_) => {}
+                        Err(e) => info!("Reserve book update failed: {:?}", e),
+                    }
+                    if new_block.block_number.as_u64() % 100 == 0 {
+                        prefilter.log_stats();
+                        tracer.log_stats();
+                        nonce_index.log_stats();
+                        info!(
+                            "Rejected pairs: {:?}",
+                            market.rejected_pairs.lock().unwrap().len()
+                        );
+                    }
+                    // 定期用最新的储备量重新分层
+                    {
+                        let pools_map = market.pools.read().unwrap();
+                        market.tiers.write().unwrap().maybe_refresh(
+                            &pools_map,
+                            &reserve_book,
+                            new_block.block_number.as_u64(),
+                        );
+                    }
+                    // 重组检查: 被重组掉的池子移除 分叉点之后重新扫描到的池子加入
+                    // 代币还没有收录的池子进入队列 读取代币之后再加入
+                    let block_number = new_block.block_number.as_u64();
+                    match reorg_watcher
+                        .check(&provider, &market.store, block_number)
+                        .await
+                    {
+                        Ok(outcome) => {
+                            unresolved_pools
+                                .retain(|pool| !outcome.removed.contains(&pool.address));
+                            unresolved_pools.extend(outcome.added);
+                            let removed = outcome.removed;
+                            let mut pools_map = market.pools.write().unwrap();
+                            for pair in removed {
+                                pools_map.remove(&pair);
+                                reserve_book.reserves.remove(&pair);
+                            }
+                        }
+                        Err(e) => info!("Reorg check failed: {:?}", e),
+                    }
+                    if !unresolved_pools.is_empty() {
+                        match load_pool_tokens(
+                            &provider,
+                            &market.store,
+                            &market.tokens,
+                            new_block.block_number,
+                            &unresolved_pools,
+                        )
                         .await
-                        .unwrap()
-                        .unwrap();
-                    let txs = block_with_txs
-                        .transactions
-                        .into_iter()
-                        .map(|tx| tx.hash)
-                        .collect();
+                        {
+                            Ok((resolved, pending)) => {
+                                unresolved_pools = pending;
+                                let added: Vec<H160> =
+                                    resolved.iter().map(|pool| pool.address).collect();
+                                {
+                                    let mut pools_map = market.pools.write().unwrap();
+                                    for pool in resolved {
+                                        pools_map.insert(pool.address, pool);
+                                    }
+                                }
+                                reserve_book
+                                    .track(&provider, added.clone(), new_block.block_number)
+                                    .await;
+                                let pools_map = market.pools.read().unwrap();
+                                market.tiers.write().unwrap().update_pairs(
+                                    &added,
+                                    &pools_map,
+                                    &reserve_book,
+                                );
+                            }
+                            Err(e) => info!("Pool tokens fetch failed: {:?}", e),
+                        }
+                    }
+                    let landed = match provider.get_block_with_txs(new_block.block_number).await {
+                        Ok(Some(block)) => block.transactions,
+                        // 节点还没有这个区块 里面的交易之后按 3 个区块过期
+                        Ok(None) => Vec::new(),
+                        // 节点连接断开 退出策略 由 main 重新连接节点并重启
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "Fetch block {:?} failed: {:?}",
+                                new_block.block_number,
+                                e
+                            ))
+                        }
+                    };
+                    // 同一个 nonce 的其它交易上链了 受害者交易永远不会上链
+                    let stale = nonce_index.on_landed(&landed);
+                    let mut txs: Vec<H256> = landed.into_iter().map(|tx| tx.hash).collect();
+                    txs.extend(stale);
+                    // 已经上链的交易不用再追踪
+                    tracer.cancel(&txs);
+                    landed_txs = txs.iter().cloned().collect();
                     // 检查pendingtx
                     for tx_hash in &txs {
                         if pending_txs.contains_key(tx_hash) {
@@ -139,27 +297,49 @@ pub async fn run_sandwich_strategy(provider: Arc<Provider<Ws>>, event_sender: Se
                     });
                     // 确保 promising_sandwiches 中的交易都存在于 pending_txs 中  每次新区区块都要判定一次
                     promising_sandwiches.retain(|h, _| pending_txs.contains_key(h));
+                    nonce_index.prune(new_block.block_number, 3);
+                    // 更早的 nonce 上链之后 受害者交易变成可以打包
+                    for info in pending_txs.values_mut() {
+                        info.executable = nonce_index.is_executable(&info.pending_tx.tx);
+                    }
                 }
-                Event::PendingTx(mut pending_tx) => {
+                // 订阅重连期间漏掉的区块 / 处理太慢被跳过的区块: 储备量在下一个区块自动重新同步
+                // 这里只补做已上链交易的清理 pending_txs 只保留 3 个区块 更早的区块不用看
+                Event::Gap(gap) => {
+                    let from_block = std::cmp::max(
+                        gap.from_block.as_u64(),
+                        gap.to_block.as_u64().saturating_sub(2),
+                    );
+                    for block_number in from_block..=gap.to_block.as_u64() {
+                        let block_with_txs = match provider.get_block_with_txs(block_number).await {
+                            Ok(Some(block)) => block,
+                            _ => continue,
+                        };
+                        let mut txs: Vec<H256> = block_with_txs
+                            .transactions
+                            .iter()
+                            .map(|tx| tx.hash)
+                            .collect();
+                        txs.extend(nonce_index.on_landed(&block_with_txs.transactions));
+                        tracer.cancel(&txs);
+                        for tx_hash in &txs {
+                            pending_txs.remove(tx_hash);
+                            promising_sandwiches.remove(tx_hash);
+                        }
+                        landed_txs.extend(txs);
+                    }
+                    info!(
+                        "Backfilled blocks {:?}..={:?} / Pending txs: {:?}",
+                        from_block,
+                        gap.to_block,
+                        pending_txs.len()
+                    );
+                }
+                Event::PendingTx(pending_tx) => {
                     let tx_hash = pending_tx.tx.hash;
-                    // 检查是否已经处理过这笔交易
+                    // 检查是否已经处理过这笔交易 回执检查在工作池里做
                     let already_received = pending_txs.contains_key(&tx_hash);
                     let mut should_add = false;
-                    // 如果是已经接受的pending_tx 检查是否有交易回执 如果有交易回执 证明交易已经被处理
-                    if !already_received {
-                        let tx_receipt = provider.get_transaction_receipt(tx_hash).await;
-                        match tx_receipt {
-                            Ok(receipt) => match receipt {
-                                Some(_) => {
-                                    pending_txs.remove(&tx_hash);
-                                }
-                                None => {
-                                    should_add = true;
-                                }
-                            },
-                            _ => {}
-                        }
-                    }
                     /////////////////////////////////////////
                     /////////未被处理的交易///////
                     /////////////////////////////////////////
@@ -180,36 +360,124 @@ pub async fn run_sandwich_strategy(provider: Arc<Provider<Ws>>, event_sender: Se
                         }
                         _ => {}
                     }
-                    // 如果应该添加进三明治 解析出 swap 信息
-                    let swap_info = if should_add {
-                        match extract_swap_info(&provider, &new_block, &pending_tx, &pools_map)
-                            .await
-                        {
-                            Ok(swap_info) => swap_info,
-                            Err(e) => Vec::new(),
-                        }
-                    } else {
-                        Vec::new()
-                    };
-                    // 如果有 swap 信息
-                    if swap_info.len() > 0 {
-                        pending_tx.added_block = Some(new_block.block_number);
-                        let pending_tx_info = PendingTxInfo {
-                            pending_tx: pending_tx.clone(),
-                            touched_pairs: swap_info.clone(),
+                    if already_received {
+                        continue;
+                    }
+                    // 如果应该添加进三明治 先用交易字段过滤 再交给工作池检查回执并追踪
+                    let should_trace = should_add && prefilter.allows(&pending_tx.tx);
+                    // UniswapV2 路由的交易 path 上的池子都不值得模拟时不追踪
+                    let should_trace = should_trace
+                        && match v2_router_path(&pending_tx.tx) {
+                            Some(path) => market
+                                .tiers
+                                .read()
+                                .unwrap()
+                                .is_path_worth_tracing(&path, new_block.next_base_fee),
+                            None => true,
                         };
-                        pending_txs.insert(tx_hash, pending_tx_info.clone());
-                        // info!(
-                        //     "🔴 V{:?} TX ADDED: {:?} / Pending txs: {:?}",
-                        //     pending_tx_info.touched_pairs.get(0).unwrap().version,
-                        //     tx_hash,
-                        //     pending_txs.len()
-                        // );
-                        match apptizer() {}
+                    // 不需要追踪的交易也可能是受害者的加速 / 取消交易 (例如取消交易是转给自己)
+                    if !should_trace && !nonce_index.contains(&pending_tx.tx) {
+                        continue;
+                    }
+                    match nonce_index.admit(&pending_tx.tx, new_block.block_number) {
+                        Admission::New => {}
+                        Admission::Replaces(stale) => {
+                            // 旧交易永远不会上链 删除它和基于它的三明治 替换交易重新追踪
+                            tracer.cancel(&[stale]);
+                            pending_txs.remove(&stale);
+                            promising_sandwiches.remove(&stale);
+                            info!("Victim {:?} replaced by {:?}", stale, tx_hash);
+                        }
+                        Admission::Known | Admission::Underpriced(_) | Admission::Stale => {
+                            continue;
+                        }
+                    }
+                    if !should_trace || !tracer.submit(pending_tx, new_block.clone()) {
+                        nonce_index.remove(&tx_hash);
                     }
                 }
             },
-            _ => {}
+            // 工作池追踪并分析完成的交易
+            // 没有分析结果的交易 不会再有结果送回来
+            Ok(StrategyEvent::Traced(TraceResult::Dropped(tx_hash))) => {
+                nonce_index.remove(&tx_hash);
+            }
+            Ok(StrategyEvent::Traced(TraceResult::Traced(traced))) => {
+                let analysis = traced.analysis;
+                let mut pending_tx_info = analysis.info;
+                let tx_hash = pending_tx_info.pending_tx.tx.hash;
+                // 追踪完成到这里之间上链的交易
+                if landed_txs.contains(&tx_hash) || pending_txs.contains_key(&tx_hash) {
+                    continue;
+                }
+                // 追踪期间被替换的交易 / nonce 已经用掉的同一账户的其它交易
+                let stale = nonce_index
+                    .set_next_nonce(pending_tx_info.pending_tx.tx.from, traced.next_nonce);
+                tracer.cancel(&stale);
+                for tx_hash in &stale {
+                    pending_txs.remove(tx_hash);
+                    promising_sandwiches.remove(tx_hash);
+                }
+                if !nonce_index.is_current(&tx_hash) {
+                    continue;
+                }
+                // 工作线程里新发现的池子 加入储备量账本 之后用 Sync 事件更新
+                for (pair, reserves) in analysis.reserves {
+                    reserve_book.reserves.entry(pair).or_insert(reserves);
+                }
+                // 如果有 swap 信息
+                if pending_tx_info.touched_pairs.len() > 0 {
+                    pending_tx_info.executable =
+                        nonce_index.is_executable(&pending_tx_info.pending_tx.tx);
+                    // 涉及到的主币的 gas 成本 在这里换算好 不要把读锁带进 await
+                    let gas_costs: HashMap<H160, U256> = {
+                        let tiers = market.tiers.read().unwrap();
+                        pending_tx_info
+                            .touched_pairs
+                            .iter()
+                            .filter_map(|info| {
+                                tiers
+                                    .gas_cost_in(info.main_currency, new_block.next_base_fee)
+                                    .map(|gas_cost| (info.main_currency, gas_cost))
+                            })
+                            .collect()
+                    };
+                    let pending_tx = pending_tx_info.pending_tx.clone();
+                    pending_txs.insert(tx_hash, pending_tx_info);
+                    // info!(
+                    //     "🔴 V{:?} TX ADDED: {:?} / Pending txs: {:?}",
+                    //     pending_tx_info.touched_pairs.get(0).unwrap().version,
+                    //     tx_hash,
+                    //     pending_txs.len()
+                    // );
+                    let victim_gas_price = pending_tx
+                        .tx
+                        .max_fee_per_gas
+                        .or(pending_tx.tx.gas_price)
+                        .unwrap_or_default();
+                    appetizer(
+                        &provider,
+                        &new_block,
+                        tx_hash,
+                        victim_gas_price,
+                        &pending_txs,
+                        &mut promising_sandwiches,
+                        &reserve_book,
+                        &analysis.balancer,
+                        &analysis.tokens,
+                        &gas_costs,
+                    )
+                    .await;
+                } else {
+                    // 不是受害者 不用再关心它的替换交易
+                    nonce_index.remove(&tx_hash);
+                }
+            }
+            Err(e) => {
+                info!("Event receiver stopped: {:?}", e);
+                break;
+            }
         }
     }
+    Ok(())
 }
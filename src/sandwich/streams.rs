@@ -2,14 +2,22 @@
 ////区块与pendingTx事件监听///
 ////////////////////////////////////////
 
-use crate::common::utils::calculate_next_block_base_fee;
-use ethers::types::*;
-use ethers_providers::{Middleware, Provider, Ws};
-use std::sync::Arc;
-use tokio::sync::broadcast::Sender;
-use tokio_stream::StreamExt;
+use crate::common::{
+    transport::{connect_provider, RpcProvider},
+    utils::calculate_next_block_base_fee,
+};
+use ethers::{types::*, utils::serialize};
+use ethers_providers::{Middleware, ProviderError};
+use futures::stream::BoxStream;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::time::{sleep, timeout};
+use tokio_stream::{iter, Stream, StreamExt};
+
+use super::{bus::EventBus, recorder::source_label};
 /// 新区块的信息
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct NewBlock {
     /// 区块号
     pub block_number: U64,
@@ -19,13 +27,19 @@ pub struct NewBlock {
     pub next_base_fee: U256,
 }
 /// 待处理交易的信息
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct NewPendingTx {
     /// 交易被添加时的区块号。None 表示刚收到还未分配区块号
     pub added_block: Option<U64>,
     /// 交易的完整信息,包含 gas、nonce、数据等
     pub tx: Transaction,
 }
+/// 订阅重连期间漏掉的区块范围 (含两端) 策略据此补处理这些区块
+#[derive(Default, Debug, Clone)]
+pub struct BlockGap {
+    pub from_block: U64,
+    pub to_block: U64,
+}
 /// 事件枚举,用于在不同组件间传递区块和交易信息
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -33,54 +47,235 @@ pub enum Event {
     Block(NewBlock),
     /// 新的待处理交易事件
     PendingTx(NewPendingTx),
+    /// 漏掉的区块 在下一个 Block 事件之前发送
+    Gap(BlockGap),
+}
+/// 订阅断开 / 卡住后的重连参数
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// 超过这个时间没有收到任何消息 认为订阅卡住了 主动重连
+    pub stall_timeout: Duration,
+    /// 重连失败后的等待时间 每次翻倍 直到 max_backoff 收到消息后恢复
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+impl ReconnectConfig {
+    pub fn new(stall_timeout: Duration) -> Self {
+        Self {
+            stall_timeout,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+/// 连接节点 失败时按指数退避一直重试
+pub async fn connect_with_backoff(
+    url: &str,
+    config: &ReconnectConfig,
+    backoff: &mut Duration,
+) -> Arc<RpcProvider> {
+    loop {
+        match connect_provider(url).await {
+            Ok(provider) => return provider,
+            Err(e) => {
+                warn!("Connect to {:?} failed, retry in {:?}: {:?}", url, backoff, e);
+                wait_backoff(config, backoff).await;
+            }
+        }
+    }
+}
+/// 等待当前的退避时间 然后翻倍
+pub async fn wait_backoff(config: &ReconnectConfig, backoff: &mut Duration) {
+    sleep(*backoff).await;
+    *backoff = std::cmp::min(*backoff * 2, config.max_backoff);
 }
 // 新区快
-pub async fn stream_new_block(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
-    let stream = provider.subscribe_blocks().await.unwrap();
-    // 格式化
-    let mut stream = stream.filter_map(|block| match block.number {
-        Some(number) => Some(NewBlock {
-            block_number: number,
-            base_fee: block.base_fee_per_gas.unwrap(),
-            next_base_fee: calculate_next_block_base_fee(
-                block.gas_used,
-                block.gas_limit,
-                block.base_fee_per_gas.unwrap_or_default(),
-            ),
-        }),
-        None => None,
-    });
-    // 发送事件
-    while let Some(block) = stream.next().await {
-        match event_sender.send(Event::Block(block)) {
-            Ok(_) => {}
-            Err(_) => {}
+// 断开或者 stall_timeout 内没有新区块时重新连接订阅 区块号不连续时先发送 Gap 事件
+pub async fn stream_new_block(url: String, bus: EventBus, config: ReconnectConfig) {
+    let source = source_label(&url);
+    let mut backoff = config.initial_backoff;
+    let mut last_block: Option<U64> = None;
+    loop {
+        let provider = connect_with_backoff(&url, &config, &mut backoff).await;
+        let mut stream = match provider.subscribe_blocks().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Block subscription failed, retry in {:?}: {:?}", backoff, e);
+                wait_backoff(&config, &mut backoff).await;
+                continue;
+            }
+        };
+        loop {
+            let block = match timeout(config.stall_timeout, stream.next()).await {
+                Ok(Some(block)) => block,
+                Ok(None) => {
+                    warn!("Block subscription closed, reconnecting");
+                    break;
+                }
+                Err(_) => {
+                    warn!("No new block for {:?}, reconnecting", config.stall_timeout);
+                    break;
+                }
+            };
+            backoff = config.initial_backoff;
+            // 格式化
+            let number = match block.number {
+                Some(number) => number,
+                None => continue,
+            };
+            match last_block {
+                Some(last) if number > last + 1 => {
+                    warn!("Missed blocks {:?}..={:?}", last + 1, number - 1);
+                    bus.publish(&source, Event::Gap(BlockGap {
+                        from_block: last + 1,
+                        to_block: number - 1,
+                    }));
+                }
+                _ => {}
+            }
+            last_block = Some(std::cmp::max(last_block.unwrap_or_default(), number));
+            // 发送事件
+            bus.publish(&source, Event::Block(NewBlock {
+                block_number: number,
+                base_fee: block.base_fee_per_gas.unwrap_or_default(),
+                next_base_fee: calculate_next_block_base_fee(
+                    block.gas_used,
+                    block.gas_limit,
+                    block.base_fee_per_gas.unwrap_or_default(),
+                ),
+            }));
         }
     }
 }
-// pending交易
-pub async fn stream_pending_transactions(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
-    let stream = provider.subscribe_pending_txs().await.unwrap();
+/// pending 交易的订阅方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingTxMode {
+    /// newPendingTransactions 带 true 参数 节点直接推送完整交易 (geth / reth / Alchemy)
+    /// 省掉每笔交易一次 eth_getTransactionByHash 也不会漏掉已经离开 mempool 的交易
+    FullBody,
+    /// 只订阅哈希 再逐个读取交易内容
+    Hash,
+}
+/// 完整交易订阅推送的一项 节点忽略了 true 参数时推送的是哈希
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PendingItem {
+    Full(Box<Transaction>),
+    Hash(H256),
+}
+/// 按 mode 订阅 pending 交易 统一成交易流
+/// FullBody 订阅失败 或者第一项推送的是哈希(节点忽略了参数)时 把 mode 改成 Hash 并改用哈希订阅
+/// 之后重连也不再尝试 probe_timeout 内没有任何推送只说明 mempool 安静 保留 FullBody
+pub async fn subscribe_pending<'a>(
+    provider: &'a RpcProvider,
+    mode: &mut PendingTxMode,
+    probe_timeout: Duration,
+) -> Result<BoxStream<'a, Transaction>, ProviderError> {
+    if *mode == PendingTxMode::FullBody {
+        let params = [serialize(&"newPendingTransactions"), serialize(&true)];
+        match provider.subscribe::<_, PendingItem>(params).await {
+            Ok(mut stream) => match timeout(probe_timeout, stream.next()).await {
+                Ok(Some(PendingItem::Hash(hash))) => warn!(
+                    "Full pending tx subscription returned hash {:?}, falling back to hash subscription",
+                    hash
+                ),
+                // 第一项已经从流里取出来了 放回到最前面
+                Ok(first) => return Ok(full_pending_stream(first, stream)),
+                Err(_) => {
+                    warn!(
+                        "No pending tx within {:?}, keeping full pending tx subscription",
+                        probe_timeout
+                    );
+                    return Ok(full_pending_stream(None, stream));
+                }
+            },
+            Err(e) => warn!(
+                "Full pending tx subscription failed, falling back to hash subscription: {:?}",
+                e
+            ),
+        }
+        *mode = PendingTxMode::Hash;
+    }
+    let stream = provider.subscribe_pending_txs().await?;
     // transactions_unordered(256)
     // 将交易哈希流转换为实际的交易数据流
     // 256 是并发限制，意味着最多同时处理 256 个交易
     // "unordered" 表示交易的处理结果可能不按原始顺序返回
     // 这是性能优化的一种方式，允许并行处理多个交易
-    // fuse()
-    // 将流转换为 "熔断" 状态
-    // 一旦流返回 None，后续所有的 poll 操作都会返回 None
-    // 这防止了流在结束后被重新激活
-    let mut stream = stream.transactions_unordered(256).fuse();
-    while let Some(result) = stream.next().await {
-        match result {
-            Ok(tx) => match event_sender.send(Event::PendingTx(NewPendingTx {
+    Ok(Box::pin(
+        stream.transactions_unordered(256).filter_map(|result| result.ok()),
+    ))
+}
+/// 完整交易订阅的流 中途混进来的哈希直接丢掉
+fn full_pending_stream<'a, S>(first: Option<PendingItem>, stream: S) -> BoxStream<'a, Transaction>
+where
+    S: Stream<Item = PendingItem> + Send + 'a,
+{
+    Box::pin(iter(first).chain(stream).filter_map(|item| match item {
+        PendingItem::Full(tx) => Some(*tx),
+        PendingItem::Hash(_) => None,
+    }))
+}
+// pending交易
+// 断开或者 stall_timeout 内没有任何交易时重新连接订阅
+pub async fn stream_pending_transactions(
+    url: String,
+    bus: EventBus,
+    config: ReconnectConfig,
+    mut mode: PendingTxMode,
+) {
+    let source = source_label(&url);
+    let mut backoff = config.initial_backoff;
+    loop {
+        let provider = connect_with_backoff(&url, &config, &mut backoff).await;
+        let mut stream = match subscribe_pending(&provider, &mut mode, config.stall_timeout).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Pending tx subscription failed, retry in {:?}: {:?}", backoff, e);
+                wait_backoff(&config, &mut backoff).await;
+                continue;
+            }
+        };
+        loop {
+            let tx = match timeout(config.stall_timeout, stream.next()).await {
+                Ok(Some(tx)) => tx,
+                Ok(None) => {
+                    warn!("Pending tx subscription closed, reconnecting");
+                    break;
+                }
+                Err(_) => {
+                    warn!("No pending tx for {:?}, reconnecting", config.stall_timeout);
+                    break;
+                }
+            };
+            backoff = config.initial_backoff;
+            bus.publish(&source, Event::PendingTx(NewPendingTx {
                 added_block: None,
                 tx,
-            })) {
-                Ok(_) => {}
-                Err(_) => {}
-            },
-            Err(_) => {}
+            }));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn full_pending_stream_keeps_bodies_and_drops_hashes() {
+        let tx = Transaction {
+            hash: H256::repeat_byte(1),
+            ..Default::default()
+        };
+        let full: PendingItem = serde_json::from_value(serde_json::to_value(&tx).unwrap()).unwrap();
+        let hash: PendingItem =
+            serde_json::from_value(serde_json::to_value(H256::repeat_byte(2)).unwrap()).unwrap();
+        assert!(matches!(full, PendingItem::Full(_)));
+        assert!(matches!(hash, PendingItem::Hash(_)));
+
+        let stream = full_pending_stream(Some(full), iter(vec![hash]));
+        let txs: Vec<Transaction> = stream.collect().await;
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].hash, H256::repeat_byte(1));
+    }
+}
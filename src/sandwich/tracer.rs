@@ -0,0 +1,571 @@
+////////////////////////////////////////
+////pending 交易追踪工作池///
+////////////////////////////////////////
+
+// 回执检查和 debug_traceCall 是最慢的两个节点请求 放在策略循环里会卡住区块处理
+// 策略把通过过滤的交易提交给工作池 工作池并发追踪 追踪完成的调用帧在工作线程里分析
+// (swap 解析 / 新池子 / 税率和风险测量) 分析结果再送回策略
+// 追踪有超时 追踪和分析期间上链的交易由策略调用 cancel 取消
+// 没有分析结果的交易(节点没有返回调用帧 / 超时 / 已经上链)也送回策略 由策略删除它的 nonce 索引
+
+use ethers::types::{CallFrame, H256, U256};
+use ethers_providers::Middleware;
+use futures::future::BoxFuture;
+use log::info;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, Semaphore},
+    time::timeout,
+};
+
+use crate::common::transport::RpcProvider;
+
+use super::{
+    analyzer::{analyze, Analysis, MarketState},
+    simulation::debug_trace_call_with_nonce,
+    streams::{NewBlock, NewPendingTx},
+};
+
+#[derive(Debug, Clone)]
+pub struct TracerConfig {
+    /// 同时追踪的交易数
+    pub workers: usize,
+    /// 等待追踪的交易最多多少笔 满了直接丢弃新交易 不会阻塞策略
+    pub queue_capacity: usize,
+    /// 单笔交易回执检查 + 追踪的超时 不包括之后的分析
+    pub timeout: Duration,
+    /// 分析时新代币税率和风险测量的超时
+    pub measure_timeout: Duration,
+}
+impl Default for TracerConfig {
+    fn default() -> Self {
+        Self {
+            workers: 16,
+            queue_capacity: 1024,
+            timeout: Duration::from_secs(3),
+            measure_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// 追踪并分析完成的交易 分析基于交易在 block 状态下的调用帧
+#[derive(Debug, Clone)]
+pub struct TracedTx {
+    pub block: NewBlock,
+    pub analysis: Analysis,
+    /// 发送者在 block 的下一个 nonce 比交易的 nonce 小说明中间有空缺
+    pub next_nonce: U256,
+}
+
+/// 工作池送回策略的结果
+#[derive(Debug, Clone)]
+pub enum TraceResult {
+    Traced(TracedTx),
+    /// 节点没有返回调用帧 / 追踪超时 / 已经上链 没有分析结果
+    /// 策略不会再收到这笔交易的结果 要删除它的 nonce 索引
+    Dropped(H256),
+}
+
+/// 单笔交易的追踪结果 被策略取消的交易不送回策略 其余都会送回
+enum TraceOutcome {
+    Traced(CallFrame, U256),
+    /// 节点没有返回调用帧 / 查询发送者的 nonce 失败
+    Empty,
+    /// 已经有回执 / nonce 已经被用掉 交易上链了或者被替换了
+    Landed,
+    TimedOut,
+    /// 追踪期间交易上链 被策略取消
+    Cancelled,
+}
+
+struct TraceJob {
+    pending_tx: NewPendingTx,
+    block: NewBlock,
+    cancel: oneshot::Receiver<()>,
+}
+
+#[derive(Debug, Default)]
+pub struct TracerStats {
+    pub submitted: AtomicU64,
+    /// 队列满了被丢弃的交易数
+    pub dropped: AtomicU64,
+    pub traced: AtomicU64,
+    pub empty: AtomicU64,
+    pub landed: AtomicU64,
+    pub timed_out: AtomicU64,
+    pub cancelled: AtomicU64,
+}
+
+/// 工作池里每笔交易的两步: 回执检查 + 追踪 / 分析调用帧
+/// 测试里换成不访问节点的实现
+trait TraceWorker: Send + Sync + 'static {
+    fn trace<'a>(
+        &'a self,
+        pending_tx: &'a NewPendingTx,
+        block: &'a NewBlock,
+    ) -> BoxFuture<'a, TraceOutcome>;
+
+    fn analyze<'a>(
+        &'a self,
+        pending_tx: NewPendingTx,
+        block: &'a NewBlock,
+        frame: &'a CallFrame,
+    ) -> BoxFuture<'a, Analysis>;
+}
+
+/// 通过节点追踪 分析时共享市场状态
+struct NodeWorker {
+    provider: Arc<RpcProvider>,
+    market: Arc<MarketState>,
+    measure_timeout: Duration,
+}
+impl TraceWorker for NodeWorker {
+    fn trace<'a>(
+        &'a self,
+        pending_tx: &'a NewPendingTx,
+        block: &'a NewBlock,
+    ) -> BoxFuture<'a, TraceOutcome> {
+        Box::pin(trace(&self.provider, pending_tx, block))
+    }
+
+    fn analyze<'a>(
+        &'a self,
+        pending_tx: NewPendingTx,
+        block: &'a NewBlock,
+        frame: &'a CallFrame,
+    ) -> BoxFuture<'a, Analysis> {
+        Box::pin(analyze(
+            &self.provider,
+            &self.market,
+            pending_tx,
+            block,
+            frame,
+            self.measure_timeout,
+        ))
+    }
+}
+
+/// 策略一侧 提交交易 / 取消交易 / 接收追踪完成的交易
+pub struct TracerPool {
+    jobs: mpsc::Sender<TraceJob>,
+    results: mpsc::UnboundedReceiver<TraceResult>,
+    /// 排队中和追踪中的交易 哈希 -> 取消信号
+    in_flight: Arc<Mutex<HashMap<H256, oneshot::Sender<()>>>>,
+    /// 提交之后还没有处理完的交易: 排队 / 追踪中 / 结果还没被策略取走 / 策略正在处理结果
+    /// 回放时事件总线用它判断策略是不是空闲
+    outstanding: Arc<AtomicUsize>,
+    /// 上一次 recv 返回了结果 下一次调用 recv 说明已经处理完
+    delivered: bool,
+    pub stats: Arc<TracerStats>,
+}
+impl TracerPool {
+    pub fn start(
+        provider: Arc<RpcProvider>,
+        config: TracerConfig,
+        market: Arc<MarketState>,
+    ) -> Self {
+        let worker = NodeWorker {
+            provider,
+            market,
+            measure_timeout: config.measure_timeout,
+        };
+        Self::start_with(worker, config)
+    }
+
+    fn start_with<W: TraceWorker>(worker: W, config: TracerConfig) -> Self {
+        let (jobs, job_receiver) = mpsc::channel(config.queue_capacity);
+        let (result_sender, results) = mpsc::unbounded_channel();
+        let in_flight = Arc::new(Mutex::new(HashMap::new()));
+        let outstanding = Arc::new(AtomicUsize::new(0));
+        let stats = Arc::new(TracerStats::default());
+        tokio::spawn(dispatch(
+            Arc::new(worker),
+            config,
+            job_receiver,
+            result_sender,
+            in_flight.clone(),
+            outstanding.clone(),
+            stats.clone(),
+        ));
+        Self {
+            jobs,
+            results,
+            in_flight,
+            outstanding,
+            delivered: false,
+            stats,
+        }
+    }
+
+    /// 提交交易 在 block 的状态下追踪 已经在排队或者追踪中 / 队列满了返回 false
+    pub fn submit(&self, pending_tx: NewPendingTx, block: NewBlock) -> bool {
+        let tx_hash = pending_tx.tx.hash;
+        let (cancel_sender, cancel) = oneshot::channel();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.contains_key(&tx_hash) {
+            return false;
+        }
+        let job = TraceJob {
+            pending_tx,
+            block,
+            cancel,
+        };
+        match self.jobs.try_send(job) {
+            Ok(_) => {
+                in_flight.insert(tx_hash, cancel_sender);
+                self.outstanding.fetch_add(1, Ordering::AcqRel);
+                self.stats.submitted.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(_) => {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// 取消排队或者追踪中的交易 (例如已经上链)
+    pub fn cancel(&self, tx_hashes: &[H256]) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        for tx_hash in tx_hashes {
+            if let Some(cancel) = in_flight.remove(tx_hash) {
+                let _ = cancel.send(());
+            }
+        }
+    }
+
+    /// 下一笔处理完的交易 工作池停止时返回 None
+    pub async fn recv(&mut self) -> Option<TraceResult> {
+        if self.delivered {
+            self.delivered = false;
+            self.outstanding.fetch_sub(1, Ordering::AcqRel);
+        }
+        let traced = self.results.recv().await;
+        self.delivered = traced.is_some();
+        traced
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+
+    /// 还没有处理完的交易数 交给事件总线的 wait_idle 等待
+    pub fn outstanding(&self) -> Arc<AtomicUsize> {
+        self.outstanding.clone()
+    }
+
+    pub fn log_stats(&self) {
+        info!(
+            "Tracer: in flight {:?} / submitted {:?} / dropped {:?} / traced {:?} / empty {:?} / landed {:?} / timed out {:?} / cancelled {:?}",
+            self.in_flight(),
+            self.stats.submitted.load(Ordering::Relaxed),
+            self.stats.dropped.load(Ordering::Relaxed),
+            self.stats.traced.load(Ordering::Relaxed),
+            self.stats.empty.load(Ordering::Relaxed),
+            self.stats.landed.load(Ordering::Relaxed),
+            self.stats.timed_out.load(Ordering::Relaxed),
+            self.stats.cancelled.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// 从队列取交易 最多 config.workers 笔同时追踪
+async fn dispatch<W: TraceWorker>(
+    worker: Arc<W>,
+    config: TracerConfig,
+    mut jobs: mpsc::Receiver<TraceJob>,
+    results: mpsc::UnboundedSender<TraceResult>,
+    in_flight: Arc<Mutex<HashMap<H256, oneshot::Sender<()>>>>,
+    outstanding: Arc<AtomicUsize>,
+    stats: Arc<TracerStats>,
+) {
+    let semaphore = Arc::new(Semaphore::new(config.workers));
+    while let Some(job) = jobs.recv().await {
+        let permit = match semaphore.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+        let worker = worker.clone();
+        let results = results.clone();
+        let in_flight = in_flight.clone();
+        let outstanding = outstanding.clone();
+        let stats = stats.clone();
+        let trace_timeout = config.timeout;
+        tokio::spawn(async move {
+            let _permit = permit;
+            run_job(
+                worker,
+                job,
+                trace_timeout,
+                results,
+                in_flight,
+                outstanding,
+                stats,
+            )
+            .await;
+        });
+    }
+}
+
+async fn run_job<W: TraceWorker>(
+    worker: Arc<W>,
+    mut job: TraceJob,
+    trace_timeout: Duration,
+    results: mpsc::UnboundedSender<TraceResult>,
+    in_flight: Arc<Mutex<HashMap<H256, oneshot::Sender<()>>>>,
+    outstanding: Arc<AtomicUsize>,
+    stats: Arc<TracerStats>,
+) {
+    let tx_hash = job.pending_tx.tx.hash;
+    // 排队期间已经被取消的交易直接跳过
+    let outcome = tokio::select! {
+        biased;
+        _ = &mut job.cancel => TraceOutcome::Cancelled,
+        traced = timeout(trace_timeout, worker.trace(&job.pending_tx, &job.block)) => {
+            match traced {
+                Ok(outcome) => outcome,
+                Err(_) => TraceOutcome::TimedOut,
+            }
+        }
+    };
+    let counter = match &outcome {
+        TraceOutcome::Traced(..) => &stats.traced,
+        TraceOutcome::Empty => &stats.empty,
+        TraceOutcome::Landed => &stats.landed,
+        TraceOutcome::TimedOut => &stats.timed_out,
+        TraceOutcome::Cancelled => &stats.cancelled,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    // 分析不计入追踪超时 期间上链的交易照样可以取消
+    let result = match outcome {
+        TraceOutcome::Traced(frame, next_nonce) => tokio::select! {
+            biased;
+            _ = &mut job.cancel => None,
+            analysis = worker.analyze(job.pending_tx, &job.block, &frame) => {
+                Some(TraceResult::Traced(TracedTx {
+                    block: job.block,
+                    analysis,
+                    next_nonce,
+                }))
+            }
+        },
+        TraceOutcome::Empty | TraceOutcome::Landed | TraceOutcome::TimedOut => {
+            Some(TraceResult::Dropped(tx_hash))
+        }
+        // 策略取消时已经处理过这笔交易
+        TraceOutcome::Cancelled => None,
+    };
+    in_flight.lock().unwrap().remove(&tx_hash);
+    match result {
+        Some(result) => {
+            let _ = results.send(result);
+        }
+        // 没有结果送回策略 这笔交易已经处理完
+        None => {
+            outstanding.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// 有回执说明交易已经被处理 不用追踪
+/// 回执查询失败时照常追踪 同时查询发送者的 nonce 查询失败时放弃追踪
+async fn trace(
+    provider: &Arc<RpcProvider>,
+    pending_tx: &NewPendingTx,
+    block: &NewBlock,
+) -> TraceOutcome {
+    match provider.get_transaction_receipt(pending_tx.tx.hash).await {
+        Ok(Some(_)) => return TraceOutcome::Landed,
+        _ => {}
+    }
+    // nonce 查询失败时不追踪 当作 0 的话追踪的 nonce 不对 还会把后面的交易当成有空缺
+    let next_nonce = match provider
+        .get_transaction_count(pending_tx.tx.from, Some(block.block_number.into()))
+        .await
+    {
+        Ok(next_nonce) => next_nonce,
+        Err(_) => return TraceOutcome::Empty,
+    };
+    if pending_tx.tx.nonce < next_nonce {
+        return TraceOutcome::Landed;
+    }
+    // nonce 有空缺时仍然按下一个 nonce 追踪 是否可以打包由策略判断
+    match debug_trace_call_with_nonce(provider, block, pending_tx, next_nonce).await {
+        Ok(Some(frame)) => TraceOutcome::Traced(frame, next_nonce),
+        _ => TraceOutcome::Empty,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Transaction, U64};
+
+    use crate::sandwich::simulation::PendingTxInfo;
+
+    /// 按交易哈希的第一个字节决定追踪结果 不访问节点
+    /// 0x01: 追踪成功 0x02: 没有调用帧 0x03: 超过追踪超时 其它: 追踪要等一会 留给测试取消
+    struct FakeWorker;
+    impl TraceWorker for FakeWorker {
+        fn trace<'a>(
+            &'a self,
+            pending_tx: &'a NewPendingTx,
+            _block: &'a NewBlock,
+        ) -> BoxFuture<'a, TraceOutcome> {
+            Box::pin(async move {
+                match pending_tx.tx.hash.as_bytes()[0] {
+                    0x01 => TraceOutcome::Traced(CallFrame::default(), U256::from(7)),
+                    0x02 => TraceOutcome::Empty,
+                    0x03 => {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        TraceOutcome::Empty
+                    }
+                    _ => {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        TraceOutcome::Traced(CallFrame::default(), U256::zero())
+                    }
+                }
+            })
+        }
+
+        fn analyze<'a>(
+            &'a self,
+            pending_tx: NewPendingTx,
+            _block: &'a NewBlock,
+            _frame: &'a CallFrame,
+        ) -> BoxFuture<'a, Analysis> {
+            Box::pin(async move {
+                Analysis {
+                    info: PendingTxInfo {
+                        pending_tx,
+                        ..Default::default()
+                    },
+                    tokens: HashMap::new(),
+                    reserves: HashMap::new(),
+                    balancer: HashMap::new(),
+                }
+            })
+        }
+    }
+
+    fn start() -> TracerPool {
+        TracerPool::start_with(
+            FakeWorker,
+            TracerConfig {
+                workers: 4,
+                queue_capacity: 16,
+                timeout: Duration::from_millis(100),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn pending_tx(kind: u8, index: u8) -> NewPendingTx {
+        let mut hash = [0u8; 32];
+        hash[0] = kind;
+        hash[31] = index;
+        NewPendingTx {
+            added_block: None,
+            tx: Transaction {
+                hash: H256::from(hash),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn block() -> NewBlock {
+        NewBlock {
+            block_number: U64::from(100),
+            ..Default::default()
+        }
+    }
+
+    async fn recv(tracer: &mut TracerPool) -> Option<TraceResult> {
+        timeout(Duration::from_secs(2), tracer.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn traced_tx_comes_back_with_analysis() {
+        let mut tracer = start();
+        let tx = pending_tx(0x01, 1);
+        let tx_hash = tx.tx.hash;
+        assert!(tracer.submit(tx, block()));
+        match recv(&mut tracer).await {
+            Some(TraceResult::Traced(traced)) => {
+                assert_eq!(traced.analysis.info.pending_tx.tx.hash, tx_hash);
+                assert_eq!(traced.next_nonce, U256::from(7));
+                assert_eq!(traced.block.block_number, U64::from(100));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(tracer.in_flight(), 0);
+        assert_eq!(tracer.stats.traced.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_and_timed_out_traces_are_reported() {
+        let mut tracer = start();
+        let empty = pending_tx(0x02, 1);
+        let slow = pending_tx(0x03, 2);
+        let mut expected = vec![empty.tx.hash, slow.tx.hash];
+        assert!(tracer.submit(empty, block()));
+        assert!(tracer.submit(slow, block()));
+        let mut dropped = Vec::new();
+        for _ in 0..2 {
+            match recv(&mut tracer).await {
+                Some(TraceResult::Dropped(tx_hash)) => dropped.push(tx_hash),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        dropped.sort();
+        expected.sort();
+        assert_eq!(dropped, expected);
+        assert_eq!(tracer.stats.empty.load(Ordering::Relaxed), 1);
+        assert_eq!(tracer.stats.timed_out.load(Ordering::Relaxed), 1);
+        // 策略取走最后一个结果之后再 recv 说明全部处理完
+        assert!(timeout(Duration::from_millis(50), tracer.recv())
+            .await
+            .is_err());
+        assert_eq!(tracer.outstanding().load(Ordering::Acquire), 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_tx_sends_nothing() {
+        let mut tracer = start();
+        let tx = pending_tx(0x04, 1);
+        let tx_hash = tx.tx.hash;
+        assert!(tracer.submit(tx, block()));
+        tracer.cancel(&[tx_hash]);
+        assert!(timeout(Duration::from_millis(500), tracer.recv())
+            .await
+            .is_err());
+        assert_eq!(tracer.in_flight(), 0);
+        assert_eq!(tracer.outstanding().load(Ordering::Acquire), 0);
+        assert_eq!(tracer.stats.cancelled.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn duplicate_submission_is_rejected() {
+        let mut tracer = start();
+        let tx = pending_tx(0x04, 1);
+        assert!(tracer.submit(tx.clone(), block()));
+        assert!(!tracer.submit(tx.clone(), block()));
+        assert_eq!(tracer.stats.submitted.load(Ordering::Relaxed), 1);
+        // 处理完之后可以再次提交
+        assert!(matches!(
+            recv(&mut tracer).await,
+            Some(TraceResult::Traced(_))
+        ));
+        assert!(tracer.submit(tx, block()));
+    }
+}